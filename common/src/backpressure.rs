@@ -0,0 +1,174 @@
+//! Flow control between a component's Kafka consumer and its producer task.
+//!
+//! When the queue of messages waiting to be produced passes a high-water mark, the consumer's
+//! partitions of the input topic are paused, and they are resumed once the queue drops to a low-water mark.
+//! Partitions of any other topic assigned to the consumer are never paused.
+use crate::metrics::names::{CONSUMER_PAUSED, CONSUMER_PAUSED_TIME, SEND_QUEUE_DEPTH};
+use clap::Args;
+use metrics::{counter, gauge};
+use rdkafka::{
+    TopicPartitionList,
+    consumer::{Consumer, ConsumerContext, StreamConsumer},
+    error::KafkaResult,
+};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// How often the queue depth should be checked whilst the consumer is paused.
+pub const BACKPRESSURE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Command line options controlling how a component responds to its send buffer filling.
+#[derive(Clone, Debug, Args)]
+pub struct BackpressureOpts {
+    /// The consumer is paused once this fraction of the send buffer is in use.
+    #[clap(long = "send-buffer-high-water-mark", default_value = "0.75")]
+    pub high_water_mark: f64,
+
+    /// A paused consumer is resumed once no more than this fraction of the send buffer is in use.
+    #[clap(long = "send-buffer-low-water-mark", default_value = "0.25")]
+    pub low_water_mark: f64,
+
+    /// If set, the component exits when the send buffer is full, rather than waiting for space.
+    #[clap(long)]
+    pub exit_on_full_send_buffer: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum BackpressureError {
+    #[error("Water marks must satisfy 0 <= low ({low}) < high ({high}) <= 1")]
+    InvalidWaterMarks { low: f64, high: f64 },
+}
+
+/// Pauses and resumes a consumer according to the depth of the queue of messages waiting to be produced.
+#[derive(Debug)]
+pub struct Backpressure {
+    high_water_mark: usize,
+    low_water_mark: usize,
+    /// The time up to which the current pause has been recorded, or `None` if the consumer is not paused.
+    paused_until: Option<Instant>,
+}
+
+impl Backpressure {
+    /// Creates a new instance.
+    /// # Parameters
+    /// - opts: the water marks, as fractions of the send buffer.
+    /// - capacity: the size of the send buffer.
+    pub fn new(opts: &BackpressureOpts, capacity: usize) -> Result<Self, BackpressureError> {
+        let (low, high) = (opts.low_water_mark, opts.high_water_mark);
+        if !(0.0 <= low && low < high && high <= 1.0) {
+            return Err(BackpressureError::InvalidWaterMarks { low, high });
+        }
+        Ok(Self {
+            high_water_mark: ((high * capacity as f64).ceil() as usize).max(1),
+            low_water_mark: (low * capacity as f64).floor() as usize,
+            paused_until: None,
+        })
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_until.is_some()
+    }
+
+    fn should_pause(&self, queue_depth: usize) -> bool {
+        !self.is_paused() && queue_depth >= self.high_water_mark
+    }
+
+    fn should_resume(&self, queue_depth: usize) -> bool {
+        self.is_paused() && queue_depth <= self.low_water_mark
+    }
+
+    /// Records the queue depth, and pauses or resumes the consumer's partitions of the input topic if a water mark has been crossed.
+    ///
+    /// Whilst paused, any partitions of the input topic newly assigned to the consumer are also paused.
+    /// # Parameters
+    /// - consumer: the consumer to pause or resume.
+    /// - topic: the input topic, whose messages fill the queue.
+    /// - queue_depth: the number of messages waiting to be produced.
+    pub fn update<C: ConsumerContext>(
+        &mut self,
+        consumer: &StreamConsumer<C>,
+        topic: &str,
+        queue_depth: usize,
+    ) -> KafkaResult<()> {
+        gauge!(SEND_QUEUE_DEPTH).set(queue_depth as f64);
+
+        let now = Instant::now();
+        if let Some(paused_until) = self.paused_until {
+            counter!(CONSUMER_PAUSED_TIME).increment((now - paused_until).as_millis() as u64);
+        }
+
+        if self.should_pause(queue_depth) {
+            warn!("Send buffer holds {queue_depth} messages, pausing consumer");
+            consumer.pause(&topic_assignment(consumer, topic)?)?;
+            self.paused_until = Some(now);
+            gauge!(CONSUMER_PAUSED).set(1);
+        } else if self.should_resume(queue_depth) {
+            info!("Send buffer holds {queue_depth} messages, resuming consumer");
+            consumer.resume(&topic_assignment(consumer, topic)?)?;
+            self.paused_until = None;
+            gauge!(CONSUMER_PAUSED).set(0);
+        } else if self.is_paused() {
+            consumer.pause(&topic_assignment(consumer, topic)?)?;
+            self.paused_until = Some(now);
+        }
+        Ok(())
+    }
+}
+
+/// Returns the partitions of the given topic which are assigned to the consumer.
+fn topic_assignment<C: ConsumerContext>(
+    consumer: &StreamConsumer<C>,
+    topic: &str,
+) -> KafkaResult<TopicPartitionList> {
+    let mut partitions = TopicPartitionList::new();
+    for element in consumer.assignment()?.elements_for_topic(topic) {
+        partitions.add_partition(topic, element.partition());
+    }
+    Ok(partitions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opts(low_water_mark: f64, high_water_mark: f64) -> BackpressureOpts {
+        BackpressureOpts {
+            high_water_mark,
+            low_water_mark,
+            exit_on_full_send_buffer: false,
+        }
+    }
+
+    #[test]
+    fn water_marks_from_fractions() {
+        let backpressure = Backpressure::new(&opts(0.25, 0.75), 1024).unwrap();
+        assert_eq!(backpressure.high_water_mark, 768);
+        assert_eq!(backpressure.low_water_mark, 256);
+
+        let backpressure = Backpressure::new(&opts(0.25, 0.75), 1).unwrap();
+        assert_eq!(backpressure.high_water_mark, 1);
+        assert_eq!(backpressure.low_water_mark, 0);
+    }
+
+    #[test]
+    fn invalid_water_marks() {
+        assert!(Backpressure::new(&opts(0.75, 0.25), 1024).is_err());
+        assert!(Backpressure::new(&opts(0.5, 0.5), 1024).is_err());
+        assert!(Backpressure::new(&opts(0.25, 1.5), 1024).is_err());
+        assert!(Backpressure::new(&opts(-0.1, 0.5), 1024).is_err());
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut backpressure = Backpressure::new(&opts(0.25, 0.75), 100).unwrap();
+        assert!(!backpressure.should_pause(74));
+        assert!(backpressure.should_pause(75));
+        assert!(!backpressure.should_resume(0));
+
+        backpressure.paused_until = Some(Instant::now());
+        assert!(!backpressure.should_pause(100));
+        assert!(!backpressure.should_resume(26));
+        assert!(backpressure.should_resume(25));
+    }
+}
@@ -0,0 +1,45 @@
+//! Receives the messages of a control topic from a consumer created by [create_latest_message_consumer],
+//! which belongs to no consumer group, so that every instance of a component receives every message.
+//!
+//! [create_latest_message_consumer]: crate::create_latest_message_consumer
+use rdkafka::{consumer::StreamConsumer, error::KafkaResult, message::BorrowedMessage};
+use std::time::Duration;
+use tracing::warn;
+
+/// The time allowed to replay the latest messages on the control topic on startup.
+pub const CONTROL_REPLAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Replays the latest messages on the control topic, so that they are applied before any other message is processed.
+///
+/// Stops early if no message arrives within [CONTROL_REPLAY_TIMEOUT].
+/// # Parameters
+/// - control_consumer: the control consumer.
+/// - replayed: the number of messages to be replayed, as returned with the consumer.
+/// - process: applies each replayed message.
+pub async fn replay_control_messages<E>(
+    control_consumer: &StreamConsumer,
+    replayed: usize,
+    mut process: impl FnMut(&BorrowedMessage<'_>) -> Result<(), E>,
+) -> Result<(), E> {
+    for _ in 0..replayed {
+        match tokio::time::timeout(CONTROL_REPLAY_TIMEOUT, control_consumer.recv()).await {
+            Ok(Ok(msg)) => process(&msg)?,
+            Ok(Err(e)) => warn!("Kafka error: {}", e),
+            Err(_) => {
+                warn!("Timed out replaying the control topic");
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Receives the next message from the control topic, or waits forever if there is no control topic.
+pub async fn recv_control(
+    control_consumer: Option<&StreamConsumer>,
+) -> KafkaResult<BorrowedMessage<'_>> {
+    match control_consumer {
+        Some(control_consumer) => control_consumer.recv().await,
+        None => std::future::pending().await,
+    }
+}
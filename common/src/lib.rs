@@ -1,14 +1,21 @@
+pub mod backpressure;
+pub mod control;
 pub mod metrics;
+pub mod partitions;
 pub mod spanned;
 pub mod tracer;
+pub mod transaction;
 mod version;
 
 use clap::Args;
 use rdkafka::{
+    Offset, TopicPartitionList,
     config::ClientConfig,
-    consumer::{Consumer, StreamConsumer},
+    consumer::{Consumer, ConsumerContext, DefaultConsumerContext, StreamConsumer},
     error::KafkaError,
 };
+use std::time::Duration;
+use tracing::warn;
 
 pub type DigitizerId = u8;
 pub type Time = u32;
@@ -76,13 +83,33 @@ pub fn create_default_consumer(
     consumer_group: &String,
     topics_to_subscribe: Option<&[&str]>,
 ) -> Result<StreamConsumer, KafkaError> {
+    create_consumer_with_context(
+        broker_address,
+        username,
+        password,
+        consumer_group,
+        topics_to_subscribe,
+        DefaultConsumerContext,
+    )
+}
+
+/// As [create_default_consumer], but the consumer is created with the given context, such as one with rebalance callbacks.
+pub fn create_consumer_with_context<C: ConsumerContext + 'static>(
+    broker_address: &String,
+    username: &Option<String>,
+    password: &Option<String>,
+    consumer_group: &String,
+    topics_to_subscribe: Option<&[&str]>,
+    context: C,
+) -> Result<StreamConsumer<C>, KafkaError> {
     // Setup consumer with arguments and default parameters.
-    let consumer: StreamConsumer = generate_kafka_client_config(broker_address, username, password)
-        .set("group.id", consumer_group)
-        .set("enable.partition.eof", "false")
-        .set("session.timeout.ms", "6000")
-        .set("enable.auto.commit", "false")
-        .create()?;
+    let consumer: StreamConsumer<C> =
+        generate_kafka_client_config(broker_address, username, password)
+            .set("group.id", consumer_group)
+            .set("enable.partition.eof", "false")
+            .set("session.timeout.ms", "6000")
+            .set("enable.auto.commit", "false")
+            .create_with_context(context)?;
 
     // Subscribe to if topics are provided.
     if let Some(topics_to_subscribe) = topics_to_subscribe {
@@ -92,3 +119,47 @@ pub fn create_default_consumer(
 
     Ok(consumer)
 }
+
+/// The time allowed to fetch the partitions of a topic, and their watermarks, in [create_latest_message_consumer].
+const LATEST_MESSAGE_METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Creates a consumer of every partition of the given topic, which belongs to no consumer group,
+/// so that every instance of a component receives every message, as is needed of a control topic.
+///
+/// Each partition is read from its latest message, if it has one, so that the message most recently
+/// sent is replayed on startup. Returns the consumer, and the number of messages to be replayed.
+pub fn create_latest_message_consumer(
+    broker_address: &String,
+    username: &Option<String>,
+    password: &Option<String>,
+    topic: &str,
+) -> Result<(StreamConsumer, usize), KafkaError> {
+    let consumer: StreamConsumer = generate_kafka_client_config(broker_address, username, password)
+        .set("enable.partition.eof", "false")
+        .set("enable.auto.commit", "false")
+        .create()?;
+
+    let metadata = consumer.fetch_metadata(Some(topic), LATEST_MESSAGE_METADATA_TIMEOUT)?;
+    let mut assignment = TopicPartitionList::new();
+    let mut replayed = 0;
+    for partition in metadata
+        .topics()
+        .iter()
+        .flat_map(|metadata| metadata.partitions())
+    {
+        let (low, high) =
+            consumer.fetch_watermarks(topic, partition.id(), LATEST_MESSAGE_METADATA_TIMEOUT)?;
+        let offset = if high > low {
+            replayed += 1;
+            high - 1
+        } else {
+            high
+        };
+        assignment.add_partition_offset(topic, partition.id(), Offset::Offset(offset))?;
+    }
+    if assignment.count() == 0 {
+        warn!("Topic \"{topic}\" has no partitions");
+    }
+    consumer.assign(&assignment)?;
+    Ok((consumer, replayed))
+}
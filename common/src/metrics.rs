@@ -22,6 +22,10 @@ pub mod names {
         concatcp!(METRIC_NAME_PREFIX, "last_message_timestamp");
     pub const LAST_MESSAGE_FRAME_NUMBER: &str =
         concatcp!(METRIC_NAME_PREFIX, "last_message_frame_number");
+    pub const SEND_QUEUE_DEPTH: &str = concatcp!(METRIC_NAME_PREFIX, "send_queue_depth");
+    pub const CONSUMER_PAUSED: &str = concatcp!(METRIC_NAME_PREFIX, "consumer_paused");
+    pub const CONSUMER_PAUSED_TIME: &str = concatcp!(METRIC_NAME_PREFIX, "consumer_paused_ms");
+    pub const ASSIGNED_PARTITIONS: &str = concatcp!(METRIC_NAME_PREFIX, "assigned_partitions");
 }
 
 pub mod messages_received {
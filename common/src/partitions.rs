@@ -0,0 +1,80 @@
+//! Monitoring of the partitions assigned to a component's consumer.
+//!
+//! When several instances of a component share a consumer group, the partitions of each topic are
+//! divided between them, and are reassigned whenever an instance joins or leaves the group.
+use crate::metrics::names::ASSIGNED_PARTITIONS;
+use metrics::gauge;
+use rdkafka::{
+    TopicPartitionList,
+    consumer::{Consumer, ConsumerContext, StreamConsumer},
+    error::KafkaResult,
+};
+use std::{collections::BTreeSet, time::Duration};
+use tracing::info;
+
+/// How often the consumer's assignment should be checked.
+pub const PARTITION_ASSIGNMENT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The topic and partition of each partition assigned to the consumer.
+type Assignment = BTreeSet<(String, i32)>;
+
+fn assignment_from_list(list: &TopicPartitionList) -> Assignment {
+    list.elements()
+        .iter()
+        .map(|element| (element.topic().to_owned(), element.partition()))
+        .collect()
+}
+
+/// Records the partitions assigned to a consumer, and exports them as a metric.
+#[derive(Debug, Default)]
+pub struct PartitionAssignment {
+    assignment: Assignment,
+}
+
+impl PartitionAssignment {
+    /// Fetches the consumer's assignment, and if it has changed, logs it and updates the metric.
+    ///
+    /// The metric is set to 1 for each partition assigned, and to 0 for each partition revoked.
+    /// # Parameters
+    /// - consumer: the consumer whose assignment is recorded.
+    pub fn update<C: ConsumerContext>(&mut self, consumer: &StreamConsumer<C>) -> KafkaResult<()> {
+        let assignment = assignment_from_list(&consumer.assignment()?);
+        if assignment == self.assignment {
+            return Ok(());
+        }
+        for (topic, partition) in self.assignment.difference(&assignment) {
+            info!("Partition {partition} of topic {topic} revoked");
+            gauge!(ASSIGNED_PARTITIONS, "topic" => topic.clone(), "partition" => partition.to_string())
+                .set(0);
+        }
+        for (topic, partition) in assignment.difference(&self.assignment) {
+            info!("Partition {partition} of topic {topic} assigned");
+            gauge!(ASSIGNED_PARTITIONS, "topic" => topic.clone(), "partition" => partition.to_string())
+                .set(1);
+        }
+        self.assignment = assignment;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assignment_from_topic_partition_list() {
+        let mut list = TopicPartitionList::new();
+        list.add_partition("Traces", 3);
+        list.add_partition("Traces", 1);
+        list.add_partition("Controls", 0);
+        assert_eq!(
+            assignment_from_list(&list),
+            [
+                ("Controls".to_owned(), 0),
+                ("Traces".to_owned(), 1),
+                ("Traces".to_owned(), 3)
+            ]
+            .into()
+        );
+    }
+}
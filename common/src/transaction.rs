@@ -0,0 +1,416 @@
+//! Exactly-once processing, in which the offsets of consumed messages are committed in the same
+//! Kafka transaction as the messages produced from them.
+//!
+//! If a component fails, either both the produced messages and the consumed offsets are committed,
+//! or neither is, so on restarting the component resumes from the last committed transaction,
+//! neither losing nor duplicating messages.
+//!
+//! Before any of the consumer's partitions are revoked by a rebalance, the open transaction is committed,
+//! or aborted if that fails, so that the instance to which the partitions are reassigned resumes
+//! from the last offsets committed here.
+use clap::Args;
+use rdkafka::{
+    ClientConfig, ClientContext, Offset, TopicPartitionList,
+    consumer::{BaseConsumer, Consumer, ConsumerContext, Rebalance},
+    error::{KafkaError, KafkaResult, RDKafkaErrorCode},
+    producer::{FutureProducer, Producer},
+    util::Timeout,
+};
+use std::{
+    collections::BTreeMap,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, info};
+
+/// The time allowed for each operation on a transaction.
+const TRANSACTION_TIMEOUT: Timeout = Timeout::After(Duration::from_secs(10));
+
+/// Command line options for producing messages in Kafka transactions.
+#[derive(Clone, Debug, Args)]
+pub struct TransactionOpts {
+    /// If set, messages are produced in Kafka transactions with this transactional id, and the offsets of consumed messages are committed in the same transactions.
+    /// Each instance of a component must have a different transactional id, which should be kept when it is restarted.
+    #[clap(long)]
+    pub transactional_id: Option<String>,
+
+    /// The maximum time, in milliseconds, for which a transaction is left open before it is committed.
+    #[clap(long, default_value = "100")]
+    pub transaction_interval_ms: u64,
+}
+
+impl TransactionOpts {
+    /// Creates a producer, which is transactional if a transactional id is given.
+    pub fn create_producer(&self, client_config: &mut ClientConfig) -> KafkaResult<FutureProducer> {
+        if let Some(transactional_id) = &self.transactional_id {
+            client_config.set("transactional.id", transactional_id);
+        }
+        let producer: FutureProducer = client_config.create()?;
+        if self.transactional_id.is_some() {
+            producer.init_transactions(TRANSACTION_TIMEOUT)?;
+        }
+        Ok(producer)
+    }
+
+    /// Returns a [SharedTransaction] if a transactional id is given, otherwise [None].
+    pub fn transaction(&self) -> Option<SharedTransaction> {
+        self.transactional_id.as_ref().map(|_| {
+            Arc::new(Mutex::new(Transaction::new(Duration::from_millis(
+                self.transaction_interval_ms,
+            ))))
+        })
+    }
+}
+
+/// A [Transaction] shared between a component's main loop and its consumer's rebalance callback.
+pub type SharedTransaction = Arc<Mutex<Transaction>>;
+
+/// A consumer context which, in transactional mode, commits the open transaction before any partitions are revoked.
+#[derive(Default)]
+pub struct TransactionalConsumerContext {
+    /// The transactional producer, and the transaction it has open, or [None] if not in transactional mode.
+    transaction: Option<(FutureProducer, SharedTransaction)>,
+}
+
+impl TransactionalConsumerContext {
+    /// Creates and returns a new context.
+    /// # Parameters
+    /// - producer: the producer in whose transactions the consumer's offsets are committed.
+    /// - transaction: the open transaction, or [None] if not in transactional mode.
+    pub fn new(producer: &FutureProducer, transaction: Option<&SharedTransaction>) -> Self {
+        Self {
+            transaction: transaction.map(|transaction| (producer.clone(), transaction.clone())),
+        }
+    }
+}
+
+impl ClientContext for TransactionalConsumerContext {}
+
+impl ConsumerContext for TransactionalConsumerContext {
+    fn pre_rebalance(&self, base_consumer: &BaseConsumer<Self>, rebalance: &Rebalance<'_>) {
+        let (Rebalance::Revoke(partitions), Some((producer, transaction))) =
+            (rebalance, &self.transaction)
+        else {
+            return;
+        };
+        info!(
+            "Committing open transaction before {} partitions are revoked",
+            partitions.count()
+        );
+        match transaction.lock() {
+            // The error has already been logged, and the transaction aborted.
+            Ok(mut transaction) => {
+                let _ = transaction.commit(&KafkaTransactions::new(producer, base_consumer));
+            }
+            Err(e) => error!("Transaction unavailable whilst partitions are revoked: {e}"),
+        }
+    }
+}
+
+/// The operations on a broker by which a consume-transform-produce transaction is made.
+pub trait TransactionalBroker {
+    fn begin_transaction(&self) -> KafkaResult<()>;
+    fn send_offsets_to_transaction(&self, offsets: &TopicPartitionList) -> KafkaResult<()>;
+    fn commit_transaction(&self) -> KafkaResult<()>;
+    fn abort_transaction(&self) -> KafkaResult<()>;
+}
+
+/// A transactional producer, together with the consumer whose offsets are committed in its transactions.
+pub struct KafkaTransactions<'a, C, X> {
+    producer: &'a FutureProducer,
+    consumer: &'a C,
+    context: PhantomData<X>,
+}
+
+impl<'a, C: Consumer<X>, X: ConsumerContext> KafkaTransactions<'a, C, X> {
+    /// Creates a new instance, the consumer of which should belong to a group.
+    pub fn new(producer: &'a FutureProducer, consumer: &'a C) -> Self {
+        Self {
+            producer,
+            consumer,
+            context: PhantomData,
+        }
+    }
+}
+
+impl<C: Consumer<X>, X: ConsumerContext> TransactionalBroker for KafkaTransactions<'_, C, X> {
+    fn begin_transaction(&self) -> KafkaResult<()> {
+        self.producer.begin_transaction()
+    }
+
+    /// The consumer's group metadata is fetched for each call, as its generation changes with every rebalance,
+    /// and offsets sent with the metadata of an earlier generation are rejected.
+    fn send_offsets_to_transaction(&self, offsets: &TopicPartitionList) -> KafkaResult<()> {
+        let group_metadata = self
+            .consumer
+            .group_metadata()
+            .ok_or(KafkaError::ConsumerCommit(RDKafkaErrorCode::InvalidGroupId))?;
+        self.producer
+            .send_offsets_to_transaction(offsets, &group_metadata, TRANSACTION_TIMEOUT)
+    }
+
+    fn commit_transaction(&self) -> KafkaResult<()> {
+        self.producer.commit_transaction(TRANSACTION_TIMEOUT)
+    }
+
+    fn abort_transaction(&self) -> KafkaResult<()> {
+        self.producer.abort_transaction(TRANSACTION_TIMEOUT)
+    }
+}
+
+/// Accumulates the offsets to be committed in the open transaction, and commits it once it is due.
+#[derive(Debug)]
+pub struct Transaction {
+    /// The maximum time for which a transaction is left open.
+    interval: Duration,
+    /// The time at which the open transaction began, or [None] if there is no open transaction.
+    began: Option<Instant>,
+    /// The offset of the next message to consume from each topic and partition.
+    offsets: BTreeMap<(String, i32), i64>,
+    /// Metadata to commit with each offset.
+    metadata: Option<String>,
+}
+
+impl Transaction {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            began: None,
+            offsets: Default::default(),
+            metadata: None,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.began.is_some()
+    }
+
+    /// Returns `true` if the open transaction has been open for longer than the interval.
+    pub fn is_due(&self) -> bool {
+        self.began
+            .is_some_and(|began| began.elapsed() >= self.interval)
+    }
+
+    /// Begins a transaction, unless one is already open.
+    /// This should be called before any message is produced.
+    pub fn begin(&mut self, broker: &impl TransactionalBroker) -> KafkaResult<()> {
+        if !self.is_open() {
+            broker.begin_transaction()?;
+            self.began = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Sets the offset of the next message to consume from the given partition, to be committed with the transaction.
+    pub fn set_offset(&mut self, topic: &str, partition: i32, offset: i64) {
+        self.offsets.insert((topic.to_owned(), partition), offset);
+    }
+
+    /// Sets metadata to be committed with the offset of each partition.
+    pub fn set_metadata(&mut self, metadata: String) {
+        self.metadata = Some(metadata);
+    }
+
+    fn offsets(&self) -> KafkaResult<TopicPartitionList> {
+        let mut offsets = TopicPartitionList::new();
+        for ((topic, partition), &offset) in &self.offsets {
+            let mut element = offsets.add_partition(topic, *partition);
+            element.set_offset(Offset::Offset(offset))?;
+            if let Some(metadata) = &self.metadata {
+                element.set_metadata(metadata.as_str());
+            }
+        }
+        Ok(offsets)
+    }
+
+    /// Commits the open transaction, if there is one, along with the offsets set since the last commit.
+    ///
+    /// If the commit fails, the transaction is aborted and the error returned,
+    /// in which case the consumer should resume from the last committed offsets.
+    pub fn commit(&mut self, broker: &impl TransactionalBroker) -> KafkaResult<()> {
+        if !self.is_open() {
+            return Ok(());
+        }
+        let result = self.offsets().and_then(|offsets| {
+            if offsets.count() > 0 {
+                broker.send_offsets_to_transaction(&offsets)?;
+            }
+            broker.commit_transaction()
+        });
+        self.began = None;
+        self.offsets.clear();
+        match result {
+            Ok(()) => {
+                debug!("Committed transaction");
+                Ok(())
+            }
+            Err(e) => {
+                error!("Transaction commit failed: {e}");
+                if let Err(e) = broker.abort_transaction() {
+                    error!("Transaction abort failed: {e}");
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    const TOPIC: &str = "Input";
+
+    /// A stand-in for a broker with one input partition and one output topic, and one consumer group.
+    #[derive(Default)]
+    struct StandInBroker {
+        input: Vec<u32>,
+        /// Messages produced in committed transactions.
+        output: RefCell<Vec<u32>>,
+        /// Messages produced in the open transaction.
+        pending: RefCell<Vec<u32>>,
+        pending_offset: RefCell<Option<i64>>,
+        committed_offset: RefCell<i64>,
+        open: RefCell<bool>,
+        /// The number of commits which succeed before the broker fails.
+        commits_until_failure: RefCell<Option<usize>>,
+    }
+
+    impl StandInBroker {
+        fn new(input: Vec<u32>) -> Self {
+            Self {
+                input,
+                ..Default::default()
+            }
+        }
+
+        fn produce(&self, message: u32) {
+            assert!(
+                *self.open.borrow(),
+                "messages must be produced in a transaction"
+            );
+            self.pending.borrow_mut().push(message);
+        }
+
+        /// Simulates the component failing, losing anything not committed.
+        fn crash(&self) {
+            self.pending.borrow_mut().clear();
+            *self.pending_offset.borrow_mut() = None;
+            *self.open.borrow_mut() = false;
+        }
+    }
+
+    impl TransactionalBroker for StandInBroker {
+        fn begin_transaction(&self) -> KafkaResult<()> {
+            assert!(!*self.open.borrow());
+            *self.open.borrow_mut() = true;
+            Ok(())
+        }
+
+        fn send_offsets_to_transaction(&self, offsets: &TopicPartitionList) -> KafkaResult<()> {
+            let offset = offsets
+                .find_partition(TOPIC, 0)
+                .and_then(|element| element.offset().to_raw());
+            *self.pending_offset.borrow_mut() = offset;
+            Ok(())
+        }
+
+        fn commit_transaction(&self) -> KafkaResult<()> {
+            if let Some(commits) = self.commits_until_failure.borrow_mut().as_mut() {
+                if *commits == 0 {
+                    return Err(KafkaError::Flush(RDKafkaErrorCode::Fail));
+                }
+                *commits -= 1;
+            }
+            self.output
+                .borrow_mut()
+                .append(&mut self.pending.borrow_mut());
+            if let Some(offset) = self.pending_offset.borrow_mut().take() {
+                *self.committed_offset.borrow_mut() = offset;
+            }
+            *self.open.borrow_mut() = false;
+            Ok(())
+        }
+
+        fn abort_transaction(&self) -> KafkaResult<()> {
+            self.crash();
+            Ok(())
+        }
+    }
+
+    /// Consumes up to `limit` messages from the committed offset, producing double each input.
+    /// Transactions are committed every `batch` messages, and at the end of the input,
+    /// but are otherwise left open.
+    fn process(
+        broker: &StandInBroker,
+        transaction: &mut Transaction,
+        limit: usize,
+        batch: usize,
+    ) -> KafkaResult<()> {
+        let start = *broker.committed_offset.borrow() as usize;
+        for (index, &input) in broker.input.iter().enumerate().skip(start).take(limit) {
+            transaction.begin(broker)?;
+            broker.produce(2 * input);
+            transaction.set_offset(TOPIC, 0, index as i64 + 1);
+            if (index + 1) % batch == 0 || index + 1 == broker.input.len() {
+                transaction.commit(broker)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn no_loss_or_duplication_after_crashes() {
+        let input = (0..20).collect::<Vec<_>>();
+        let expected = input.iter().map(|x| 2 * x).collect::<Vec<_>>();
+        for batch in 1..5 {
+            // Each run must last at least one batch to make progress.
+            for crash_after in batch..batch + 5 {
+                let broker = StandInBroker::new(input.clone());
+                for _ in 0..input.len() {
+                    let mut transaction = Transaction::new(Duration::ZERO);
+                    process(&broker, &mut transaction, crash_after, batch).unwrap();
+                    broker.crash();
+                }
+                assert_eq!(*broker.committed_offset.borrow(), input.len() as i64);
+                assert_eq!(*broker.output.borrow(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn failed_commit_is_aborted() {
+        let input = (0..10).collect::<Vec<_>>();
+        let broker = StandInBroker::new(input.clone());
+        *broker.commits_until_failure.borrow_mut() = Some(3);
+
+        let mut transaction = Transaction::new(Duration::ZERO);
+        assert!(process(&broker, &mut transaction, input.len(), 2).is_err());
+        assert_eq!(*broker.committed_offset.borrow(), 6);
+        assert_eq!(*broker.output.borrow(), vec![0, 2, 4, 6, 8, 10]);
+        assert!(broker.pending.borrow().is_empty());
+
+        *broker.commits_until_failure.borrow_mut() = None;
+        process(&broker, &mut transaction, input.len(), 2).unwrap();
+        transaction.commit(&broker).unwrap();
+        assert_eq!(
+            *broker.output.borrow(),
+            input.iter().map(|x| 2 * x).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn commit_without_transaction_does_nothing() {
+        let broker = StandInBroker::new(vec![]);
+        let mut transaction = Transaction::new(Duration::from_secs(1));
+        assert!(!transaction.is_due());
+        transaction.commit(&broker).unwrap();
+        assert!(!*broker.open.borrow());
+
+        transaction.begin(&broker).unwrap();
+        assert!(transaction.is_open());
+        assert!(!transaction.is_due());
+    }
+}
@@ -0,0 +1,287 @@
+//! Checkpoints the state of the aggregator to local disk, so that partial frames survive a restart.
+//!
+//! The offset of a message is only committed once the frame it belongs to has been dispatched, so after a
+//! restart, the messages of frames which were still in the cache are consumed again, and the frames rebuilt.
+//! The checkpoint records these offsets, together with the timestamp of the last frame dispatched,
+//! so that the messages of frames which have already been dispatched are rejected.
+//!
+//! The checkpoint only applies to the partitions assigned at startup. A partition assigned after a rebalance
+//! resumes from its committed offset, which may have been advanced by the instance it was assigned to.
+use crate::{data::EventData, frame::FrameCache, transaction::HeldOffsets};
+use chrono::{DateTime, Utc};
+use rdkafka::{
+    Offset, TopicPartitionList,
+    consumer::{CommitMode, Consumer, ConsumerContext, StreamConsumer},
+    error::KafkaError,
+    message::{BorrowedMessage, Message},
+    util::Timeout,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+    time::Duration,
+};
+use thiserror::Error;
+use tracing::info;
+
+/// The time allowed to seek a partition back to its checkpointed offset.
+const SEEK_TIMEOUT: Timeout = Timeout::After(Duration::from_secs(10));
+
+#[derive(Debug, Error)]
+pub(crate) enum CheckpointError {
+    #[error("Json Error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("File Error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("Kafka Error: {0}")]
+    Kafka(#[from] KafkaError),
+}
+
+/// The state of the aggregator from which it resumes after a restart.
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Checkpoint {
+    /// The metadata timestamp of the last frame dispatched, if any.
+    pub(crate) latest_timestamp_dispatched: Option<DateTime<Utc>>,
+    /// The offset from which each partition of the input topic is resumed.
+    pub(crate) offsets: BTreeMap<i32, i64>,
+}
+
+impl Checkpoint {
+    /// Reads the checkpoint from the given file, or returns [None] if the file does not exist.
+    pub(crate) fn load(path: &Path) -> Result<Option<Self>, CheckpointError> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_reader(File::open(path)?)?))
+    }
+
+    /// Writes the checkpoint to the given file.
+    ///
+    /// The checkpoint is written to a temporary file alongside, which then replaces the file,
+    /// so that a crash whilst writing never leaves a partial checkpoint.
+    pub(crate) fn save(&self, path: &Path) -> Result<(), CheckpointError> {
+        let temporary = path.with_extension("tmp");
+        let mut file = BufWriter::new(File::create(&temporary)?);
+        serde_json::to_writer(&mut file, self)?;
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        fs::rename(temporary, path)?;
+        Ok(())
+    }
+
+    /// Restores the timestamp of the last frame dispatched to the cache.
+    pub(crate) fn restore(&self, cache: &mut FrameCache<EventData>) {
+        if let Some(timestamp) = self.latest_timestamp_dispatched {
+            info!("Restoring latest timestamp dispatched from checkpoint: {timestamp}");
+            cache.restore_latest_timestamp_dispatched(timestamp);
+        }
+    }
+
+    /// Returns the checkpointed offset of the given partition, if the message at `offset` is beyond it,
+    /// in which case the partition should be resumed from the returned offset instead.
+    pub(crate) fn rewind_offset(&self, partition: i32, offset: i64) -> Option<i64> {
+        self.offsets
+            .get(&partition)
+            .copied()
+            .filter(|&checkpointed| checkpointed < offset)
+    }
+
+    /// Forgets the offsets of partitions for which `is_assigned` is `false`,
+    /// so that they are not rewound if they are assigned again after a rebalance.
+    pub(crate) fn retain_partitions(&mut self, is_assigned: impl Fn(i32) -> bool) {
+        self.offsets.retain(|&partition, _| is_assigned(partition));
+    }
+
+    /// Seeks the message's partition back to its checkpointed offset, if the message is beyond it.
+    /// This should be called on first consuming from each partition.
+    ///
+    /// Each partition is considered at most once, after which its checkpointed offset is forgotten,
+    /// as are those of partitions which are no longer assigned to the consumer.
+    ///
+    /// Returns `true` if the partition is rewound, in which case the message should be ignored,
+    /// as it is consumed again later.
+    pub(crate) fn rewind<C: ConsumerContext>(
+        &mut self,
+        consumer: &StreamConsumer<C>,
+        msg: &BorrowedMessage,
+    ) -> Result<bool, CheckpointError> {
+        let assignment = consumer.assignment()?;
+        self.retain_partitions(|partition| {
+            assignment.find_partition(msg.topic(), partition).is_some()
+        });
+        let rewind_offset = self.rewind_offset(msg.partition(), msg.offset());
+        self.offsets.remove(&msg.partition());
+        let Some(offset) = rewind_offset else {
+            return Ok(false);
+        };
+        info!(
+            "Rewinding partition {} from offset {} to checkpointed offset {offset}",
+            msg.partition(),
+            msg.offset()
+        );
+        consumer.seek(
+            msg.topic(),
+            msg.partition(),
+            Offset::Offset(offset),
+            SEEK_TIMEOUT,
+        )?;
+        Ok(true)
+    }
+}
+
+/// Writes a checkpoint of the offsets of messages whose frames have been dispatched,
+/// and the timestamp of the last frame dispatched, then commits the same offsets.
+///
+/// The offsets are only committed once the checkpoint is written, so the committed offsets never
+/// pass those in the checkpoint. Partitions no longer assigned to the consumer are forgotten.
+pub(crate) fn write_checkpoint<C: ConsumerContext>(
+    path: &Path,
+    consumer: &StreamConsumer<C>,
+    held_offsets: &mut HeldOffsets,
+    cache: &FrameCache<EventData>,
+    topic: &str,
+) -> Result<(), CheckpointError> {
+    let assignment = consumer.assignment()?;
+    held_offsets
+        .retain_partitions(|partition| assignment.find_partition(topic, partition).is_some());
+    let latest_timestamp_dispatched = cache.get_latest_timestamp_dispatched();
+    let checkpoint = Checkpoint {
+        latest_timestamp_dispatched,
+        offsets: held_offsets.offsets_to_commit(latest_timestamp_dispatched),
+    };
+    checkpoint.save(path)?;
+
+    if !checkpoint.offsets.is_empty() {
+        let mut offsets = TopicPartitionList::new();
+        for (&partition, &offset) in &checkpoint.offsets {
+            offsets.add_partition_offset(topic, partition, Offset::Offset(offset))?;
+        }
+        consumer.commit(&offsets, CommitMode::Async)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restart_harness::{self, DispatchedFrame, metadata};
+    use digital_muon_common::DigitizerId;
+    use digital_muon_streaming_types::FrameMetadata;
+    use std::path::PathBuf;
+
+    fn checkpoint_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "digitiser-aggregator-{name}-{}.json",
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let path = checkpoint_path("round-trip");
+        assert!(Checkpoint::load(&path).unwrap().is_none());
+
+        let checkpoint = Checkpoint {
+            latest_timestamp_dispatched: Some(metadata(3).timestamp),
+            offsets: [(0, 12), (2, 7)].into(),
+        };
+        checkpoint.save(&path).unwrap();
+        assert_eq!(Checkpoint::load(&path).unwrap(), Some(checkpoint));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rewound_to_checkpointed_offset() {
+        let checkpoint = Checkpoint {
+            latest_timestamp_dispatched: None,
+            offsets: [(0, 12)].into(),
+        };
+        assert_eq!(checkpoint.rewind_offset(0, 15), Some(12));
+        assert_eq!(checkpoint.rewind_offset(0, 12), None);
+        assert_eq!(checkpoint.rewind_offset(0, 10), None);
+        assert_eq!(checkpoint.rewind_offset(1, 15), None);
+    }
+
+    #[test]
+    fn unassigned_partitions_not_rewound() {
+        let mut checkpoint = Checkpoint {
+            latest_timestamp_dispatched: None,
+            offsets: [(0, 12), (1, 4)].into(),
+        };
+        // Partition 1 is not assigned at startup.
+        checkpoint.retain_partitions(|partition| partition == 0);
+        assert_eq!(checkpoint.rewind_offset(0, 15), Some(12));
+        assert_eq!(checkpoint.rewind_offset(1, 15), None);
+    }
+
+    /// Runs the aggregator from the checkpoint, if there is one, checkpointing after each message,
+    /// until `crash_after` messages have been consumed, or the input is exhausted.
+    /// Returns the frames dispatched.
+    fn run(
+        input: &[(DigitizerId, FrameMetadata)],
+        path: &Path,
+        crash_after: usize,
+    ) -> Vec<DispatchedFrame> {
+        let checkpoint = Checkpoint::load(path).unwrap().unwrap_or_default();
+        let mut dispatched = Vec::new();
+        restart_harness::run(
+            input,
+            checkpoint.offsets.get(&0).copied().unwrap_or_default(),
+            checkpoint.latest_timestamp_dispatched,
+            crash_after,
+            |mut frames, offsets, latest_timestamp_dispatched| {
+                dispatched.append(&mut frames);
+                Checkpoint {
+                    latest_timestamp_dispatched,
+                    offsets,
+                }
+                .save(path)
+                .unwrap();
+            },
+        );
+        dispatched
+    }
+
+    #[test]
+    fn partial_frames_recovered_after_crash() {
+        let input = [
+            (0, metadata(1)),
+            (0, metadata(2)),
+            (1, metadata(1)),
+            (0, metadata(3)),
+            (1, metadata(2)),
+            (1, metadata(3)),
+        ];
+        let path = checkpoint_path("recovery");
+
+        // Crashes part way through frame 1, whose first message is consumed again after the restart.
+        let mut dispatched = run(&input, &path, 2);
+        assert!(dispatched.is_empty());
+        assert_eq!(
+            Checkpoint::load(&path).unwrap().unwrap().offsets,
+            [(0, 0)].into()
+        );
+
+        // Crashes part way through frame 3, after frames 1 and 2 are dispatched.
+        dispatched.append(&mut run(&input, &path, 5));
+        assert_eq!(dispatched, vec![(1, vec![0, 1]), (2, vec![0, 1])]);
+        let checkpoint = Checkpoint::load(&path).unwrap().unwrap();
+        assert_eq!(checkpoint.offsets, [(0, 3)].into());
+        assert_eq!(
+            checkpoint.latest_timestamp_dispatched,
+            Some(metadata(2).timestamp)
+        );
+
+        // The messages of frames 1 and 2 which are consumed again are rejected.
+        dispatched.append(&mut run(&input, &path, input.len()));
+        assert_eq!(
+            dispatched,
+            vec![(1, vec![0, 1]), (2, vec![0, 1]), (3, vec![0, 1])]
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
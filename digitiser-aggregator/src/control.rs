@@ -0,0 +1,102 @@
+//! Runtime reconfiguration of the expected digitisers via the Kafka control topic.
+//!
+//! Each message on the control topic is a JSON document listing the digitisers which
+//! make up a complete frame. If it is valid, it replaces the expected digitisers before
+//! the next digitiser message is processed.
+//!
+//! The control topic is read by a consumer of its own, which belongs to no consumer group,
+//! so that every instance of the component receives every control message. On startup, the latest
+//! message is replayed, so that the expected digitisers last requested survive a restart.
+use crate::{data::EventData, frame::FrameCache};
+use digital_muon_common::{
+    DigitizerId,
+    metrics::{
+        failures::{self, FailureKind},
+        names::FAILURES,
+    },
+};
+use metrics::counter;
+use rdkafka::{Message, message::BorrowedMessage};
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::{info, instrument, warn};
+
+/// The contents of a message on the control topic.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ControlMessage {
+    /// The digitisers from which a frame must receive messages to be complete, in any order.
+    digitiser_ids: Vec<DigitizerId>,
+}
+
+#[derive(Debug, Error)]
+enum ControlError {
+    #[error("Control message has no payload")]
+    NoPayload,
+    #[error("Control message lists no digitisers")]
+    NoDigitisers,
+    #[error("Json Error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+fn parse_control_message(payload: Option<&[u8]>) -> Result<ControlMessage, ControlError> {
+    let payload = payload.ok_or(ControlError::NoPayload)?;
+    let message: ControlMessage = serde_json::from_slice(payload)?;
+    if message.digitiser_ids.is_empty() {
+        return Err(ControlError::NoDigitisers);
+    }
+    Ok(message)
+}
+
+/// Handles a message from the control topic, replacing the expected digitisers of `cache` if the message is valid.
+/// # Parameters
+/// - cache: the cache whose expected digitisers are replaced.
+/// - m: the control message.
+#[instrument(skip_all, level = "debug")]
+pub(crate) fn process_control_message(cache: &mut FrameCache<EventData>, m: &BorrowedMessage) {
+    match parse_control_message(m.payload()) {
+        Ok(ControlMessage { digitiser_ids }) => {
+            cache.set_expected_digitisers(digitiser_ids);
+            info!("Expected digitisers: {:?}", cache.get_expected_digitisers());
+        }
+        Err(e) => {
+            warn!("Rejected control message: {e}");
+            counter!(
+                FAILURES,
+                &[failures::get_label(FailureKind::UnableToDecodeMessage)]
+            )
+            .increment(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_message_parsed() {
+        assert_eq!(
+            parse_control_message(Some(br#"{ "digitiser-ids": [4, 0, 1] }"#)).unwrap(),
+            ControlMessage {
+                digitiser_ids: vec![4, 0, 1]
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_control_message_rejected() {
+        assert!(matches!(
+            parse_control_message(None),
+            Err(ControlError::NoPayload)
+        ));
+        assert!(matches!(
+            parse_control_message(Some(br#"{ "digitisers": [0] }"#)),
+            Err(ControlError::Json(_))
+        ));
+        assert!(matches!(
+            parse_control_message(Some(br#"{ "digitiser-ids": [] }"#)),
+            Err(ControlError::NoDigitisers)
+        ));
+    }
+}
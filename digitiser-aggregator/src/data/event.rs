@@ -11,9 +11,118 @@ use digital_muon_streaming_types::{
     flatbuffers::FlatBufferBuilder,
     frame_metadata_v2_generated::{FrameMetadataV2, FrameMetadataV2Args},
 };
+use tracing::warn;
+
+/// Pulse shape quantities of an event list, one entry per event.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct PulseShapes {
+    /// Time from the start to the end of the event's pulse (ns).
+    width: Vec<Time>,
+    /// Integral of the baselined trace over the event's pulse (intensity ns).
+    area: Vec<f32>,
+    /// Time from the start to the peak of the event's pulse (ns).
+    rise_time: Vec<Time>,
+    /// Whether the event's pulse was cut off by the start or end of the trace.
+    truncated: Vec<bool>,
+}
+
+impl PulseShapes {
+    #[cfg(test)]
+    pub(crate) fn new(
+        width: Vec<Time>,
+        area: Vec<f32>,
+        rise_time: Vec<Time>,
+        truncated: Vec<bool>,
+    ) -> Self {
+        Self {
+            width,
+            area,
+            rise_time,
+            truncated,
+        }
+    }
+
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            width: Vec::with_capacity(capacity),
+            area: Vec::with_capacity(capacity),
+            rise_time: Vec::with_capacity(capacity),
+            truncated: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Returns whether every field has one entry for each of the given number of events.
+    fn has_event_count(&self, event_count: usize) -> bool {
+        [
+            self.width.len(),
+            self.area.len(),
+            self.rise_time.len(),
+            self.truncated.len(),
+        ]
+        .iter()
+        .all(|&len| len == event_count)
+    }
+
+    /// Appends `other`, or zeros if the events have no pulse shapes.
+    fn append(&mut self, other: Option<&mut PulseShapes>, event_count: usize) {
+        match other {
+            Some(other) => {
+                self.width.append(&mut other.width);
+                self.area.append(&mut other.area);
+                self.rise_time.append(&mut other.rise_time);
+                self.truncated.append(&mut other.truncated);
+            }
+            None => {
+                self.width.resize(self.width.len() + event_count, 0);
+                self.area.resize(self.area.len() + event_count, 0.0);
+                self.rise_time.resize(self.rise_time.len() + event_count, 0);
+                self.truncated
+                    .resize(self.truncated.len() + event_count, false);
+            }
+        }
+    }
+}
+
+/// Statistics of the channels of an event list, one entry per channel.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ChannelStatistics {
+    /// Id of the detector to which each entry refers.
+    channel: Vec<Channel>,
+    /// Fraction of the trace during which the detector could register an event.
+    live_fraction: Vec<f32>,
+    /// Events found per microsecond of trace (MHz).
+    observed_rate: Vec<f32>,
+    /// True events per microsecond (MHz), estimated under a non-paralysable dead-time model.
+    estimated_rate: Vec<f32>,
+}
+
+impl ChannelStatistics {
+    #[cfg(test)]
+    pub(crate) fn new(
+        channel: Vec<Channel>,
+        live_fraction: Vec<f32>,
+        observed_rate: Vec<f32>,
+        estimated_rate: Vec<f32>,
+    ) -> Self {
+        Self {
+            channel,
+            live_fraction,
+            observed_rate,
+            estimated_rate,
+        }
+    }
+
+    /// Appends `other`, whose channels are distinct from those already present.
+    fn append(&mut self, other: &mut ChannelStatistics) {
+        self.channel.append(&mut other.channel);
+        self.live_fraction.append(&mut other.live_fraction);
+        self.observed_rate.append(&mut other.observed_rate);
+        self.estimated_rate.append(&mut other.estimated_rate);
+    }
+}
 
 /// Event list, either for a digitiser message, or frame message.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq)]
 pub(crate) struct EventData {
     /// Time at which event occurred, relative to frame metadata timestamp (ns).
     time: Vec<Time>,
@@ -21,6 +130,12 @@ pub(crate) struct EventData {
     intensity: Vec<Intensity>,
     /// Id of the detector which registered the event.
     channel: Vec<Channel>,
+    /// Pulse shape of each event, if available.
+    pulse_shapes: Option<PulseShapes>,
+    /// Statistics of each channel, if available.
+    statistics: Option<ChannelStatistics>,
+    /// Whether the frame is vetoed, but was passed on rather than dropped.
+    vetoed: bool,
 }
 
 impl EventData {
@@ -30,9 +145,30 @@ impl EventData {
             time,
             intensity,
             channel,
+            pulse_shapes: None,
+            statistics: None,
+            vetoed: false,
         }
     }
 
+    #[cfg(test)]
+    pub(crate) fn with_vetoed(mut self, vetoed: bool) -> Self {
+        self.vetoed = vetoed;
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_pulse_shapes(mut self, pulse_shapes: PulseShapes) -> Self {
+        self.pulse_shapes = Some(pulse_shapes);
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_statistics(mut self, statistics: ChannelStatistics) -> Self {
+        self.statistics = Some(statistics);
+        self
+    }
+
     #[cfg(test)]
     pub(crate) fn dummy_data(
         time_offset: Time,
@@ -59,6 +195,9 @@ impl EventData {
             time,
             intensity,
             channel,
+            pulse_shapes: None,
+            statistics: None,
+            vetoed: false,
         }
     }
 
@@ -72,6 +211,9 @@ impl EventData {
             time: Vec::with_capacity(capacity),
             intensity: Vec::with_capacity(capacity),
             channel: Vec::with_capacity(capacity),
+            pulse_shapes: None,
+            statistics: None,
+            vetoed: false,
         }
     }
 
@@ -86,8 +228,8 @@ impl EventData {
 
 impl<'a> From<DigitizerEventListMessage<'a>> for EventData {
     fn from(msg: DigitizerEventListMessage<'a>) -> Self {
-        let time = msg.time().expect("data should have times").iter().collect();
-        let intensity = msg
+        let time: Vec<Time> = msg.time().expect("data should have times").iter().collect();
+        let intensity: Vec<Intensity> = msg
             .voltage()
             .expect("data should have intensities")
             .iter()
@@ -97,6 +239,38 @@ impl<'a> From<DigitizerEventListMessage<'a>> for EventData {
             .expect("data should have channel numbers")
             .iter()
             .collect();
+        // Pulse shapes are dropped unless they have one entry for each event,
+        // as they could not otherwise be attributed to the events.
+        let pulse_shapes = Option::zip(msg.width(), msg.area())
+            .zip(Option::zip(msg.rise_time(), msg.truncated()))
+            .map(|((width, area), (rise_time, truncated))| PulseShapes {
+                width: width.iter().collect(),
+                area: area.iter().collect(),
+                rise_time: rise_time.iter().collect(),
+                truncated: truncated.iter().collect(),
+            })
+            .filter(|pulse_shapes| {
+                let valid =
+                    time.len() == intensity.len() && pulse_shapes.has_event_count(time.len());
+                if !valid {
+                    warn!(
+                        "Pulse shapes of digitiser {} dropped, as their lengths do not match its {} events",
+                        msg.digitizer_id(),
+                        time.len()
+                    );
+                }
+                valid
+            });
+        let statistics = Option::zip(msg.statistics_channel(), msg.live_fraction())
+            .zip(Option::zip(msg.observed_rate(), msg.estimated_rate()))
+            .map(
+                |((channel, live_fraction), (observed_rate, estimated_rate))| ChannelStatistics {
+                    channel: channel.iter().collect(),
+                    live_fraction: live_fraction.iter().collect(),
+                    observed_rate: observed_rate.iter().collect(),
+                    estimated_rate: estimated_rate.iter().collect(),
+                },
+            );
 
         // The guarantee that all fields are of equal length depends on the inputs
         // having fields of equal length. This is guaranteed by the `trace-to-events`
@@ -105,6 +279,9 @@ impl<'a> From<DigitizerEventListMessage<'a>> for EventData {
             time,
             intensity,
             channel,
+            pulse_shapes,
+            statistics,
+            vetoed: msg.vetoed(),
         }
     }
 }
@@ -115,13 +292,35 @@ impl Accumulate<EventData> for DigitiserData<EventData> {
         // inputs in the collection having fields of equal length.
         let total_len = data.iter().map(|(_, v)| v.event_count()).sum();
 
-        data.iter_mut()
-            .fold(EventData::with_capacity(total_len), |mut acc, value| {
-                acc.time.append(&mut value.1.time);
-                acc.intensity.append(&mut value.1.intensity);
-                acc.channel.append(&mut value.1.channel);
-                acc
-            })
+        // If any digitiser provided pulse shapes, then the frame does too,
+        // with zeros for the events of those digitisers which did not.
+        let mut result = EventData::with_capacity(total_len);
+        if data.iter().any(|(_, v)| v.pulse_shapes.is_some()) {
+            result.pulse_shapes = Some(PulseShapes::with_capacity(total_len));
+        }
+        // Channel statistics are only present for the digitisers which provided them.
+        if data.iter().any(|(_, v)| v.statistics.is_some()) {
+            result.statistics = Some(ChannelStatistics::default());
+        }
+
+        // The frame is vetoed if any digitiser's message was.
+        result.vetoed = data.iter().any(|(_, v)| v.vetoed);
+
+        data.iter_mut().fold(result, |mut acc, value| {
+            let event_count = value.1.event_count();
+            acc.time.append(&mut value.1.time);
+            acc.intensity.append(&mut value.1.intensity);
+            acc.channel.append(&mut value.1.channel);
+            if let Some(pulse_shapes) = &mut acc.pulse_shapes {
+                pulse_shapes.append(value.1.pulse_shapes.as_mut(), event_count);
+            }
+            if let Some((statistics, other)) =
+                Option::zip(acc.statistics.as_mut(), value.1.statistics.as_mut())
+            {
+                statistics.append(other);
+            }
+            acc
+        })
     }
 }
 
@@ -140,6 +339,8 @@ impl From<AggregatedFrame<EventData>> for Vec<u8> {
         };
         let metadata = FrameMetadataV2::create(&mut fbb, &metadata);
 
+        let pulse_shapes = frame.digitiser_data.pulse_shapes.as_ref();
+        let statistics = frame.digitiser_data.statistics.as_ref();
         let message = FrameAssembledEventListMessageArgs {
             metadata: Some(metadata),
             time: Some(fbb.create_vector::<Time>(&frame.digitiser_data.time)),
@@ -147,6 +348,21 @@ impl From<AggregatedFrame<EventData>> for Vec<u8> {
             channel: Some(fbb.create_vector::<Channel>(&frame.digitiser_data.channel)),
             complete: frame.complete,
             digitizers_present: Some(fbb.create_vector::<DigitizerId>(&frame.digitiser_ids)),
+            width: pulse_shapes.map(|pulse_shapes| fbb.create_vector::<Time>(&pulse_shapes.width)),
+            area: pulse_shapes.map(|pulse_shapes| fbb.create_vector::<f32>(&pulse_shapes.area)),
+            rise_time: pulse_shapes
+                .map(|pulse_shapes| fbb.create_vector::<Time>(&pulse_shapes.rise_time)),
+            truncated: pulse_shapes
+                .map(|pulse_shapes| fbb.create_vector::<bool>(&pulse_shapes.truncated)),
+            vetoed: frame.digitiser_data.vetoed,
+            statistics_channel: statistics
+                .map(|statistics| fbb.create_vector::<Channel>(&statistics.channel)),
+            live_fraction: statistics
+                .map(|statistics| fbb.create_vector::<f32>(&statistics.live_fraction)),
+            observed_rate: statistics
+                .map(|statistics| fbb.create_vector::<f32>(&statistics.observed_rate)),
+            estimated_rate: statistics
+                .map(|statistics| fbb.create_vector::<f32>(&statistics.estimated_rate)),
         };
         let message = FrameAssembledEventListMessage::create(&mut fbb, &message);
 
@@ -159,7 +375,14 @@ impl From<AggregatedFrame<EventData>> for Vec<u8> {
 #[cfg(test)]
 mod test {
     use chrono::Utc;
-    use digital_muon_streaming_types::FrameMetadata;
+    use digital_muon_streaming_types::{
+        FrameMetadata,
+        aev2_frame_assembled_event_v2_generated::root_as_frame_assembled_event_list_message,
+        dev2_digitizer_event_v2_generated::{
+            DigitizerEventListMessageArgs, finish_digitizer_event_list_message_buffer,
+            root_as_digitizer_event_list_message,
+        },
+    };
 
     use super::*;
 
@@ -202,6 +425,7 @@ mod test {
                 channel: Some(fbb.create_vector::<Channel>(&[1, 3, 1, 0, 4])),
                 complete: true,
                 digitizers_present: Some(fbb.create_vector::<DigitizerId>(&[0, 1])),
+                ..Default::default()
             };
             let message = FrameAssembledEventListMessage::create(&mut fbb, &message);
 
@@ -226,6 +450,9 @@ mod test {
                     time: vec![1, 2, 8, 9, 7],
                     intensity: vec![2, 8, 8, 2, 7],
                     channel: vec![1, 3, 1, 0, 4],
+                    pulse_shapes: None,
+                    statistics: None,
+                    vetoed: false,
                 },
             );
             frame.into()
@@ -233,4 +460,226 @@ mod test {
 
         assert_eq!(test, reference);
     }
+
+    #[test]
+    fn accumulate_pulse_shapes() {
+        let mut data = vec![
+            (
+                0,
+                EventData::new(vec![1, 2], vec![3, 4], vec![0, 1]).with_pulse_shapes(
+                    PulseShapes::new(vec![10, 20], vec![1.5, 2.5], vec![3, 4], vec![false, true]),
+                ),
+            ),
+            (1, EventData::new(vec![5], vec![6], vec![2])),
+        ];
+
+        let accumulated = DigitiserData::<EventData>::accumulate(&mut data);
+        assert_eq!(
+            accumulated,
+            EventData::new(vec![1, 2, 5], vec![3, 4, 6], vec![0, 1, 2]).with_pulse_shapes(
+                PulseShapes::new(
+                    vec![10, 20, 0],
+                    vec![1.5, 2.5, 0.0],
+                    vec![3, 4, 0],
+                    vec![false, true, false],
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn accumulate_without_pulse_shapes() {
+        let mut data = vec![
+            (0, EventData::new(vec![1, 2], vec![3, 4], vec![0, 1])),
+            (1, EventData::new(vec![5], vec![6], vec![2])),
+        ];
+
+        let accumulated = DigitiserData::<EventData>::accumulate(&mut data);
+        assert_eq!(accumulated.pulse_shapes, None);
+        assert_eq!(accumulated.statistics, None);
+    }
+
+    #[test]
+    fn accumulate_vetoed() {
+        let mut data = vec![
+            (0, EventData::new(vec![1], vec![3], vec![0])),
+            (
+                1,
+                EventData::new(vec![5], vec![6], vec![2]).with_vetoed(true),
+            ),
+        ];
+        assert!(DigitiserData::<EventData>::accumulate(&mut data).vetoed);
+
+        let mut data = vec![(0, EventData::new(vec![1], vec![3], vec![0]))];
+        assert!(!DigitiserData::<EventData>::accumulate(&mut data).vetoed);
+    }
+
+    #[test]
+    fn accumulate_statistics() {
+        let mut data = vec![
+            (
+                0,
+                EventData::new(vec![1], vec![3], vec![0]).with_statistics(ChannelStatistics::new(
+                    vec![0, 1],
+                    vec![0.9, 1.0],
+                    vec![2.0, 0.0],
+                    vec![2.2, 0.0],
+                )),
+            ),
+            (1, EventData::new(vec![5], vec![6], vec![2])),
+            (
+                2,
+                EventData::new(vec![], vec![], vec![]).with_statistics(ChannelStatistics::new(
+                    vec![4],
+                    vec![1.0],
+                    vec![0.0],
+                    vec![0.0],
+                )),
+            ),
+        ];
+
+        let accumulated = DigitiserData::<EventData>::accumulate(&mut data);
+        assert_eq!(
+            accumulated.statistics,
+            Some(ChannelStatistics::new(
+                vec![0, 1, 4],
+                vec![0.9, 1.0, 1.0],
+                vec![2.0, 0.0, 0.0],
+                vec![2.2, 0.0, 0.0],
+            ))
+        );
+    }
+
+    #[test]
+    fn aggregate_frame_with_statistics_to_flatbuffer_bytes() {
+        let frame = AggregatedFrame::new(
+            FrameMetadata {
+                timestamp: Utc::now(),
+                period_number: 1,
+                protons_per_pulse: 8,
+                running: true,
+                frame_number: 1337,
+                veto_flags: 4,
+            },
+            true,
+            vec![0],
+            EventData::new(vec![1], vec![2], vec![1]).with_statistics(ChannelStatistics::new(
+                vec![1, 3],
+                vec![0.5, 1.0],
+                vec![4.0, 0.0],
+                vec![8.0, 0.0],
+            )),
+        );
+        let bytes: Vec<u8> = frame.into();
+
+        let message = root_as_frame_assembled_event_list_message(&bytes).unwrap();
+        assert_eq!(
+            message
+                .statistics_channel()
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+        assert_eq!(
+            message.live_fraction().unwrap().iter().collect::<Vec<_>>(),
+            vec![0.5, 1.0]
+        );
+        assert_eq!(
+            message.observed_rate().unwrap().iter().collect::<Vec<_>>(),
+            vec![4.0, 0.0]
+        );
+        assert_eq!(
+            message.estimated_rate().unwrap().iter().collect::<Vec<_>>(),
+            vec![8.0, 0.0]
+        );
+        assert!(message.width().is_none());
+        assert!(!message.vetoed());
+    }
+
+    #[test]
+    fn aggregate_frame_with_pulse_shapes_to_flatbuffer_bytes() {
+        let frame =
+            AggregatedFrame::new(
+                FrameMetadata {
+                    timestamp: Utc::now(),
+                    period_number: 1,
+                    protons_per_pulse: 8,
+                    running: true,
+                    frame_number: 1337,
+                    veto_flags: 4,
+                },
+                true,
+                vec![0],
+                EventData::new(vec![1, 2], vec![2, 8], vec![1, 3]).with_pulse_shapes(
+                    PulseShapes::new(vec![10, 20], vec![1.5, 2.5], vec![3, 4], vec![false, true]),
+                ),
+            );
+        let bytes: Vec<u8> = frame.into();
+
+        let message = root_as_frame_assembled_event_list_message(&bytes).unwrap();
+        assert_eq!(
+            message.width().unwrap().iter().collect::<Vec<_>>(),
+            vec![10, 20]
+        );
+        assert_eq!(
+            message.area().unwrap().iter().collect::<Vec<_>>(),
+            vec![1.5, 2.5]
+        );
+        assert_eq!(
+            message.rise_time().unwrap().iter().collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        assert_eq!(
+            message.truncated().unwrap().iter().collect::<Vec<_>>(),
+            vec![false, true]
+        );
+    }
+
+    fn digitiser_message_with_pulse_shapes(width: &[Time]) -> Vec<u8> {
+        let mut fbb = FlatBufferBuilder::new();
+        let timestamp = Utc::now().into();
+        let metadata = FrameMetadataV2Args {
+            timestamp: Some(&timestamp),
+            ..Default::default()
+        };
+        let metadata = FrameMetadataV2::create(&mut fbb, &metadata);
+        let message = DigitizerEventListMessageArgs {
+            metadata: Some(metadata),
+            time: Some(fbb.create_vector::<Time>(&[1, 2])),
+            voltage: Some(fbb.create_vector::<Intensity>(&[2, 8])),
+            channel: Some(fbb.create_vector::<Channel>(&[1, 3])),
+            width: Some(fbb.create_vector::<Time>(width)),
+            area: Some(fbb.create_vector::<f32>(&[1.5, 2.5])),
+            rise_time: Some(fbb.create_vector::<Time>(&[3, 4])),
+            truncated: Some(fbb.create_vector::<bool>(&[false, true])),
+            ..Default::default()
+        };
+        let message = DigitizerEventListMessage::create(&mut fbb, &message);
+        finish_digitizer_event_list_message_buffer(&mut fbb, message);
+        fbb.finished_data().to_vec()
+    }
+
+    #[test]
+    fn pulse_shapes_read_from_digitiser_message() {
+        let bytes = digitiser_message_with_pulse_shapes(&[10, 20]);
+        let data: EventData = root_as_digitizer_event_list_message(&bytes).unwrap().into();
+        assert_eq!(
+            data.pulse_shapes,
+            Some(PulseShapes::new(
+                vec![10, 20],
+                vec![1.5, 2.5],
+                vec![3, 4],
+                vec![false, true]
+            ))
+        );
+    }
+
+    #[test]
+    fn pulse_shapes_of_wrong_length_dropped() {
+        let bytes = digitiser_message_with_pulse_shapes(&[10]);
+        let data: EventData = root_as_digitizer_event_list_message(&bytes).unwrap().into();
+        assert_eq!(data.event_count(), 2);
+        assert_eq!(data.pulse_shapes, None);
+    }
 }
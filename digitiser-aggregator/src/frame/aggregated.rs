@@ -1,5 +1,5 @@
 //! Defines the struct for a frame which is ready to be dispatched.
-use super::partial::PartialFrame;
+use super::{IncompleteFrameDiagnostics, partial::PartialFrame};
 use crate::data::{Accumulate, DigitiserData};
 use digital_muon_common::{
     DigitizerId,
@@ -21,6 +21,8 @@ pub(crate) struct AggregatedFrame<D> {
     pub(crate) digitiser_ids: Vec<DigitizerId>,
     /// The frame's event data.
     pub(crate) digitiser_data: D,
+    /// If the frame is incomplete, describes which digitisers were missing.
+    pub(crate) diagnostics: Option<IncompleteFrameDiagnostics>,
 }
 
 #[cfg(test)]
@@ -37,6 +39,7 @@ impl<D> AggregatedFrame<D> {
             complete,
             digitiser_ids,
             digitiser_data,
+            diagnostics: None,
         }
     }
 }
@@ -57,6 +60,7 @@ where
             digitiser_data: <DigitiserData<D> as Accumulate<D>>::accumulate(
                 &mut partial.digitiser_data,
             ),
+            diagnostics: None,
         }
     }
 }
@@ -1,14 +1,25 @@
 //! Defines the cache stores frames as they are assembled from digitiser messages.
-use super::{AggregatedFrame, RejectMessageError, partial::PartialFrame};
+use super::{
+    AggregatedFrame, ClockSkewGuard, ExpectedDigitisers, IncompleteFrameDiagnostics,
+    RejectMessageError, partial::PartialFrame,
+};
 use crate::data::{Accumulate, DigitiserData};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
+use const_format::concatcp;
 use digital_muon_common::{
-    DigitizerId, record_metadata_fields_to_span, spanned::SpannedAggregator,
+    DigitizerId, metrics::names::METRIC_NAME_PREFIX, record_metadata_fields_to_span,
+    spanned::SpannedAggregator,
 };
 use digital_muon_streaming_types::FrameMetadata;
+use metrics::{counter, histogram};
 use std::{collections::VecDeque, fmt::Debug, time::Duration};
 use tracing::{info_span, warn};
 
+pub(crate) const TIMESTAMP_NEAR_MISSES_METRIC: &str =
+    concatcp!(METRIC_NAME_PREFIX, "timestamp_near_misses");
+pub(crate) const TIMESTAMP_OFFSET_METRIC: &str =
+    concatcp!(METRIC_NAME_PREFIX, "timestamp_offset_ns");
+
 /// Contains all the partial frames as well as handling the frame lifetime and completeness.
 pub(crate) struct FrameCache<D: Debug> {
     /// Specifies the maximum time that a partial frame should live
@@ -16,7 +27,12 @@ pub(crate) struct FrameCache<D: Debug> {
     ttl: Duration,
     /// Specifies the complete set of digitisers
     /// a partial frame should have before being complete.
-    expected_digitisers: Vec<DigitizerId>,
+    expected_digitisers: ExpectedDigitisers,
+    /// The greatest difference between the timestamp of a digitiser message,
+    /// and that of a partial frame with otherwise equal metadata, for the message to belong to the frame.
+    timestamp_tolerance: TimeDelta,
+    /// If set, messages with timestamps too far in the future are quarantined, rather than pushed into the cache.
+    clock_skew_guard: Option<ClockSkewGuard>,
     /// The metadata timestamp of the last frame to be dispatched,
     /// value is [None] if no frame has been dispatched yet.
     latest_timestamp_dispatched: Option<DateTime<Utc>>,
@@ -31,13 +47,20 @@ where
     /// Creates and returns a new [FrameCache] instance.
     /// # Parameters
     /// - ttl: time-to-live duration
-    /// - expected_digitisers: list of digitisers that form a complete frame.
-    ///
-    /// Note that `expected_digitisers` should be increasing and without duplicates, this is not checked.
-    pub(crate) fn new(ttl: Duration, expected_digitisers: Vec<DigitizerId>) -> Self {
+    /// - expected_digitisers: the digitisers that form a complete frame.
+    /// - timestamp_tolerance: the greatest timestamp difference between messages of the same frame.
+    /// - clock_skew_guard: if set, quarantines messages with timestamps too far in the future.
+    pub(crate) fn new(
+        ttl: Duration,
+        expected_digitisers: ExpectedDigitisers,
+        timestamp_tolerance: TimeDelta,
+        clock_skew_guard: Option<ClockSkewGuard>,
+    ) -> Self {
         Self {
             ttl,
             expected_digitisers,
+            timestamp_tolerance,
+            clock_skew_guard,
             latest_timestamp_dispatched: None,
             frames: Default::default(),
         }
@@ -47,28 +70,67 @@ where
     /// If a partial frame with the same `metadata` already exists, and is yet
     /// to receive a message with the same `digitiser_id`, then `data` is added
     /// to the partial frame, otherwise a new [PartialFrame] is created.
+    ///
+    /// The metadata of the partial frame need only have a timestamp within [Self::timestamp_tolerance]
+    /// of that of `metadata`, if its other fields (in particular its frame number) are equal.
+    /// If only the timestamp is beyond the tolerance, the message is recorded as a near-miss.
+    /// As a message within the tolerance of the last frame dispatched may belong to it, such messages are rejected.
+    ///
+    /// Returns the timestamp of the partial frame the message was added to.
     #[tracing::instrument(skip_all, level = "trace")]
     pub(crate) fn push<'a>(
         &'a mut self,
         digitiser_id: DigitizerId,
         metadata: &FrameMetadata,
         data: D,
-    ) -> Result<(), RejectMessageError> {
+    ) -> Result<DateTime<Utc>, RejectMessageError> {
+        if let Some(clock_skew_guard) = &mut self.clock_skew_guard {
+            if !clock_skew_guard.accept(metadata.timestamp, Utc::now()) {
+                return Err(RejectMessageError::Quarantined);
+            }
+        }
         if let Some(latest_timestamp_dispatched) = self.latest_timestamp_dispatched {
-            if metadata.timestamp <= latest_timestamp_dispatched {
+            if metadata.timestamp <= latest_timestamp_dispatched + self.timestamp_tolerance {
                 warn!(
-                    "Frame's timestamp earlier than or equal to the latest frame dispatched: {0} <= {1}",
-                    metadata.timestamp, latest_timestamp_dispatched
+                    "Frame's timestamp earlier than, or within tolerance of, the latest frame dispatched: {0} <= {1} + {2}",
+                    metadata.timestamp, latest_timestamp_dispatched, self.timestamp_tolerance
                 );
                 return Err(RejectMessageError::TimestampTooEarly);
             }
         }
+        self.expected_digitisers.discover(digitiser_id);
+
+        // The partial frame with the same frame number, whose timestamp is closest to that of the message.
+        let closest = self
+            .frames
+            .iter()
+            .enumerate()
+            .filter(|(_, frame)| {
+                frame
+                    .metadata
+                    .equals_ignoring_timestamp_and_veto_flags(metadata)
+            })
+            .map(|(index, frame)| (index, (metadata.timestamp - frame.metadata.timestamp).abs()))
+            .min_by_key(|&(_, offset)| offset);
+        if let Some((_, offset)) = closest {
+            histogram!(TIMESTAMP_OFFSET_METRIC)
+                .record(offset.num_nanoseconds().unwrap_or(i64::MAX) as f64);
+        }
+        let matched = match closest {
+            Some((index, offset)) if offset <= self.timestamp_tolerance => Some(index),
+            Some((_, offset)) => {
+                warn!(
+                    "Frame {0} timestamp differs by {offset} between digitisers, beyond the tolerance",
+                    metadata.frame_number
+                );
+                counter!(TIMESTAMP_NEAR_MISSES_METRIC).increment(1);
+                None
+            }
+            None => None,
+        };
+
         let frame = {
-            match self
-                .frames
-                .iter_mut()
-                .find(|frame| frame.metadata.equals_ignoring_veto_flags(metadata))
-            {
+            match matched.and_then(|index| self.frames.get_mut(index)) {
                 Some(frame) => {
                     if frame.has_digitiser_id(digitiser_id) {
                         warn!("Frame already has digitiser id: {digitiser_id}");
@@ -76,7 +138,7 @@ where
                     }
                     frame.push(digitiser_id, data);
                     frame.push_veto_flags(metadata.veto_flags);
-                    frame.set_completion_status(&self.expected_digitisers);
+                    frame.set_completion_status(self.expected_digitisers.complete_set());
                     frame
                 }
                 None => {
@@ -88,6 +150,7 @@ where
                     }
 
                     frame.push(digitiser_id, data);
+                    frame.set_completion_status(self.expected_digitisers.complete_set());
                     self.frames.push_back(frame);
                     self.frames
                         .back()
@@ -114,7 +177,7 @@ where
             warn!("Frame span linking failed {e}")
         }
 
-        Ok(())
+        Ok(frame.metadata.timestamp)
     }
 
     /// Checks whether any partial frame is ready to be dispatched, that is either
@@ -137,27 +200,78 @@ where
 
             // This frame is the next to be set to latest timestamp dispatched
             self.latest_timestamp_dispatched = Some(frame.metadata.timestamp);
-            Some(frame.into())
+            let diagnostics = (!frame.is_complete())
+                .then(|| IncompleteFrameDiagnostics::new(&frame, self.expected_digitisers.ids()));
+            if self
+                .expected_digitisers
+                .record_frame(&frame.digitiser_ids())
+            {
+                self.update_completion_status();
+            }
+            let mut frame = AggregatedFrame::from(frame);
+            frame.diagnostics = diagnostics;
+            Some(frame)
         } else {
             None
         }
     }
 
+    /// Returns the digitisers currently expected in each frame, in increasing order.
+    pub(crate) fn get_expected_digitisers(&self) -> &[DigitizerId] {
+        self.expected_digitisers.ids()
+    }
+
+    /// Replaces the digitisers expected in each frame, so that frames already in
+    /// the cache are complete once they have messages from each of the new set.
+    pub(crate) fn set_expected_digitisers(&mut self, expected_digitisers: Vec<DigitizerId>) {
+        if self.expected_digitisers.replace(expected_digitisers) {
+            self.update_completion_status();
+        }
+    }
+
+    /// Checks each partial frame against the expected digitisers, after they have changed,
+    /// so frames may become complete, or no longer be complete.
+    fn update_completion_status(&mut self) {
+        let expected = self.expected_digitisers.complete_set();
+        for frame in self.frames.iter_mut() {
+            frame.set_completion_status(expected);
+        }
+    }
+
     /// Returns the number of partial frames currently in the cache.
     pub(crate) fn get_num_partial_frames(&self) -> usize {
         self.frames.len()
     }
+
+    /// Returns the metadata timestamp of the last frame to be dispatched, if any.
+    pub(crate) fn get_latest_timestamp_dispatched(&self) -> Option<DateTime<Utc>> {
+        self.latest_timestamp_dispatched
+    }
+
+    /// Raises the metadata timestamp of the last frame to be dispatched to `timestamp`,
+    /// so that messages from frames dispatched before a restart are rejected.
+    pub(crate) fn restore_latest_timestamp_dispatched(&mut self, timestamp: DateTime<Utc>) {
+        self.latest_timestamp_dispatched = Some(
+            self.latest_timestamp_dispatched
+                .map_or(timestamp, |latest| latest.max(timestamp)),
+        );
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::data::EventData;
+    use crate::{data::EventData, frame::SkewReference};
     use chrono::Utc;
 
     #[test]
     fn one_frame_in_one_frame_out() {
-        let mut cache = FrameCache::<EventData>::new(Duration::from_millis(100), vec![0, 1, 4, 8]);
+        let mut cache = FrameCache::<EventData>::new(
+            Duration::from_millis(100),
+            ExpectedDigitisers::new(vec![0, 1, 4, 8], None),
+            TimeDelta::zero(),
+            None,
+        );
 
         let frame_1 = FrameMetadata {
             timestamp: Utc::now(),
@@ -207,6 +321,7 @@ mod test {
             assert_eq!(cache.get_num_partial_frames(), 0);
 
             assert_eq!(frame.metadata, frame_1);
+            assert!(frame.diagnostics.is_none());
 
             let mut dids = frame.digitiser_ids;
             dids.sort();
@@ -235,7 +350,12 @@ mod test {
 
     #[tokio::test]
     async fn one_frame_in_one_frame_out_missing_digitiser_timeout() {
-        let mut cache = FrameCache::<EventData>::new(Duration::from_millis(100), vec![0, 1, 4, 8]);
+        let mut cache = FrameCache::<EventData>::new(
+            Duration::from_millis(100),
+            ExpectedDigitisers::new(vec![0, 1, 4, 8], None),
+            TimeDelta::zero(),
+            None,
+        );
 
         let frame_1 = FrameMetadata {
             timestamp: Utc::now(),
@@ -279,6 +399,20 @@ mod test {
 
             assert_eq!(frame.metadata, frame_1);
 
+            let diagnostics = frame.diagnostics.unwrap();
+            assert_eq!(diagnostics.metadata.frame_number, 1728);
+            assert_eq!(diagnostics.expected_digitiser_ids, &[0, 1, 4, 8]);
+            assert_eq!(diagnostics.missing_digitiser_ids, &[4]);
+            assert_eq!(
+                diagnostics
+                    .arrivals
+                    .iter()
+                    .map(|arrival| arrival.digitiser_id)
+                    .collect::<Vec<_>>(),
+                &[0, 1, 8]
+            );
+            assert!(diagnostics.age_ms >= 100.0);
+
             let mut dids = frame.digitiser_ids;
             dids.sort();
             assert_eq!(dids, &[0, 1, 8]);
@@ -304,7 +438,12 @@ mod test {
 
     #[tokio::test]
     async fn one_frame_in_one_frame_out_missing_digitiser_and_late_message_timeout() {
-        let mut cache = FrameCache::<EventData>::new(Duration::from_millis(100), vec![0, 1, 4, 8]);
+        let mut cache = FrameCache::<EventData>::new(
+            Duration::from_millis(100),
+            ExpectedDigitisers::new(vec![0, 1, 4, 8], None),
+            TimeDelta::zero(),
+            None,
+        );
 
         let frame_1 = FrameMetadata {
             timestamp: Utc::now(),
@@ -344,7 +483,12 @@ mod test {
 
     #[test]
     fn test_metadata_equality() {
-        let mut cache = FrameCache::<EventData>::new(Duration::from_millis(100), vec![1, 2]);
+        let mut cache = FrameCache::<EventData>::new(
+            Duration::from_millis(100),
+            ExpectedDigitisers::new(vec![1, 2], None),
+            TimeDelta::zero(),
+            None,
+        );
 
         let timestamp = Utc::now();
         let frame_1 = FrameMetadata {
@@ -386,4 +530,283 @@ mod test {
         assert_eq!(cache.frames.len(), 1);
         assert!(cache.poll().is_some());
     }
+
+    #[test]
+    fn timestamps_matched_within_tolerance() {
+        let mut cache = FrameCache::<EventData>::new(
+            Duration::from_millis(100),
+            ExpectedDigitisers::new(vec![0, 1, 2], None),
+            TimeDelta::nanoseconds(10),
+            None,
+        );
+
+        let frame_1 = FrameMetadata {
+            timestamp: Utc::now(),
+            period_number: 1,
+            protons_per_pulse: 8,
+            running: true,
+            frame_number: 1728,
+            veto_flags: 0,
+        };
+        let jittered = FrameMetadata {
+            timestamp: frame_1.timestamp + TimeDelta::nanoseconds(7),
+            ..frame_1.clone()
+        };
+        let near_miss = FrameMetadata {
+            timestamp: frame_1.timestamp - TimeDelta::nanoseconds(11),
+            ..frame_1.clone()
+        };
+        let frame_2 = FrameMetadata {
+            timestamp: frame_1.timestamp + TimeDelta::nanoseconds(3),
+            frame_number: 1729,
+            ..frame_1.clone()
+        };
+
+        assert!(
+            cache
+                .push(0, &frame_1, EventData::dummy_data(0, 5, &[0]))
+                .is_ok()
+        );
+        assert!(
+            cache
+                .push(1, &jittered, EventData::dummy_data(0, 5, &[1]))
+                .is_ok()
+        );
+        assert_eq!(cache.get_num_partial_frames(), 1);
+
+        // Neither a near-miss nor a different frame number are matched.
+        assert!(
+            cache
+                .push(2, &near_miss, EventData::dummy_data(0, 5, &[2]))
+                .is_ok()
+        );
+        assert!(
+            cache
+                .push(2, &frame_2, EventData::dummy_data(0, 5, &[2]))
+                .is_ok()
+        );
+        assert_eq!(cache.get_num_partial_frames(), 3);
+        assert!(cache.poll().is_none());
+
+        // The jitter of the last digitiser is also within tolerance.
+        assert!(
+            cache
+                .push(2, &jittered, EventData::dummy_data(0, 5, &[2]))
+                .is_ok()
+        );
+        let frame = cache.poll().unwrap();
+        assert_eq!(frame.metadata.timestamp, frame_1.timestamp);
+        let mut dids = frame.digitiser_ids;
+        dids.sort();
+        assert_eq!(dids, &[0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn jittered_message_rejected_after_dispatch() {
+        let mut cache = FrameCache::<EventData>::new(
+            Duration::from_millis(100),
+            ExpectedDigitisers::new(vec![0, 1, 2], None),
+            TimeDelta::nanoseconds(10),
+            None,
+        );
+
+        let frame_1 = FrameMetadata {
+            timestamp: Utc::now(),
+            period_number: 1,
+            protons_per_pulse: 8,
+            running: true,
+            frame_number: 1728,
+            veto_flags: 0,
+        };
+        let jittered = FrameMetadata {
+            timestamp: frame_1.timestamp + TimeDelta::nanoseconds(7),
+            ..frame_1.clone()
+        };
+
+        // The jittered message joins the frame, which keeps the timestamp of its first message.
+        assert!(matches!(
+            cache.push(0, &frame_1, EventData::dummy_data(0, 5, &[0])),
+            Ok(timestamp) if timestamp == frame_1.timestamp
+        ));
+        assert!(matches!(
+            cache.push(1, &jittered, EventData::dummy_data(0, 5, &[1])),
+            Ok(timestamp) if timestamp == frame_1.timestamp
+        ));
+
+        tokio::time::sleep(Duration::from_millis(105)).await;
+        assert!(cache.poll().unwrap().diagnostics.is_some());
+
+        // The straggler arrives after its frame was dispatched, and does not start a duplicate.
+        assert!(matches!(
+            cache.push(2, &jittered, EventData::dummy_data(0, 5, &[2])),
+            Err(RejectMessageError::TimestampTooEarly)
+        ));
+        assert_eq!(cache.get_num_partial_frames(), 0);
+    }
+
+    #[test]
+    fn future_timestamp_quarantined() {
+        let mut cache = FrameCache::<EventData>::new(
+            Duration::from_millis(100),
+            ExpectedDigitisers::new(vec![0], None),
+            TimeDelta::zero(),
+            Some(ClockSkewGuard::new(
+                TimeDelta::seconds(1),
+                SkewReference::WallClock,
+                1,
+            )),
+        );
+
+        let frame_1 = FrameMetadata {
+            timestamp: Utc::now(),
+            period_number: 1,
+            protons_per_pulse: 8,
+            running: true,
+            frame_number: 1728,
+            veto_flags: 0,
+        };
+        let future = FrameMetadata {
+            timestamp: frame_1.timestamp + TimeDelta::days(1),
+            frame_number: 1727,
+            ..frame_1.clone()
+        };
+
+        assert!(matches!(
+            cache.push(0, &future, EventData::dummy_data(0, 5, &[0])),
+            Err(RejectMessageError::Quarantined)
+        ));
+        assert_eq!(cache.get_num_partial_frames(), 0);
+
+        // The quarantined message does not cause later messages to be rejected.
+        assert!(
+            cache
+                .push(0, &frame_1, EventData::dummy_data(0, 5, &[0]))
+                .is_ok()
+        );
+        assert_eq!(cache.poll().unwrap().metadata, frame_1);
+        assert_eq!(
+            cache.get_latest_timestamp_dispatched(),
+            Some(frame_1.timestamp)
+        );
+    }
+
+    #[test]
+    fn frames_no_longer_complete_once_expected_digitisers_added() {
+        let mut cache = FrameCache::<EventData>::new(
+            Duration::from_millis(100),
+            ExpectedDigitisers::new(vec![0, 1], None),
+            TimeDelta::zero(),
+            None,
+        );
+        let frame = |frame_number| FrameMetadata {
+            timestamp: Utc::now(),
+            period_number: 1,
+            protons_per_pulse: 8,
+            running: true,
+            frame_number,
+            veto_flags: 0,
+        };
+        let (frame_1, frame_2) = (frame(1), frame(2));
+
+        // The second frame is complete, but held behind the first.
+        for (digitiser_id, metadata) in [(0, &frame_1), (0, &frame_2), (1, &frame_2)] {
+            assert!(
+                cache
+                    .push(digitiser_id, metadata, EventData::dummy_data(0, 5, &[0]))
+                    .is_ok()
+            );
+        }
+        assert!(cache.poll().is_none());
+
+        cache.set_expected_digitisers(vec![0, 1, 2]);
+        for digitiser_id in [1, 2] {
+            assert!(
+                cache
+                    .push(digitiser_id, &frame_1, EventData::dummy_data(0, 5, &[0]))
+                    .is_ok()
+            );
+        }
+        assert_eq!(cache.poll().unwrap().metadata, frame_1);
+        assert!(cache.poll().is_none());
+    }
+
+    #[test]
+    fn frames_complete_once_expected_digitisers_removed() {
+        let mut cache = FrameCache::<EventData>::new(
+            Duration::from_millis(100),
+            ExpectedDigitisers::new(vec![0, 1, 2], None),
+            TimeDelta::zero(),
+            None,
+        );
+        let frame_1 = FrameMetadata {
+            timestamp: Utc::now(),
+            period_number: 1,
+            protons_per_pulse: 8,
+            running: true,
+            frame_number: 1728,
+            veto_flags: 0,
+        };
+        for digitiser_id in [0, 2] {
+            assert!(
+                cache
+                    .push(digitiser_id, &frame_1, EventData::dummy_data(0, 5, &[0]))
+                    .is_ok()
+            );
+        }
+        assert!(cache.poll().is_none());
+
+        // The frame has a message from a digitiser no longer expected, but is complete nonetheless.
+        cache.set_expected_digitisers(vec![0]);
+        let frame = cache.poll().unwrap();
+        assert_eq!(frame.metadata, frame_1);
+        assert!(frame.diagnostics.is_none());
+    }
+
+    #[tokio::test]
+    async fn discovered_digitisers_complete_frames_once_a_frame_has_expired() {
+        let mut cache = FrameCache::<EventData>::new(
+            Duration::from_millis(100),
+            ExpectedDigitisers::new(Vec::new(), Some(4)),
+            TimeDelta::zero(),
+            None,
+        );
+        let frame = |frame_number| FrameMetadata {
+            timestamp: Utc::now(),
+            period_number: 1,
+            protons_per_pulse: 8,
+            running: true,
+            frame_number,
+            veto_flags: 0,
+        };
+        let frame_1 = frame(1);
+
+        // The first frame is not complete with only the digitiser discovered first.
+        assert!(
+            cache
+                .push(0, &frame_1, EventData::dummy_data(0, 5, &[0, 1, 2]))
+                .is_ok()
+        );
+        assert!(cache.poll().is_none());
+        assert!(
+            cache
+                .push(1, &frame_1, EventData::dummy_data(0, 5, &[3, 4, 5]))
+                .is_ok()
+        );
+        assert!(cache.poll().is_none());
+
+        tokio::time::sleep(Duration::from_millis(105)).await;
+        assert!(cache.poll().unwrap().diagnostics.is_some());
+        assert_eq!(cache.get_expected_digitisers(), &[0, 1]);
+
+        // Once the first frame has expired, frames are complete with the digitisers discovered.
+        let frame_2 = frame(2);
+        for digitiser_id in [1, 0] {
+            assert!(
+                cache
+                    .push(digitiser_id, &frame_2, EventData::dummy_data(0, 5, &[0]))
+                    .is_ok()
+            );
+        }
+        assert!(cache.poll().unwrap().diagnostics.is_none());
+    }
 }
@@ -0,0 +1,87 @@
+//! Defines the diagnostics of a frame which is dispatched before receiving messages from all expected digitisers.
+use super::partial::PartialFrame;
+use chrono::{DateTime, Utc};
+use digital_muon_common::DigitizerId;
+use digital_muon_streaming_types::FrameMetadata;
+use serde::Serialize;
+use std::time::Duration;
+
+/// The metadata of an incomplete frame.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct DiagnosticsMetadata {
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) frame_number: u32,
+    pub(crate) period_number: u64,
+    pub(crate) protons_per_pulse: u8,
+    pub(crate) running: bool,
+    pub(crate) veto_flags: u16,
+}
+
+impl From<&FrameMetadata> for DiagnosticsMetadata {
+    fn from(metadata: &FrameMetadata) -> Self {
+        Self {
+            timestamp: metadata.timestamp,
+            frame_number: metadata.frame_number,
+            period_number: metadata.period_number,
+            protons_per_pulse: metadata.protons_per_pulse,
+            running: metadata.running,
+            veto_flags: metadata.veto_flags,
+        }
+    }
+}
+
+/// When the message of a digitiser arrived, relative to the first message of the frame.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct DigitiserArrival {
+    pub(crate) digitiser_id: DigitizerId,
+    pub(crate) delay_ms: f64,
+}
+
+/// Describes why a frame was incomplete when it was dispatched.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct IncompleteFrameDiagnostics {
+    pub(crate) metadata: DiagnosticsMetadata,
+    /// The digitisers expected at the time the frame was dispatched.
+    pub(crate) expected_digitiser_ids: Vec<DigitizerId>,
+    /// The expected digitisers from which no message was received.
+    pub(crate) missing_digitiser_ids: Vec<DigitizerId>,
+    /// The arrival of each message received, in the order received, so the last are the stragglers.
+    pub(crate) arrivals: Vec<DigitiserArrival>,
+    /// The time the frame spent in the cache, from its first message until it was dispatched.
+    pub(crate) age_ms: f64,
+}
+
+fn as_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1_000.0
+}
+
+impl IncompleteFrameDiagnostics {
+    /// Creates the diagnostics of a partial frame about to be dispatched.
+    /// # Parameters
+    /// - frame: the partial frame.
+    /// - expected_digitisers: the digitisers currently expected, in increasing order.
+    pub(super) fn new<D>(frame: &PartialFrame<D>, expected_digitisers: &[DigitizerId]) -> Self {
+        let digitiser_ids = frame.digitiser_ids();
+        Self {
+            metadata: (&frame.metadata).into(),
+            expected_digitiser_ids: expected_digitisers.to_vec(),
+            missing_digitiser_ids: expected_digitisers
+                .iter()
+                .copied()
+                .filter(|id| digitiser_ids.binary_search(id).is_err())
+                .collect(),
+            arrivals: frame
+                .arrivals()
+                .iter()
+                .map(|&(digitiser_id, delay)| DigitiserArrival {
+                    digitiser_id,
+                    delay_ms: as_ms(delay),
+                })
+                .collect(),
+            age_ms: as_ms(frame.age()),
+        }
+    }
+}
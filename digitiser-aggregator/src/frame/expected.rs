@@ -0,0 +1,244 @@
+//! Defines the set of digitisers from which a frame must receive messages to be complete.
+//!
+//! The set is given at startup, and can be replaced whilst running.
+//! In auto-discovery mode, a digitiser is added to the set when its first message arrives,
+//! and removed once it misses a given number of consecutive frames. As the other digitisers
+//! sending the same frame may not yet have been discovered, no frame is complete from the time
+//! a digitiser is discovered until the next frame is dispatched, which it then is on expiry.
+use const_format::concatcp;
+use digital_muon_common::{DigitizerId, metrics::names::METRIC_NAME_PREFIX};
+use metrics::{counter, gauge};
+use std::collections::BTreeMap;
+use tracing::info;
+
+pub(crate) const EXPECTED_DIGITISERS_METRIC: &str =
+    concatcp!(METRIC_NAME_PREFIX, "expected_digitisers");
+pub(crate) const EXPECTED_DIGITISER_CHANGES_METRIC: &str =
+    concatcp!(METRIC_NAME_PREFIX, "expected_digitiser_changes");
+
+/// Why a digitiser was added to, or removed from, the expected set.
+#[derive(Clone, Copy, Debug)]
+enum ChangeReason {
+    /// The set was replaced by a control message.
+    Control,
+    /// A message arrived from a digitiser not in the set.
+    Discovered,
+    /// The digitiser missed too many consecutive frames.
+    MissedFrames,
+}
+
+impl ChangeReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Control => "control",
+            Self::Discovered => "discovered",
+            Self::MissedFrames => "missed_frames",
+        }
+    }
+}
+
+/// The digitisers expected in each frame.
+#[derive(Debug)]
+pub(crate) struct ExpectedDigitisers {
+    /// The expected digitisers, in increasing order and without duplicates.
+    ids: Vec<DigitizerId>,
+    /// If set, digitisers are discovered from their messages, and removed after missing this many consecutive frames.
+    max_missed_frames: Option<u32>,
+    /// The number of consecutive frames each expected digitiser has missed, only tracked in auto-discovery mode.
+    missed_frames: BTreeMap<DigitizerId, u32>,
+    /// Set if a digitiser has been discovered since the last frame was dispatched.
+    discovering: bool,
+}
+
+impl ExpectedDigitisers {
+    /// Creates and returns a new [ExpectedDigitisers] instance.
+    /// # Parameters
+    /// - ids: the digitisers expected initially, in any order.
+    /// - max_missed_frames: if set, enables auto-discovery mode.
+    pub(crate) fn new(mut ids: Vec<DigitizerId>, max_missed_frames: Option<u32>) -> Self {
+        ids.sort();
+        ids.dedup();
+        for id in ids.iter() {
+            gauge!(
+                EXPECTED_DIGITISERS_METRIC,
+                &[("digitizer_id", id.to_string())]
+            )
+            .set(1);
+        }
+        let missed_frames = match max_missed_frames {
+            Some(_) => ids.iter().map(|&id| (id, 0)).collect(),
+            None => Default::default(),
+        };
+        Self {
+            ids,
+            max_missed_frames,
+            missed_frames,
+            discovering: false,
+        }
+    }
+
+    /// Returns the expected digitisers, in increasing order and without duplicates.
+    pub(crate) fn ids(&self) -> &[DigitizerId] {
+        &self.ids
+    }
+
+    /// Returns the digitisers against which frames are judged complete, or [None] whilst digitisers are being discovered.
+    pub(crate) fn complete_set(&self) -> Option<&[DigitizerId]> {
+        (!self.discovering).then_some(&self.ids)
+    }
+
+    /// Replaces the expected digitisers with `ids`, which may be in any order, as requested by a control message.
+    ///
+    /// Returns `true` if the set changed, or is no longer being discovered.
+    pub(crate) fn replace(&mut self, mut ids: Vec<DigitizerId>) -> bool {
+        ids.sort();
+        ids.dedup();
+        let removed = self
+            .ids
+            .iter()
+            .copied()
+            .filter(|id| ids.binary_search(id).is_err())
+            .collect::<Vec<_>>();
+        let added = ids
+            .iter()
+            .copied()
+            .filter(|id| self.ids.binary_search(id).is_err())
+            .collect::<Vec<_>>();
+        for id in removed.iter().copied() {
+            self.remove(id, ChangeReason::Control);
+        }
+        for id in added.iter().copied() {
+            self.insert(id, ChangeReason::Control);
+        }
+        let was_discovering = std::mem::take(&mut self.discovering);
+        was_discovering || !(removed.is_empty() && added.is_empty())
+    }
+
+    /// In auto-discovery mode, adds the digitiser to the set if it is not already expected.
+    ///
+    /// Returns `true` if the set changed.
+    pub(crate) fn discover(&mut self, id: DigitizerId) -> bool {
+        if self.max_missed_frames.is_none() || self.ids.binary_search(&id).is_ok() {
+            return false;
+        }
+        self.insert(id, ChangeReason::Discovered);
+        self.discovering = true;
+        true
+    }
+
+    /// In auto-discovery mode, records which digitisers contributed to a dispatched frame,
+    /// and removes those which have now missed too many consecutive frames.
+    ///
+    /// Returns `true` if the set changed, or is no longer being discovered.
+    /// # Parameters
+    /// - digitiser_ids: the digitisers present in the frame.
+    pub(crate) fn record_frame(&mut self, digitiser_ids: &[DigitizerId]) -> bool {
+        let Some(max_missed_frames) = self.max_missed_frames else {
+            return false;
+        };
+        let lost = self
+            .missed_frames
+            .iter_mut()
+            .filter_map(|(id, missed)| {
+                if digitiser_ids.contains(id) {
+                    *missed = 0;
+                    None
+                } else {
+                    *missed += 1;
+                    (*missed >= max_missed_frames).then_some(*id)
+                }
+            })
+            .collect::<Vec<_>>();
+        for id in lost.iter().copied() {
+            self.remove(id, ChangeReason::MissedFrames);
+        }
+        let was_discovering = std::mem::take(&mut self.discovering);
+        was_discovering || !lost.is_empty()
+    }
+
+    fn insert(&mut self, id: DigitizerId, reason: ChangeReason) {
+        if let Err(index) = self.ids.binary_search(&id) {
+            info!(
+                "Digitiser {id} added to expected digitisers ({})",
+                reason.as_str()
+            );
+            self.ids.insert(index, id);
+            if self.max_missed_frames.is_some() {
+                self.missed_frames.insert(id, 0);
+            }
+            change_metrics(id, "added", reason, true);
+        }
+    }
+
+    fn remove(&mut self, id: DigitizerId, reason: ChangeReason) {
+        if let Ok(index) = self.ids.binary_search(&id) {
+            info!(
+                "Digitiser {id} removed from expected digitisers ({})",
+                reason.as_str()
+            );
+            self.ids.remove(index);
+            self.missed_frames.remove(&id);
+            change_metrics(id, "removed", reason, false);
+        }
+    }
+}
+
+fn change_metrics(id: DigitizerId, change: &'static str, reason: ChangeReason, expected: bool) {
+    gauge!(
+        EXPECTED_DIGITISERS_METRIC,
+        &[("digitizer_id", id.to_string())]
+    )
+    .set(if expected { 1 } else { 0 });
+    counter!(
+        EXPECTED_DIGITISER_CHANGES_METRIC,
+        &[("change", change), ("reason", reason.as_str())]
+    )
+    .increment(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaced_in_order() {
+        let mut expected = ExpectedDigitisers::new(vec![4, 0, 1, 4], None);
+        assert_eq!(expected.ids(), &[0, 1, 4]);
+
+        assert!(expected.replace(vec![1, 8, 0]));
+        assert_eq!(expected.ids(), &[0, 1, 8]);
+        assert!(!expected.replace(vec![8, 1, 0]));
+    }
+
+    #[test]
+    fn not_discovered_without_auto_discovery() {
+        let mut expected = ExpectedDigitisers::new(vec![0, 1], None);
+        assert!(!expected.discover(2));
+        assert!(!expected.record_frame(&[]));
+        assert_eq!(expected.ids(), &[0, 1]);
+    }
+
+    #[test]
+    fn discovered_and_dropped_after_missed_frames() {
+        let mut expected = ExpectedDigitisers::new(Vec::new(), Some(2));
+        assert!(expected.discover(1));
+        assert!(expected.discover(0));
+        assert!(!expected.discover(1));
+        assert_eq!(expected.ids(), &[0, 1]);
+        assert_eq!(expected.complete_set(), None);
+
+        // Digitiser 1 misses one frame, then returns, so is kept.
+        assert!(expected.record_frame(&[0]));
+        assert_eq!(expected.complete_set(), Some([0, 1].as_slice()));
+        expected.record_frame(&[0, 1]);
+        expected.record_frame(&[0]);
+        assert_eq!(expected.ids(), &[0, 1]);
+
+        // Digitiser 1 misses a second consecutive frame, so is dropped.
+        assert!(expected.record_frame(&[0]));
+        assert_eq!(expected.ids(), &[0]);
+
+        assert!(expected.discover(1));
+        assert_eq!(expected.ids(), &[0, 1]);
+    }
+}
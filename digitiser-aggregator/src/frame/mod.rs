@@ -4,17 +4,29 @@
 //! defined in the [crate::data] module.
 mod aggregated;
 mod cache;
+mod diagnostics;
+mod expected;
 mod partial;
+mod skew;
 
 pub(crate) use aggregated::AggregatedFrame;
-pub(crate) use cache::FrameCache;
+pub(crate) use cache::{FrameCache, TIMESTAMP_NEAR_MISSES_METRIC, TIMESTAMP_OFFSET_METRIC};
+pub(crate) use diagnostics::IncompleteFrameDiagnostics;
+pub(crate) use expected::{
+    EXPECTED_DIGITISER_CHANGES_METRIC, EXPECTED_DIGITISERS_METRIC, ExpectedDigitisers,
+};
+pub(crate) use skew::{
+    CLOCK_SKEW_ALARM_METRIC, ClockSkewGuard, QUARANTINED_MESSAGES_METRIC, SkewReference,
+};
 
 /// Represents the reason why a digitiser event list message is rejected
 pub(crate) enum RejectMessageError {
     /// The frame has already encountered an event list from this digitiser.
     IdAlreadyPresent,
-    /// The event list's timestamp occurs before [FrameCache::latest_timestamp_dispatched].
+    /// The event list's timestamp is no later than [FrameCache::latest_timestamp_dispatched], plus the timestamp tolerance.
     TimestampTooEarly,
+    /// The event list's timestamp is too far ahead of the reference time of the [ClockSkewGuard].
+    Quarantined,
 }
 
 impl From<RejectMessageError> for &'static str {
@@ -22,6 +34,7 @@ impl From<RejectMessageError> for &'static str {
         match value {
             RejectMessageError::IdAlreadyPresent => "id_already_present",
             RejectMessageError::TimestampTooEarly => "timestamp_too_early",
+            RejectMessageError::Quarantined => "quarantined",
         }
     }
 }
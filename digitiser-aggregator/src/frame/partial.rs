@@ -17,9 +17,13 @@ pub(crate) struct PartialFrame<D> {
     span: SpanOnce,
     /// IS `true` if and only if all expected digitiser messages have been collected.
     complete: bool,
+    /// Time at which the first digitiser message of the frame was received.
+    created: Instant,
     /// Time at which the partial frame should be considered expired, and can be dispatched
     /// from the cache even if incomplete.
     expiry: Instant,
+    /// The digitisers from which messages have been received, in the order received, and how long after [Self::created].
+    arrivals: Vec<(DigitizerId, Duration)>,
     /// The uniquely identifying metadata of the frame, common to all digitiser messages related to this frame (except possibly for [FrameMetadata::veto_flags]).
     pub(super) metadata: FrameMetadata,
     /// The frame's event data.
@@ -28,12 +32,15 @@ pub(crate) struct PartialFrame<D> {
 
 impl<D> PartialFrame<D> {
     pub(super) fn new(ttl: Duration, metadata: FrameMetadata) -> Self {
-        let expiry = Instant::now() + ttl;
+        let created = Instant::now();
+        let expiry = created + ttl;
 
         Self {
             span: SpanOnce::default(),
             complete: false,
+            created,
             expiry,
+            arrivals: Vec::new(),
             metadata,
             digitiser_data: Default::default(),
         }
@@ -47,16 +54,21 @@ impl<D> PartialFrame<D> {
         cache_digitiser_ids
     }
 
-    /// Sets the [self.complete] flag to true only if [Self::digitiser_ids] returns
-    /// a list equal to the given `expected_digitisers`.
-    /// Note that `expected_digitisers` must be increasing and non-repeating, otherwise the
-    /// [self.complete] flag is never set. This is not checked, and left to the user.
+    /// Sets the [self.complete] flag to whether messages have been received from every one of `expected_digitisers`,
+    /// which may be fewer than those received from if the expected set has since shrunk, and clears it otherwise.
+    ///
+    /// The flag is also cleared if `expected_digitisers` is [None], as whilst digitisers are being discovered,
+    /// or empty, as a frame cannot be complete without any messages expected.
     ///
     /// [self.complete]: Self::complete
-    pub(super) fn set_completion_status(&mut self, expected_digitisers: &[DigitizerId]) {
-        if self.digitiser_ids() == expected_digitisers {
-            self.complete = true;
-        }
+    pub(super) fn set_completion_status(&mut self, expected_digitisers: Option<&[DigitizerId]>) {
+        let digitiser_ids = self.digitiser_ids();
+        self.complete = expected_digitisers.is_some_and(|expected| {
+            !expected.is_empty()
+                && expected
+                    .iter()
+                    .all(|id| digitiser_ids.binary_search(id).is_ok())
+        });
     }
 
     /// Returns `true` if and only if this provided [DigitizerId] has been seen before.
@@ -69,9 +81,21 @@ impl<D> PartialFrame<D> {
     /// - digitiser_id: the id of the digitiser sending the data.
     /// - data: the data in the message.
     pub(super) fn push(&mut self, digitiser_id: DigitizerId, data: D) {
+        self.arrivals.push((digitiser_id, self.age()));
         self.digitiser_data.push((digitiser_id, data));
     }
 
+    /// Returns the digitisers from which messages have been received, in the order received,
+    /// and how long after the first message each arrived.
+    pub(super) fn arrivals(&self) -> &[(DigitizerId, Duration)] {
+        &self.arrivals
+    }
+
+    /// Returns the time since the first digitiser message of the frame was received.
+    pub(super) fn age(&self) -> Duration {
+        self.created.elapsed()
+    }
+
     /// Ammends the metadata [veto_flags] field with `veto_flags` from a new digitiser message.
     /// This is necessary until it is determined whether [veto_flags] should be identical accross
     /// all digitisers during a frame.
@@ -0,0 +1,176 @@
+//! Guards the frame cache against digitiser messages whose timestamps are too far in the future.
+//!
+//! Once a frame is dispatched, messages with earlier timestamps are rejected, so a single message with
+//! a future timestamp would cause all correctly timestamped messages to be rejected until that time arrives.
+//! Instead, messages whose timestamps are ahead of a reference time by more than the maximum clock skew are
+//! quarantined: they are rejected before reaching the cache, so never become part of a dispatched frame.
+use chrono::{DateTime, TimeDelta, Utc};
+use clap::ValueEnum;
+use const_format::concatcp;
+use digital_muon_common::metrics::names::METRIC_NAME_PREFIX;
+use metrics::{counter, gauge};
+use std::collections::VecDeque;
+use tracing::warn;
+
+pub(crate) const QUARANTINED_MESSAGES_METRIC: &str =
+    concatcp!(METRIC_NAME_PREFIX, "quarantined_messages");
+pub(crate) const CLOCK_SKEW_ALARM_METRIC: &str = concatcp!(METRIC_NAME_PREFIX, "clock_skew_alarm");
+
+/// The time against which the timestamp of each digitiser message is compared.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum SkewReference {
+    /// The current time of the host.
+    #[default]
+    WallClock,
+    /// The median timestamp of recently accepted messages.
+    RollingMedian,
+}
+
+/// Quarantines digitiser messages whose timestamps are too far ahead of the reference time.
+#[derive(Debug)]
+pub(crate) struct ClockSkewGuard {
+    /// The greatest amount by which a timestamp may be ahead of the reference time.
+    max_skew: TimeDelta,
+    reference: SkewReference,
+    /// The number of recently accepted timestamps from which the rolling median is taken.
+    window: usize,
+    /// The most recently accepted timestamps, only kept if the reference is [SkewReference::RollingMedian].
+    recent: VecDeque<DateTime<Utc>>,
+    /// The timestamps of messages quarantined since a message was last accepted, only kept if the reference is [SkewReference::RollingMedian].
+    quarantined: Vec<DateTime<Utc>>,
+}
+
+impl ClockSkewGuard {
+    /// Creates and returns a new [ClockSkewGuard] instance.
+    /// # Parameters
+    /// - max_skew: the greatest amount by which a timestamp may be ahead of the reference time.
+    /// - reference: the time against which timestamps are compared.
+    /// - window: the number of recently accepted timestamps from which the rolling median is taken.
+    pub(crate) fn new(max_skew: TimeDelta, reference: SkewReference, window: usize) -> Self {
+        Self {
+            max_skew,
+            reference,
+            window: window.max(1),
+            recent: Default::default(),
+            quarantined: Default::default(),
+        }
+    }
+
+    /// Returns the time against which timestamps are compared, if there is one.
+    ///
+    /// The rolling median is undefined until a message has been accepted.
+    fn reference_time(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self.reference {
+            SkewReference::WallClock => Some(now),
+            SkewReference::RollingMedian => {
+                let mut recent = self.recent.iter().copied().collect::<Vec<_>>();
+                recent.sort();
+                recent.get(recent.len() / 2).copied()
+            }
+        }
+    }
+
+    /// Checks the timestamp of a digitiser message against the reference time.
+    ///
+    /// Returns `true` if the message is accepted, or `false` if it is quarantined,
+    /// in which case the alarm metric is raised until a message is next accepted.
+    ///
+    /// If the reference is the rolling median, and as many consecutive messages as the window
+    /// are quarantined, the timestamps have genuinely jumped forward (or those previously accepted were wrong),
+    /// so the window is replaced by the quarantined timestamps, and the last of these messages is accepted.
+    /// # Parameters
+    /// - timestamp: the metadata timestamp of the message.
+    /// - now: the current time of the host.
+    pub(crate) fn accept(&mut self, timestamp: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        if let Some(reference) = self.reference_time(now) {
+            let skew = timestamp - reference;
+            if skew > self.max_skew {
+                if self.reference == SkewReference::RollingMedian {
+                    self.quarantined.push(timestamp);
+                    if self.quarantined.len() >= self.window {
+                        warn!(
+                            "Rolling median reset after {} consecutive messages were quarantined",
+                            self.quarantined.len()
+                        );
+                        self.recent = self.quarantined.drain(..).collect();
+                        gauge!(CLOCK_SKEW_ALARM_METRIC).set(0);
+                        return true;
+                    }
+                }
+                warn!(
+                    "Quarantined message whose timestamp {timestamp} is {skew} ahead of {reference}"
+                );
+                counter!(QUARANTINED_MESSAGES_METRIC).increment(1);
+                gauge!(CLOCK_SKEW_ALARM_METRIC).set(1);
+                return false;
+            }
+        }
+        gauge!(CLOCK_SKEW_ALARM_METRIC).set(0);
+        if self.reference == SkewReference::RollingMedian {
+            self.quarantined.clear();
+            if self.recent.len() == self.window {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(timestamp);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quarantined_against_wall_clock() {
+        let now = DateTime::UNIX_EPOCH + TimeDelta::days(10_000);
+        let mut guard = ClockSkewGuard::new(TimeDelta::seconds(1), SkewReference::WallClock, 8);
+        assert!(guard.accept(now - TimeDelta::days(1), now));
+        assert!(guard.accept(now + TimeDelta::seconds(1), now));
+        assert!(!guard.accept(now + TimeDelta::seconds(2), now));
+        assert!(!guard.accept(now + TimeDelta::days(1), now));
+    }
+
+    #[test]
+    fn quarantined_against_rolling_median() {
+        // The wall clock is ignored.
+        let now = DateTime::UNIX_EPOCH;
+        let start = DateTime::UNIX_EPOCH + TimeDelta::days(10_000);
+        let frame = |number: i64| start + TimeDelta::milliseconds(20 * number);
+        let mut guard = ClockSkewGuard::new(TimeDelta::seconds(1), SkewReference::RollingMedian, 3);
+
+        for number in 0..5 {
+            assert!(guard.accept(frame(number), now));
+        }
+        // The median of frames 2, 3 and 4 is that of frame 3.
+        assert!(guard.accept(frame(3) + TimeDelta::seconds(1), now));
+        assert!(!guard.accept(frame(4) + TimeDelta::days(1), now));
+        // The quarantined message does not move the median.
+        assert!(guard.accept(frame(5), now));
+    }
+
+    #[test]
+    fn rolling_median_recovers_after_jump() {
+        let now = DateTime::UNIX_EPOCH;
+        let start = DateTime::UNIX_EPOCH + TimeDelta::days(10_000);
+        let frame = |number: i64| start + TimeDelta::milliseconds(20 * number);
+        let mut guard = ClockSkewGuard::new(TimeDelta::seconds(1), SkewReference::RollingMedian, 3);
+
+        for number in 0..3 {
+            assert!(guard.accept(frame(number), now));
+        }
+
+        // An isolated future message is quarantined, and does not count towards a reset.
+        assert!(!guard.accept(frame(3) + TimeDelta::days(1), now));
+        assert!(guard.accept(frame(3), now));
+
+        // After a genuine jump of an hour, such as a gap between runs, the first messages are quarantined
+        // until there are as many as the window, after which the median follows the new timestamps.
+        let jump = TimeDelta::hours(1);
+        assert!(!guard.accept(frame(4) + jump, now));
+        assert!(!guard.accept(frame(5) + jump, now));
+        assert!(guard.accept(frame(6) + jump, now));
+        assert!(guard.accept(frame(7) + jump, now));
+        assert!(!guard.accept(frame(8) + jump + TimeDelta::days(1), now));
+    }
+}
@@ -12,6 +12,12 @@
 //! * Records completion status of a frame event list message as well as all digitiser ids that contributed to it.
 //! * Ignores any digitiser message whose timestamp is before the that of last frame event list to be dispatched.
 //! * Ignores any digitiser message whose [id] and [metadata] have already been seen.
+//! * Optionally matches digitiser messages to frames whose timestamps differ by no more than a given tolerance.
+//! * Optionally quarantines digitiser messages whose timestamps are too far in the future.
+//! * Optionally publishes the diagnostics of each incomplete frame, listing its missing digitisers, to a separate topic.
+//! * Optionally produces frames in Kafka transactions, in which the offsets of the digitiser messages they contain are also committed.
+//! * Optionally checkpoints its state to local disk, so that frames still in the cache are rebuilt after a restart.
+//! * The expected digitisers can be replaced via a control topic, or discovered from the messages received.
 //!
 //! ## Assumptions
 //! * That each [DigitizerEventListMessage] has equally sized event fields (i.e. [time], [channel], and [voltage] are
@@ -20,9 +26,11 @@
 //!
 //! ## Error Conditions
 //! * Missing fields of the [DigitizerEventListMessage] will cause it to be ignored.
+//! * Pulse shape fields whose lengths differ from the number of events are dropped, with a warning.
 //! * If a single digitser message has metadata timestamp set to a future time,
 //!   this will cause the component to reject all subsequent messages (correctly timestamped)
-//!   until the time of the erroneous future timestamp arrives.
+//!   until the time of the erroneous future timestamp arrives, unless `--max-clock-skew-ms` is given,
+//!   in which case messages more than this far ahead of the reference time are quarantined.
 //! * If a digitser message has metadata timestamp set earlier than intended, it will be ignored
 //!   unless it happens to be before the timestamp of the last message to be dispatched.
 //!   In this case the digitser message may be inserted into the wrong frame, or may result in a
@@ -33,22 +41,39 @@
 //! [voltage]: DigitizerEventListMessage::voltage
 //! [id]: DigitizerEventListMessage::digitizer_id()
 //! [metadata]: DigitizerEventListMessage::metadata()
+mod checkpoint;
+mod control;
 mod data;
 mod frame;
+#[cfg(test)]
+mod restart_harness;
+mod transaction;
 
 use crate::data::EventData;
+use checkpoint::Checkpoint;
+use chrono::TimeDelta;
 use clap::Parser;
 use digital_muon_common::{
-    CommonKafkaOpts, DigitizerId, init_tracer,
+    CommonKafkaOpts, DigitizerId,
+    backpressure::{BACKPRESSURE_POLL_INTERVAL, Backpressure, BackpressureOpts},
+    control::{recv_control, replay_control_messages},
+    init_tracer,
     metrics::{
         component_info_metric,
         failures::{self, FailureKind},
         messages_received::{self, MessageKind},
-        names::{FAILURES, FRAMES_SENT, MESSAGES_PROCESSED, MESSAGES_RECEIVED},
+        names::{
+            CONSUMER_PAUSED, CONSUMER_PAUSED_TIME, FAILURES, FRAMES_SENT, MESSAGES_PROCESSED,
+            MESSAGES_RECEIVED, SEND_QUEUE_DEPTH,
+        },
     },
     record_metadata_fields_to_span,
     spanned::Spanned,
     tracer::{FutureRecordTracerExt, OptionalHeaderTracerExt, TracerEngine, TracerOptions},
+    transaction::{
+        KafkaTransactions, SharedTransaction, Transaction, TransactionOpts,
+        TransactionalConsumerContext,
+    },
 };
 use digital_muon_streaming_types::{
     dev2_digitizer_event_v2_generated::{
@@ -57,30 +82,180 @@ use digital_muon_streaming_types::{
     },
     flatbuffers::InvalidFlatbuffer,
 };
-use frame::{AggregatedFrame, FrameCache};
+use frame::{
+    AggregatedFrame, CLOCK_SKEW_ALARM_METRIC, ClockSkewGuard, EXPECTED_DIGITISER_CHANGES_METRIC,
+    EXPECTED_DIGITISERS_METRIC, ExpectedDigitisers, FrameCache, IncompleteFrameDiagnostics,
+    QUARANTINED_MESSAGES_METRIC, SkewReference, TIMESTAMP_NEAR_MISSES_METRIC,
+    TIMESTAMP_OFFSET_METRIC,
+};
 use metrics::counter;
 use metrics_exporter_prometheus::PrometheusBuilder;
 use miette::{Context, IntoDiagnostic};
 use rdkafka::{
     consumer::{CommitMode, Consumer},
+    error::KafkaError,
     message::{BorrowedMessage, Message},
-    producer::{FutureProducer, FutureRecord},
+    producer::{FutureProducer, FutureRecord, Producer},
+    types::RDKafkaErrorCode,
     util::Timeout,
 };
-use std::{fmt::Debug, net::SocketAddr, time::Duration};
+use std::{fmt::Debug, net::SocketAddr, path::PathBuf, sync::MutexGuard, time::Duration};
+use thiserror::Error;
 use tokio::{
     select,
     signal::unix::{Signal, SignalKind, signal},
-    sync::mpsc::{Receiver, Sender, error::SendError},
+    sync::mpsc::{Receiver, Sender, error::TrySendError},
     task::JoinHandle,
 };
-use tracing::{debug, error, info, info_span, instrument, warn};
+use tracing::{Span, debug, error, info, info_span, instrument, warn};
+use transaction::HeldOffsets;
 
 /// Triggers error if the producer takes longer than this to dispatch a message.
 const PRODUCER_TIMEOUT: Timeout = Timeout::After(Duration::from_millis(100));
 
+/// How long to wait before retrying to produce a frame, whilst the producer's queue is full.
+const QUEUE_FULL_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
 type AggregatedFrameToBufferSender = Sender<AggregatedFrame<EventData>>;
-type SendAggregatedFrameError = SendError<AggregatedFrame<EventData>>;
+
+/// Represents the reasons a completed frame could not be dispatched.
+#[derive(Debug, Error)]
+enum DispatchFrameError {
+    #[error("Send-Frame channel closed")]
+    ChannelClosed,
+    #[error("Send-Frame buffer full")]
+    BufferFull,
+    #[error("Kafka Error: {0}")]
+    Kafka(#[from] KafkaError),
+}
+
+/// Where completed frames are sent.
+enum FrameDispatch<'a> {
+    /// To the producer task, through the send channel.
+    Channel {
+        channel_send: &'a AggregatedFrameToBufferSender,
+        /// If true, then an error is returned when the send channel is full, rather than waiting for space.
+        exit_on_full_send_buffer: bool,
+    },
+    /// Directly to the producer, in the open transaction.
+    Transaction {
+        use_otel: bool,
+        producer: &'a FutureProducer,
+        output_topic: &'a str,
+        /// If true, then an error is returned when the producer's queue is full, rather than waiting for space.
+        exit_on_full_send_buffer: bool,
+    },
+}
+
+impl FrameDispatch<'_> {
+    /// Dispatches the given frame.
+    async fn send(&self, frame: AggregatedFrame<EventData>) -> Result<(), DispatchFrameError> {
+        match self {
+            Self::Channel {
+                channel_send,
+                exit_on_full_send_buffer,
+            } => {
+                // Reserves space in the message queue if it is available
+                // Or waits for space if none is available, unless exiting instead.
+                let permit = if *exit_on_full_send_buffer {
+                    channel_send.try_reserve().map_err(|e| match e {
+                        TrySendError::Full(_) => DispatchFrameError::BufferFull,
+                        TrySendError::Closed(_) => DispatchFrameError::ChannelClosed,
+                    })
+                } else {
+                    channel_send
+                        .reserve()
+                        .await
+                        .map_err(|_| DispatchFrameError::ChannelClosed)
+                };
+                match permit {
+                    Ok(permit) => {
+                        permit.send(frame);
+                        Ok(())
+                    }
+                    Err(e) => {
+                        error!("{e}");
+                        Err(e)
+                    }
+                }
+            }
+            Self::Transaction {
+                use_otel,
+                producer,
+                output_topic,
+                exit_on_full_send_buffer,
+            } => {
+                produce_frame_in_transaction(
+                    *use_otel,
+                    frame,
+                    producer,
+                    output_topic,
+                    *exit_on_full_send_buffer,
+                )
+                .await
+            }
+        }
+    }
+
+    /// Returns the number of frames waiting to be produced.
+    ///
+    /// In transactional mode these are the messages the producer has yet to deliver,
+    /// which include any diagnostics of incomplete frames.
+    fn queue_depth(&self) -> usize {
+        match self {
+            Self::Channel { channel_send, .. } => {
+                channel_send.max_capacity() - channel_send.capacity()
+            }
+            Self::Transaction { producer, .. } => producer.in_flight_count().max(0) as usize,
+        }
+    }
+}
+
+/// Where the diagnostics of incomplete frames are sent.
+struct DiagnosticsDispatch<'a> {
+    producer: &'a FutureProducer,
+    topic: &'a str,
+}
+
+impl DiagnosticsDispatch<'_> {
+    /// Publishes the diagnostics of an incomplete frame, without waiting for them to be delivered.
+    fn send(&self, diagnostics: &IncompleteFrameDiagnostics) {
+        let payload = match serde_json::to_vec(diagnostics) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Failed to serialise incomplete frame diagnostics: {e}");
+                return;
+            }
+        };
+
+        match self.producer.send_result(
+            FutureRecord::to(self.topic)
+                .payload(&payload)
+                .key("Incomplete Frame Diagnostics"),
+        ) {
+            Ok(future) => {
+                tokio::spawn(async move {
+                    if let Ok(Err((e, _))) = future.await {
+                        error!("Failed to publish incomplete frame diagnostics: {e}");
+                        counter!(
+                            FAILURES,
+                            &[failures::get_label(FailureKind::KafkaPublishFailed)]
+                        )
+                        .increment(1);
+                    }
+                });
+            }
+            Err((e, _)) => {
+                error!("Failed to publish incomplete frame diagnostics: {e}");
+                counter!(
+                    FAILURES,
+                    &[failures::get_label(FailureKind::KafkaPublishFailed)]
+                )
+                .increment(1);
+            }
+        }
+    }
+}
 
 /// [clap] derived struct to handle command line parameters.
 #[derive(Debug, Parser)]
@@ -101,27 +276,78 @@ struct Cli {
     #[clap(long)]
     output_topic: String,
 
+    /// If set, a JSON document describing each incomplete frame is published to this topic, see README.md.
+    #[clap(long)]
+    incomplete_frame_topic: Option<String>,
+
     /// A list of expected digitiser IDs.
     /// Can be passed as `-d0 -d1 ...` or `-d=0,1,...`
     /// A frame is only "complete" when a message has been received from each of these IDs.
-    #[clap(short, long, value_delimiter = ',')]
+    /// Required unless the digitisers are discovered.
+    #[clap(
+        short,
+        long,
+        value_delimiter = ',',
+        required_unless_present = "auto_discovery_missed_frames"
+    )]
     digitiser_ids: Vec<DigitizerId>,
 
+    /// If set, digitisers are added to the expected digitisers when their first message is received,
+    /// and removed after missing this many consecutive frames, see README.md.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    auto_discovery_missed_frames: Option<u32>,
+
+    /// Kafka topic on which to listen for messages replacing the expected digitiser IDs, see README.md.
+    #[clap(long)]
+    control_topic: Option<String>,
+
     /// Frame TTL in milliseconds.
     /// The time in which messages for a given frame must have been received from all digitisers.
     #[clap(long, default_value = "500")]
     frame_ttl_ms: u64,
 
+    /// The greatest difference, in nanoseconds, between the timestamps of digitiser messages belonging to the same frame.
+    /// Messages must also agree on frame number and the rest of their metadata, see README.md.
+    #[clap(long, default_value = "0", value_parser = clap::value_parser!(i64).range(0..))]
+    timestamp_tolerance_ns: i64,
+
+    /// If set, digitiser messages whose timestamps are more than this many milliseconds ahead of
+    /// the reference time are quarantined, see README.md.
+    #[clap(long, value_parser = clap::value_parser!(i64).range(0..))]
+    max_clock_skew_ms: Option<i64>,
+
+    /// The time against which the timestamps of digitiser messages are compared.
+    #[clap(long, default_value = "wall-clock", requires = "max_clock_skew_ms")]
+    clock_skew_reference: SkewReference,
+
+    /// The number of recently accepted timestamps from which the rolling median is taken.
+    #[clap(long, default_value = "64", value_parser = clap::value_parser!(u64).range(1..), requires = "max_clock_skew_ms")]
+    clock_skew_window: u64,
+
     /// Frame cache poll interval in milliseconds.
     /// This may affect the rate at which incomplete frames are transmitted.
     #[clap(long, default_value = "500")]
     cache_poll_ms: u64,
 
     /// Size of the send frame buffer.
-    /// If this limit is exceeded, the component will exit.
+    /// The consumer is paused whilst the buffer is filled beyond its high-water mark.
     #[clap(long, default_value = "1024")]
     send_frame_buffer_size: usize,
 
+    #[clap(flatten)]
+    backpressure_options: BackpressureOpts,
+
+    #[clap(flatten)]
+    transaction_options: TransactionOpts,
+
+    /// If set, the state of the aggregator is checkpointed to this file, from which it resumes after a restart, see README.md.
+    #[clap(long, conflicts_with = "transactional_id")]
+    checkpoint_file: Option<PathBuf>,
+
+    /// Checkpoint interval in milliseconds.
+    #[clap(long, default_value = "1000", requires = "checkpoint_file")]
+    checkpoint_interval_ms: u64,
+
     /// Endpoint on which Prometheus text format metrics are available
     #[clap(long, env, default_value = "127.0.0.1:9090")]
     observability_address: SocketAddr,
@@ -147,26 +373,71 @@ async fn main() -> miette::Result<()> {
 
     let kafka_opts = args.common_kafka_options;
 
-    let consumer = digital_muon_common::create_default_consumer(
+    let producer = args
+        .transaction_options
+        .create_producer(&mut digital_muon_common::generate_kafka_client_config(
+            &kafka_opts.broker,
+            &kafka_opts.username,
+            &kafka_opts.password,
+        ))
+        .into_diagnostic()?;
+
+    // In transactional mode, frames are produced directly in the open transaction, rather than by the producer task.
+    let transaction = args.transaction_options.transaction();
+
+    let consumer = digital_muon_common::create_consumer_with_context(
         &kafka_opts.broker,
         &kafka_opts.username,
         &kafka_opts.password,
         &args.consumer_group,
         Some(&[args.input_topic.as_str()]),
+        TransactionalConsumerContext::new(&producer, transaction.as_ref()),
     )
     .into_diagnostic()?;
 
-    let producer: FutureProducer = digital_muon_common::generate_kafka_client_config(
-        &kafka_opts.broker,
-        &kafka_opts.username,
-        &kafka_opts.password,
-    )
-    .create()
-    .into_diagnostic()?;
+    // The control topic is read outside the consumer group, so that every instance receives every control message.
+    let control = args
+        .control_topic
+        .as_deref()
+        .map(|control_topic| {
+            digital_muon_common::create_latest_message_consumer(
+                &kafka_opts.broker,
+                &kafka_opts.username,
+                &kafka_opts.password,
+                control_topic,
+            )
+        })
+        .transpose()
+        .into_diagnostic()?;
 
     let ttl = Duration::from_millis(args.frame_ttl_ms);
 
-    let mut cache = FrameCache::<EventData>::new(ttl, args.digitiser_ids.clone());
+    let mut cache = FrameCache::<EventData>::new(
+        ttl,
+        ExpectedDigitisers::new(
+            args.digitiser_ids.clone(),
+            args.auto_discovery_missed_frames,
+        ),
+        TimeDelta::nanoseconds(args.timestamp_tolerance_ns),
+        args.max_clock_skew_ms.map(|max_clock_skew_ms| {
+            ClockSkewGuard::new(
+                TimeDelta::milliseconds(max_clock_skew_ms),
+                args.clock_skew_reference,
+                args.clock_skew_window as usize,
+            )
+        }),
+    );
+
+    // If checkpointing, the component resumes from the last checkpoint written, if any.
+    let mut checkpoint = args
+        .checkpoint_file
+        .as_deref()
+        .map(Checkpoint::load)
+        .transpose()
+        .into_diagnostic()?
+        .flatten()
+        .unwrap_or_default();
+    checkpoint.restore(&mut cache);
 
     // Install exporter and register metrics
     let builder = PrometheusBuilder::new();
@@ -195,8 +466,53 @@ async fn main() -> miette::Result<()> {
         metrics::Unit::Count,
         "Number of complete frames sent by the aggregator"
     );
+    metrics::describe_gauge!(SEND_QUEUE_DEPTH, "Number of frames waiting to be produced");
+    metrics::describe_gauge!(
+        CONSUMER_PAUSED,
+        "Set to 1 whilst the consumer is paused as the send buffer is full"
+    );
+    metrics::describe_counter!(
+        CONSUMER_PAUSED_TIME,
+        metrics::Unit::Milliseconds,
+        "Total time for which the consumer has been paused"
+    );
+    metrics::describe_gauge!(
+        EXPECTED_DIGITISERS_METRIC,
+        "Set to 1 for each expected digitiser, and 0 for each which has been removed"
+    );
+    metrics::describe_counter!(
+        EXPECTED_DIGITISER_CHANGES_METRIC,
+        metrics::Unit::Count,
+        "Number of digitisers added to, or removed from, the expected digitisers"
+    );
+    metrics::describe_counter!(
+        TIMESTAMP_NEAR_MISSES_METRIC,
+        metrics::Unit::Count,
+        "Number of digitiser messages whose timestamp differs from that of a frame with the same frame number by more than the tolerance"
+    );
+    metrics::describe_histogram!(
+        TIMESTAMP_OFFSET_METRIC,
+        metrics::Unit::Nanoseconds,
+        "Timestamp difference between each digitiser message and the frame with the same frame number"
+    );
+    metrics::describe_counter!(
+        QUARANTINED_MESSAGES_METRIC,
+        metrics::Unit::Count,
+        "Number of digitiser messages quarantined as their timestamps are too far in the future"
+    );
+    metrics::describe_gauge!(
+        CLOCK_SKEW_ALARM_METRIC,
+        "Set to 1 whilst the latest digitiser message has been quarantined, 0 otherwise"
+    );
+
+    let mut backpressure =
+        Backpressure::new(&args.backpressure_options, args.send_frame_buffer_size)
+            .into_diagnostic()?;
+    let mut backpressure_interval = tokio::time::interval(BACKPRESSURE_POLL_INTERVAL);
 
     let mut cache_poll_interval = tokio::time::interval(Duration::from_millis(args.cache_poll_ms));
+    let mut checkpoint_interval =
+        tokio::time::interval(Duration::from_millis(args.checkpoint_interval_ms));
 
     // Creates Send-Frame thread and returns channel sender
     let (channel_send, producer_task_handle) = create_producer_task(
@@ -207,33 +523,118 @@ async fn main() -> miette::Result<()> {
     )
     .into_diagnostic()?;
 
+    let transactions = KafkaTransactions::new(&producer, &consumer);
+    let dispatch = match transaction {
+        Some(_) => FrameDispatch::Transaction {
+            use_otel: tracer.use_otel(),
+            producer: &producer,
+            output_topic: &args.output_topic,
+            exit_on_full_send_buffer: args.backpressure_options.exit_on_full_send_buffer,
+        },
+        None => FrameDispatch::Channel {
+            channel_send: &channel_send,
+            exit_on_full_send_buffer: args.backpressure_options.exit_on_full_send_buffer,
+        },
+    };
+
+    let diagnostics = args
+        .incomplete_frame_topic
+        .as_deref()
+        .map(|topic| DiagnosticsDispatch {
+            producer: &producer,
+            topic,
+        });
+
+    // In transactional or checkpointing mode, the offset of each message is held until the frame it belongs to is dispatched.
+    let mut held_offsets =
+        (transaction.is_some() || args.checkpoint_file.is_some()).then(HeldOffsets::default);
+
     // Is used to await any sigint signals
     let mut sigint = signal(SignalKind::interrupt()).into_diagnostic()?;
 
     component_info_metric("digitiser-aggregator");
 
+    // The latest expected digitisers on the control topic are applied before any digitiser message is processed.
+    if let Some((control_consumer, replayed)) = &control {
+        replay_control_messages(control_consumer, *replayed, |msg| {
+            control::process_control_message(&mut cache, msg);
+            Ok::<_, miette::Report>(())
+        })
+        .await?;
+    }
+
     loop {
         tokio::select! {
+            msg = recv_control(control.as_ref().map(|(control_consumer, _)| control_consumer)) => match msg {
+                Ok(msg) => control::process_control_message(&mut cache, &msg),
+                Err(e) => warn!("Kafka error: {}", e),
+            },
             event = consumer.recv() => {
                 match event {
                     Ok(msg) => {
-                        process_kafka_message(tracer.use_otel(), &channel_send, &mut cache, &msg).await.into_diagnostic().wrap_err("Failed to process incomming message")?;
-                        consumer.commit_message(&msg, CommitMode::Async)
-                            .expect("Message should commit");
+                        // On first consuming from a partition, it is rewound to its checkpointed offset, if it is beyond it.
+                        let rewound = held_offsets.as_ref().is_some_and(|held_offsets| held_offsets.is_new_partition(msg.partition()))
+                            && checkpoint.rewind(&consumer, &msg).into_diagnostic()?;
+                        if !rewound {
+                            if let (Some(transaction), Some(held_offsets)) = (&transaction, &held_offsets) {
+                                if held_offsets.is_new_partition(msg.partition()) {
+                                    transaction::restore_latest_timestamp_dispatched(&consumer, &mut cache, msg.topic(), msg.partition()).into_diagnostic()?;
+                                }
+                                lock(transaction).begin(&transactions).into_diagnostic()?;
+                            }
+                            if let Some(held_offsets) = &mut held_offsets {
+                                held_offsets.consume(msg.partition(), msg.offset());
+                            }
+                            process_kafka_message(tracer.use_otel(), &dispatch, diagnostics.as_ref(), held_offsets.as_mut(), &mut cache, &msg).await.into_diagnostic().wrap_err("Failed to process incomming message")?;
+                            if let (Some(transaction), Some(held_offsets)) = (&transaction, &mut held_offsets) {
+                                transaction::stage_offsets(&consumer, &mut lock(transaction), held_offsets, &cache, &args.input_topic).into_diagnostic()?;
+                            }
+                            if held_offsets.is_none() {
+                                consumer.commit_message(&msg, CommitMode::Async)
+                                    .expect("Message should commit");
+                            }
+                        }
+                        backpressure.update(&consumer, &args.input_topic, dispatch.queue_depth()).into_diagnostic()?;
                     }
                     Err(e) => warn!("Kafka error: {}", e),
                 };
             }
             _ = cache_poll_interval.tick() => {
-                cache_poll(&channel_send, &mut cache).await.into_diagnostic()?;
+                if let Some(transaction) = &transaction {
+                    lock(transaction).begin(&transactions).into_diagnostic()?;
+                }
+                cache_poll(&dispatch, diagnostics.as_ref(), &mut cache).await.into_diagnostic()?;
+                if let (Some(transaction), Some(held_offsets)) = (&transaction, &mut held_offsets) {
+                    transaction::stage_offsets(&consumer, &mut lock(transaction), held_offsets, &cache, &args.input_topic).into_diagnostic()?;
+                }
+            }
+            _ = backpressure_interval.tick() => {
+                backpressure.update(&consumer, &args.input_topic, dispatch.queue_depth()).into_diagnostic()?;
+            }
+            _ = checkpoint_interval.tick() => {
+                if let (Some(path), Some(held_offsets)) = (&args.checkpoint_file, &mut held_offsets) {
+                    checkpoint::write_checkpoint(path, &consumer, held_offsets, &cache, &args.input_topic).into_diagnostic()?;
+                }
             }
             _ = sigint.recv() => {
+                if let Some(transaction) = &transaction {
+                    lock(transaction).commit(&transactions).into_diagnostic()?;
+                }
+                if let (Some(path), Some(held_offsets)) = (&args.checkpoint_file, &mut held_offsets) {
+                    checkpoint::write_checkpoint(path, &consumer, held_offsets, &cache, &args.input_topic).into_diagnostic()?;
+                }
                 //  Wait for the channel to close and
                 //  all pending production tasks to finish
                 producer_task_handle.await.into_diagnostic()?;
                 return Ok(());
             }
         }
+        if let Some(transaction) = &transaction {
+            let mut transaction = lock(transaction);
+            if transaction.is_due() {
+                transaction.commit(&transactions).into_diagnostic()?;
+            }
+        }
     }
 }
 
@@ -248,7 +649,9 @@ fn spanned_root_as_digitizer_event_list_message(
 /// Extracts the payload of a Kafka message and passes it to [process_digitiser_event_list_message]
 /// # Parameters
 /// - use_otel: if true, then attempts to extract a parent [Span] from the Kafka headers.
-/// - channel_send: send channel which takes [AggregatedFrame] objects to dispatch.
+/// - dispatch: where to dispatch [AggregatedFrame] objects.
+/// - diagnostics: if given, where to send the diagnostics of incomplete frames.
+/// - held_offsets: if given, the offset of the message is held until its frame is dispatched.
 /// - cache: the cache in which frames are stored whilst awaiting digitiser messages.
 /// - msg: the message.
 ///
@@ -256,10 +659,12 @@ fn spanned_root_as_digitizer_event_list_message(
 #[instrument(skip_all, level = "debug", err(level = "warn"))]
 async fn process_kafka_message(
     use_otel: bool,
-    channel_send: &AggregatedFrameToBufferSender,
+    dispatch: &FrameDispatch<'_>,
+    diagnostics: Option<&DiagnosticsDispatch<'_>>,
+    held_offsets: Option<&mut HeldOffsets>,
     cache: &mut FrameCache<EventData>,
     msg: &BorrowedMessage<'_>,
-) -> Result<(), SendAggregatedFrameError> {
+) -> Result<(), DispatchFrameError> {
     msg.headers().conditional_extract_to_current_span(use_otel);
 
     if let Some(payload) = msg.payload() {
@@ -273,9 +678,12 @@ async fn process_kafka_message(
                 Ok(data) => {
                     let kafka_timestamp_ms = msg.timestamp().to_millis().unwrap_or(-1);
                     process_digitiser_event_list_message(
-                        channel_send,
+                        dispatch,
+                        diagnostics,
+                        held_offsets,
                         cache,
                         kafka_timestamp_ms,
+                        (msg.partition(), msg.offset()),
                         data,
                     )
                     .await?;
@@ -305,8 +713,11 @@ async fn process_kafka_message(
 
 /// Processes a [DigitizerEventListMessage], pushing it to the given [FrameCache].
 /// # Parameters
-/// - channel_send: send channel which takes [AggregatedFrame] objects to dispatch.
+/// - dispatch: where to dispatch [AggregatedFrame] objects.
+/// - diagnostics: if given, where to send the diagnostics of incomplete frames.
+/// - held_offsets: if given, the offset of the message is held until its frame is dispatched.
 /// - kafka_message_timestamp_ms: the timestamp in milliseconds as reported in the Kafka message header. Only used for tracing.
+/// - (partition, offset): the position of the Kafka message.
 /// - cache: the cache in which frames are stored whilst awaiting digitiser messages.
 /// - message: the digitiser message.
 #[tracing::instrument(skip_all, fields(
@@ -321,25 +732,37 @@ async fn process_kafka_message(
     num_cached_frames = cache.get_num_partial_frames(),
     timestamp_too_early = false,
     id_already_present = false,
+    quarantined = false,
 ))]
 async fn process_digitiser_event_list_message(
-    channel_send: &AggregatedFrameToBufferSender,
+    dispatch: &FrameDispatch<'_>,
+    diagnostics: Option<&DiagnosticsDispatch<'_>>,
+    held_offsets: Option<&mut HeldOffsets>,
     cache: &mut FrameCache<EventData>,
     kafka_message_timestamp_ms: i64,
+    (partition, offset): (i32, i64),
     message: DigitizerEventListMessage<'_>,
-) -> Result<(), SendAggregatedFrameError> {
+) -> Result<(), DispatchFrameError> {
     match message.metadata().try_into() {
         Ok(metadata) => {
             debug!("Event packet: metadata: {:?}", message.metadata());
 
             // Push the current digitiser message to the frame cache, possibly creating a new partial frame
-            if let Err(err) = cache.push(message.digitizer_id(), &metadata, message.into()) {
-                tracing::Span::current().record(err.into(), true);
+            // Only the offsets of messages accepted into the cache are held, under the timestamp of the frame they joined.
+            match cache.push(message.digitizer_id(), &metadata, message.into()) {
+                Ok(frame_timestamp) => {
+                    if let Some(held_offsets) = held_offsets {
+                        held_offsets.hold(partition, offset, frame_timestamp);
+                    }
+                }
+                Err(err) => {
+                    tracing::Span::current().record(err.into(), true);
+                }
             }
 
             record_metadata_fields_to_span!(&metadata, tracing::Span::current());
 
-            cache_poll(channel_send, cache).await?;
+            cache_poll(dispatch, diagnostics, cache).await?;
         }
         Err(e) => {
             warn!("Invalid Metadata: {e}");
@@ -357,13 +780,15 @@ async fn process_digitiser_event_list_message(
 ///
 /// If there are, this function removes them from the cache and sends them to the given send channel.
 /// # Parameters
-/// - channel_send: send channel which takes [AggregatedFrame] objects to dispatch.
+/// - dispatch: where to dispatch [AggregatedFrame] objects.
+/// - diagnostics: if given, where to send the diagnostics of incomplete frames.
 /// - cache: the cache in which frames are stored whilst awaiting digitiser messages.
 #[tracing::instrument(skip_all, level = "trace")]
 async fn cache_poll(
-    channel_send: &AggregatedFrameToBufferSender,
+    dispatch: &FrameDispatch<'_>,
+    diagnostics: Option<&DiagnosticsDispatch<'_>>,
     cache: &mut FrameCache<EventData>,
-) -> Result<(), SendAggregatedFrameError> {
+) -> Result<(), DispatchFrameError> {
     while let Some(frame) = cache.poll() {
         let span = info_span!("Frame Completed");
         span.follows_from(
@@ -374,24 +799,26 @@ async fn cache_poll(
         );
         let _guard = span.enter();
 
-        // Reserves space in the message queue if it is available
-        // Or waits for space if none is available.
-        match channel_send.reserve().await {
-            Ok(permit) => permit.send(frame),
-            Err(_) => {
-                error!("Send-Frame Error");
-                return Err(SendError(frame));
-            }
+        if let (Some(diagnostics), Some(frame_diagnostics)) = (diagnostics, &frame.diagnostics) {
+            diagnostics.send(frame_diagnostics);
         }
+        dispatch.send(frame).await?;
     }
     Ok(())
 }
 
+/// Locks the open transaction, which is shared with the consumer's rebalance callback.
+fn lock(transaction: &SharedTransaction) -> MutexGuard<'_, Transaction> {
+    transaction
+        .lock()
+        .expect("Transaction lock should not be poisoned")
+}
+
 // The following functions control the kafka producer thread.
 /// Create a new thread and setup the producer task.
 /// # Parameters
 /// - use_otel: if true, then the thread attempts to inject [AggregatedFrame::span()] into the Kafka header.
-/// - send_frame_buffer_size: the maximum number of [AggregatedFrame] objects to store in the channel's buffer. If the buffer is filled, then sending another frame will block until there is sufficient space in the buffer.
+/// - send_frame_buffer_size: the maximum number of [AggregatedFrame] objects to store in the channel's buffer. If the buffer is filled, then sending another frame will block until there is sufficient space in the buffer, unless the component is set to exit instead.
 /// - producer: the Kafka producer object.
 /// - output_topic: the Kafka topic to produce the message to.
 fn create_producer_task(
@@ -509,12 +936,13 @@ async fn produce_frame_to_kafka(
     let frame_span = frame.span().get().expect("Span should exist").clone();
     let data: Vec<u8> = frame.into();
 
-    let future_record = FutureRecord::to(output_topic)
-        .payload(data.as_slice())
-        .conditional_inject_span_into_headers(use_otel, &frame_span)
-        .key("Frame Events List");
-
-    match producer.send(future_record, PRODUCER_TIMEOUT).await {
+    match producer
+        .send(
+            frame_record(use_otel, &frame_span, &data, output_topic),
+            PRODUCER_TIMEOUT,
+        )
+        .await
+    {
         Ok(r) => {
             debug!("Delivery: {:?}", r);
             counter!(FRAMES_SENT).increment(1)
@@ -529,3 +957,136 @@ async fn produce_frame_to_kafka(
         }
     }
 }
+
+/// Enqueues the given frame to be produced in the open transaction.
+///
+/// The frame is delivered by the time the transaction is committed, and if it cannot be,
+/// the commit fails.
+/// # Parameters
+/// - use_otel: if true, then the thread attempts to inject [AggregatedFrame::span()] into the Kafka header.
+/// - frame: the frame to dispatch.
+/// - producer: the transactional Kafka producer object.
+/// - output_topic: the Kafka topic to produce the message to.
+/// - exit_on_full_send_buffer: if true, then an error is returned when the producer's queue is full, rather than waiting for space.
+async fn produce_frame_in_transaction(
+    use_otel: bool,
+    frame: AggregatedFrame<EventData>,
+    producer: &FutureProducer,
+    output_topic: &str,
+    exit_on_full_send_buffer: bool,
+) -> Result<(), DispatchFrameError> {
+    let frame_span = frame.span().get().expect("Span should exist").clone();
+    let data: Vec<u8> = frame.into();
+
+    if let Err(e) = send_when_queue_has_space(
+        |record| producer.send_result(record),
+        frame_record(use_otel, &frame_span, &data, output_topic),
+        exit_on_full_send_buffer,
+    )
+    .await
+    {
+        error!("Delivery failed: {:?}", e);
+        counter!(
+            FAILURES,
+            &[failures::get_label(FailureKind::KafkaPublishFailed)]
+        )
+        .increment(1);
+        return Err(e);
+    }
+    counter!(FRAMES_SENT).increment(1);
+    Ok(())
+}
+
+/// Enqueues the record with `send`, retrying whilst the producer's queue is full.
+/// # Parameters
+/// - send: enqueues the record, or returns it with the error if it cannot.
+/// - record: the record to enqueue.
+/// - exit_on_full_send_buffer: if true, then an error is returned when the queue is full, rather than waiting for space.
+async fn send_when_queue_has_space<R, T>(
+    mut send: impl FnMut(R) -> Result<T, (KafkaError, R)>,
+    mut record: R,
+    exit_on_full_send_buffer: bool,
+) -> Result<T, DispatchFrameError> {
+    loop {
+        match send(record) {
+            Ok(sent) => return Ok(sent),
+            Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), returned)) => {
+                if exit_on_full_send_buffer {
+                    return Err(DispatchFrameError::BufferFull);
+                }
+                record = returned;
+                tokio::time::sleep(QUEUE_FULL_RETRY_INTERVAL).await;
+            }
+            Err((e, _)) => return Err(e.into()),
+        }
+    }
+}
+
+/// Creates the record by which a frame is produced.
+/// # Parameters
+/// - use_otel: if true, then the frame's span is injected into the Kafka header.
+/// - frame_span: the span of the frame.
+/// - data: the serialised frame.
+/// - output_topic: the Kafka topic to produce the message to.
+fn frame_record<'a>(
+    use_otel: bool,
+    frame_span: &Span,
+    data: &'a [u8],
+    output_topic: &'a str,
+) -> FutureRecord<'a, str, [u8]> {
+    FutureRecord::to(output_topic)
+        .payload(data)
+        .conditional_inject_span_into_headers(use_otel, frame_span)
+        .key("Frame Events List")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a stand-in for [FutureProducer::send_result] whose queue is full for the first `full` attempts.
+    fn queue_full_for(full: usize) -> impl FnMut(u32) -> Result<u32, (KafkaError, u32)> {
+        let mut attempts = 0;
+        move |record| {
+            attempts += 1;
+            if attempts <= full {
+                Err((
+                    KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull),
+                    record,
+                ))
+            } else {
+                Ok(record)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn frame_produced_once_queue_has_space() {
+        assert!(matches!(
+            send_when_queue_has_space(queue_full_for(3), 7, false).await,
+            Ok(7)
+        ));
+    }
+
+    #[tokio::test]
+    async fn full_queue_fails_if_exiting_on_full_buffer() {
+        assert!(matches!(
+            send_when_queue_has_space(queue_full_for(1), 7, true).await,
+            Err(DispatchFrameError::BufferFull)
+        ));
+    }
+
+    #[tokio::test]
+    async fn other_errors_not_retried() {
+        let send = |record| {
+            Err::<u32, _>((
+                KafkaError::MessageProduction(RDKafkaErrorCode::MessageSizeTooLarge),
+                record,
+            ))
+        };
+        assert!(matches!(
+            send_when_queue_has_space(send, 7, false).await,
+            Err(DispatchFrameError::Kafka(_))
+        ));
+    }
+}
@@ -0,0 +1,96 @@
+//! This module implements a harness which runs the aggregator over a single input partition,
+//! resuming from the state persisted by a previous run, as after a crash.
+//! This is used for testing purposes only, by both the checkpointing and transactional modes.
+use crate::{
+    data::EventData,
+    frame::{AggregatedFrame, ExpectedDigitisers, FrameCache},
+    transaction::HeldOffsets,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use digital_muon_common::DigitizerId;
+use digital_muon_streaming_types::FrameMetadata;
+use std::{collections::BTreeMap, time::Duration};
+
+/// The frame number, and sorted digitiser ids, of a dispatched frame.
+pub(crate) type DispatchedFrame = (u32, Vec<DigitizerId>);
+
+/// Returns the metadata of the given frame, whose timestamp increases with the frame number.
+pub(crate) fn metadata(frame_number: u32) -> FrameMetadata {
+    FrameMetadata {
+        timestamp: DateTime::UNIX_EPOCH + TimeDelta::milliseconds(20 * frame_number as i64),
+        period_number: 0,
+        protons_per_pulse: 0,
+        running: true,
+        frame_number,
+        veto_flags: 0,
+    }
+}
+
+/// Returns the metadata of the given frame, with its timestamp jittered by less than [TIMESTAMP_TOLERANCE].
+pub(crate) fn jittered_metadata(frame_number: u32) -> FrameMetadata {
+    let metadata = metadata(frame_number);
+    FrameMetadata {
+        timestamp: metadata.timestamp + TimeDelta::microseconds(1),
+        ..metadata
+    }
+}
+
+/// The greatest difference between the timestamps of messages of the same frame.
+const TIMESTAMP_TOLERANCE: TimeDelta = TimeDelta::milliseconds(1);
+
+/// Runs the aggregator, expecting digitisers 0 and 1, over partition 0 of the input from offset `start`,
+/// until `crash_after` messages have been consumed, or the input is exhausted.
+/// # Parameters
+/// - input: the digitiser and frame of each message on the partition.
+/// - start: the offset from which the partition is resumed.
+/// - latest_timestamp_dispatched: the timestamp of the last frame dispatched by a previous run, if any.
+/// - crash_after: the number of messages consumed before the run ends.
+/// - persist: called after each message with the frames it caused to be dispatched, the offsets to commit,
+///   and the timestamp of the last frame dispatched.
+pub(crate) fn run(
+    input: &[(DigitizerId, FrameMetadata)],
+    start: i64,
+    latest_timestamp_dispatched: Option<DateTime<Utc>>,
+    crash_after: usize,
+    mut persist: impl FnMut(Vec<DispatchedFrame>, BTreeMap<i32, i64>, Option<DateTime<Utc>>),
+) {
+    let mut cache = FrameCache::<EventData>::new(
+        Duration::from_secs(60),
+        ExpectedDigitisers::new(vec![0, 1], None),
+        TIMESTAMP_TOLERANCE,
+        None,
+    );
+    if let Some(timestamp) = latest_timestamp_dispatched {
+        cache.restore_latest_timestamp_dispatched(timestamp);
+    }
+    let mut held_offsets = HeldOffsets::default();
+    for (offset, (digitiser_id, metadata)) in input
+        .iter()
+        .enumerate()
+        .skip(start as usize)
+        .take(crash_after)
+    {
+        held_offsets.consume(0, offset as i64);
+        if let Ok(frame_timestamp) =
+            cache.push(*digitiser_id, metadata, EventData::dummy_data(0, 1, &[0]))
+        {
+            held_offsets.hold(0, offset as i64, frame_timestamp);
+        }
+        let mut dispatched = Vec::new();
+        while let Some(frame) = cache.poll() {
+            let AggregatedFrame {
+                metadata,
+                mut digitiser_ids,
+                ..
+            } = frame;
+            digitiser_ids.sort();
+            dispatched.push((metadata.frame_number, digitiser_ids));
+        }
+        let latest_timestamp_dispatched = cache.get_latest_timestamp_dispatched();
+        persist(
+            dispatched,
+            held_offsets.offsets_to_commit(latest_timestamp_dispatched),
+            latest_timestamp_dispatched,
+        );
+    }
+}
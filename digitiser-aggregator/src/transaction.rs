@@ -0,0 +1,255 @@
+//! Determines which offsets may be committed in transactional mode, given the frames held in the cache.
+//!
+//! A message's offset is only committed once the frame it belongs to has been dispatched.
+//! The timestamp of the last frame dispatched is committed as metadata with the offsets,
+//! so that after a restart, the messages of frames which have already been dispatched are rejected.
+use crate::{data::EventData, frame::FrameCache};
+use chrono::{DateTime, Utc};
+use digital_muon_common::transaction::Transaction;
+use rdkafka::{
+    TopicPartitionList,
+    consumer::{Consumer, ConsumerContext, StreamConsumer},
+    error::KafkaResult,
+    util::Timeout,
+};
+use std::{collections::BTreeMap, time::Duration};
+use tracing::info;
+
+/// The time allowed to fetch the committed offsets of a partition.
+const COMMITTED_OFFSETS_TIMEOUT: Timeout = Timeout::After(Duration::from_secs(10));
+
+/// Tracks the offsets of consumed messages, and which of them belong to frames yet to be dispatched.
+#[derive(Default)]
+pub(crate) struct HeldOffsets {
+    /// The offset of the next message to consume from each partition.
+    next: BTreeMap<i32, i64>,
+    /// The partition, offset and frame timestamp of each message which may belong to a frame in the cache.
+    held: Vec<(i32, i64, DateTime<Utc>)>,
+}
+
+impl HeldOffsets {
+    /// Returns `true` if no message has yet been consumed from the given partition.
+    pub(crate) fn is_new_partition(&self, partition: i32) -> bool {
+        !self.next.contains_key(&partition)
+    }
+
+    /// Records that the message at the given offset has been consumed.
+    pub(crate) fn consume(&mut self, partition: i32, offset: i64) {
+        self.next.insert(partition, offset + 1);
+    }
+
+    /// Records that the message at the given offset belongs to the frame with the given timestamp.
+    pub(crate) fn hold(&mut self, partition: i32, offset: i64, timestamp: DateTime<Utc>) {
+        self.held.push((partition, offset, timestamp));
+    }
+
+    /// Forgets the offsets of partitions for which `is_assigned` is `false`,
+    /// so that they are not committed over those of the instance to which the partitions have been reassigned.
+    pub(crate) fn retain_partitions(&mut self, is_assigned: impl Fn(i32) -> bool) {
+        self.next.retain(|&partition, _| is_assigned(partition));
+        self.held
+            .retain(|&(partition, _, _)| is_assigned(partition));
+    }
+
+    /// Returns the offset to commit for each partition, which is that of the earliest message whose
+    /// frame is yet to be dispatched, or otherwise the next message to consume.
+    /// # Parameters
+    /// - latest_timestamp_dispatched: the timestamp of the last frame to be dispatched. Messages from frames no later than this are no longer held.
+    pub(crate) fn offsets_to_commit(
+        &mut self,
+        latest_timestamp_dispatched: Option<DateTime<Utc>>,
+    ) -> BTreeMap<i32, i64> {
+        if let Some(latest) = latest_timestamp_dispatched {
+            self.held.retain(|&(_, _, timestamp)| timestamp > latest);
+        }
+        let mut offsets = self.next.clone();
+        for &(partition, offset, _) in &self.held {
+            offsets
+                .entry(partition)
+                .and_modify(|next| *next = (*next).min(offset))
+                .or_insert(offset);
+        }
+        offsets
+    }
+}
+
+/// Encodes the timestamp of the last frame dispatched, for committing with the offsets.
+pub(crate) fn timestamp_to_metadata(timestamp: DateTime<Utc>) -> String {
+    timestamp.to_rfc3339()
+}
+
+/// Decodes the timestamp of the last frame dispatched from committed metadata, if there is one.
+pub(crate) fn timestamp_from_metadata(metadata: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(metadata)
+        .ok()
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+}
+
+/// Raises the timestamp of the last frame dispatched to that committed with the offset of the given partition, if any,
+/// so that messages from frames dispatched before the component restarted are rejected.
+pub(crate) fn restore_latest_timestamp_dispatched<C: ConsumerContext>(
+    consumer: &StreamConsumer<C>,
+    cache: &mut FrameCache<EventData>,
+    topic: &str,
+    partition: i32,
+) -> KafkaResult<()> {
+    let mut partitions = TopicPartitionList::new();
+    partitions.add_partition(topic, partition);
+    for element in consumer
+        .committed_offsets(partitions, COMMITTED_OFFSETS_TIMEOUT)?
+        .elements()
+    {
+        if let Some(timestamp) = timestamp_from_metadata(element.metadata()) {
+            info!("Restoring latest timestamp dispatched from partition {partition}: {timestamp}");
+            cache.restore_latest_timestamp_dispatched(timestamp);
+        }
+    }
+    Ok(())
+}
+
+/// Sets the offsets to commit with the open transaction, which are those of messages whose frames have been dispatched,
+/// and the timestamp of the last frame dispatched.
+///
+/// This should be called whenever a message is consumed or a frame dispatched, so that the transaction is ready
+/// to be committed by the consumer's rebalance callback. Partitions no longer assigned to the consumer are forgotten.
+pub(crate) fn stage_offsets<C: ConsumerContext>(
+    consumer: &StreamConsumer<C>,
+    transaction: &mut Transaction,
+    held_offsets: &mut HeldOffsets,
+    cache: &FrameCache<EventData>,
+    topic: &str,
+) -> KafkaResult<()> {
+    let assignment = consumer.assignment()?;
+    held_offsets
+        .retain_partitions(|partition| assignment.find_partition(topic, partition).is_some());
+    let latest_timestamp_dispatched = cache.get_latest_timestamp_dispatched();
+    for (partition, offset) in held_offsets.offsets_to_commit(latest_timestamp_dispatched) {
+        transaction.set_offset(topic, partition, offset);
+    }
+    if let Some(timestamp) = latest_timestamp_dispatched {
+        transaction.set_metadata(timestamp_to_metadata(timestamp));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restart_harness::{self, DispatchedFrame, jittered_metadata, metadata};
+    use digital_muon_common::DigitizerId;
+    use digital_muon_streaming_types::FrameMetadata;
+
+    #[test]
+    fn offsets_held_until_frame_dispatched() {
+        let mut offsets = HeldOffsets::default();
+        assert!(offsets.is_new_partition(0));
+
+        offsets.consume(0, 10);
+        offsets.hold(0, 10, metadata(1).timestamp);
+        offsets.consume(1, 4);
+        offsets.hold(1, 4, metadata(2).timestamp);
+        offsets.consume(0, 11);
+        offsets.hold(0, 11, metadata(2).timestamp);
+        assert!(!offsets.is_new_partition(0));
+
+        assert_eq!(offsets.offsets_to_commit(None), [(0, 10), (1, 4)].into());
+        assert_eq!(
+            offsets.offsets_to_commit(Some(metadata(1).timestamp)),
+            [(0, 11), (1, 4)].into()
+        );
+        assert_eq!(
+            offsets.offsets_to_commit(Some(metadata(2).timestamp)),
+            [(0, 12), (1, 5)].into()
+        );
+
+        // Partition 1 is revoked.
+        offsets.retain_partitions(|partition| partition == 0);
+        assert!(offsets.is_new_partition(1));
+        assert_eq!(offsets.offsets_to_commit(None), [(0, 12)].into());
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let timestamp = metadata(3).timestamp;
+        assert_eq!(
+            timestamp_from_metadata(&timestamp_to_metadata(timestamp)),
+            Some(timestamp)
+        );
+        assert_eq!(timestamp_from_metadata(""), None);
+    }
+
+    /// What a stand-in broker records as committed, for a single input partition.
+    #[derive(Default)]
+    struct Committed {
+        offset: i64,
+        metadata: String,
+        frames: Vec<DispatchedFrame>,
+    }
+
+    /// Runs the aggregator from the committed state, committing after each message,
+    /// until `crash_after` messages have been consumed, or the input is exhausted.
+    fn run(input: &[(DigitizerId, FrameMetadata)], committed: &mut Committed, crash_after: usize) {
+        restart_harness::run(
+            input,
+            committed.offset,
+            timestamp_from_metadata(&committed.metadata),
+            crash_after,
+            |mut frames, offsets, latest_timestamp_dispatched| {
+                // Commits the produced frames and the offsets in the same transaction.
+                committed.frames.append(&mut frames);
+                committed.offset = offsets[&0];
+                if let Some(timestamp) = latest_timestamp_dispatched {
+                    committed.metadata = timestamp_to_metadata(timestamp);
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn no_loss_or_duplication_after_crashes() {
+        let input = [
+            (0, metadata(1)),
+            (0, metadata(2)),
+            (1, metadata(1)),
+            (0, metadata(3)),
+            (1, metadata(2)),
+            (1, metadata(3)),
+            (1, metadata(4)),
+            (0, metadata(4)),
+            (0, metadata(5)),
+            (1, metadata(5)),
+        ];
+        let expected = (1..=5).map(|frame| (frame, vec![0, 1])).collect::<Vec<_>>();
+        // Each run must span the messages of at least one frame to make progress.
+        for crash_after in 4..=input.len() {
+            let mut committed = Committed::default();
+            for _ in 0..input.len() {
+                run(&input, &mut committed, crash_after);
+            }
+            assert_eq!(committed.offset, input.len() as i64);
+            assert_eq!(committed.frames, expected, "crash after {crash_after}");
+        }
+    }
+
+    #[test]
+    fn jittered_messages_released_with_their_frame() {
+        // The messages of digitiser 1 are held under the timestamp of the frame they join, not their own.
+        let input = [
+            (0, metadata(1)),
+            (1, jittered_metadata(1)),
+            (0, metadata(2)),
+            (1, jittered_metadata(2)),
+            (1, jittered_metadata(3)),
+            (0, metadata(3)),
+        ];
+        let expected = (1..=3).map(|frame| (frame, vec![0, 1])).collect::<Vec<_>>();
+        for crash_after in 2..=input.len() {
+            let mut committed = Committed::default();
+            for _ in 0..input.len() {
+                run(&input, &mut committed, crash_after);
+            }
+            assert_eq!(committed.offset, input.len() as i64);
+            assert_eq!(committed.frames, expected, "crash after {crash_after}");
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Estimates the baseline of a trace from a portion of it which is free of events.
+use super::Real;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BaselineEstimator {
+    /// The median of the samples.
+    Median,
+    /// The mean of the samples, once the given fraction of the lowest,
+    /// and of the highest, samples are discarded.
+    TrimmedMean(Real),
+}
+
+impl BaselineEstimator {
+    /// Estimates the baseline from the given samples, or returns `None` if there are no samples.
+    pub fn estimate(&self, samples: impl Iterator<Item = Real>) -> Option<Real> {
+        let mut samples: Vec<Real> = samples.collect();
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_by(Real::total_cmp);
+
+        match self {
+            Self::Median => median(&samples),
+            Self::TrimmedMean(fraction) => {
+                let trim = (samples.len() as Real * fraction.clamp(0.0, 0.5)) as usize;
+                let trimmed = samples.get(trim..(samples.len() - trim))?;
+                if trimmed.is_empty() {
+                    median(&samples)
+                } else {
+                    Some(trimmed.iter().sum::<Real>() / trimmed.len() as Real)
+                }
+            }
+        }
+    }
+}
+
+/// Returns the median of a sorted, non-empty, slice.
+pub fn median(sorted: &[Real]) -> Option<Real> {
+    let middle = sorted.len() / 2;
+    if sorted.len() % 2 == 0 {
+        Some((sorted.get(middle - 1)? + sorted.get(middle)?) / 2.0)
+    } else {
+        sorted.get(middle).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn no_samples() {
+        let data: [Real; 0] = [];
+        assert_eq!(BaselineEstimator::Median.estimate(data.into_iter()), None);
+        assert_eq!(
+            BaselineEstimator::TrimmedMean(0.1).estimate(data.into_iter()),
+            None
+        );
+    }
+
+    #[test]
+    fn median_odd() {
+        let data = [5.0, 3.0, 100.0, 4.0, 2.0];
+        assert_eq!(
+            BaselineEstimator::Median.estimate(data.into_iter()),
+            Some(4.0)
+        );
+    }
+
+    #[test]
+    fn median_even() {
+        let data = [5.0, 3.0, 100.0, 4.0];
+        assert_eq!(
+            BaselineEstimator::Median.estimate(data.into_iter()),
+            Some(4.5)
+        );
+    }
+
+    #[test]
+    fn trimmed_mean() {
+        // Trimming 20% of ten samples discards the two lowest and two highest.
+        let data = [-50.0, 3.0, 4.0, 5.0, 6.0, 100.0, 3.0, 4.0, 5.0, 0.0];
+        assert_approx_eq!(
+            BaselineEstimator::TrimmedMean(0.2)
+                .estimate(data.into_iter())
+                .unwrap(),
+            4.0
+        );
+    }
+
+    #[test]
+    fn untrimmed_mean() {
+        let data = [1.0, 2.0, 3.0, 6.0];
+        assert_approx_eq!(
+            BaselineEstimator::TrimmedMean(0.0)
+                .estimate(data.into_iter())
+                .unwrap(),
+            3.0
+        );
+    }
+
+    #[test]
+    fn fully_trimmed_mean() {
+        let data = [1.0, 2.0, 3.0, 6.0];
+        assert_approx_eq!(
+            BaselineEstimator::TrimmedMean(0.5)
+                .estimate(data.into_iter())
+                .unwrap(),
+            2.5
+        );
+    }
+}
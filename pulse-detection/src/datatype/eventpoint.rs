@@ -1,7 +1,7 @@
 use super::{EventData, Temporal};
 use std::fmt::Debug;
 
-pub(crate) trait EventPoint: Debug + Clone {
+pub trait EventPoint: Debug + Clone {
     type TimeType: Temporal;
     type EventType: EventData;
 
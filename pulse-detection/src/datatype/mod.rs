@@ -0,0 +1,20 @@
+use super::Real;
+use digital_muon_common::Intensity;
+use std::fmt::{Debug, Display};
+
+pub mod eventdata;
+pub mod eventpoint;
+pub mod tracepoint;
+pub mod tracevalue;
+
+pub use eventdata::EventData;
+pub use eventpoint::EventPoint;
+pub use tracepoint::TracePoint;
+pub use tracevalue::{RealArray, Stats, TraceValue};
+
+/// This trait abstracts any type used as a time variable
+pub trait Temporal: Default + Copy + Debug + Display + PartialEq {}
+
+impl Temporal for Intensity {}
+
+impl Temporal for Real {}
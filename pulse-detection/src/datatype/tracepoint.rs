@@ -3,7 +3,7 @@ use super::{EventData, Temporal, TraceValue, eventdata::Empty};
 /// An abstraction of the types that are processed by the various filters
 /// To implement TracePoint a type must contain time data, a value,
 /// and a parameter (which is used for applying feedback).
-pub(crate) trait TracePoint: Clone {
+pub trait TracePoint: Clone {
     /// The type which represents the time of the data point.
     /// This should be trivially copyable (usually a scalar).
     type Time: Temporal;
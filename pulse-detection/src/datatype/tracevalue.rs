@@ -14,7 +14,7 @@ use std::{
 /// * Methods
 /// - get_value(): returns an immutable reference to the value of the data point.
 /// - take_value(): destructs the data point and gives the caller ownership of the value.
-pub(crate) trait TraceValue: Default + Clone + Debug + Display {
+pub trait TraceValue: Default + Clone + Debug + Display {
     type ContentType: Default + Clone + Debug + Display;
 }
 
@@ -25,7 +25,7 @@ impl TraceValue for Real {
 /// This type allows the use of static arrays of TraceValue types as TraceValues
 /// that can be used in the pipeline.
 #[derive(Debug, Clone, Copy, PartialEq)]
-pub(crate) struct TraceArray<const N: usize, T>(pub(crate) [T; N])
+pub struct TraceArray<const N: usize, T>(pub [T; N])
 where
     T: TraceValue;
 
@@ -33,7 +33,7 @@ impl<const N: usize, T> TraceArray<N, T>
 where
     T: TraceValue,
 {
-    pub(crate) fn new(value: [T; N]) -> Self {
+    pub fn new(value: [T; N]) -> Self {
         Self(value)
     }
 }
@@ -85,14 +85,14 @@ impl<const N: usize, T: TraceValue + Copy> TraceValue for TraceArray<N, T> {
 }
 
 /// In practice arrays of Real types are mostly used.
-pub(crate) type RealArray<const N: usize> = TraceArray<N, Real>;
+pub type RealArray<const N: usize> = TraceArray<N, Real>;
 
 /// This type allows contains descriptive statistical data.
 #[derive(Default, Clone, Debug)]
-pub(crate) struct Stats {
-    pub(crate) value: Real,
-    pub(crate) mean: Real,
-    pub(crate) variance: Real,
+pub struct Stats {
+    pub value: Real,
+    pub mean: Real,
+    pub variance: Real,
 }
 
 impl From<Real> for Stats {
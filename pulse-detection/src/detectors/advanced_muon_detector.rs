@@ -2,7 +2,7 @@ use super::{Assembler, Detector, EventData, EventPoint, Pulse, Real, RealArray,
 use std::fmt::Display;
 
 #[derive(Default, Debug, Clone, PartialEq)]
-pub(crate) enum Class {
+pub enum Class {
     #[default]
     Onset,
     Peak,
@@ -20,22 +20,22 @@ impl Display for Class {
 }
 
 #[derive(Default, Debug, Clone, PartialEq)]
-pub(crate) struct Data {
+pub struct Data {
     class: Class,
     value: Real,
     superlative: Option<TimeValue<RealArray<2>>>,
 }
 
 impl Data {
-    pub(crate) fn get_class(&self) -> Class {
+    pub fn get_class(&self) -> Class {
         self.class.clone()
     }
 
-    pub(crate) fn get_value(&self) -> Real {
+    pub fn get_value(&self) -> Real {
         self.value
     }
 
-    pub(crate) fn get_superlative(&self) -> Option<TimeValue<RealArray<2>>> {
+    pub fn get_superlative(&self) -> Option<TimeValue<RealArray<2>>> {
         self.superlative.clone()
     }
 }
@@ -125,7 +125,7 @@ impl State {
 }
 
 #[derive(Default, Clone)]
-pub(crate) struct AdvancedMuonDetector {
+pub struct AdvancedMuonDetector {
     onset_threshold: Real,
     fall_threshold: Real,
     termination_threshold: Real,
@@ -138,7 +138,7 @@ pub(crate) struct AdvancedMuonDetector {
 }
 
 impl AdvancedMuonDetector {
-    pub(crate) fn new(onset: Real, fall: Real, termination: Real, duration: Real) -> Self {
+    pub fn new(onset: Real, fall: Real, termination: Real, duration: Real) -> Self {
         Self {
             onset_threshold: onset,
             fall_threshold: fall,
@@ -239,7 +239,7 @@ enum AssemblerMode {
 }
 
 #[derive(Default, Clone)]
-pub(crate) struct AdvancedMuonAssembler {
+pub struct AdvancedMuonAssembler {
     mode: AssemblerMode,
 }
 
@@ -312,7 +312,7 @@ impl Assembler for AdvancedMuonAssembler {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::pulse_detection::{
+    use crate::{
         EventFilter, WindowFilter, datatype::tracevalue::TraceArray, window::FiniteDifferences,
     };
 
@@ -0,0 +1,243 @@
+use super::{Detector, EventData, Real};
+use std::{collections::VecDeque, fmt::Display};
+
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Data {
+    pub pulse_height: Real,
+}
+
+impl Display for Data {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.pulse_height)
+    }
+}
+
+impl EventData for Data {}
+
+/// The state of a pulse which the discriminator is armed for.
+#[derive(Default, Clone)]
+struct ArmedPulse {
+    time_crossed: Option<Real>,
+    max_pulse_height: Real,
+}
+
+/// Registers an event at the time the pulse reaches a constant fraction of its height.
+///
+/// The trace is delayed and inverted, and added to the trace scaled by `fraction`.
+/// The event time is the time this bipolar signal crosses zero (from positive to negative),
+/// interpolated linearly between samples, which does not depend on the pulse amplitude.
+/// The discriminator only registers zero crossings of pulses whose height exceeds `threshold`.
+#[derive(Default, Clone)]
+pub struct ConstantFractionDiscriminator {
+    threshold: Real,
+    fraction: Real,
+    delay: usize,
+
+    /// The trace values of the last `delay` samples.
+    delayed: VecDeque<Real>,
+    /// The previous time and value of the bipolar signal.
+    previous: Option<(Real, Real)>,
+    /// The most recent zero crossing, which is cleared once the bipolar signal is positive again.
+    last_zero_crossing: Option<Real>,
+    armed: Option<ArmedPulse>,
+}
+
+impl ConstantFractionDiscriminator {
+    /// Creates a new discriminator.
+    /// # Parameters
+    /// - threshold: the trace must exceed this value for a zero crossing to register an event.
+    /// - fraction: the fraction of the pulse height at which events are registered, should be between 0 and 1.
+    /// - delay: the delay, in samples, applied to the inverted trace.
+    pub fn new(threshold: Real, fraction: Real, delay: usize) -> Self {
+        Self {
+            threshold,
+            fraction,
+            delay,
+            delayed: VecDeque::with_capacity(delay + 1),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the time of the zero crossing of the bipolar signal, if it crosses zero at `time`.
+    fn zero_crossing(&self, time: Real, bipolar: Real) -> Option<Real> {
+        let (previous_time, previous_bipolar) = self.previous?;
+        (previous_bipolar > 0.0 && bipolar <= 0.0).then(|| {
+            previous_time + (time - previous_time) * previous_bipolar / (previous_bipolar - bipolar)
+        })
+    }
+}
+
+pub type ConstantFractionEvent = (Real, Data);
+
+impl Detector for ConstantFractionDiscriminator {
+    type TracePointType = (Real, Real);
+    type EventPointType = (Real, Data);
+
+    fn signal(&mut self, time: Real, value: Real) -> Option<ConstantFractionEvent> {
+        self.delayed.push_back(value);
+        if self.delayed.len() <= self.delay {
+            // Until there are enough samples, the delayed trace is undefined.
+            return None;
+        }
+        let delayed = self.delayed.pop_front()?;
+
+        let bipolar = self.fraction * value - delayed;
+        let zero_crossing = self.zero_crossing(time, bipolar);
+        self.previous = Some((time, bipolar));
+        if zero_crossing.is_some() {
+            self.last_zero_crossing = zero_crossing;
+        } else if bipolar > 0.0 {
+            self.last_zero_crossing = None;
+        }
+
+        match &mut self.armed {
+            Some(armed) => {
+                armed.max_pulse_height = armed.max_pulse_height.max(value);
+                if armed.time_crossed.is_none() {
+                    armed.time_crossed = zero_crossing;
+                }
+
+                if value <= self.threshold {
+                    // The pulse has ended, so the event (if any) is registered.
+                    let armed = self.armed.take()?;
+                    armed.time_crossed.map(|time| {
+                        (
+                            time,
+                            Data {
+                                pulse_height: armed.max_pulse_height,
+                            },
+                        )
+                    })
+                } else {
+                    None
+                }
+            }
+            None => {
+                if value > self.threshold {
+                    // If the bipolar signal crossed zero before the trace passed the threshold,
+                    // and has not been positive since, then that zero crossing belongs to this pulse.
+                    self.armed = Some(ArmedPulse {
+                        time_crossed: self.last_zero_crossing,
+                        max_pulse_height: value,
+                    });
+                }
+                None
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Option<Self::EventPointType> {
+        let armed = self.armed.take()?;
+        armed.time_crossed.map(|time| {
+            (
+                time,
+                Data {
+                    pulse_height: armed.max_pulse_height,
+                },
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EventFilter, Real};
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn zero_data() {
+        let data: [Real; 0] = [];
+        let detector = ConstantFractionDiscriminator::new(3.0, 0.5, 2);
+        let mut iter = data
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i as Real, v as Real))
+            .events(detector);
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn below_threshold() {
+        let data = [0, 0, 0, 0, 2, 4, 6, 8, 10, 8, 6, 4, 2, 0, 0, 0];
+        let detector = ConstantFractionDiscriminator::new(10.0, 0.5, 2);
+        let mut iter = data
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i as Real, v as Real))
+            .events(detector);
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn time_independent_of_amplitude() {
+        let data = [0, 0, 0, 0, 2, 4, 6, 8, 10, 8, 6, 4, 2, 0, 0, 0];
+        for scale in [1, 3, 10] {
+            let detector = ConstantFractionDiscriminator::new(3.0, 0.5, 2);
+            let mut iter = data
+                .into_iter()
+                .enumerate()
+                .map(|(i, v)| (i as Real, (scale * v) as Real))
+                .events(detector);
+            assert_eq!(
+                iter.next(),
+                Some((
+                    7.0,
+                    Data {
+                        pulse_height: (scale * 10) as Real
+                    }
+                ))
+            );
+            assert_eq!(iter.next(), None);
+        }
+    }
+
+    #[test]
+    fn sub_sample_timing() {
+        // The trace rises linearly from time 3, so the pulse reaches
+        // the given fraction at 3 + delay/(1 - fraction) = 4.333...
+        let data = [0, 0, 0, 0, 4, 8, 12, 16, 8, 0, 0];
+        let detector = ConstantFractionDiscriminator::new(2.0, 0.25, 1);
+        let mut iter = data
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i as Real, v as Real))
+            .events(detector);
+        let (time, data) = iter.next().unwrap();
+        assert_approx_eq!(time, 3.0 + 1.0 / 0.75);
+        assert_eq!(data.pulse_height, 16.0);
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn zero_crossing_before_threshold() {
+        // As above, but the trace only passes the threshold after the zero crossing.
+        let data = [0, 0, 0, 0, 4, 8, 12, 16, 8, 0, 0];
+        let detector = ConstantFractionDiscriminator::new(10.0, 0.25, 1);
+        let mut iter = data
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i as Real, v as Real))
+            .events(detector);
+        let (time, data) = iter.next().unwrap();
+        assert_approx_eq!(time, 3.0 + 1.0 / 0.75);
+        assert_eq!(data.pulse_height, 16.0);
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn two_pulses() {
+        let data = [
+            0, 0, 0, 0, 2, 4, 6, 8, 10, 8, 6, 4, 2, 0, 0, 0, 3, 6, 9, 12, 15, 0, 0,
+        ];
+        let detector = ConstantFractionDiscriminator::new(3.0, 0.5, 2);
+        let mut iter = data
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i as Real, v as Real))
+            .events(detector);
+        assert_eq!(iter.next(), Some((7.0, Data { pulse_height: 10.0 })));
+        assert_eq!(iter.next(), Some((19.0, Data { pulse_height: 15.0 })));
+        assert_eq!(iter.next(), None);
+    }
+}
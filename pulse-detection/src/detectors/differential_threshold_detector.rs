@@ -1,12 +1,10 @@
 use super::{Detector, EventData, Real};
-use crate::pulse_detection::{
-    datatype::tracevalue::TraceArray, threshold_detector::ThresholdDuration,
-};
+use crate::{datatype::tracevalue::TraceArray, threshold_detector::ThresholdDuration};
 use std::fmt::Display;
 
 #[derive(Default, Debug, Clone, PartialEq)]
-pub(crate) struct Data {
-    pub(crate) pulse_height: Real,
+pub struct Data {
+    pub pulse_height: Real,
 }
 
 impl Display for Data {
@@ -18,7 +16,7 @@ impl Display for Data {
 impl EventData for Data {}
 
 #[derive(Default, Clone)]
-pub(crate) struct DifferentialThresholdDetector {
+pub struct DifferentialThresholdDetector {
     trigger: ThresholdDuration,
 
     time_of_last_return: Option<Real>,
@@ -31,7 +29,7 @@ pub(crate) struct DifferentialThresholdDetector {
 }
 
 impl DifferentialThresholdDetector {
-    pub(crate) fn new(trigger: &ThresholdDuration, constant_multiple: Option<Real>) -> Self {
+    pub fn new(trigger: &ThresholdDuration, constant_multiple: Option<Real>) -> Self {
         Self {
             trigger: trigger.clone(),
             constant_multiple,
@@ -40,7 +38,7 @@ impl DifferentialThresholdDetector {
     }
 }
 
-pub(crate) type ThresholdEvent = (Real, Data);
+pub type ThresholdEvent = (Real, Data);
 
 impl Detector for DifferentialThresholdDetector {
     type TracePointType = (Real, TraceArray<2, Real>);
@@ -130,7 +128,7 @@ impl Detector for DifferentialThresholdDetector {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::pulse_detection::{EventFilter, Real, WindowFilter, window::FiniteDifferences};
+    use crate::{EventFilter, Real, WindowFilter, window::FiniteDifferences};
     use digital_muon_common::Intensity;
 
     #[test]
@@ -0,0 +1,85 @@
+pub mod advanced_muon_detector;
+pub mod constant_fraction_discriminator;
+pub mod differential_threshold_detector;
+pub mod threshold_detector;
+
+use super::{EventData, EventPoint, Pulse, Real, RealArray, TracePoint, pulse::TimeValue};
+
+/// Finds events in a stream of trace points, which is usually done with [EventFilter](crate::EventFilter).
+///
+/// A detector is given each point in turn, and returns an event once it has seen enough of the trace to register one.
+/// ```rust
+/// use digital_muon_pulse_detection::{Detector, EventFilter, Real, threshold_detector::Data};
+///
+/// /// Registers an event at every sample which exceeds its predecessor.
+/// #[derive(Default, Clone)]
+/// struct RiseDetector {
+///     previous: Option<Real>,
+/// }
+///
+/// impl Detector for RiseDetector {
+///     type TracePointType = (Real, Real);
+///     type EventPointType = (Real, Data);
+///
+///     fn signal(&mut self, time: Real, value: Real) -> Option<(Real, Data)> {
+///         let previous = self.previous.replace(value)?;
+///         (value > previous).then_some((time, Data { pulse_height: value }))
+///     }
+///
+///     fn finish(&mut self) -> Option<(Real, Data)> {
+///         None
+///     }
+/// }
+///
+/// let events: Vec<_> = [(0.0, 1.0), (1.0, 3.0), (2.0, 2.0), (3.0, 4.0)]
+///     .into_iter()
+///     .events(RiseDetector::default())
+///     .map(|(time, data)| (time, data.pulse_height))
+///     .collect();
+/// assert_eq!(events, [(1.0, 3.0), (3.0, 4.0)]);
+/// ```
+pub trait Detector: Default + Clone {
+    type TracePointType: TracePoint;
+    type EventPointType: EventPoint<TimeType = <Self::TracePointType as TracePoint>::Time>;
+
+    fn signal(
+        &mut self,
+        time: <Self::TracePointType as TracePoint>::Time,
+        value: <Self::TracePointType as TracePoint>::Value,
+    ) -> Option<Self::EventPointType>;
+
+    /// Called once the trace is exhausted, to return any event which is still pending.
+    fn finish(&mut self) -> Option<Self::EventPointType>;
+}
+
+/// Combines the events registered by a [Detector] into [Pulse]s, which is usually done with
+/// [AssembleFilter](crate::AssembleFilter).
+/// ```rust
+/// use digital_muon_pulse_detection::{
+///     AssembleFilter, EventFilter, Real, WindowFilter,
+///     advanced_muon_detector::{AdvancedMuonAssembler, AdvancedMuonDetector},
+///     window::FiniteDifferences,
+/// };
+///
+/// let trace = [4, 3, 2, 5, 6, 1, 5, 7, 2, 4];
+/// let pulses: Vec<_> = trace
+///     .into_iter()
+///     .enumerate()
+///     .map(|(i, v)| (i as Real, v as Real))
+///     .window(FiniteDifferences::<2>::new())
+///     .events(AdvancedMuonDetector::new(1.0, 1.0, 1.0, 0.0))
+///     .assemble(AdvancedMuonAssembler::default())
+///     .collect();
+///
+/// assert_eq!(pulses[0].start.time, Some(3.0));
+/// assert_eq!(pulses[0].peak.time, Some(4.0));
+/// assert_eq!(pulses[0].end.time, Some(5.0));
+/// ```
+pub trait Assembler: Default + Clone {
+    type DetectorType: Detector;
+
+    fn assemble_pulses(
+        &mut self,
+        source: <Self::DetectorType as Detector>::EventPointType,
+    ) -> Option<Pulse>;
+}
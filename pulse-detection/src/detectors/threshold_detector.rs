@@ -2,8 +2,8 @@ use super::{Detector, EventData, Real};
 use std::fmt::Display;
 
 #[derive(Default, Debug, Clone, PartialEq)]
-pub(crate) struct Data {
-    pub(crate) pulse_height: Real,
+pub struct Data {
+    pub pulse_height: Real,
 }
 
 impl Display for Data {
@@ -15,14 +15,14 @@ impl Display for Data {
 impl EventData for Data {}
 
 #[derive(Default, Debug, Clone)]
-pub(crate) struct ThresholdDuration {
-    pub(crate) threshold: Real,
-    pub(crate) duration: i32,
-    pub(crate) cool_off: i32,
+pub struct ThresholdDuration {
+    pub threshold: Real,
+    pub duration: i32,
+    pub cool_off: i32,
 }
 
 #[derive(Default, Clone)]
-pub(crate) struct ThresholdDetector {
+pub struct ThresholdDetector {
     trigger: ThresholdDuration,
     time_of_last_return: Option<Real>,
     time_crossed: Option<Real>,
@@ -31,7 +31,7 @@ pub(crate) struct ThresholdDetector {
 }
 
 impl ThresholdDetector {
-    pub(crate) fn new(trigger: &ThresholdDuration) -> Self {
+    pub fn new(trigger: &ThresholdDuration) -> Self {
         Self {
             trigger: trigger.clone(),
             ..Default::default()
@@ -39,7 +39,7 @@ impl ThresholdDetector {
     }
 }
 
-pub(crate) type ThresholdEvent = (Real, Data);
+pub type ThresholdEvent = (Real, Data);
 
 impl Detector for ThresholdDetector {
     type TracePointType = (Real, Real);
@@ -121,7 +121,7 @@ impl Detector for ThresholdDetector {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::pulse_detection::{EventFilter, Real};
+    use crate::{EventFilter, Real};
 
     #[test]
     fn zero_data() {
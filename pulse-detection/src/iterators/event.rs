@@ -2,7 +2,7 @@ use super::{Assembler, Detector, Pulse, TracePoint};
 use tracing::trace;
 
 #[derive(Clone)]
-pub(crate) struct EventIter<I, D>
+pub struct EventIter<I, D>
 where
     I: Iterator<Item = D::TracePointType>,
     D: Detector,
@@ -36,7 +36,27 @@ where
     }
 }
 
-pub(crate) trait EventFilter<I, D>
+/// Applies a [Detector] to an iterator of trace points, yielding the events it registers.
+/// ```rust
+/// use digital_muon_pulse_detection::{
+///     EventFilter, Real,
+///     threshold_detector::{ThresholdDetector, ThresholdDuration},
+/// };
+///
+/// let events: Vec<_> = [0.0, 6.0, 7.0, 0.0, 4.0]
+///     .into_iter()
+///     .enumerate()
+///     .map(|(i, v)| (i as Real, v))
+///     .events(ThresholdDetector::new(&ThresholdDuration {
+///         threshold: 5.0,
+///         duration: 1,
+///         cool_off: 0,
+///     }))
+///     .map(|(time, data)| (time, data.pulse_height))
+///     .collect();
+/// assert_eq!(events, [(1.0, 7.0)]);
+/// ```
+pub trait EventFilter<I, D>
 where
     I: Iterator,
     I: Iterator<Item = D::TracePointType>,
@@ -60,7 +80,7 @@ where
 }
 
 #[derive(Clone)]
-pub(crate) struct AssemblerIter<I, A>
+pub struct AssemblerIter<I, A>
 where
     A: Assembler,
     I: Iterator<Item = <A::DetectorType as Detector>::EventPointType> + Clone,
@@ -86,7 +106,9 @@ where
     }
 }
 
-pub(crate) trait AssembleFilter<I, A>
+/// Applies an [Assembler] to an iterator of events, yielding the pulses it assembles.
+/// See [Assembler] for an example.
+pub trait AssembleFilter<I, A>
 where
     A: Assembler,
     I: Iterator<Item = <A::DetectorType as Detector>::EventPointType> + Clone,
@@ -0,0 +1,4 @@
+pub mod event;
+
+use super::{Assembler, Detector, Pulse, TracePoint};
+pub use event::{AssembleFilter, EventFilter};
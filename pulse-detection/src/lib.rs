@@ -0,0 +1,61 @@
+//! This crate provides tools for converting raw trace data into
+//! a stream of events which represent pulses in the trace stream.
+//!
+//! A raw trace takes the form of a Vec (or some other similar container)
+//! of scalar values. These are converted into `(time, value)` pairs, which may then be passed
+//! through any number of [Window] functions, before a [Detector] finds events in them.
+//! Typical usage of this crate may look like:
+//! ```rust
+//! use digital_muon_pulse_detection::{
+//!     EventFilter, Real, WindowFilter,
+//!     threshold_detector::{ThresholdDetector, ThresholdDuration},
+//!     window::SmoothingWindow,
+//! };
+//!
+//! let trace = [0, 0, 0, 10, 12, 11, 0, 0, 0];
+//! let events: Vec<_> = trace
+//!     .iter()
+//!     .enumerate()
+//!     .map(|(i, v)| (i as Real, *v as Real))              // converts to (Real,Real) format.
+//!     .window(SmoothingWindow::new(3))                    // A moving average window of length 3.
+//!     .map(|(time, stats)| (time, stats.mean))
+//!     .events(ThresholdDetector::new(&ThresholdDuration { // Registers an event when the averaged
+//!         threshold: 5.0,                                 // signal exceeds 5 for 1 sample, with
+//!         duration: 1,                                    // a cool-down of 0 samples.
+//!         cool_off: 0,
+//!     }))
+//!     .collect();
+//!
+//! assert_eq!(events.len(), 1);
+//! assert_eq!(events[0].0, 3.0);
+//! assert_eq!(events[0].1.pulse_height, 11.0);
+//! ```
+//!
+//! Detectors which register several events per pulse are combined into [Pulse]s by an [Assembler],
+//! see [AssembleFilter].
+//!
+//! The [parameters] module contains the parameters of each detection mode, which can be parsed
+//! from the command line, or deserialised from a settings file.
+
+pub mod baseline_estimator;
+pub mod datatype;
+pub mod parameters;
+pub mod pulse;
+pub mod template_fit;
+
+pub mod detectors;
+pub mod iterators;
+pub mod window;
+
+pub use datatype::{EventData, EventPoint, RealArray, Stats, Temporal, TracePoint};
+pub use detectors::{
+    Assembler, Detector, advanced_muon_detector, constant_fraction_discriminator,
+    differential_threshold_detector, threshold_detector,
+};
+pub use iterators::{AssembleFilter, EventFilter};
+pub use window::{Window, WindowFilter};
+
+pub use pulse::Pulse;
+
+/// The type in which all times and trace values are processed.
+pub type Real = f64;
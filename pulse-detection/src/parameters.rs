@@ -0,0 +1,171 @@
+//! The parameters of each detection mode.
+//!
+//! These can be parsed from the command line, as each is a [clap::Parser],
+//! or deserialised from a settings file, in which the fields are in kebab-case:
+//! ```rust
+//! use clap::Parser;
+//! use digital_muon_pulse_detection::parameters::FixedThresholdDiscriminatorParameters;
+//!
+//! let parameters: FixedThresholdDiscriminatorParameters =
+//!     serde_json::from_str(r#"{ "threshold": 10.0, "cool-off": 2 }"#).unwrap();
+//! assert_eq!(parameters.threshold, 10.0);
+//! assert_eq!(parameters.duration, 1);
+//! assert_eq!(parameters.cool_off, 2);
+//!
+//! let parameters =
+//!     FixedThresholdDiscriminatorParameters::parse_from(["detector", "--threshold", "10"]);
+//! assert_eq!(parameters.duration, 1);
+//! assert_eq!(parameters.cool_off, 0);
+//! ```
+use super::Real;
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+
+fn default_duration() -> i32 {
+    1
+}
+
+#[derive(Default, Debug, Clone, Parser, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FixedThresholdDiscriminatorParameters {
+    /// If the detector is armed, an event is registered when the trace passes this value for the given duration.
+    #[clap(long)]
+    pub threshold: Real,
+
+    /// The duration, in samples, that the trace must exceed the threshold for.
+    #[clap(long, default_value = "1")]
+    #[serde(default = "default_duration")]
+    pub duration: i32,
+
+    /// After an event is registered, the detector disarms for this many samples.
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub cool_off: i32,
+}
+
+#[derive(Default, Debug, Clone, Parser, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DifferentialThresholdDiscriminatorParameters {
+    /// If the detector is armed, an event is registered when the trace passes this value for the given duration.
+    #[clap(long)]
+    pub threshold: Real,
+
+    /// The duration, in samples, that the trace must exceed the threshold for.
+    #[clap(long, default_value = "1")]
+    #[serde(default = "default_duration")]
+    pub duration: i32,
+
+    /// After an event is registered, the detector disarms for this many samples.
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub cool_off: i32,
+
+    /// If set, the pulse height is the value of the rising edge, scaled by this factor,
+    /// otherwise the maximum trace value is used for the pulse height.
+    pub constant_multiple: Option<Real>,
+}
+
+#[derive(Default, Debug, Clone, Parser, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AdvancedMuonDetectorParameters {
+    /// Differential threshold for detecting muon onset. See README.md.
+    #[clap(long)]
+    pub muon_onset: Real,
+
+    /// Differential threshold for detecting muon peak. See README.md.
+    #[clap(long)]
+    pub muon_fall: Real,
+
+    /// Differential threshold for detecting muon termination. See README.md.
+    #[clap(long)]
+    pub muon_termination: Real,
+
+    /// Length of time a threshold must be passed to register. See README.md.
+    #[clap(long)]
+    pub duration: Real,
+
+    /// Size of initial portion of the trace to use for determining the baseline. Initial portion should be event free.
+    #[clap(long)]
+    pub baseline_length: Option<usize>,
+
+    /// Size of the moving average window to use for the lopass filter.
+    #[clap(long)]
+    pub smoothing_window_size: Option<usize>,
+
+    /// Optional parameter which (if set) filters out events whose peak is greater than the given value.
+    #[clap(long)]
+    pub max_amplitude: Option<Real>,
+
+    /// Optional parameter which (if set) filters out events whose peak is less than the given value.
+    #[clap(long)]
+    pub min_amplitude: Option<Real>,
+
+    /// If set, the amplitude of each saturated pulse is estimated by extrapolating its steepest rise and sharpest fall. See README.md.
+    #[clap(long)]
+    #[serde(default)]
+    pub estimate_saturated_amplitude: bool,
+}
+
+fn default_max_pulses() -> usize {
+    4
+}
+
+fn default_onset_search_window() -> usize {
+    8
+}
+
+fn default_max_refinement_passes() -> usize {
+    8
+}
+
+#[derive(Default, Debug, Clone, Parser, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TemplateFitDetectorParameters {
+    /// Parameters of the advanced muon detector, used to find candidate pulses.
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub advanced: AdvancedMuonDetectorParameters,
+
+    /// Rise time constant, in ns, of the biexponential pulse template.
+    #[clap(long)]
+    pub template_rise: Real,
+
+    /// Decay time constant, in ns, of the biexponential pulse template.
+    #[clap(long)]
+    pub template_decay: Real,
+
+    /// Pulses are added to the fit of each candidate until no residual exceeds this value.
+    #[clap(long)]
+    pub residual_threshold: Real,
+
+    /// The maximum number of pulses a single candidate can be split into.
+    #[clap(long, default_value = "4")]
+    #[serde(default = "default_max_pulses")]
+    pub max_pulses: usize,
+
+    /// The number of samples, either side of its current onset, to which each pulse may be moved when the fit is refined.
+    #[clap(long, default_value = "8")]
+    #[serde(default = "default_onset_search_window")]
+    pub onset_search_window: usize,
+
+    /// The maximum number of passes made over the pulses when the fit is refined.
+    #[clap(long, default_value = "8")]
+    #[serde(default = "default_max_refinement_passes")]
+    pub max_refinement_passes: usize,
+}
+
+#[derive(Default, Debug, Clone, Parser, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConstantFractionDiscriminatorParameters {
+    /// An event is only registered for pulses whose trace passes this value.
+    #[clap(long)]
+    pub threshold: Real,
+
+    /// The fraction of the delayed trace at which the event is registered, should be between 0 and 1.
+    #[clap(long)]
+    pub fraction: Real,
+
+    /// The delay, in samples, applied to the inverted trace. Should be roughly the rise time of a pulse.
+    #[clap(long)]
+    pub delay: usize,
+}
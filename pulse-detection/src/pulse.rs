@@ -0,0 +1,150 @@
+use std::fmt::Display;
+
+use super::Real;
+use super::RealArray;
+
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct TimeValue<T>
+where
+    T: Default + Clone,
+{
+    pub time: Real,
+    pub value: T,
+}
+
+impl<T> Display for TimeValue<T>
+where
+    T: Default + Clone + Copy + Display,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{0},{1}", self.time, self.value))
+    }
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct TimeValueOptional<T>
+where
+    T: Default + Clone,
+{
+    pub time: Option<Real>,
+    pub value: Option<T>,
+}
+
+impl<T> From<TimeValue<T>> for TimeValueOptional<T>
+where
+    T: Default + Clone + Copy + Display,
+{
+    fn from(source: TimeValue<T>) -> Self {
+        TimeValueOptional {
+            time: Some(source.time),
+            value: Some(source.value),
+        }
+    }
+}
+
+impl<T> Display for TimeValueOptional<T>
+where
+    T: Default + Clone + Copy + Display,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{0},{1}",
+            self.time.unwrap_or_default(),
+            self.value.unwrap_or_default()
+        ))
+    }
+}
+
+#[derive(Default)]
+pub struct Pulse {
+    pub start: TimeValueOptional<Real>,
+    pub end: TimeValueOptional<Real>,
+    pub peak: TimeValueOptional<Real>,
+    pub steepest_rise: TimeValueOptional<RealArray<2>>,
+    pub sharpest_fall: TimeValueOptional<RealArray<2>>,
+}
+
+impl Pulse {
+    /// The time from the start to the end of the pulse, if both are known.
+    pub fn width(&self) -> Option<Real> {
+        Option::zip(self.start.time, self.end.time).map(|(start, end)| end - start)
+    }
+
+    /// The time from the start to the peak of the pulse, if both are known.
+    pub fn rise_time(&self) -> Option<Real> {
+        Option::zip(self.start.time, self.peak.time).map(|(start, peak)| peak - start)
+    }
+
+    /// Estimates the peak of a pulse whose top is clipped, by extrapolating the tangents at its
+    /// steepest rise and sharpest fall to where they meet. The estimate is never less than the measured peak.
+    /// # Parameters
+    /// - sample_time: the time between samples, as the differences are taken between consecutive samples.
+    pub fn extrapolated_peak(&self, sample_time: Real) -> Option<Real> {
+        let (rise_time, rise) = Option::zip(self.steepest_rise.time, self.steepest_rise.value)?;
+        let (fall_time, fall) = Option::zip(self.sharpest_fall.time, self.sharpest_fall.value)?;
+        let rise_gradient = rise[1] / sample_time;
+        let fall_gradient = fall[1] / sample_time;
+        if rise_gradient <= 0.0 || fall_gradient >= 0.0 {
+            return None;
+        }
+
+        let time = (fall[0] - rise[0] + rise_gradient * rise_time - fall_gradient * fall_time)
+            / (rise_gradient - fall_gradient);
+        let peak = rise[0] + rise_gradient * (time - rise_time);
+        Some(self.peak.value.map_or(peak, |value| peak.max(value)))
+    }
+}
+
+impl Display for Pulse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{0},{1},{2},{3},{4}",
+            self.start, self.end, self.peak, self.steepest_rise, self.sharpest_fall
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn extrapolate_clipped_peak() {
+        // Rises at 4 per sample through (2, 8), and falls at 2 per sample through (8, 8), so the tangents meet at (4, 16).
+        let pulse = Pulse {
+            peak: TimeValue {
+                time: 4.0,
+                value: 15.0,
+            }
+            .into(),
+            steepest_rise: TimeValue {
+                time: 2.0,
+                value: RealArray::new([8.0, 4.0]),
+            }
+            .into(),
+            sharpest_fall: TimeValue {
+                time: 8.0,
+                value: RealArray::new([8.0, -2.0]),
+            }
+            .into(),
+            ..Default::default()
+        };
+        assert_approx_eq!(pulse.extrapolated_peak(1.0).unwrap(), 16.0);
+        // With two ns between samples the tangents meet at (4, 12), below the measured peak.
+        assert_approx_eq!(pulse.extrapolated_peak(2.0).unwrap(), 15.0);
+    }
+
+    #[test]
+    fn extrapolate_without_fall() {
+        let pulse = Pulse {
+            steepest_rise: TimeValue {
+                time: 2.0,
+                value: RealArray::new([8.0, 4.0]),
+            }
+            .into(),
+            ..Default::default()
+        };
+        assert_eq!(pulse.extrapolated_peak(1.0), None);
+    }
+}
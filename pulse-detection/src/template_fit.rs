@@ -0,0 +1,385 @@
+//! Resolves overlapping (piled-up) pulses by fitting a pulse template to a region of the trace.
+//!
+//! Pulses are added to the fit one at a time: the onset time of each new pulse is the one
+//! which best matches the residual of the current fit, after which the amplitudes of all
+//! pulses are refitted together by linear least squares. This continues until the residual
+//! falls below a threshold, or the maximum number of pulses is reached.
+use super::Real;
+
+/// A biexponential pulse template, normalised to have a peak height of one.
+///
+/// This is the same pulse shape as the simulator's `biexp` pulse template.
+#[derive(Default, Debug, Clone)]
+pub struct BiexpTemplate {
+    rise: Real,
+    decay: Real,
+    coef: Real,
+}
+
+impl BiexpTemplate {
+    /// Creates a new template, returning `None` if either time constant is not positive.
+    /// # Parameters
+    /// - rise: the rise time constant of the pulse.
+    /// - decay: the decay time constant of the pulse.
+    pub fn new(rise: Real, decay: Real) -> Option<Self> {
+        if !(rise.is_finite() && decay.is_finite() && rise > 0.0 && decay > 0.0) {
+            return None;
+        }
+        let mut template = Self {
+            rise,
+            decay,
+            coef: 1.0,
+        };
+        template.coef = 1.0 / template.value(template.peak_time());
+        template.coef.is_finite().then_some(template)
+    }
+
+    /// The time, after onset, of the peak of the pulse.
+    pub fn peak_time(&self) -> Real {
+        if self.rise == self.decay {
+            self.rise
+        } else {
+            Real::ln(self.decay / self.rise) * self.decay * self.rise / (self.decay - self.rise)
+        }
+    }
+
+    /// The integral of the template over all time after onset.
+    pub fn area(&self) -> Real {
+        if self.rise == self.decay {
+            self.coef * self.rise
+        } else {
+            self.coef * (self.decay - self.rise)
+        }
+    }
+
+    /// The value of the template at the given time after onset.
+    pub fn value(&self, time: Real) -> Real {
+        if time < 0.0 {
+            0.0
+        } else if self.rise == self.decay {
+            // The limit of the biexponential as the time constants converge.
+            self.coef * (time / self.rise) * Real::exp(-time / self.rise)
+        } else {
+            self.coef * (Real::exp(-time / self.decay) - Real::exp(-time / self.rise))
+        }
+    }
+}
+
+/// A single pulse found by the fit.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct FittedPulse {
+    /// The onset time of the pulse, which is also the time of its steepest rise.
+    pub time: Real,
+    /// The peak height of the pulse.
+    pub amplitude: Real,
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct TemplateFitter {
+    template: BiexpTemplate,
+    residual_threshold: Real,
+    max_pulses: usize,
+    onset_search_window: usize,
+    max_refinement_passes: usize,
+}
+
+impl TemplateFitter {
+    /// Creates a new fitter.
+    /// # Parameters
+    /// - template: the shape of a single pulse.
+    /// - residual_threshold: pulses are added to the fit until no residual exceeds this value.
+    /// - max_pulses: the maximum number of pulses which a single region can be resolved into.
+    /// - onset_search_window: the number of samples, either side of an onset, to which it may be moved in each refinement pass.
+    /// - max_refinement_passes: the maximum number of passes made when refining the onsets.
+    pub fn new(
+        template: BiexpTemplate,
+        residual_threshold: Real,
+        max_pulses: usize,
+        onset_search_window: usize,
+        max_refinement_passes: usize,
+    ) -> Self {
+        Self {
+            template,
+            residual_threshold,
+            max_pulses,
+            onset_search_window,
+            max_refinement_passes,
+        }
+    }
+
+    fn template_at(&self, region: &[(Real, Real)], onset: Real) -> Vec<Real> {
+        region
+            .iter()
+            .map(|(time, _)| self.template.value(time - onset))
+            .collect()
+    }
+
+    /// Finds the onset time, from the times in the region, which best matches the residual.
+    fn best_onset(&self, region: &[(Real, Real)], residual: &[Real]) -> Option<Real> {
+        region
+            .iter()
+            .filter_map(|&(onset, _)| {
+                let template = self.template_at(region, onset);
+                let overlap: Real = template.iter().zip(residual).map(|(f, r)| f * r).sum();
+                let norm: Real = template.iter().map(|f| f * f).sum();
+                // Only onsets which would add a positive pulse are considered.
+                (overlap > 0.0 && norm > 0.0).then_some((onset, overlap * overlap / norm))
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(onset, _)| onset)
+    }
+
+    /// Fits the amplitudes of pulses at the given onsets, by linear least squares.
+    fn fit_amplitudes(&self, region: &[(Real, Real)], onsets: &[Real]) -> Option<Vec<Real>> {
+        let templates: Vec<Vec<Real>> = onsets
+            .iter()
+            .map(|&onset| self.template_at(region, onset))
+            .collect();
+        let gram: Vec<Vec<Real>> = templates
+            .iter()
+            .map(|f| {
+                templates
+                    .iter()
+                    .map(|g| f.iter().zip(g).map(|(f, g)| f * g).sum())
+                    .collect()
+            })
+            .collect();
+        let projections: Vec<Real> = templates
+            .iter()
+            .map(|f| f.iter().zip(region).map(|(f, (_, y))| f * y).sum())
+            .collect();
+        solve_linear_system(gram, projections)
+    }
+
+    /// Fits the amplitudes of pulses at the given onsets, returning the
+    /// amplitudes and the residual of the fit, if all amplitudes are positive.
+    fn fit(&self, region: &[(Real, Real)], onsets: &[Real]) -> Option<(Vec<Real>, Vec<Real>)> {
+        let amplitudes = self.fit_amplitudes(region, onsets)?;
+        if amplitudes.iter().any(|&amplitude| amplitude <= 0.0) {
+            return None;
+        }
+        let residual = region
+            .iter()
+            .map(|(time, value)| {
+                value
+                    - onsets
+                        .iter()
+                        .zip(&amplitudes)
+                        .map(|(onset, amplitude)| amplitude * self.template.value(time - onset))
+                        .sum::<Real>()
+            })
+            .collect();
+        Some((amplitudes, residual))
+    }
+
+    /// Moves each onset in turn to the time, within the search window of its current sample,
+    /// which minimises the squared residual, until no onset moves (or the maximum number of passes have been made).
+    fn refine_onsets(
+        &self,
+        region: &[(Real, Real)],
+        onsets: &mut [Real],
+    ) -> Option<(Vec<Real>, Vec<Real>)> {
+        let sum_of_squares = |residual: &[Real]| residual.iter().map(|r| r * r).sum::<Real>();
+
+        let mut best = self.fit(region, onsets)?;
+        for _ in 0..self.max_refinement_passes {
+            let mut moved = false;
+            for index in 0..onsets.len() {
+                let mut best_onset = onsets[index];
+                // Onsets are always times in the region, so the current sample is found.
+                let Some(sample) = region.iter().position(|&(time, _)| time == best_onset) else {
+                    continue;
+                };
+                let candidates = region
+                    .iter()
+                    .skip(sample.saturating_sub(self.onset_search_window))
+                    .take(2 * self.onset_search_window + 1);
+                for &(candidate, _) in candidates {
+                    if onsets.contains(&candidate) {
+                        continue;
+                    }
+                    onsets[index] = candidate;
+                    if let Some(fit) = self.fit(region, onsets) {
+                        if sum_of_squares(&fit.1) < sum_of_squares(&best.1) {
+                            best = fit;
+                            best_onset = candidate;
+                            moved = true;
+                        }
+                    }
+                }
+                onsets[index] = best_onset;
+            }
+            if !moved {
+                break;
+            }
+        }
+        Some(best)
+    }
+
+    /// Fits the template to a region of the trace, returning the pulses found, in time order.
+    /// The region should be baselined, and contain at least one pulse.
+    pub fn fit_pulses(&self, region: &[(Real, Real)]) -> Vec<FittedPulse> {
+        let mut onsets = Vec::<Real>::new();
+        let mut amplitudes = Vec::<Real>::new();
+        let mut residual: Vec<Real> = region.iter().map(|(_, value)| *value).collect();
+
+        while onsets.len() < self.max_pulses
+            && residual
+                .iter()
+                .any(|value| value.abs() > self.residual_threshold)
+        {
+            let Some(onset) = self.best_onset(region, &residual) else {
+                break;
+            };
+            if onsets.contains(&onset) {
+                break;
+            }
+
+            let mut new_onsets = onsets.clone();
+            new_onsets.push(onset);
+            match self.refine_onsets(region, &mut new_onsets) {
+                Some((new_amplitudes, new_residual)) => {
+                    onsets = new_onsets;
+                    amplitudes = new_amplitudes;
+                    residual = new_residual;
+                }
+                // The new pulse cannot be fitted, so the previous fit is kept.
+                None => break,
+            }
+        }
+
+        let mut pulses: Vec<FittedPulse> = onsets
+            .into_iter()
+            .zip(amplitudes)
+            .map(|(time, amplitude)| FittedPulse { time, amplitude })
+            .collect();
+        pulses.sort_by(|a, b| a.time.total_cmp(&b.time));
+        pulses
+    }
+}
+
+/// Solves the system `matrix * x = vector` by Gaussian elimination with partial pivoting.
+/// Returns `None` if the matrix is singular.
+fn solve_linear_system(mut matrix: Vec<Vec<Real>>, mut vector: Vec<Real>) -> Option<Vec<Real>> {
+    let n = vector.len();
+    for col in 0..n {
+        let pivot =
+            (col..n).max_by(|&i, &j| matrix[i][col].abs().total_cmp(&matrix[j][col].abs()))?;
+        if matrix[pivot][col].abs() < Real::EPSILON {
+            return None;
+        }
+        matrix.swap(col, pivot);
+        vector.swap(col, pivot);
+        for row in (col + 1)..n {
+            let factor = matrix[row][col] / matrix[col][col];
+            for k in col..n {
+                matrix[row][k] -= factor * matrix[col][k];
+            }
+            vector[row] -= factor * vector[col];
+        }
+    }
+
+    let mut solution = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: Real = ((row + 1)..n).map(|k| matrix[row][k] * solution[k]).sum();
+        solution[row] = (vector[row] - sum) / matrix[row][row];
+    }
+    Some(solution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    fn trace(
+        template: &BiexpTemplate,
+        pulses: &[(Real, Real)],
+        length: usize,
+    ) -> Vec<(Real, Real)> {
+        (0..length)
+            .map(|i| {
+                let time = i as Real;
+                let value = pulses
+                    .iter()
+                    .map(|(onset, amplitude)| amplitude * template.value(time - onset))
+                    .sum();
+                (time, value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn template_peak_is_one() {
+        let template = BiexpTemplate::new(2.0, 10.0).unwrap();
+        assert_approx_eq!(template.value(template.peak_time()), 1.0);
+        assert_approx_eq!(template.value(0.0), 0.0);
+        assert_eq!(template.value(-1.0), 0.0);
+        assert!(template.value(template.peak_time() - 0.5) < 1.0);
+        assert!(template.value(template.peak_time() + 0.5) < 1.0);
+
+        let template = BiexpTemplate::new(4.0, 4.0).unwrap();
+        assert_approx_eq!(template.peak_time(), 4.0);
+        assert_approx_eq!(template.value(4.0), 1.0);
+    }
+
+    #[test]
+    fn template_area() {
+        // Numerically integrate the template, which has decayed to nothing by t = 500.
+        for (rise, decay) in [(2.0, 10.0), (4.0, 4.0)] {
+            let template = BiexpTemplate::new(rise, decay).unwrap();
+            let integral: Real = (0..50_000)
+                .map(|i| template.value(i as Real * 0.01) * 0.01)
+                .sum();
+            assert!((template.area() - integral).abs() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn invalid_template() {
+        assert!(BiexpTemplate::new(0.0, 10.0).is_none());
+        assert!(BiexpTemplate::new(2.0, -1.0).is_none());
+        assert!(BiexpTemplate::new(Real::NAN, 1.0).is_none());
+    }
+
+    #[test]
+    fn single_pulse() {
+        let template = BiexpTemplate::new(2.0, 10.0).unwrap();
+        let region = trace(&template, &[(5.0, 100.0)], 60);
+        let fitter = TemplateFitter::new(template, 1.0, 4, 8, 8);
+        let pulses = fitter.fit_pulses(&region);
+        assert_eq!(pulses.len(), 1);
+        assert_approx_eq!(pulses[0].time, 5.0);
+        assert_approx_eq!(pulses[0].amplitude, 100.0);
+    }
+
+    #[test]
+    fn overlapping_pulses() {
+        let template = BiexpTemplate::new(2.0, 10.0).unwrap();
+        let region = trace(&template, &[(5.0, 100.0), (14.0, 60.0)], 80);
+        let fitter = TemplateFitter::new(template, 1.0, 4, 8, 8);
+        let pulses = fitter.fit_pulses(&region);
+        assert_eq!(pulses.len(), 2);
+        assert_approx_eq!(pulses[0].time, 5.0);
+        assert_approx_eq!(pulses[0].amplitude, 100.0);
+        assert_approx_eq!(pulses[1].time, 14.0);
+        assert_approx_eq!(pulses[1].amplitude, 60.0);
+    }
+
+    #[test]
+    fn max_pulses() {
+        let template = BiexpTemplate::new(2.0, 10.0).unwrap();
+        let region = trace(&template, &[(5.0, 100.0), (14.0, 60.0)], 80);
+        let fitter = TemplateFitter::new(template, 1.0, 1, 8, 8);
+        let pulses = fitter.fit_pulses(&region);
+        assert_eq!(pulses.len(), 1);
+    }
+
+    #[test]
+    fn below_residual_threshold() {
+        let template = BiexpTemplate::new(2.0, 10.0).unwrap();
+        let region = trace(&template, &[(5.0, 10.0)], 60);
+        let fitter = TemplateFitter::new(template, 20.0, 4, 8, 8);
+        assert!(fitter.fit_pulses(&region).is_empty());
+    }
+}
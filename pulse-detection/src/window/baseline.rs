@@ -1,7 +1,7 @@
 use super::{Real, Window};
 
 #[derive(Default, Clone)]
-pub(crate) struct Baseline {
+pub struct Baseline {
     baseline: Real,
     value: Real,
     smoothing_factor: Real,
@@ -10,7 +10,7 @@ pub(crate) struct Baseline {
 }
 
 impl Baseline {
-    pub(crate) fn new(warm_up: usize, smoothing_factor: Real) -> Self {
+    pub fn new(warm_up: usize, smoothing_factor: Real) -> Self {
         Baseline {
             warm_up,
             smoothing_factor,
@@ -51,7 +51,7 @@ impl Window for Baseline {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::pulse_detection::window::WindowFilter;
+    use crate::window::WindowFilter;
     use assert_approx_eq::assert_approx_eq;
 
     #[test]
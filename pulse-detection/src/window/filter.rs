@@ -0,0 +1,43 @@
+use super::{FirFilter, IirFilter, MatchedFilter, MedianFilter, Real, Window};
+
+/// Any of the filters which can be chained ahead of a detector, so that the chain can be chosen at runtime.
+#[derive(Clone)]
+pub enum Filter {
+    Fir(FirFilter),
+    Iir(IirFilter),
+    Median(MedianFilter),
+    Matched(MatchedFilter),
+}
+
+impl Window for Filter {
+    type TimeType = Real;
+    type InputType = Real;
+    type OutputType = Real;
+
+    fn push(&mut self, value: Real) -> bool {
+        match self {
+            Self::Fir(filter) => filter.push(value),
+            Self::Iir(filter) => filter.push(value),
+            Self::Median(filter) => filter.push(value),
+            Self::Matched(filter) => filter.push(value),
+        }
+    }
+
+    fn output(&self) -> Option<Real> {
+        match self {
+            Self::Fir(filter) => filter.output(),
+            Self::Iir(filter) => filter.output(),
+            Self::Median(filter) => filter.output(),
+            Self::Matched(filter) => filter.output(),
+        }
+    }
+
+    fn apply_time_shift(&self, time: Real) -> Real {
+        match self {
+            Self::Fir(filter) => filter.apply_time_shift(time),
+            Self::Iir(filter) => filter.apply_time_shift(time),
+            Self::Median(filter) => filter.apply_time_shift(time),
+            Self::Matched(filter) => filter.apply_time_shift(time),
+        }
+    }
+}
@@ -3,14 +3,14 @@ use num::integer::binomial;
 use std::collections::VecDeque;
 
 #[derive(Default, Clone)]
-pub(crate) struct FiniteDifferences<const N: usize> {
+pub struct FiniteDifferences<const N: usize> {
     coefficients: Vec<Vec<Real>>,
     values: VecDeque<Real>,
     diffs: Vec<Real>,
 }
 
 impl<const N: usize> FiniteDifferences<N> {
-    pub(crate) fn new() -> Self {
+    pub fn new() -> Self {
         FiniteDifferences {
             values: VecDeque::<Real>::with_capacity(N),
             coefficients: (0..N)
@@ -63,7 +63,7 @@ impl<const N: usize> Window for FiniteDifferences<N> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::pulse_detection::window::WindowFilter;
+    use crate::window::WindowFilter;
     use digital_muon_common::Intensity;
 
     #[test]
@@ -0,0 +1,131 @@
+use super::{Real, Window};
+use std::collections::VecDeque;
+
+/// A finite impulse response filter, whose output is a weighted sum of the most recent samples.
+#[derive(Default, Clone)]
+pub struct FirFilter {
+    coefficients: Vec<Real>,
+    window: VecDeque<Real>,
+    sample_time: Real,
+}
+
+impl FirFilter {
+    /// Creates a new filter, returning `None` if there are no coefficients.
+    /// # Parameters
+    /// - coefficients: the weights of the samples, the first of which applies to the most recent sample.
+    ///   These should be symmetric, so that the delay of the filter is half its length.
+    /// - sample_time: the time between samples.
+    pub fn new(coefficients: Vec<Real>, sample_time: Real) -> Option<Self> {
+        (!coefficients.is_empty()).then(|| FirFilter {
+            window: VecDeque::with_capacity(coefficients.len() + 1),
+            coefficients,
+            sample_time,
+        })
+    }
+
+    fn is_full(&self) -> bool {
+        self.window.len() == self.coefficients.len()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.coefficients.len()
+    }
+}
+
+impl Window for FirFilter {
+    type TimeType = Real;
+    type InputType = Real;
+    type OutputType = Real;
+
+    fn push(&mut self, value: Real) -> bool {
+        self.window.push_front(value);
+        if self.window.len() > self.coefficients.len() {
+            self.window.pop_back();
+        }
+        self.is_full()
+    }
+
+    fn output(&self) -> Option<Real> {
+        self.is_full().then(|| {
+            self.coefficients
+                .iter()
+                .zip(&self.window)
+                .map(|(coefficient, value)| coefficient * value)
+                .sum()
+        })
+    }
+
+    fn apply_time_shift(&self, time: Real) -> Real {
+        time - (self.len() as Real - 1.0) * self.sample_time / 2.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::window::WindowFilter;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn no_coefficients() {
+        assert!(FirFilter::new(vec![], 1.0).is_none());
+    }
+
+    #[test]
+    fn identity() {
+        let data = [4.0, 3.0, 2.0, 5.0];
+        let output: Vec<_> = data
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i as Real, v as Real))
+            .window(FirFilter::new(vec![1.0], 1.0).unwrap())
+            .collect();
+        assert_eq!(output, [(0.0, 4.0), (1.0, 3.0), (2.0, 2.0), (3.0, 5.0)]);
+    }
+
+    #[test]
+    fn insufficient_data() {
+        let data = [4.0, 3.0];
+        assert!(
+            data.into_iter()
+                .enumerate()
+                .map(|(i, v)| (i as Real, v as Real))
+                .window(FirFilter::new(vec![0.25, 0.5, 0.25], 1.0).unwrap())
+                .next()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn weighted_sum() {
+        let data = [4.0, 3.0, 1.0, 5.0, 3.0];
+        let output: Vec<_> = data
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i as Real * 2.0, v as Real))
+            .window(FirFilter::new(vec![0.25, 0.5, 0.25], 2.0).unwrap())
+            .collect();
+
+        assert_eq!(output.len(), 3);
+        // The output is centred on the middle sample of the window.
+        assert_eq!(output[0].0, 2.0);
+        assert_approx_eq!(output[0].1, 0.25 * 4.0 + 0.5 * 3.0 + 0.25 * 1.0);
+        assert_eq!(output[1].0, 4.0);
+        assert_approx_eq!(output[1].1, 0.25 * 3.0 + 0.5 * 1.0 + 0.25 * 5.0);
+        assert_eq!(output[2].0, 6.0);
+        assert_approx_eq!(output[2].1, 0.25 * 1.0 + 0.5 * 5.0 + 0.25 * 3.0);
+    }
+
+    #[test]
+    fn first_coefficient_applies_to_newest_sample() {
+        let data = [1.0, 10.0];
+        let (_, value) = data
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i as Real, v as Real))
+            .window(FirFilter::new(vec![1.0, 0.0], 1.0).unwrap())
+            .next()
+            .unwrap();
+        assert_eq!(value, 10.0);
+    }
+}
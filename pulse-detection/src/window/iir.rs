@@ -0,0 +1,205 @@
+use super::{Real, Window};
+use std::f64::consts::PI;
+
+/// A section of an IIR filter, of up to second order, in direct form I.
+#[derive(Default, Clone)]
+struct Section {
+    /// Feedforward coefficients.
+    b: [Real; 3],
+    /// Feedback coefficients, excluding the leading coefficient which is normalised to one.
+    a: [Real; 2],
+    /// The previous two inputs, most recent first.
+    x: [Real; 2],
+    /// The previous two outputs, most recent first.
+    y: [Real; 2],
+}
+
+impl Section {
+    /// A second order Butterworth section, designed by the bilinear transform.
+    /// # Parameters
+    /// - k: the prewarped cutoff frequency, `tan(π f)` where `f` is the cutoff as a fraction of the sample rate.
+    /// - q: the quality factor of the section.
+    fn second_order(k: Real, q: Real, high_pass: bool) -> Self {
+        let norm = 1.0 / (1.0 + k / q + k * k);
+        let b0 = if high_pass { norm } else { k * k * norm };
+        Self {
+            b: [b0, if high_pass { -2.0 * b0 } else { 2.0 * b0 }, b0],
+            a: [2.0 * (k * k - 1.0) * norm, (1.0 - k / q + k * k) * norm],
+            ..Default::default()
+        }
+    }
+
+    /// A first order Butterworth section, designed by the bilinear transform.
+    fn first_order(k: Real, high_pass: bool) -> Self {
+        let norm = 1.0 / (1.0 + k);
+        let b0 = if high_pass { norm } else { k * norm };
+        Self {
+            b: [b0, if high_pass { -b0 } else { b0 }, 0.0],
+            a: [(k - 1.0) * norm, 0.0],
+            ..Default::default()
+        }
+    }
+
+    fn process(&mut self, x: Real) -> Real {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// An infinite impulse response filter, implemented as a cascade of sections.
+///
+/// The filter starts from rest, so should be applied to a trace whose baseline has been removed.
+#[derive(Default, Clone)]
+pub struct IirFilter {
+    sections: Vec<Section>,
+    value: Real,
+}
+
+impl IirFilter {
+    /// Creates a Butterworth low-pass filter, returning `None` if the parameters are invalid.
+    /// # Parameters
+    /// - cutoff: the cutoff frequency, as a fraction of the sample rate, which must be between zero and one half.
+    /// - order: the order of the filter, which must be at least one.
+    pub fn butterworth_low_pass(cutoff: Real, order: usize) -> Option<Self> {
+        Self::butterworth(cutoff, order, false)
+    }
+
+    /// Creates a Butterworth high-pass filter, returning `None` if the parameters are invalid.
+    /// # Parameters
+    /// - cutoff: the cutoff frequency, as a fraction of the sample rate, which must be between zero and one half.
+    /// - order: the order of the filter, which must be at least one.
+    pub fn butterworth_high_pass(cutoff: Real, order: usize) -> Option<Self> {
+        Self::butterworth(cutoff, order, true)
+    }
+
+    fn butterworth(cutoff: Real, order: usize, high_pass: bool) -> Option<Self> {
+        if !(cutoff > 0.0 && cutoff < 0.5) || order == 0 {
+            return None;
+        }
+        let k = Real::tan(PI * cutoff);
+        // Each pair of conjugate poles forms a second order section, and an odd order adds a real pole.
+        let mut sections: Vec<Section> = (1..=order / 2)
+            .map(|pair| {
+                let angle = PI * (2 * pair + order - 1) as Real / (2 * order) as Real;
+                Section::second_order(k, -1.0 / (2.0 * angle.cos()), high_pass)
+            })
+            .collect();
+        if order % 2 == 1 {
+            sections.push(Section::first_order(k, high_pass));
+        }
+        Some(Self {
+            sections,
+            value: 0.0,
+        })
+    }
+}
+
+impl Window for IirFilter {
+    type TimeType = Real;
+    type InputType = Real;
+    type OutputType = Real;
+
+    fn push(&mut self, value: Real) -> bool {
+        self.value = self
+            .sections
+            .iter_mut()
+            .fold(value, |value, section| section.process(value));
+        true
+    }
+
+    fn output(&self) -> Option<Real> {
+        Some(self.value)
+    }
+
+    fn apply_time_shift(&self, time: Real) -> Real {
+        time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::window::WindowFilter;
+    use assert_approx_eq::assert_approx_eq;
+
+    fn filter(data: &[Real], filter: IirFilter) -> Vec<Real> {
+        data.iter()
+            .enumerate()
+            .map(|(i, v)| (i as Real, *v))
+            .window(filter)
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        assert!(IirFilter::butterworth_low_pass(0.0, 2).is_none());
+        assert!(IirFilter::butterworth_low_pass(0.5, 2).is_none());
+        assert!(IirFilter::butterworth_high_pass(0.1, 0).is_none());
+    }
+
+    #[test]
+    fn low_pass_second_order_coefficients() {
+        // At a quarter of the sample rate the prewarped cutoff is one, which gives simple coefficients.
+        let output = filter(
+            &[1.0, 0.0, 0.0, 0.0],
+            IirFilter::butterworth_low_pass(0.25, 2).unwrap(),
+        );
+        let norm = 1.0 / (2.0 + Real::sqrt(2.0));
+        let a2 = (2.0 - Real::sqrt(2.0)) * norm;
+        assert_approx_eq!(output[0], norm);
+        assert_approx_eq!(output[1], 2.0 * norm);
+        assert_approx_eq!(output[2], norm - a2 * norm);
+    }
+
+    #[test]
+    fn low_pass_passes_constant() {
+        for order in 1..=5 {
+            let output = filter(
+                &[3.0; 200],
+                IirFilter::butterworth_low_pass(0.05, order).unwrap(),
+            );
+            assert_approx_eq!(output.last().unwrap(), 3.0, 1e-6);
+        }
+    }
+
+    #[test]
+    fn low_pass_rejects_nyquist() {
+        let data: Vec<Real> = (0..200)
+            .map(|i| if i % 2 == 0 { 1.0 } else { -1.0 })
+            .collect();
+        for order in 1..=5 {
+            let output = filter(&data, IirFilter::butterworth_low_pass(0.05, order).unwrap());
+            assert_approx_eq!(output.last().unwrap(), 0.0, 1e-6);
+        }
+    }
+
+    #[test]
+    fn high_pass_rejects_constant() {
+        for order in 1..=5 {
+            let output = filter(
+                &[3.0; 400],
+                IirFilter::butterworth_high_pass(0.05, order).unwrap(),
+            );
+            assert_approx_eq!(output.last().unwrap(), 0.0, 1e-6);
+        }
+    }
+
+    #[test]
+    fn high_pass_passes_nyquist() {
+        let data: Vec<Real> = (0..400)
+            .map(|i| if i % 2 == 0 { 1.0 } else { -1.0 })
+            .collect();
+        for order in 1..=5 {
+            let output = filter(
+                &data,
+                IirFilter::butterworth_high_pass(0.05, order).unwrap(),
+            );
+            assert_approx_eq!(output.last().unwrap().abs(), 1.0, 1e-6);
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use super::{FirFilter, Real, Window};
+
+/// Correlates the trace with a pulse template, which maximises the signal to noise ratio of
+/// pulses of that shape in white noise.
+///
+/// The output is normalised so that a pulse which matches the template has its amplitude
+/// as the output at its onset.
+#[derive(Default, Clone)]
+pub struct MatchedFilter {
+    fir: FirFilter,
+    sample_time: Real,
+}
+
+impl MatchedFilter {
+    /// Creates a new filter, returning `None` if the template is empty or zero.
+    /// # Parameters
+    /// - template: the samples of the pulse template, from its onset.
+    /// - sample_time: the time between samples.
+    pub fn new(template: &[Real], sample_time: Real) -> Option<Self> {
+        let norm: Real = template.iter().map(|value| value * value).sum();
+        if !(norm.is_finite() && norm > 0.0) {
+            return None;
+        }
+        // The first coefficient applies to the most recent sample, so the template is reversed.
+        let coefficients = template.iter().rev().map(|value| value / norm).collect();
+        Some(Self {
+            fir: FirFilter::new(coefficients, sample_time)?,
+            sample_time,
+        })
+    }
+}
+
+impl Window for MatchedFilter {
+    type TimeType = Real;
+    type InputType = Real;
+    type OutputType = Real;
+
+    fn push(&mut self, value: Real) -> bool {
+        self.fir.push(value)
+    }
+
+    fn output(&self) -> Option<Real> {
+        self.fir.output()
+    }
+
+    fn apply_time_shift(&self, time: Real) -> Real {
+        // The window is aligned with a pulse when its oldest sample is the pulse's onset.
+        time - (self.fir.len() as Real - 1.0) * self.sample_time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::window::WindowFilter;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn invalid_template() {
+        assert!(MatchedFilter::new(&[], 1.0).is_none());
+        assert!(MatchedFilter::new(&[0.0, 0.0], 1.0).is_none());
+    }
+
+    #[test]
+    fn matching_pulse() {
+        let template = [0.5, 1.0, 0.5];
+        let data = [0.0, 0.0, 3.0, 6.0, 3.0, 0.0, 0.0];
+        let output: Vec<_> = data
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i as Real * 4.0, v as Real))
+            .window(MatchedFilter::new(&template, 4.0).unwrap())
+            .collect();
+
+        assert_eq!(output.len(), 5);
+        let (time, peak) = output
+            .iter()
+            .copied()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap();
+        // The peak is at the onset of the pulse, and has the pulse's amplitude.
+        assert_eq!(time, 8.0);
+        assert_approx_eq!(peak, 6.0);
+    }
+
+    #[test]
+    fn asymmetric_template() {
+        let template = [1.0, 0.25];
+        let data = [0.0, 4.0, 1.0, 0.0];
+        let output: Vec<_> = data
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i as Real, v as Real))
+            .window(MatchedFilter::new(&template, 1.0).unwrap())
+            .collect();
+
+        let norm = 1.0 + 0.25 * 0.25;
+        assert_eq!(output.len(), 3);
+        assert_eq!(output[0].0, 0.0);
+        assert_approx_eq!(output[0].1, 0.25 * 4.0 / norm);
+        assert_eq!(output[1].0, 1.0);
+        assert_approx_eq!(output[1].1, 4.0);
+        assert_eq!(output[2].0, 2.0);
+        assert_approx_eq!(output[2].1, 1.0 / norm);
+    }
+}
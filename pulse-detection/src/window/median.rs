@@ -0,0 +1,121 @@
+use super::{Real, Window};
+use crate::baseline_estimator::median;
+use std::collections::VecDeque;
+
+/// A moving median, which rejects spikes narrower than half the window without smoothing the edges of pulses.
+#[derive(Default, Clone)]
+pub struct MedianFilter {
+    size: usize,
+    window: VecDeque<Real>,
+    sample_time: Real,
+}
+
+impl MedianFilter {
+    /// Creates a new filter, returning `None` if the size is zero.
+    /// # Parameters
+    /// - size: the number of samples of which the median is taken.
+    /// - sample_time: the time between samples.
+    pub fn new(size: usize, sample_time: Real) -> Option<Self> {
+        (size > 0).then(|| MedianFilter {
+            size,
+            window: VecDeque::with_capacity(size + 1),
+            sample_time,
+        })
+    }
+
+    fn is_full(&self) -> bool {
+        self.window.len() == self.size
+    }
+}
+
+impl Window for MedianFilter {
+    type TimeType = Real;
+    type InputType = Real;
+    type OutputType = Real;
+
+    fn push(&mut self, value: Real) -> bool {
+        self.window.push_back(value);
+        if self.window.len() > self.size {
+            self.window.pop_front();
+        }
+        self.is_full()
+    }
+
+    fn output(&self) -> Option<Real> {
+        if !self.is_full() {
+            return None;
+        }
+        let mut sorted: Vec<Real> = self.window.iter().copied().collect();
+        sorted.sort_by(Real::total_cmp);
+        median(&sorted)
+    }
+
+    fn apply_time_shift(&self, time: Real) -> Real {
+        time - (self.size as Real - 1.0) * self.sample_time / 2.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::window::WindowFilter;
+
+    #[test]
+    fn zero_size() {
+        assert!(MedianFilter::new(0, 1.0).is_none());
+    }
+
+    #[test]
+    fn insufficient_data() {
+        let data = [4.0, 3.0];
+        assert!(
+            data.into_iter()
+                .enumerate()
+                .map(|(i, v)| (i as Real, v as Real))
+                .window(MedianFilter::new(3, 1.0).unwrap())
+                .next()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn spike_rejected() {
+        let data = [1.0, 1.0, 50.0, 1.0, 1.0, 2.0, 2.0];
+        let output: Vec<_> = data
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i as Real, v as Real))
+            .window(MedianFilter::new(3, 1.0).unwrap())
+            .collect();
+        assert_eq!(
+            output,
+            [(1.0, 1.0), (2.0, 1.0), (3.0, 1.0), (4.0, 1.0), (5.0, 2.0)]
+        );
+    }
+
+    #[test]
+    fn step_preserved() {
+        let data = [0.0, 0.0, 0.0, 8.0, 8.0, 8.0];
+        let output: Vec<_> = data
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i as Real * 2.0, v as Real))
+            .window(MedianFilter::new(3, 2.0).unwrap())
+            .collect();
+        assert_eq!(output, [(2.0, 0.0), (4.0, 0.0), (6.0, 8.0), (8.0, 8.0)]);
+    }
+
+    #[test]
+    fn even_size() {
+        let data = [1.0, 3.0, 2.0, 10.0];
+        let (time, value) = data
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i as Real, v as Real))
+            .window(MedianFilter::new(4, 1.0).unwrap())
+            .next()
+            .unwrap();
+        assert_eq!(time, 1.5);
+        assert_eq!(value, 2.5);
+    }
+}
@@ -1,24 +1,52 @@
-pub(crate) mod baseline;
-pub(crate) mod finite_differences;
-pub(crate) mod smoothing_window;
+pub mod baseline;
+pub mod filter;
+pub mod finite_differences;
+pub mod fir;
+pub mod iir;
+pub mod matched;
+pub mod median;
+pub mod smoothing_window;
 
 use super::{Real, RealArray, Stats, Temporal, TracePoint};
-pub(crate) use baseline::Baseline;
-pub(crate) use finite_differences::FiniteDifferences;
-pub(crate) use smoothing_window::SmoothingWindow;
+pub use baseline::Baseline;
+pub use filter::Filter;
+pub use finite_differences::FiniteDifferences;
+pub use fir::FirFilter;
+pub use iir::IirFilter;
+pub use matched::MatchedFilter;
+pub use median::MedianFilter;
+pub use smoothing_window::SmoothingWindow;
 
-pub(crate) trait Window: Clone {
+/// A function of the most recent points of a trace, such as a filter, which is usually applied with [WindowFilter].
+///
+/// The output of the window is registered at the time returned by [Window::apply_time_shift],
+/// which compensates for the delay the window introduces.
+/// ```rust
+/// use digital_muon_pulse_detection::{Real, WindowFilter, window::FiniteDifferences};
+///
+/// // Outputs each value, and its difference from the previous value.
+/// let output: Vec<_> = [(0.0, 1.0), (1.0, 3.0), (2.0, 2.0)]
+///     .into_iter()
+///     .window(FiniteDifferences::<2>::new())
+///     .map(|(time, value)| (time, value[0], value[1]))
+///     .collect();
+/// assert_eq!(output, [(1.0, 3.0, 2.0), (2.0, 2.0, -1.0)]);
+/// ```
+pub trait Window: Clone {
     type TimeType: Temporal;
     type InputType: Copy;
     type OutputType;
 
+    /// Adds a value to the window, returning true if the window has an output.
     fn push(&mut self, value: Self::InputType) -> bool;
+    /// The output of the window, given the values pushed so far.
     fn output(&self) -> Option<Self::OutputType>;
+    /// Converts the time of the most recent value into the time to which the output applies.
     fn apply_time_shift(&self, time: Self::TimeType) -> Self::TimeType;
 }
 
 #[derive(Clone)]
-pub(crate) struct WindowIter<I, W>
+pub struct WindowIter<I, W>
 where
     I: Iterator,
     I::Item: TracePoint,
@@ -67,7 +95,8 @@ where
         }
     }
 }
-pub(crate) trait WindowFilter<I, W>
+/// Applies a [Window] to an iterator of trace points.
+pub trait WindowFilter<I, W>
 where
     I: Iterator,
     I::Item: TracePoint,
@@ -2,7 +2,7 @@ use super::{Real, Stats, Window};
 use std::collections::VecDeque;
 
 #[derive(Default, Clone)]
-pub(crate) struct SmoothingWindow {
+pub struct SmoothingWindow {
     value: Real,
     sum: Real,
     sum_of_squares: Real,
@@ -11,7 +11,7 @@ pub(crate) struct SmoothingWindow {
 }
 
 impl SmoothingWindow {
-    pub(crate) fn new(size: usize) -> Self {
+    pub fn new(size: usize) -> Self {
         if size < 1 {
             panic!("Size must be >= 1");
         }
@@ -85,7 +85,7 @@ impl Window for SmoothingWindow {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::pulse_detection::window::WindowFilter;
+    use crate::window::WindowFilter;
     use assert_approx_eq::assert_approx_eq;
 
     #[test]
@@ -129,6 +129,7 @@ pub(crate) fn build_digitiser_event_list_message(
         time: Some(fbb.create_vector(&time)),
         voltage: Some(fbb.create_vector(&voltage)),
         channel: Some(fbb.create_vector(&channel)),
+        ..Default::default()
     };
     let message = DigitizerEventListMessage::create(fbb, &message);
     finish_digitizer_event_list_message_buffer(fbb, message);
@@ -175,6 +176,7 @@ pub(crate) fn build_aggregated_event_list_message(
         channel: Some(fbb.create_vector(channels)),
         complete: true,
         digitizers_present: None,
+        ..Default::default()
     };
     let message = FrameAssembledEventListMessage::create(fbb, &message);
     finish_frame_assembled_event_list_message_buffer(fbb, message);
@@ -299,6 +299,7 @@ async fn send(
                 u32::try_from(now.as_millis()).into_diagnostic()?;
                 digitiser_cli_options.events_per_frame
             ])),
+            ..Default::default()
         };
         let message = DigitizerEventListMessage::create(fbb, &message);
         finish_digitizer_event_list_message_buffer(fbb, message);
@@ -15,8 +15,13 @@ pub struct FrameMetadata {
 
 impl FrameMetadata {
     pub fn equals_ignoring_veto_flags(&self, other: &Self) -> bool {
-        self.timestamp == other.timestamp
-            && self.period_number == other.period_number
+        self.timestamp == other.timestamp && self.equals_ignoring_timestamp_and_veto_flags(other)
+    }
+
+    /// Compares all fields except [Self::timestamp] and [Self::veto_flags],
+    /// for matching metadata from digitisers whose clocks are not exactly synchronised.
+    pub fn equals_ignoring_timestamp_and_veto_flags(&self, other: &Self) -> bool {
+        self.period_number == other.period_number
             && self.protons_per_pulse == other.protons_per_pulse
             && self.running == other.running
             && self.frame_number == other.frame_number
@@ -89,6 +94,7 @@ mod tests {
             time,
             channel,
             voltage,
+            ..Default::default()
         };
         let message = DigitizerEventListMessage::create(&mut fbb, &message);
         finish_digitizer_event_list_message_buffer(&mut fbb, message);
@@ -175,5 +181,9 @@ mod tests {
         assert!(!m1.equals_ignoring_veto_flags(&m6));
         // This one should be true however
         assert!(m1.equals_ignoring_veto_flags(&m7));
+
+        // Only the timestamp differs
+        assert!(m1.equals_ignoring_timestamp_and_veto_flags(&m6));
+        assert!(!m1.equals_ignoring_timestamp_and_veto_flags(&m5));
     }
 }
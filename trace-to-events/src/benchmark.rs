@@ -0,0 +1,640 @@
+//! Benchmarks the detector against simulated traces, for which the true events are known.
+//!
+//! Only the detector stage is benchmarked: each channel trace is processed with its detector settings,
+//! and the baseline estimation if enabled, but without masking, calibration, dead time or the veto policy.
+//!
+//! The true events are given as `dev2` messages, such as those sent by the simulator alongside its traces,
+//! in the same length-prefixed format as the input file of offline mode. Each trace message is matched to
+//! the true events with the same digitiser id, frame number and timestamp.
+use crate::{
+    channels::find_channel_events,
+    offline::{OfflineError, read_message},
+    parameters::{BaselineEstimation, DetectorSettings},
+};
+use chrono::{DateTime, Utc};
+use digital_muon_common::{Channel, DigitizerId, Intensity, Time};
+use digital_muon_pulse_detection::Real;
+use digital_muon_streaming_types::{
+    dat2_digitizer_analog_trace_v2_generated::{
+        DigitizerAnalogTraceMessage, digitizer_analog_trace_message_buffer_has_identifier,
+        root_as_digitizer_analog_trace_message,
+    },
+    dev2_digitizer_event_v2_generated::{
+        DigitizerEventListMessage, digitizer_event_list_message_buffer_has_identifier,
+        root_as_digitizer_event_list_message,
+    },
+    frame_metadata_v2_generated::FrameMetadataV2,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// Identifies the frame of a message from a single digitiser.
+type FrameKey = (DigitizerId, u32, DateTime<Utc>);
+
+/// The times and intensities of the events of a single channel.
+type Events = Vec<(Time, Intensity)>;
+
+#[derive(Debug, Error)]
+pub(crate) enum BenchmarkError {
+    #[error("Offline Error: {0}")]
+    Offline(#[from] OfflineError),
+    #[error("Json Error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("File Error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("Swept setting {0} is not a detector setting")]
+    UnknownSetting(String),
+    #[error("Swept setting {0} has no values")]
+    NoValues(String),
+}
+
+/// Values of detector settings, every combination of which is benchmarked.
+/// Each key is the name of a setting, as it appears in the detector settings file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct Sweep(BTreeMap<String, Vec<Value>>);
+
+impl Sweep {
+    /// Loads a sweep from a JSON file.
+    pub(crate) fn from_file(path: &Path) -> Result<Self, BenchmarkError> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    /// Every combination of the values of the swept settings, in the order of [Sweep::names].
+    /// If no settings are swept, there is a single, empty, combination.
+    fn points(&self) -> Result<Vec<Vec<&Value>>, BenchmarkError> {
+        self.0
+            .iter()
+            .try_fold(vec![Vec::new()], |points, (name, values)| {
+                if values.is_empty() {
+                    return Err(BenchmarkError::NoValues(name.clone()));
+                }
+                Ok(points
+                    .iter()
+                    .flat_map(|point| {
+                        values.iter().map(move |value| {
+                            let mut point = point.clone();
+                            point.push(value);
+                            point
+                        })
+                    })
+                    .collect())
+            })
+    }
+
+    /// The names of the swept settings.
+    fn names(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+
+    /// Returns the settings with the values of the given point in place of the swept settings of every channel.
+    ///
+    /// Channels whose mode has no such setting are left unchanged, but each swept setting must be one of some channel.
+    fn apply(
+        &self,
+        settings: &DetectorSettings,
+        point: &[&Value],
+    ) -> Result<DetectorSettings, BenchmarkError> {
+        let mut applied = vec![false; point.len()];
+        let settings = settings.try_map(|channel_settings| {
+            let mut json = serde_json::to_value(channel_settings)?;
+            if let Some(object) = json.as_object_mut() {
+                for ((name, &value), applied) in self.names().zip(point).zip(applied.iter_mut()) {
+                    if let Some(setting) = object.get_mut(name) {
+                        *setting = value.clone();
+                        *applied = true;
+                    }
+                }
+            }
+            Ok(serde_json::from_value(json)?)
+        })?;
+        match self.names().zip(applied).find(|&(_, applied)| !applied) {
+            Some((name, _)) => Err(BenchmarkError::UnknownSetting(name.to_owned())),
+            None => Ok(settings),
+        }
+    }
+}
+
+/// Pairs true events with detected events whose times differ by no more than the tolerance.
+/// Pairs are chosen in order of increasing time difference, so each event is in at most one pair.
+/// # Parameters
+/// - truth: the true events.
+/// - detected: the events found by the detector.
+/// - tolerance: the maximum time difference, in ns, of a pair.
+fn match_events(
+    truth: &[(Time, Intensity)],
+    detected: &[(Time, Intensity)],
+    tolerance: Real,
+) -> Vec<(usize, usize)> {
+    let mut candidates: Vec<(Real, usize, usize)> = truth
+        .iter()
+        .enumerate()
+        .flat_map(|(t, &(true_time, _))| {
+            detected
+                .iter()
+                .enumerate()
+                .filter_map(move |(d, &(time, _))| {
+                    let difference = (time as Real - true_time as Real).abs();
+                    (difference <= tolerance).then_some((difference, t, d))
+                })
+        })
+        .collect();
+    candidates.sort_by(|(a, _, _), (b, _, _)| a.total_cmp(b));
+
+    let mut true_matched = vec![false; truth.len()];
+    let mut detected_matched = vec![false; detected.len()];
+    let mut matches = Vec::new();
+    for (_, t, d) in candidates {
+        if !true_matched[t] && !detected_matched[d] {
+            true_matched[t] = true;
+            detected_matched[d] = true;
+            matches.push((t, d));
+        }
+    }
+    matches
+}
+
+/// Returns the mean and standard deviation of the values, or `None` if there are none.
+fn mean_and_deviation(values: &[Real]) -> Option<(Real, Real)> {
+    if values.is_empty() {
+        return None;
+    }
+    let mean = values.iter().sum::<Real>() / values.len() as Real;
+    let variance = values
+        .iter()
+        .map(|value| (value - mean).powi(2))
+        .sum::<Real>()
+        / values.len() as Real;
+    Some((mean, variance.sqrt()))
+}
+
+/// The matched events of a channel, or of all channels, accumulated over every frame.
+#[derive(Default, Debug, Clone)]
+struct Matches {
+    true_events: usize,
+    detected_events: usize,
+    /// The time of each matched detected event, less that of its true event, in ns.
+    time_differences: Vec<Real>,
+    /// The intensity of each matched detected event, less that of its true event, relative to that of its true event.
+    amplitude_differences: Vec<Real>,
+}
+
+impl Matches {
+    /// Matches the events of a single channel in a single frame, and accumulates the results.
+    fn add(
+        &mut self,
+        truth: &[(Time, Intensity)],
+        detected: &[(Time, Intensity)],
+        tolerance: Real,
+    ) {
+        self.true_events += truth.len();
+        self.detected_events += detected.len();
+        for (t, d) in match_events(truth, detected, tolerance) {
+            let (true_time, true_intensity) = truth[t];
+            let (time, intensity) = detected[d];
+            self.time_differences.push(time as Real - true_time as Real);
+            if true_intensity != 0 {
+                self.amplitude_differences
+                    .push((intensity as Real - true_intensity as Real) / true_intensity as Real);
+            }
+        }
+    }
+
+    /// Accumulates the results of another channel.
+    fn extend(&mut self, other: &Self) {
+        self.true_events += other.true_events;
+        self.detected_events += other.detected_events;
+        self.time_differences
+            .extend_from_slice(&other.time_differences);
+        self.amplitude_differences
+            .extend_from_slice(&other.amplitude_differences);
+    }
+
+    fn matched_events(&self) -> usize {
+        self.time_differences.len()
+    }
+
+    /// The fraction of the true events which are matched.
+    fn efficiency(&self) -> Option<Real> {
+        (self.true_events > 0).then(|| self.matched_events() as Real / self.true_events as Real)
+    }
+
+    /// The fraction of the detected events which are not matched.
+    fn false_positive_rate(&self) -> Option<Real> {
+        (self.detected_events > 0).then(|| {
+            (self.detected_events - self.matched_events()) as Real / self.detected_events as Real
+        })
+    }
+
+    /// Writes the columns of a row of the results table, following the digitiser id and channel.
+    fn write_columns(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let (time_bias, time_resolution) = mean_and_deviation(&self.time_differences).unzip();
+        let (amplitude_bias, amplitude_resolution) =
+            mean_and_deviation(&self.amplitude_differences).unzip();
+        let columns = [
+            self.efficiency(),
+            self.false_positive_rate(),
+            time_bias,
+            time_resolution,
+            amplitude_bias,
+            amplitude_resolution,
+        ]
+        .map(|column| column.map(|value| value.to_string()).unwrap_or_default());
+        writeln!(
+            writer,
+            "{},{},{},{}",
+            self.true_events,
+            self.detected_events,
+            self.matched_events(),
+            columns.join(",")
+        )
+    }
+}
+
+/// Returns the key of the frame of a message, or `None` if the message has no valid timestamp.
+fn frame_key(digitizer_id: DigitizerId, metadata: FrameMetadataV2) -> Option<FrameKey> {
+    let timestamp = (*metadata.timestamp()?).try_into().ok()?;
+    Some((digitizer_id, metadata.frame_number(), timestamp))
+}
+
+/// Groups the events of an event list message by channel.
+fn channel_events(message: &DigitizerEventListMessage) -> HashMap<Channel, Events> {
+    let mut events = HashMap::<Channel, Events>::new();
+    for ((channel, time), intensity) in message
+        .channel()
+        .into_iter()
+        .flatten()
+        .zip(message.time().into_iter().flatten())
+        .zip(message.voltage().into_iter().flatten())
+    {
+        events.entry(channel).or_default().push((time, intensity));
+    }
+    events
+}
+
+/// Reads every length-prefixed message.
+fn read_messages(reader: &mut impl Read) -> Result<Vec<Vec<u8>>, OfflineError> {
+    let mut messages = Vec::new();
+    while let Some(message) = read_message(reader, messages.len() + 1)? {
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+/// Reads the true events of every frame.
+fn read_truth(
+    reader: &mut impl Read,
+) -> Result<HashMap<FrameKey, HashMap<Channel, Events>>, OfflineError> {
+    let mut truth = HashMap::new();
+    for (number, payload) in (1..).zip(read_messages(reader)?) {
+        let key = digitizer_event_list_message_buffer_has_identifier(&payload)
+            .then(|| root_as_digitizer_event_list_message(&payload).ok())
+            .flatten()
+            .and_then(|message| {
+                Some((
+                    frame_key(message.digitizer_id(), message.metadata())?,
+                    channel_events(&message),
+                ))
+            });
+        match key {
+            Some((key, events)) => {
+                truth.insert(key, events);
+            }
+            None => warn!("True event message {number} is not a valid event list message"),
+        }
+    }
+    Ok(truth)
+}
+
+/// Detects the events of every trace for which the true events are known, and matches them.
+/// # Parameters
+/// - traces: the trace messages, with the true events of their frames.
+/// - tolerance: the maximum time difference, in ns, of a matched pair of events.
+/// - detector_settings: the settings used to detect the events of each channel.
+/// - baseline_estimation: if set, the baseline of each channel is estimated from its trace.
+fn benchmark_point(
+    traces: &[(DigitizerAnalogTraceMessage, &HashMap<Channel, Events>)],
+    tolerance: Real,
+    detector_settings: &DetectorSettings,
+    baseline_estimation: Option<&BaselineEstimation>,
+) -> BTreeMap<(DigitizerId, Channel), Matches> {
+    let mut matches = BTreeMap::<(DigitizerId, Channel), Matches>::new();
+    for (trace, truth) in traces {
+        let sample_time: Real = 1_000_000_000.0 / trace.sample_rate() as Real;
+        for channel_trace in trace.channels().into_iter().flatten() {
+            let channel = channel_trace.channel();
+            let events = find_channel_events(
+                &channel_trace,
+                sample_time,
+                detector_settings.get(trace.digitizer_id(), channel),
+                baseline_estimation,
+                false,
+            );
+            let detected = events
+                .time
+                .into_iter()
+                .zip(events.voltage)
+                .collect::<Events>();
+            matches
+                .entry((trace.digitizer_id(), channel))
+                .or_default()
+                .add(
+                    truth.get(&channel).map(Vec::as_slice).unwrap_or_default(),
+                    &detected,
+                    tolerance,
+                );
+        }
+    }
+    matches
+}
+
+/// Benchmarks the detector with every combination of the swept settings, and writes a table of the results.
+///
+/// The table has a row for each channel, and a row for all channels, at each point of the sweep.
+/// Returns the number of trace messages benchmarked.
+/// # Parameters
+/// - input: the length-prefixed trace messages.
+/// - truth: the length-prefixed event list messages of the true events.
+/// - output: where the results table is written, as CSV.
+/// - sweep: the settings to vary.
+/// - tolerance: the maximum time difference, in ns, of a matched pair of events.
+/// - detector_settings: the settings to which the sweep is applied.
+/// - baseline_estimation: if set, the baseline of each channel is estimated from its trace.
+pub(crate) fn benchmark(
+    input: &mut impl Read,
+    truth: &mut impl Read,
+    output: &mut impl Write,
+    sweep: &Sweep,
+    tolerance: Real,
+    detector_settings: &DetectorSettings,
+    baseline_estimation: Option<&BaselineEstimation>,
+) -> Result<usize, BenchmarkError> {
+    let truth = read_truth(truth)?;
+    let payloads = read_messages(input)?;
+    let traces = (1..)
+        .zip(&payloads)
+        .filter_map(|(number, payload)| {
+            let trace = digitizer_analog_trace_message_buffer_has_identifier(payload)
+                .then(|| root_as_digitizer_analog_trace_message(payload).ok())
+                .flatten();
+            let Some(trace) = trace else {
+                warn!("Message {number} is not a valid trace message");
+                return None;
+            };
+            let truth =
+                frame_key(trace.digitizer_id(), trace.metadata()).and_then(|key| truth.get(&key));
+            if truth.is_none() {
+                warn!("No true events for the frame of trace message {number}");
+            }
+            Some((trace, truth?))
+        })
+        .collect::<Vec<_>>();
+
+    let names = sweep.names().collect::<Vec<_>>();
+    writeln!(
+        output,
+        "{}digitizer-id,channel,true-events,detected-events,matched-events,efficiency,false-positive-rate,time-bias,time-resolution,amplitude-bias,amplitude-resolution",
+        names
+            .iter()
+            .map(|name| format!("{name},"))
+            .collect::<String>()
+    )?;
+
+    for point in sweep.points()? {
+        let matches = benchmark_point(
+            &traces,
+            tolerance,
+            &sweep.apply(detector_settings, &point)?,
+            baseline_estimation,
+        );
+
+        let values = point
+            .iter()
+            .map(|value| format!("{value},"))
+            .collect::<String>();
+        let mut total = Matches::default();
+        for ((digitizer_id, channel), matches) in &matches {
+            write!(output, "{values}{digitizer_id},{channel},")?;
+            matches.write_columns(output)?;
+            total.extend(matches);
+        }
+        write!(output, "{values}all,all,")?;
+        total.write_columns(output)?;
+    }
+    Ok(traces.len())
+}
+
+/// Benchmarks the detector against files of traces and true events, see [benchmark].
+/// # Parameters
+/// - input_path: the file of trace messages.
+/// - truth_path: the file of event list messages of the true events.
+/// - benchmark_path: the file the results table is written to.
+/// - sweep: the settings to vary.
+/// - tolerance: the maximum time difference, in ns, of a matched pair of events.
+/// - detector_settings: the settings to which the sweep is applied.
+/// - baseline_estimation: if set, the baseline of each channel is estimated from its trace.
+pub(crate) fn run(
+    input_path: &Path,
+    truth_path: &Path,
+    benchmark_path: &Path,
+    sweep: &Sweep,
+    tolerance: Real,
+    detector_settings: &DetectorSettings,
+    baseline_estimation: Option<&BaselineEstimation>,
+) -> Result<(), BenchmarkError> {
+    let mut input = BufReader::new(File::open(input_path)?);
+    let mut truth = BufReader::new(File::open(truth_path)?);
+    let mut output = BufWriter::new(File::create(benchmark_path)?);
+
+    let traces = benchmark(
+        &mut input,
+        &mut truth,
+        &mut output,
+        sweep,
+        tolerance,
+        detector_settings,
+        baseline_estimation,
+    )?;
+    output.flush()?;
+    info!(
+        "Benchmarked {traces} trace messages at {} points",
+        sweep.points()?.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::offline::write_message;
+    use crate::parameters::Mode;
+    use crate::test_fixtures::{create_metadata, detector_settings, timestamp, trace_message};
+    use digital_muon_streaming_types::{
+        dev2_digitizer_event_v2_generated::{
+            DigitizerEventListMessageArgs, finish_digitizer_event_list_message_buffer,
+        },
+        flatbuffers::FlatBufferBuilder,
+    };
+
+    fn create_event_list_message(
+        frame_number: u32,
+        events: &[(Channel, Time, Intensity)],
+    ) -> Vec<u8> {
+        let mut fbb = FlatBufferBuilder::new();
+        let metadata = create_metadata(&mut fbb, frame_number, &timestamp());
+        let channel = Some(fbb.create_vector(&events.iter().map(|e| e.0).collect::<Vec<_>>()));
+        let time = Some(fbb.create_vector(&events.iter().map(|e| e.1).collect::<Vec<_>>()));
+        let voltage = Some(fbb.create_vector(&events.iter().map(|e| e.2).collect::<Vec<_>>()));
+        let message = DigitizerEventListMessageArgs {
+            digitizer_id: 2,
+            metadata: Some(metadata),
+            channel,
+            time,
+            voltage,
+            ..Default::default()
+        };
+        let message = DigitizerEventListMessage::create(&mut fbb, &message);
+        finish_digitizer_event_list_message_buffer(&mut fbb, message);
+        fbb.finished_data().to_vec()
+    }
+
+    #[test]
+    fn nearest_events_matched_first() {
+        let truth = [(10, 5), (20, 5)];
+        let detected = [(19, 5), (12, 5), (40, 5)];
+        let mut matches = match_events(&truth, &detected, 3.0);
+        matches.sort();
+        assert_eq!(matches, [(0, 1), (1, 0)]);
+
+        // Both detected events are within the tolerance, but only the nearest is matched.
+        assert_eq!(match_events(&[(10, 5)], &[(8, 5), (11, 5)], 3.0), [(0, 1)]);
+        assert!(match_events(&[(10, 5)], &[(14, 5)], 3.0).is_empty());
+    }
+
+    #[test]
+    fn sweep_every_combination() {
+        let sweep: Sweep =
+            serde_json::from_str(r#"{ "threshold": [5, 10, 15], "duration": [1, 2] }"#).unwrap();
+        assert_eq!(sweep.names().collect::<Vec<_>>(), ["duration", "threshold"]);
+
+        let points = sweep.points().unwrap();
+        assert_eq!(points.len(), 6);
+        assert_eq!(points[1], [&Value::from(1), &Value::from(10)]);
+
+        let settings = sweep.apply(&detector_settings(), &points[5]).unwrap();
+        let Mode::FixedThresholdDiscriminator(parameters) = &settings.get(0, 0).mode else {
+            panic!("mode should not be changed");
+        };
+        assert_eq!(parameters.threshold, 15.0);
+        assert_eq!(parameters.duration, 2);
+    }
+
+    #[test]
+    fn sweep_unknown_setting() {
+        let sweep: Sweep = serde_json::from_str(r#"{ "thresold": [5] }"#).unwrap();
+        let points = sweep.points().unwrap();
+        assert!(matches!(
+            sweep.apply(&detector_settings(), &points[0]),
+            Err(BenchmarkError::UnknownSetting(name)) if name == "thresold"
+        ));
+
+        let sweep: Sweep = serde_json::from_str(r#"{ "threshold": [] }"#).unwrap();
+        assert!(matches!(sweep.points(), Err(BenchmarkError::NoValues(_))));
+    }
+
+    #[test]
+    fn sweep_skips_channels_without_setting() {
+        let settings_file = serde_json::from_str(
+            r#"{
+                "channels": [{
+                    "digitizer-id": 2,
+                    "channel": 1,
+                    "polarity": "positive",
+                    "mode": "advanced-muon-detector",
+                    "muon-onset": 0.5,
+                    "muon-fall": -0.01,
+                    "muon-termination": 0.001,
+                    "duration": 0.0
+                }]
+            }"#,
+        )
+        .unwrap();
+        let settings = DetectorSettings::from_settings_file(
+            settings_file,
+            detector_settings().get(0, 0).clone(),
+        )
+        .unwrap();
+        let sweep: Sweep = serde_json::from_str(r#"{ "threshold": [7] }"#).unwrap();
+        let points = sweep.points().unwrap();
+        let swept = sweep.apply(&settings, &points[0]).unwrap();
+        assert!(matches!(
+            &swept.get(2, 0).mode,
+            Mode::FixedThresholdDiscriminator(parameters) if parameters.threshold == 7.0
+        ));
+        assert!(matches!(
+            &swept.get(2, 1).mode,
+            Mode::AdvancedMuonDetector(parameters) if parameters.muon_onset == 0.5
+        ));
+    }
+
+    #[test]
+    fn benchmark_table() {
+        let mut input = Vec::new();
+        write_message(
+            &mut input,
+            &trace_message(2, 1, &[&[0, 8, 0, 8, 0], &[0; 5]]),
+        )
+        .unwrap();
+        // There are no true events for this frame, so it is skipped.
+        write_message(
+            &mut input,
+            &trace_message(2, 2, &[&[0, 8, 0, 8, 0], &[0; 5]]),
+        )
+        .unwrap();
+
+        let mut truth = Vec::new();
+        write_message(
+            &mut truth,
+            &create_event_list_message(1, &[(0, 1, 8), (0, 4, 16), (1, 2, 5)]),
+        )
+        .unwrap();
+
+        let sweep: Sweep = serde_json::from_str(r#"{ "threshold": [5, 9] }"#).unwrap();
+        let mut output = Vec::new();
+        let traces = benchmark(
+            &mut input.as_slice(),
+            &mut truth.as_slice(),
+            &mut output,
+            &sweep,
+            1.0,
+            &detector_settings(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(traces, 1);
+
+        assert_eq!(
+            String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .collect::<Vec<_>>(),
+            [
+                "threshold,digitizer-id,channel,true-events,detected-events,matched-events,efficiency,false-positive-rate,time-bias,time-resolution,amplitude-bias,amplitude-resolution",
+                "5,2,0,2,2,2,1,0,-0.5,0.5,-0.25,0.25",
+                "5,2,1,1,0,0,0,,,,,",
+                "5,all,all,3,2,2,0.6666666666666666,0,-0.5,0.5,-0.25,0.25",
+                "9,2,0,2,0,0,0,,,,,",
+                "9,2,1,1,0,0,0,,,,,",
+                "9,all,all,3,0,0,0,,,,,",
+            ]
+        );
+    }
+}
@@ -0,0 +1,208 @@
+//! Per-channel calibration of the times and intensities of events.
+//!
+//! The calibration table is a JSON file, with a version which is recorded in every event list message.
+use crate::channels::ChannelEvents;
+use digital_muon_common::{Channel, DigitizerId, Intensity, Time};
+use digital_muon_pulse_detection::Real;
+use serde::Deserialize;
+use std::{collections::HashMap, fs::File, path::Path};
+use thiserror::Error;
+
+fn default_gain() -> Real {
+    1.0
+}
+
+/// The calibration of a single channel.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ChannelCalibration {
+    /// Added to the time of each event, in ns.
+    #[serde(default)]
+    time_offset: Real,
+    /// The intensity of each event is multiplied by this.
+    #[serde(default = "default_gain")]
+    gain: Real,
+    /// Added to the intensity of each event, after the gain is applied.
+    #[serde(default)]
+    offset: Real,
+}
+
+impl ChannelCalibration {
+    /// Calibrates the events of a channel in place.
+    /// Times and intensities are rounded, and limited to the range of their types.
+    /// Pulse areas are scaled by the gain, other pulse shape quantities are unchanged.
+    pub(crate) fn apply(&self, events: &mut ChannelEvents) {
+        // Casting from a float to an integer saturates at the bounds of the integer.
+        for time in &mut events.time {
+            *time = (*time as Real + self.time_offset).round() as Time;
+        }
+        for voltage in &mut events.voltage {
+            *voltage = (*voltage as Real * self.gain + self.offset).round() as Intensity;
+        }
+        if let Some(pulse_shapes) = &mut events.pulse_shapes {
+            for area in &mut pulse_shapes.area {
+                *area *= self.gain as f32;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ChannelCalibrationEntry {
+    digitizer_id: DigitizerId,
+    channel: Channel,
+    #[serde(flatten)]
+    calibration: ChannelCalibration,
+}
+
+///
+/// This struct is created from the calibration JSON file.
+///
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct CalibrationFile {
+    version: String,
+    #[serde(default)]
+    channels: Vec<ChannelCalibrationEntry>,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum CalibrationError {
+    #[error("Duplicate calibration for digitiser {0}, channel {1}")]
+    DuplicateChannel(DigitizerId, Channel),
+    #[error("Json Error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("File Error: {0}")]
+    IO(#[from] std::io::Error),
+}
+
+/// The calibration of every channel, keyed by digitiser id and channel.
+/// Channels which are not listed are not calibrated.
+#[derive(Debug, Clone)]
+pub(crate) struct Calibration {
+    version: String,
+    channels: HashMap<(DigitizerId, Channel), ChannelCalibration>,
+}
+
+impl Calibration {
+    /// Creates the calibration from a parsed calibration file.
+    pub(crate) fn from_calibration_file(
+        calibration_file: CalibrationFile,
+    ) -> Result<Self, CalibrationError> {
+        let mut channels = HashMap::new();
+        for entry in calibration_file.channels {
+            if channels
+                .insert((entry.digitizer_id, entry.channel), entry.calibration)
+                .is_some()
+            {
+                return Err(CalibrationError::DuplicateChannel(
+                    entry.digitizer_id,
+                    entry.channel,
+                ));
+            }
+        }
+        Ok(Self {
+            version: calibration_file.version,
+            channels,
+        })
+    }
+
+    /// Loads the calibration from a JSON file.
+    pub(crate) fn from_file(path: &Path) -> Result<Self, CalibrationError> {
+        let calibration_file: CalibrationFile = serde_json::from_reader(File::open(path)?)?;
+        Self::from_calibration_file(calibration_file)
+    }
+
+    /// The version of the calibration, as given in the file.
+    pub(crate) fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Returns the calibration of the given channel of the given digitiser, if it is calibrated.
+    pub(crate) fn get(
+        &self,
+        digitizer_id: DigitizerId,
+        channel: Channel,
+    ) -> Option<&ChannelCalibration> {
+        self.channels.get(&(digitizer_id, channel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::PulseShapes;
+
+    const CALIBRATION_JSON: &str = r#"
+    {
+        "version": "2024-06-01",
+        "channels": [
+            { "digitizer-id": 3, "channel": 0, "time-offset": -2.5, "gain": 2.0, "offset": -1.0 },
+            { "digitizer-id": 3, "channel": 1, "time-offset": 7 }
+        ]
+    }
+    "#;
+
+    fn calibration() -> Calibration {
+        Calibration::from_calibration_file(serde_json::from_str(CALIBRATION_JSON).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parse_calibration_file() {
+        let calibration = calibration();
+        assert_eq!(calibration.version(), "2024-06-01");
+        assert_eq!(
+            calibration.get(3, 1),
+            Some(&ChannelCalibration {
+                time_offset: 7.0,
+                gain: 1.0,
+                offset: 0.0,
+            })
+        );
+        assert_eq!(calibration.get(3, 2), None);
+    }
+
+    #[test]
+    fn duplicate_channel_rejected() {
+        let calibration_file = serde_json::from_str(
+            r#"
+            {
+                "version": "1",
+                "channels": [
+                    { "digitizer-id": 1, "channel": 2, "gain": 1.5 },
+                    { "digitizer-id": 1, "channel": 2, "gain": 2.5 }
+                ]
+            }
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            Calibration::from_calibration_file(calibration_file),
+            Err(CalibrationError::DuplicateChannel(1, 2))
+        ));
+    }
+
+    #[test]
+    fn apply_calibration() {
+        let mut events = ChannelEvents {
+            time: vec![1, 10],
+            voltage: vec![8, 40000],
+            pulse_shapes: Some(PulseShapes {
+                width: vec![4, 3],
+                area: vec![10.0, 5.0],
+                rise_time: vec![1, 2],
+                truncated: vec![false, true],
+            }),
+            ..Default::default()
+        };
+        calibration().get(3, 0).unwrap().apply(&mut events);
+
+        // Times before the start of the frame are limited to zero, and intensities to the range of the ADC.
+        assert_eq!(events.time, [0, 8]);
+        assert_eq!(events.voltage, [15, Intensity::MAX]);
+        let pulse_shapes = events.pulse_shapes.unwrap();
+        assert_eq!(pulse_shapes.area, [20.0, 10.0]);
+        assert_eq!(pulse_shapes.width, [4, 3]);
+    }
+}
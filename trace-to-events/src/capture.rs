@@ -0,0 +1,199 @@
+//! Captures every stage of the processing of selected channel traces, so that mis-detections can be diagnosed later.
+//!
+//! Each captured channel trace is written to its own directory of CSV files, see README.md.
+use digital_muon_common::{Channel, DigitizerId, FrameNumber};
+use digital_muon_pulse_detection::{Pulse, Real};
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// The header of the file of assembled pulses, matching the [Display] implementation of [Pulse].
+const PULSES_HEADER: &str = "start_time,start_value,end_time,end_value,peak_time,peak_value,\
+    steepest_rise_time,steepest_rise_value,steepest_rise_difference,\
+    sharpest_fall_time,sharpest_fall_value,sharpest_fall_difference";
+
+/// A single CSV file of a capture.
+#[derive(Debug)]
+struct CaptureFile {
+    name: String,
+    header: &'static str,
+    rows: Vec<String>,
+}
+
+/// The output of each stage of the processing of a single channel trace.
+#[derive(Debug, Default)]
+pub(crate) struct ChannelCapture {
+    /// The output of each stage, in the order they are applied.
+    stages: Vec<CaptureFile>,
+    /// The pulses assembled from the detector's events, if the mode assembles pulses.
+    pulses: Vec<String>,
+}
+
+impl ChannelCapture {
+    /// Records the output of a stage, as rows of the time followed by the CSV representation of the value.
+    /// # Parameters
+    /// - name: the name of the file, without extension.
+    /// - header: the column names.
+    /// - points: the `(time, value)` pairs output by the stage.
+    pub(crate) fn record<V: Display>(
+        &mut self,
+        name: impl Into<String>,
+        header: &'static str,
+        points: impl IntoIterator<Item = (Real, V)>,
+    ) {
+        self.stages.push(CaptureFile {
+            name: name.into(),
+            header,
+            rows: points
+                .into_iter()
+                .map(|(time, value)| format!("{time},{value}"))
+                .collect(),
+        });
+    }
+
+    /// Records a pulse assembled from the detector's events.
+    pub(crate) fn record_pulse(&mut self, pulse: &Pulse) {
+        self.pulses.push(pulse.to_string());
+    }
+
+    fn files(&self) -> impl Iterator<Item = (&str, &'static str, &[String])> {
+        self.stages
+            .iter()
+            .map(|file| (file.name.as_str(), file.header, file.rows.as_slice()))
+            .chain((!self.pulses.is_empty()).then_some((
+                "pulses",
+                PULSES_HEADER,
+                self.pulses.as_slice(),
+            )))
+    }
+}
+
+/// Selects which channel traces are captured, and writes them to the capture directory.
+#[derive(Debug, Clone)]
+pub(crate) struct DebugCapture {
+    /// The directory to which captures are written.
+    pub(crate) directory: PathBuf,
+    /// Only one in this many frames, by frame number, is captured.
+    pub(crate) every: FrameNumber,
+    /// If non-empty, only these channels are captured.
+    pub(crate) channels: Vec<(DigitizerId, Channel)>,
+}
+
+impl DebugCapture {
+    /// Returns `true` if the given channel trace should be captured.
+    pub(crate) fn selects(
+        &self,
+        frame_number: FrameNumber,
+        digitizer_id: DigitizerId,
+        channel: Channel,
+    ) -> bool {
+        frame_number % self.every.max(1) == 0
+            && (self.channels.is_empty() || self.channels.contains(&(digitizer_id, channel)))
+    }
+
+    /// Returns the directory to which the capture of the given channel trace is written.
+    /// # Parameters
+    /// - frame: identifies the frame, by its frame number and timestamp.
+    /// - digitizer_id, channel: identifies the channel.
+    pub(crate) fn path(&self, frame: &str, digitizer_id: DigitizerId, channel: Channel) -> PathBuf {
+        self.directory
+            .join(frame)
+            .join(format!("digitiser-{digitizer_id}_channel-{channel}"))
+    }
+
+    /// Writes each stage of the capture to a CSV file in the given directory, which is created if necessary.
+    pub(crate) fn write(&self, path: &Path, capture: &ChannelCapture) -> std::io::Result<()> {
+        fs::create_dir_all(path)?;
+        for (name, header, rows) in capture.files() {
+            let mut file = BufWriter::new(File::create(path.join(format!("{name}.csv")))?);
+            writeln!(file, "{header}")?;
+            for row in rows {
+                writeln!(file, "{row}")?;
+            }
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a channel to capture, given as `<DIGITIZER_ID>:<CHANNEL>`.
+pub(crate) fn parse_capture_channel(channel: &str) -> Result<(DigitizerId, Channel), String> {
+    let (digitizer_id, channel) = channel
+        .split_once(':')
+        .ok_or_else(|| format!("Expected <DIGITIZER_ID>:<CHANNEL>, found {channel}"))?;
+    Ok((
+        digitizer_id.parse().map_err(|e| format!("{e}"))?,
+        channel.parse().map_err(|e| format!("{e}"))?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use digital_muon_pulse_detection::pulse::TimeValue;
+
+    fn debug_capture(directory: PathBuf) -> DebugCapture {
+        DebugCapture {
+            directory,
+            every: 4,
+            channels: vec![(1, 2), (3, 0)],
+        }
+    }
+
+    #[test]
+    fn channels_selected() {
+        let capture = debug_capture(PathBuf::new());
+        assert!(capture.selects(8, 1, 2));
+        assert!(capture.selects(0, 3, 0));
+        assert!(!capture.selects(9, 1, 2));
+        assert!(!capture.selects(8, 1, 3));
+
+        let capture = DebugCapture {
+            channels: Vec::new(),
+            ..capture
+        };
+        assert!(capture.selects(8, 5, 7));
+    }
+
+    #[test]
+    fn capture_channels_parsed() {
+        assert_eq!(parse_capture_channel("4:7"), Ok((4, 7)));
+        assert!(parse_capture_channel("4").is_err());
+        assert!(parse_capture_channel("256:1").is_err());
+    }
+
+    #[test]
+    fn capture_written_as_csv() {
+        let directory = std::env::temp_dir().join(format!(
+            "trace-to-events-capture-test-{}",
+            std::process::id()
+        ));
+        let debug_capture = debug_capture(directory.clone());
+
+        let mut capture = ChannelCapture::default();
+        capture.record("trace", "time,intensity", [(0.0, 3), (1.0, 8)]);
+        capture.record_pulse(&Pulse {
+            peak: TimeValue {
+                time: 1.0,
+                value: 8.0,
+            }
+            .into(),
+            ..Default::default()
+        });
+
+        let path = debug_capture.path("frame-8", 1, 2);
+        debug_capture.write(&path, &capture).unwrap();
+        assert_eq!(
+            fs::read_to_string(path.join("trace.csv")).unwrap(),
+            "time,intensity\n0,3\n1,8\n"
+        );
+        assert_eq!(
+            fs::read_to_string(path.join("pulses.csv")).unwrap(),
+            format!("{PULSES_HEADER}\n0,0,0,0,1,8,0,0,0,0,0,0\n")
+        );
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -1,71 +1,407 @@
 use crate::{
+    capture::ChannelCapture,
     parameters::{
-        AdvancedMuonDetectorParameters, DetectorSettings,
-        DifferentialThresholdDiscriminatorParameters, FixedThresholdDiscriminatorParameters, Mode,
-        Polarity,
-    },
-    pulse_detection::{
-        AssembleFilter, EventFilter, Real,
-        advanced_muon_detector::{AdvancedMuonAssembler, AdvancedMuonDetector},
-        detectors::differential_threshold_detector::DifferentialThresholdDetector,
-        threshold_detector::{ThresholdDetector, ThresholdDuration},
-        window::{Baseline, FiniteDifferences, SmoothingWindow, WindowFilter},
+        AdvancedMuonDetectorParameters, BaselineEstimation, ChannelDetectorSettings,
+        ConstantFractionDiscriminatorParameters, DifferentialThresholdDiscriminatorParameters,
+        FilterSettings, FixedThresholdDiscriminatorParameters, Mode, Polarity,
+        TemplateFitDetectorParameters,
     },
 };
 use digital_muon_common::{Intensity, Time};
+use digital_muon_pulse_detection::{
+    AssembleFilter, EventFilter, Real,
+    advanced_muon_detector::{AdvancedMuonAssembler, AdvancedMuonDetector},
+    detectors::constant_fraction_discriminator::ConstantFractionDiscriminator,
+    detectors::differential_threshold_detector::DifferentialThresholdDetector,
+    pulse::Pulse,
+    template_fit::{BiexpTemplate, TemplateFitter},
+    threshold_detector::{ThresholdDetector, ThresholdDuration},
+    window::{
+        Baseline, Filter, FiniteDifferences, FirFilter, IirFilter, MatchedFilter, MedianFilter,
+        SmoothingWindow, WindowFilter,
+    },
+};
 use digital_muon_streaming_types::dat2_digitizer_analog_trace_v2_generated::ChannelTrace;
 
+/// The shapes of the pulses found by modes which assemble pulses, one entry per event.
+#[derive(Default, Debug)]
+pub(crate) struct PulseShapes {
+    /// Time from the start to the end of each pulse, in ns.
+    pub(crate) width: Vec<Time>,
+    /// Integral of the baselined trace over each pulse, in intensity ns.
+    pub(crate) area: Vec<f32>,
+    /// Time from the start to the peak of each pulse, in ns.
+    pub(crate) rise_time: Vec<Time>,
+    /// Whether each pulse was cut off by the start or end of the trace, so only the part within it was measured.
+    pub(crate) truncated: Vec<bool>,
+}
+
+impl PulseShapes {
+    fn push(&mut self, width: Real, area: Real, rise_time: Real, truncated: bool) {
+        self.width.push(width as Time);
+        self.area.push(area as f32);
+        self.rise_time.push(rise_time as Time);
+        self.truncated.push(truncated);
+    }
+
+    /// Appends the shapes of a channel's events, or zeros if the channel has none.
+    pub(crate) fn extend(&mut self, other: Option<&PulseShapes>, num_events: usize) {
+        match other {
+            Some(other) => {
+                self.width.extend_from_slice(&other.width);
+                self.area.extend_from_slice(&other.area);
+                self.rise_time.extend_from_slice(&other.rise_time);
+                self.truncated.extend_from_slice(&other.truncated);
+            }
+            None => {
+                self.width.resize(self.width.len() + num_events, 0);
+                self.area.resize(self.area.len() + num_events, 0.0);
+                self.rise_time.resize(self.rise_time.len() + num_events, 0);
+                self.truncated
+                    .resize(self.truncated.len() + num_events, false);
+            }
+        }
+    }
+
+    /// Pushes the shape of a pulse assembled from the baselined `trace`.
+    ///
+    /// A pulse which is cut off by the start or end of the trace is measured from, or to,
+    /// the edge of the trace, and flagged as truncated.
+    fn push_pulse(&mut self, pulse: &Pulse, trace: &[(Real, Real)], sample_time: Real) {
+        let (Some(&(first, _)), Some(&(last, _))) = (trace.first(), trace.last()) else {
+            self.push(0.0, 0.0, 0.0, true);
+            return;
+        };
+        let start = pulse.start.time.unwrap_or(first).clamp(first, last);
+        let end = pulse.end.time.unwrap_or(last).clamp(start, last);
+        let peak = pulse.peak.time.unwrap_or(start).clamp(start, end);
+        let (cut_at_start, cut_at_end) = truncation(pulse, trace);
+        self.push(
+            end - start,
+            pulse_area(trace, start, end, sample_time),
+            peak - start,
+            cut_at_start || cut_at_end,
+        );
+    }
+}
+
+/// Returns whether the pulse is cut off by the start, and by the end, of the trace,
+/// which is the case if the pulse's start or end is unknown, or lies at or beyond the edge of the trace.
+fn truncation(pulse: &Pulse, trace: &[(Real, Real)]) -> (bool, bool) {
+    let first = trace.first().map(|&(time, _)| time);
+    let last = trace.last().map(|&(time, _)| time);
+    (
+        Option::zip(pulse.start.time, first).is_none_or(|(start, first)| start <= first),
+        Option::zip(pulse.end.time, last).is_none_or(|(end, last)| end >= last),
+    )
+}
+
+/// Integrates the trace between `start` and `end` inclusive.
+fn pulse_area(trace: &[(Real, Real)], start: Real, end: Real, sample_time: Real) -> Real {
+    trace
+        .iter()
+        .filter(|(time, _)| (start..=end).contains(time))
+        .map(|(_, value)| value * sample_time)
+        .sum()
+}
+
+/// Returns the limit of the ADC towards which pulses of the given polarity extend.
+fn pulse_limit(polarity: &Polarity) -> Intensity {
+    match polarity {
+        Polarity::Positive => Intensity::MAX,
+        Polarity::Negative => Intensity::MIN,
+    }
+}
+
+/// Returns the fraction of samples of the trace which are at the limit of the ADC towards which pulses extend.
+///
+/// Samples at the opposite limit are not counted, as they are not clipped pulses.
+fn clipping_fraction(trace: &ChannelTrace, polarity: &Polarity) -> Real {
+    let voltage = trace.voltage().unwrap();
+    if voltage.is_empty() {
+        return 0.0;
+    }
+    let limit = pulse_limit(polarity);
+    let clipped = voltage.iter().filter(|&v| v == limit).count();
+    clipped as Real / voltage.len() as Real
+}
+
+/// Builds the filter described by the settings, which have been validated when they were loaded.
+///
+/// Returns `None` if the filter cannot be realised at the trace's sample rate, which is the case when
+/// a Butterworth cutoff is at or above the Nyquist frequency.
+fn build_filter(settings: &FilterSettings, sample_time: Real) -> Option<Filter> {
+    // Cutoff frequencies are given in MHz, and sample times in ns.
+    let sample_rate = 1_000.0 / sample_time;
+    match settings {
+        FilterSettings::Fir { coefficients } => {
+            FirFilter::new(coefficients.clone(), sample_time).map(Filter::Fir)
+        }
+        FilterSettings::ButterworthLowPass { cutoff, order } => {
+            IirFilter::butterworth_low_pass(cutoff / sample_rate, *order).map(Filter::Iir)
+        }
+        FilterSettings::ButterworthHighPass { cutoff, order } => {
+            IirFilter::butterworth_high_pass(cutoff / sample_rate, *order).map(Filter::Iir)
+        }
+        FilterSettings::Median { size } => {
+            MedianFilter::new(*size, sample_time).map(Filter::Median)
+        }
+        FilterSettings::Matched {
+            template_rise,
+            template_decay,
+            length,
+        } => {
+            let template = BiexpTemplate::new(*template_rise, *template_decay)?;
+            let samples: Vec<Real> = (0..*length)
+                .map(|i| template.value(i as Real * sample_time))
+                .collect();
+            MatchedFilter::new(&samples, sample_time).map(Filter::Matched)
+        }
+    }
+}
+
+/// Applies each of the filters in turn to the trace.
+///
+/// A Butterworth filter whose cutoff is at or above the Nyquist frequency of the trace would pass
+/// every frequency present if it is low-pass, so the trace is left unchanged, and none if it is high-pass,
+/// so the trace is zeroed.
+///
+/// If capturing, the output of each filter is recorded.
+fn apply_filters(
+    mut trace: Vec<(Real, Real)>,
+    filters: &[FilterSettings],
+    sample_time: Real,
+    mut capture: Option<&mut ChannelCapture>,
+) -> Vec<(Real, Real)> {
+    for (index, settings) in filters.iter().enumerate() {
+        match build_filter(settings, sample_time) {
+            Some(filter) => trace = trace.into_iter().window(filter).collect(),
+            None => {
+                if let FilterSettings::ButterworthHighPass { .. } = settings {
+                    trace.iter_mut().for_each(|(_, value)| *value = 0.0);
+                }
+            }
+        }
+        if let Some(capture) = capture.as_deref_mut() {
+            capture.record(
+                format!("filter-{}", index + 1),
+                "time,value",
+                trace.iter().copied(),
+            );
+        }
+    }
+    trace
+}
+
+/// The runs of consecutive samples at the limit of the ADC in the direction of the pulses,
+/// each given by the times of its first and last samples.
+struct SaturatedRuns(Vec<(Real, Real)>);
+
+impl SaturatedRuns {
+    fn new(trace: &ChannelTrace, sample_time: Real, polarity: &Polarity) -> Self {
+        let limit = pulse_limit(polarity);
+        let mut runs = Vec::<(Real, Real)>::new();
+        let mut in_run = false;
+        for (i, v) in trace.voltage().unwrap().iter().enumerate() {
+            if v != limit {
+                in_run = false;
+                continue;
+            }
+            let time = i as Real * sample_time;
+            match runs.last_mut() {
+                Some(run) if in_run => run.1 = time,
+                _ => runs.push((time, time)),
+            }
+            in_run = true;
+        }
+        Self(runs)
+    }
+
+    /// Whether any run overlaps the interval from `start` up to, but not including, `end`.
+    fn overlaps(&self, start: Real, end: Real) -> bool {
+        self.0
+            .iter()
+            .any(|&(first, last)| first < end && last >= start)
+    }
+
+    /// Flags the events of a mode which does not assemble pulses.
+    ///
+    /// The pulse of each event is taken to start at the event's time, and to end once the trace,
+    /// having risen above `level`, falls back to it, or at the next event if that is sooner.
+    /// A run is only attributed to the event whose pulse contains it.
+    /// # Parameters
+    /// - times: the times of the events, in increasing order.
+    /// - trace: the baselined trace in which the events were found.
+    /// - level: the value above which the trace is within a pulse, or [None] for the value at the foot of each event's rise.
+    fn flag(&self, times: &[Time], trace: &[(Real, Real)], level: Option<Real>) -> Vec<bool> {
+        let nexts = times
+            .iter()
+            .skip(1)
+            .map(|&time| time as Real)
+            .chain(std::iter::once(Real::INFINITY));
+        times
+            .iter()
+            .zip(nexts)
+            .map(|(&start, next)| {
+                let start = start as Real;
+                let end = pulse_end(trace, start, level).min(next);
+                self.overlaps(start, end)
+            })
+            .collect()
+    }
+
+    /// Whether an assembled pulse contains any saturated samples.
+    fn contains(&self, pulse: &Pulse) -> bool {
+        Option::zip(pulse.start.time, pulse.end.time)
+            .is_some_and(|(start, end)| self.overlaps(start, end))
+    }
+}
+
+/// Returns the time at which a pulse starting at `start` ends, which is the first sample at which the trace,
+/// having risen above `level`, falls back to it, or infinity if it does not before the end of the trace.
+/// If `level` is [None], the value of the sample before `start`, at the foot of the pulse's rise, is used.
+fn pulse_end(trace: &[(Real, Real)], start: Real, level: Option<Real>) -> Real {
+    let index = trace.partition_point(|&(time, _)| time < start);
+    let Some(level) = level.or_else(|| trace.get(index.saturating_sub(1)).map(|&(_, value)| value))
+    else {
+        return Real::INFINITY;
+    };
+    trace[index..]
+        .iter()
+        .skip_while(|&&(_, value)| value <= level)
+        .find(|&&(_, value)| value <= level)
+        .map(|&(time, _)| time)
+        .unwrap_or(Real::INFINITY)
+}
+
+/// The height of an assembled pulse, which is extrapolated if the pulse is saturated and `estimate` is set.
+fn pulse_height(pulse: &Pulse, saturated: bool, estimate: bool, sample_time: Real) -> Intensity {
+    (saturated && estimate)
+        .then(|| pulse.extrapolated_peak(sample_time))
+        .flatten()
+        .or(pulse.peak.value)
+        .unwrap_or_default() as Intensity
+}
+
+/// The events found in a single channel trace.
+#[derive(Default, Debug)]
+pub(crate) struct ChannelEvents {
+    pub(crate) time: Vec<Time>,
+    pub(crate) voltage: Vec<Intensity>,
+    /// The shape of each event's pulse, if the mode assembles pulses.
+    pub(crate) pulse_shapes: Option<PulseShapes>,
+    /// Whether each event's pulse reached the limit of the ADC.
+    pub(crate) saturated: Vec<bool>,
+    /// The number of candidate pulses which were resolved into more than one event.
+    pub(crate) pile_ups: usize,
+    /// The baseline estimated from the trace, if baseline estimation is enabled.
+    pub(crate) estimated_baseline: Option<Real>,
+    /// The fraction of samples of the trace which are at the limit of the ADC towards which pulses extend.
+    pub(crate) clipping_fraction: Real,
+    /// The output of each stage of processing, if the trace is captured.
+    pub(crate) capture: Option<ChannelCapture>,
+}
+
 #[tracing::instrument(skip_all, fields(channel = trace.channel(), num_pulses))]
 pub(crate) fn find_channel_events(
     trace: &ChannelTrace,
     sample_time: Real,
-    detector_settings: &DetectorSettings,
-) -> (Vec<Time>, Vec<Intensity>) {
-    let result = match &detector_settings.mode {
-        Mode::FixedThresholdDiscriminator(parameters) => find_fixed_threshold_events(
-            trace,
-            sample_time,
-            detector_settings.polarity,
-            detector_settings.baseline as Real,
+    detector_settings: &ChannelDetectorSettings,
+    baseline_estimation: Option<&BaselineEstimation>,
+    capture: bool,
+) -> ChannelEvents {
+    let mut capture = capture.then(ChannelCapture::default);
+    if let Some(capture) = &mut capture {
+        capture.record(
+            "trace",
+            "time,intensity",
+            trace
+                .voltage()
+                .unwrap()
+                .into_iter()
+                .enumerate()
+                .map(|(i, v)| (i as Real * sample_time, v)),
+        );
+    }
+
+    let estimated_baseline = baseline_estimation.and_then(|estimation| {
+        estimation.estimator.estimate(
+            trace
+                .voltage()
+                .unwrap()
+                .iter()
+                .take(estimation.length)
+                .map(|v| v as Real),
+        )
+    });
+    let baseline = estimated_baseline.unwrap_or(detector_settings.baseline as Real);
+
+    let sign = match detector_settings.polarity {
+        Polarity::Positive => 1.0,
+        Polarity::Negative => -1.0,
+    };
+    let raw: Vec<(Real, Real)> = trace
+        .voltage()
+        .unwrap()
+        .into_iter()
+        .enumerate()
+        .map(|(i, v)| (i as Real * sample_time, sign * (v as Real - baseline)))
+        .collect();
+    if let Some(capture) = &mut capture {
+        capture.record("baselined", "time,value", raw.iter().copied());
+    }
+    // The filters are applied ahead of every mode.
+    let baselined = apply_filters(
+        raw,
+        &detector_settings.filters,
+        sample_time,
+        capture.as_mut(),
+    );
+    let saturated_runs = SaturatedRuns::new(trace, sample_time, &detector_settings.polarity);
+
+    let mut result = match &detector_settings.mode {
+        Mode::FixedThresholdDiscriminator(parameters) => {
+            find_fixed_threshold_events(&baselined, &saturated_runs, parameters, capture.as_mut())
+        }
+        Mode::DifferentialThresholdDiscriminator(parameters) => find_differential_threshold_events(
+            &baselined,
+            &saturated_runs,
             parameters,
+            capture.as_mut(),
         ),
-        Mode::DifferentialThresholdDiscriminator(parameters) => find_differential_threshold_events(
-            trace,
+        Mode::AdvancedMuonDetector(parameters) => find_advanced_events(
+            &baselined,
+            &saturated_runs,
             sample_time,
-            detector_settings.polarity,
-            detector_settings.baseline as Real,
             parameters,
+            capture.as_mut(),
         ),
-        Mode::AdvancedMuonDetector(parameters) => find_advanced_events(
-            trace,
+        Mode::ConstantFractionDiscriminator(parameters) => {
+            find_constant_fraction_events(&baselined, &saturated_runs, parameters, capture.as_mut())
+        }
+        Mode::TemplateFitDetector(parameters) => find_template_fit_events(
+            &baselined,
+            &saturated_runs,
             sample_time,
-            detector_settings.polarity,
-            detector_settings.baseline as Real,
             parameters,
+            capture.as_mut(),
         ),
     };
-    tracing::Span::current().record("num_pulses", result.0.len());
+    result.estimated_baseline = estimated_baseline;
+    result.clipping_fraction = clipping_fraction(trace, &detector_settings.polarity);
+    result.capture = capture;
+    tracing::Span::current().record("num_pulses", result.time.len());
     result
 }
 
 #[tracing::instrument(skip_all, level = "trace")]
 fn find_fixed_threshold_events(
-    trace: &ChannelTrace,
-    sample_time: Real,
-    polarity: &Polarity,
-    baseline: Real,
+    trace: &[(Real, Real)],
+    saturated_runs: &SaturatedRuns,
     parameters: &FixedThresholdDiscriminatorParameters,
-) -> (Vec<Time>, Vec<Intensity>) {
-    let sign = match polarity {
-        Polarity::Positive => 1.0,
-        Polarity::Negative => -1.0,
-    };
-    let raw = trace
-        .voltage()
-        .unwrap()
-        .into_iter()
-        .enumerate()
-        .map(|(i, v)| (i as Real * sample_time, sign * (v as Real - baseline)));
+    capture: Option<&mut ChannelCapture>,
+) -> ChannelEvents {
+    let raw = trace.iter().copied();
 
     let pulses = raw
         .clone()
@@ -74,94 +410,119 @@ fn find_fixed_threshold_events(
             duration: parameters.duration,
             cool_off: parameters.cool_off,
         }));
+    if let Some(capture) = capture {
+        capture.record("events", "time,pulse_height", pulses.clone());
+    }
 
-    let mut time = Vec::<Time>::new();
-    let mut voltage = Vec::<Intensity>::new();
+    let mut events = ChannelEvents::default();
     for pulse in pulses {
-        time.push(pulse.0 as Time);
-        voltage.push(pulse.1.pulse_height as Intensity);
+        events.time.push(pulse.0 as Time);
+        events.voltage.push(pulse.1.pulse_height as Intensity);
     }
-    (time, voltage)
+    events.saturated = saturated_runs.flag(&events.time, trace, Some(parameters.threshold));
+    events
 }
 
 #[tracing::instrument(skip_all, level = "trace")]
 fn find_differential_threshold_events(
-    trace: &ChannelTrace,
-    sample_time: Real,
-    polarity: &Polarity,
-    baseline: Real,
+    trace: &[(Real, Real)],
+    saturated_runs: &SaturatedRuns,
     parameters: &DifferentialThresholdDiscriminatorParameters,
-) -> (Vec<Time>, Vec<Intensity>) {
-    let sign = match polarity {
-        Polarity::Positive => 1.0,
-        Polarity::Negative => -1.0,
-    };
-    let raw = trace
-        .voltage()
-        .unwrap()
-        .into_iter()
-        .enumerate()
-        .map(|(i, v)| (i as Real * sample_time, sign * (v as Real - baseline)));
+    capture: Option<&mut ChannelCapture>,
+) -> ChannelEvents {
+    let raw = trace.iter().copied();
 
-    let pulses = raw.clone().window(FiniteDifferences::<2>::new()).events(
-        DifferentialThresholdDetector::new(
+    let differences = raw.clone().window(FiniteDifferences::<2>::new());
+    let pulses = differences
+        .clone()
+        .events(DifferentialThresholdDetector::new(
             &ThresholdDuration {
                 threshold: parameters.threshold,
                 duration: parameters.duration,
                 cool_off: parameters.cool_off,
             },
             parameters.constant_multiple,
-        ),
-    );
+        ));
+    if let Some(capture) = capture {
+        capture.record("differences", "time,value,difference", differences);
+        capture.record("events", "time,pulse_height", pulses.clone());
+    }
 
-    let mut time = Vec::<Time>::new();
-    let mut voltage = Vec::<Intensity>::new();
+    let mut events = ChannelEvents::default();
     for pulse in pulses {
-        time.push(pulse.0 as Time);
-        voltage.push(pulse.1.pulse_height as Intensity);
+        events.time.push(pulse.0 as Time);
+        events.voltage.push(pulse.1.pulse_height as Intensity);
     }
-    (time, voltage)
+    // The event is registered on the pulse's rise, so the pulse lasts until the trace returns to the value at its foot.
+    events.saturated = saturated_runs.flag(&events.time, trace, None);
+    events
 }
 
 #[tracing::instrument(skip_all, level = "trace")]
-fn find_advanced_events(
-    trace: &ChannelTrace,
-    sample_time: Real,
-    polarity: &Polarity,
-    baseline: Real,
+fn find_constant_fraction_events(
+    trace: &[(Real, Real)],
+    saturated_runs: &SaturatedRuns,
+    parameters: &ConstantFractionDiscriminatorParameters,
+    capture: Option<&mut ChannelCapture>,
+) -> ChannelEvents {
+    let raw = trace.iter().copied();
+
+    let pulses = raw.clone().events(ConstantFractionDiscriminator::new(
+        parameters.threshold,
+        parameters.fraction,
+        parameters.delay,
+    ));
+    if let Some(capture) = capture {
+        capture.record("events", "time,pulse_height", pulses.clone());
+    }
+
+    let mut events = ChannelEvents::default();
+    for pulse in pulses {
+        // The event time is interpolated between samples, so is rounded rather than truncated.
+        events.time.push(pulse.0.round() as Time);
+        events.voltage.push(pulse.1.pulse_height as Intensity);
+    }
+    events.saturated = saturated_runs.flag(&events.time, trace, Some(parameters.threshold));
+    events
+}
+
+/// Finds the candidate pulses of the advanced muon detector, which are also those to which the template-fit detector fits its template.
+///
+/// Returns the baselined trace, over which the pulses are measured, and the pulses within the amplitude limits.
+fn find_candidate_pulses(
+    trace: &[(Real, Real)],
     parameters: &AdvancedMuonDetectorParameters,
-) -> (Vec<Time>, Vec<Intensity>) {
-    let sign = match polarity {
-        Polarity::Positive => 1.0,
-        Polarity::Negative => -1.0,
-    };
-    let raw = trace
-        .voltage()
-        .unwrap()
-        .into_iter()
-        .enumerate()
-        .map(|(i, v)| (i as Real * sample_time, sign * (v as Real - baseline)));
+    mut capture: Option<&mut ChannelCapture>,
+) -> (Vec<(Real, Real)>, Vec<Pulse>) {
+    let raw = trace.iter().copied();
 
-    let smoothed = raw
-        .clone()
+    // The baselined trace is kept, as the pulse areas are integrated, and templates fitted, over it.
+    let baselined: Vec<(Real, Real)> = raw
         .window(Baseline::new(parameters.baseline_length.unwrap_or(0), 0.1))
+        .collect();
+    let smoothed = baselined
+        .iter()
+        .copied()
         .window(SmoothingWindow::new(
             parameters.smoothing_window_size.unwrap_or(1),
         ))
         .map(|(i, stats)| (i, stats.mean));
 
-    let events = smoothed
-        .clone()
-        .window(FiniteDifferences::<2>::new())
-        .events(AdvancedMuonDetector::new(
-            parameters.muon_onset,
-            parameters.muon_fall,
-            parameters.muon_termination,
-            parameters.duration,
-        ));
+    let differences = smoothed.clone().window(FiniteDifferences::<2>::new());
+    let events = differences.clone().events(AdvancedMuonDetector::new(
+        parameters.muon_onset,
+        parameters.muon_fall,
+        parameters.muon_termination,
+        parameters.duration,
+    ));
+    if let Some(capture) = capture.as_deref_mut() {
+        capture.record("baseline-window", "time,value", baselined.iter().copied());
+        capture.record("smoothed", "time,value", smoothed);
+        capture.record("differences", "time,value,difference", differences);
+        capture.record("events", "time,class,value", events.clone());
+    }
 
-    let pulses = events
-        .clone()
+    let pulses: Vec<Pulse> = events
         .assemble(AdvancedMuonAssembler::default())
         .filter(|pulse| {
             Option::zip(parameters.min_amplitude, pulse.peak.value)
@@ -172,13 +533,171 @@ fn find_advanced_events(
             Option::zip(parameters.max_amplitude, pulse.peak.value)
                 .map(|(max, val)| max >= val)
                 .unwrap_or(true)
-        });
+        })
+        .collect();
+    if let Some(capture) = capture {
+        for pulse in &pulses {
+            capture.record_pulse(pulse);
+        }
+    }
+    (baselined, pulses)
+}
 
-    let mut time = Vec::<Time>::new();
-    let mut voltage = Vec::<Intensity>::new();
+/// Reports a candidate pulse as a single event, as the advanced muon detector does.
+/// # Parameters
+/// - events: the events to which the event is added.
+/// - pulse_shapes: the pulse shapes to which the shape of the pulse is added.
+/// - pulse: the candidate pulse.
+/// - saturated: whether the pulse reached the limit of the ADC.
+/// - baselined: the baselined trace, over which the pulse is measured.
+/// - sample_time: the time between samples, in ns.
+/// - parameters: the settings of the advanced muon detector.
+fn push_candidate_event(
+    events: &mut ChannelEvents,
+    pulse_shapes: &mut PulseShapes,
+    pulse: &Pulse,
+    saturated: bool,
+    baselined: &[(Real, Real)],
+    sample_time: Real,
+    parameters: &AdvancedMuonDetectorParameters,
+) {
+    events
+        .time
+        .push(pulse.steepest_rise.time.unwrap_or_default() as Time);
+    events.voltage.push(pulse_height(
+        pulse,
+        saturated,
+        parameters.estimate_saturated_amplitude,
+        sample_time,
+    ));
+    events.saturated.push(saturated);
+    pulse_shapes.push_pulse(pulse, baselined, sample_time);
+}
+
+#[tracing::instrument(skip_all, level = "trace")]
+fn find_advanced_events(
+    trace: &[(Real, Real)],
+    saturated_runs: &SaturatedRuns,
+    sample_time: Real,
+    parameters: &AdvancedMuonDetectorParameters,
+    capture: Option<&mut ChannelCapture>,
+) -> ChannelEvents {
+    let (baselined, pulses) = find_candidate_pulses(trace, parameters, capture);
+
+    let mut events = ChannelEvents::default();
+    let mut pulse_shapes = PulseShapes::default();
     for pulse in pulses {
-        time.push(pulse.steepest_rise.time.unwrap_or_default() as Time);
-        voltage.push(pulse.peak.value.unwrap_or_default() as Intensity);
+        let saturated = saturated_runs.contains(&pulse);
+        push_candidate_event(
+            &mut events,
+            &mut pulse_shapes,
+            &pulse,
+            saturated,
+            &baselined,
+            sample_time,
+            parameters,
+        );
+    }
+    events.pulse_shapes = Some(pulse_shapes);
+    events
+}
+
+#[tracing::instrument(skip_all, level = "trace")]
+fn find_template_fit_events(
+    trace: &[(Real, Real)],
+    saturated_runs: &SaturatedRuns,
+    sample_time: Real,
+    parameters: &TemplateFitDetectorParameters,
+    capture: Option<&mut ChannelCapture>,
+) -> ChannelEvents {
+    // The template is validated when the settings are loaded.
+    let Some(template) = BiexpTemplate::new(parameters.template_rise, parameters.template_decay)
+    else {
+        return find_advanced_events(
+            trace,
+            saturated_runs,
+            sample_time,
+            &parameters.advanced,
+            capture,
+        );
+    };
+    let fitter = TemplateFitter::new(
+        template.clone(),
+        parameters.residual_threshold,
+        parameters.max_pulses,
+        parameters.onset_search_window,
+        parameters.max_refinement_passes,
+    );
+    let advanced = &parameters.advanced;
+
+    // The template is fitted to the baselined trace, rather than the smoothed trace.
+    let (baselined, pulses) = find_candidate_pulses(trace, advanced, capture);
+
+    let mut events = ChannelEvents::default();
+    let mut pulse_shapes = PulseShapes::default();
+    for pulse in pulses {
+        let saturated = saturated_runs.contains(&pulse);
+        let region: Vec<(Real, Real)> = Option::zip(pulse.start.time, pulse.end.time)
+            .map(|(start, end)| {
+                baselined
+                    .iter()
+                    .filter(|(time, _)| (start..=end).contains(time))
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let fitted = fitter.fit_pulses(&region);
+        if fitted.is_empty() {
+            // If the fit fails, the candidate pulse is reported as the advanced muon detector would.
+            push_candidate_event(
+                &mut events,
+                &mut pulse_shapes,
+                &pulse,
+                saturated,
+                &baselined,
+                sample_time,
+                advanced,
+            );
+        } else if fitted.len() == 1 {
+            // The template is also fitted to the clipped samples, so underestimates the amplitude of a saturated pulse.
+            events.time.push(fitted[0].time as Time);
+            events
+                .voltage
+                .push(if saturated && advanced.estimate_saturated_amplitude {
+                    pulse_height(&pulse, true, true, sample_time)
+                } else {
+                    fitted[0].amplitude as Intensity
+                });
+            events.saturated.push(saturated);
+            pulse_shapes.push_pulse(&pulse, &baselined, sample_time);
+        } else {
+            events.pile_ups += 1;
+            // Piled-up pulses cannot be measured individually, so each is given the rise time
+            // and area of its fitted template, and is taken to last until the next onset.
+            // Only the first can be cut off by the start of the trace, and only the last by its end.
+            let region_end = pulse.end.time.unwrap_or_default();
+            let (cut_at_start, cut_at_end) = truncation(&pulse, &baselined);
+            let ends = fitted
+                .iter()
+                .skip(1)
+                .map(|next| next.time)
+                .chain(std::iter::once(region_end));
+            for (index, (fitted_pulse, end)) in fitted.iter().zip(ends).enumerate() {
+                events.time.push(fitted_pulse.time as Time);
+                events.voltage.push(fitted_pulse.amplitude as Intensity);
+                events
+                    .saturated
+                    .push(saturated_runs.overlaps(fitted_pulse.time, end));
+                pulse_shapes.push(
+                    (end - fitted_pulse.time).max(0.0),
+                    fitted_pulse.amplitude * template.area(),
+                    template.peak_time(),
+                    (index == 0 && cut_at_start) || (index + 1 == fitted.len() && cut_at_end),
+                );
+            }
+        }
     }
-    (time, voltage)
+    events.pulse_shapes = Some(pulse_shapes);
+    events
 }
@@ -0,0 +1,288 @@
+//! Runtime reconfiguration of the detector settings via the Kafka control topic.
+//!
+//! Each message on the control topic is a JSON document in the same format as the
+//! detector settings file. If it is valid, it replaces the active detector settings
+//! before the next trace message is processed. Whether or not it is valid, an acknowledgement
+//! containing the active settings is published to the acknowledgement topic.
+//!
+//! The control topic is read by a consumer of its own, which belongs to no consumer group,
+//! so that every instance of the component receives every control message.
+use crate::parameters::{
+    ChannelDetectorSettings, DetectorSettings, DetectorSettingsError, DetectorSettingsFile,
+    MaskedChannelEntry,
+};
+use const_format::concatcp;
+use digital_muon_common::{
+    Channel, DigitizerId,
+    metrics::{
+        failures::{self, FailureKind},
+        names::{FAILURES, METRIC_NAME_PREFIX},
+    },
+};
+use metrics::{counter, describe_gauge, gauge};
+use rdkafka::{
+    Message,
+    message::BorrowedMessage,
+    producer::{FutureProducer, FutureRecord},
+};
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{error, info, instrument, warn};
+
+pub(crate) const DETECTOR_SETTINGS_INFO_METRIC: &str =
+    concatcp!(METRIC_NAME_PREFIX, "detector_settings_info");
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ControlStatus {
+    Accepted,
+    Rejected,
+}
+
+/// Published to the acknowledgement topic in response to each control message.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ControlAcknowledgement {
+    status: ControlStatus,
+    /// If the control message was rejected, this describes why.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// The settings which are active once the control message has been handled.
+    active_settings: DetectorSettingsFile,
+    /// The channels which were masked automatically, but are not masked in the new settings, so have been unmasked.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    cleared_auto_masks: Vec<MaskedChannelEntry>,
+}
+
+#[derive(Debug, Error)]
+enum ControlError {
+    #[error("Control message has no payload")]
+    NoPayload,
+    #[error("Json Error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Detector Settings Error: {0}")]
+    DetectorSettings(#[from] DetectorSettingsError),
+}
+
+fn parse_control_message(
+    m: &BorrowedMessage,
+    default: &ChannelDetectorSettings,
+) -> Result<DetectorSettings, ControlError> {
+    let payload = m.payload().ok_or(ControlError::NoPayload)?;
+    let settings_file: DetectorSettingsFile = serde_json::from_slice(payload)?;
+    Ok(DetectorSettings::from_settings_file(
+        settings_file,
+        default.clone(),
+    )?)
+}
+
+/// Handles a message from the control topic, replacing `detector_settings` if the message is valid.
+/// # Parameters
+/// - producer: used to publish the acknowledgement.
+/// - acknowledgement_topic: the topic to publish the acknowledgement to.
+/// - default: settings to use for unlisted channels, if the message does not specify its own default.
+/// - detector_settings: the currently active settings.
+/// - m: the control message.
+#[instrument(skip_all, level = "debug", fields(status))]
+pub(crate) fn process_control_message(
+    producer: &FutureProducer,
+    acknowledgement_topic: &str,
+    default: &ChannelDetectorSettings,
+    detector_settings: &mut DetectorSettings,
+    m: &BorrowedMessage,
+) {
+    let mut cleared_auto_masks = Vec::new();
+    let error = match parse_control_message(m, default) {
+        Ok(new_settings) => {
+            info!("New detector settings: {new_settings:?}");
+            cleared_auto_masks = detector_settings.auto_masks_cleared_by(&new_settings);
+            if !cleared_auto_masks.is_empty() {
+                warn!(
+                    "Automatically masked channels unmasked by new settings: {cleared_auto_masks:?}"
+                );
+            }
+            detector_settings_info_metric(detector_settings, false);
+            masked_channels_metric(detector_settings, false);
+            *detector_settings = new_settings;
+            detector_settings_info_metric(detector_settings, true);
+            masked_channels_metric(detector_settings, true);
+            None
+        }
+        Err(e) => {
+            warn!("Rejected detector settings: {e}");
+            counter!(
+                FAILURES,
+                &[failures::get_label(FailureKind::UnableToDecodeMessage)]
+            )
+            .increment(1);
+            Some(e.to_string())
+        }
+    };
+
+    let acknowledgement = ControlAcknowledgement {
+        status: if error.is_none() {
+            ControlStatus::Accepted
+        } else {
+            ControlStatus::Rejected
+        },
+        error,
+        active_settings: detector_settings.to_settings_file(),
+        cleared_auto_masks: cleared_auto_masks
+            .into_iter()
+            .map(MaskedChannelEntry::from)
+            .collect(),
+    };
+    tracing::Span::current().record("status", format!("{:?}", acknowledgement.status));
+
+    let payload = match serde_json::to_vec(&acknowledgement) {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!("Failed to serialise acknowledgement: {e}");
+            return;
+        }
+    };
+
+    let key = m.key().unwrap_or(b"Detector Settings");
+    match producer.send_result(
+        FutureRecord::to(acknowledgement_topic)
+            .payload(&payload)
+            .key(key),
+    ) {
+        Ok(future) => {
+            tokio::spawn(async move {
+                if let Ok(Err((e, _))) = future.await {
+                    error!("Failed to publish acknowledgement: {e}");
+                    counter!(
+                        FAILURES,
+                        &[failures::get_label(FailureKind::KafkaPublishFailed)]
+                    )
+                    .increment(1);
+                }
+            });
+        }
+        Err((e, _)) => {
+            error!("Failed to publish acknowledgement: {e}");
+            counter!(
+                FAILURES,
+                &[failures::get_label(FailureKind::KafkaPublishFailed)]
+            )
+            .increment(1);
+        }
+    }
+}
+
+fn info_labels(
+    key: Option<(DigitizerId, Channel)>,
+    settings: &ChannelDetectorSettings,
+) -> Result<Vec<(&'static str, String)>, serde_json::Error> {
+    let (digitizer_id, channel) = key
+        .map(|(digitizer_id, channel)| (digitizer_id.to_string(), channel.to_string()))
+        .unwrap_or_else(|| ("default".to_owned(), "default".to_owned()));
+
+    // The mode is tagged by the "mode" field, the remaining fields are its parameters.
+    let mut parameters = serde_json::to_value(&settings.mode)?;
+    let mode = parameters
+        .as_object_mut()
+        .and_then(|parameters| parameters.remove("mode"))
+        .and_then(|mode| mode.as_str().map(ToOwned::to_owned))
+        .unwrap_or_default();
+    let polarity = serde_json::to_value(settings.polarity)?
+        .as_str()
+        .map(ToOwned::to_owned)
+        .unwrap_or_default();
+
+    Ok(vec![
+        ("digitizer_id", digitizer_id),
+        ("channel", channel),
+        ("polarity", polarity),
+        ("baseline", settings.baseline.to_string()),
+        ("mode", mode),
+        ("parameters", parameters.to_string()),
+    ])
+}
+
+/// Exposes the detector settings as labels of an info metric.
+/// # Parameters
+/// - detector_settings: the settings to expose.
+/// - active: whether the settings are active, inactive settings are set to zero.
+pub(crate) fn detector_settings_info_metric(detector_settings: &DetectorSettings, active: bool) {
+    describe_gauge!(
+        DETECTOR_SETTINGS_INFO_METRIC,
+        "The detector settings of each configured channel"
+    );
+
+    for (key, settings) in detector_settings.iter() {
+        match info_labels(key, settings) {
+            Ok(labels) => {
+                gauge!(DETECTOR_SETTINGS_INFO_METRIC, &labels).set(if active { 1 } else { 0 })
+            }
+            Err(e) => warn!("Failed to build detector settings labels: {e}"),
+        }
+    }
+}
+
+/// Exposes each masked channel, and why it is masked, as labels of a gauge.
+/// # Parameters
+/// - detector_settings: the settings whose masked channels are exposed.
+/// - active: whether the settings are active, inactive settings are set to zero.
+pub(crate) fn masked_channels_metric(detector_settings: &DetectorSettings, active: bool) {
+    for ((digitizer_id, channel), reason) in detector_settings.masked() {
+        gauge!(
+            crate::MASKED_CHANNELS_METRIC,
+            &[
+                ("digitizer_id", digitizer_id.to_string()),
+                ("channel", channel.to_string()),
+                ("reason", reason.as_str().to_owned()),
+            ]
+        )
+        .set(if active { 1 } else { 0 });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parameters::{FixedThresholdDiscriminatorParameters, Mode, Polarity};
+
+    #[test]
+    fn info_labels_of_channel() {
+        let settings = ChannelDetectorSettings {
+            polarity: Polarity::Negative,
+            baseline: 100,
+            mode: Mode::FixedThresholdDiscriminator(FixedThresholdDiscriminatorParameters {
+                threshold: 5.0,
+                duration: 2,
+                cool_off: 3,
+            }),
+            filters: Vec::new(),
+        };
+        let labels = info_labels(Some((4, 1)), &settings).unwrap();
+        assert_eq!(
+            labels,
+            vec![
+                ("digitizer_id", "4".to_owned()),
+                ("channel", "1".to_owned()),
+                ("polarity", "negative".to_owned()),
+                ("baseline", "100".to_owned()),
+                ("mode", "fixed-threshold-discriminator".to_owned()),
+                (
+                    "parameters",
+                    r#"{"cool-off":3,"duration":2,"threshold":5.0}"#.to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn info_labels_of_default() {
+        let settings = ChannelDetectorSettings {
+            polarity: Polarity::Positive,
+            baseline: 0,
+            mode: Mode::FixedThresholdDiscriminator(Default::default()),
+            filters: Vec::new(),
+        };
+        let labels = info_labels(None, &settings).unwrap();
+        assert_eq!(labels[0], ("digitizer_id", "default".to_owned()));
+        assert_eq!(labels[1], ("channel", "default".to_owned()));
+    }
+}
@@ -1,25 +1,45 @@
+mod benchmark;
+mod calibration;
+mod capture;
 mod channels;
+mod control;
+mod masking;
+mod offline;
 mod parameters;
 mod processing;
-mod pulse_detection;
-
+mod sampling;
+mod statistics;
+#[cfg(test)]
+mod test_fixtures;
+mod veto;
+
+use benchmark::Sweep;
+use calibration::Calibration;
+use capture::DebugCapture;
 use chrono::{DateTime, Utc};
-use clap::Parser;
+use clap::{ArgGroup, Args, Parser};
 use const_format::concatcp;
 use digital_muon_common::{
-    CommonKafkaOpts, Intensity, init_tracer,
+    Channel, CommonKafkaOpts, DigitizerId, FrameNumber, Intensity,
+    backpressure::{BACKPRESSURE_POLL_INTERVAL, Backpressure, BackpressureOpts},
+    control::{recv_control, replay_control_messages},
+    init_tracer,
     metrics::{
         component_info_metric,
         failures::{self, FailureKind},
         messages_received::{self, MessageKind},
         names::{
-            FAILURES, LAST_MESSAGE_FRAME_NUMBER, LAST_MESSAGE_TIMESTAMP, MESSAGES_PROCESSED,
-            MESSAGES_RECEIVED, METRIC_NAME_PREFIX,
+            ASSIGNED_PARTITIONS, CONSUMER_PAUSED, CONSUMER_PAUSED_TIME, FAILURES,
+            LAST_MESSAGE_FRAME_NUMBER, LAST_MESSAGE_TIMESTAMP, MESSAGES_PROCESSED,
+            MESSAGES_RECEIVED, METRIC_NAME_PREFIX, SEND_QUEUE_DEPTH,
         },
     },
+    partitions::{PARTITION_ASSIGNMENT_POLL_INTERVAL, PartitionAssignment},
     record_metadata_fields_to_span,
     tracer::{FutureRecordTracerExt, OptionalHeaderTracerExt, TracerEngine, TracerOptions},
+    transaction::{KafkaTransactions, TransactionOpts, TransactionalConsumerContext},
 };
+use digital_muon_pulse_detection::{Real, baseline_estimator::BaselineEstimator};
 use digital_muon_streaming_types::{
     FrameMetadata,
     dat2_digitizer_analog_trace_v2_generated::{
@@ -28,17 +48,22 @@ use digital_muon_streaming_types::{
     },
     flatbuffers::{FlatBufferBuilder, InvalidFlatbuffer},
 };
+use masking::{AutoMask, AutoMaskLimits};
 use metrics::{counter, describe_counter, describe_gauge, gauge};
 use metrics_exporter_prometheus::PrometheusBuilder;
 use miette::IntoDiagnostic;
-use parameters::{DetectorSettings, Mode, Polarity};
+use parameters::{
+    BaselineEstimate, BaselineEstimation, ChannelDetectorSettings, DetectorSettings, Mode, Polarity,
+};
+use processing::TraceProcessor;
 use rdkafka::{
     Message,
     consumer::{CommitMode, Consumer},
-    message::{BorrowedHeaders, BorrowedMessage},
+    message::BorrowedMessage,
     producer::{DeliveryFuture, FutureProducer, FutureRecord},
 };
-use std::net::SocketAddr;
+use sampling::TraceSampling;
+use std::{net::SocketAddr, path::PathBuf};
 use tokio::{
     select,
     signal::unix::{Signal, SignalKind, signal},
@@ -46,29 +71,73 @@ use tokio::{
     task::JoinHandle,
 };
 use tracing::{debug, error, info, instrument, trace, warn};
+use veto::{VetoAction, VetoPolicy};
 
 type DigitiserEventListToBufferSender = Sender<DeliveryFuture>;
 type TrySendDigitiserEventListError = TrySendError<DeliveryFuture>;
 
 const EVENTS_FOUND_METRIC: &str = concatcp!(METRIC_NAME_PREFIX, "events_found");
+const PILE_UPS_RESOLVED_METRIC: &str = concatcp!(METRIC_NAME_PREFIX, "pile_ups_resolved");
+const SATURATED_EVENTS_METRIC: &str = concatcp!(METRIC_NAME_PREFIX, "saturated_events");
+const ESTIMATED_BASELINE_METRIC: &str = concatcp!(METRIC_NAME_PREFIX, "estimated_baseline");
+const MASKED_CHANNELS_METRIC: &str = concatcp!(METRIC_NAME_PREFIX, "masked_channels");
+const VETOED_FRAMES_METRIC: &str = concatcp!(METRIC_NAME_PREFIX, "vetoed_frames");
+const LIVE_FRACTION_METRIC: &str = concatcp!(METRIC_NAME_PREFIX, "live_fraction");
+const SAMPLED_TRACES_METRIC: &str = concatcp!(METRIC_NAME_PREFIX, "sampled_traces");
 
 #[derive(Debug, Parser)]
 #[clap(author, version = digital_muon_common::version!(), about)]
+#[command(group(ArgGroup::new("source").required(true).args(["broker", "input_file"])))]
+#[command(group(ArgGroup::new("offline_output").args(["output_file", "truth_file"])))]
 struct Cli {
+    // Exactly one of the broker and the input file is given, see the `source` group above.
+    // The arguments of these groups are required unless the input file is given, with which they conflict.
     #[clap(flatten)]
-    common_kafka_options: CommonKafkaOpts,
+    common_kafka_options: Option<CommonKafkaOpts>,
 
-    /// Kafka consumer group
-    #[clap(long)]
-    consumer_group: String,
+    #[clap(flatten)]
+    kafka_topics: Option<KafkaTopics>,
 
-    /// The Kafka topic that trace messages are consumed from
-    #[clap(long)]
-    trace_topic: String,
+    /// If set, trace messages are read from this file instead of from Kafka, see README.md.
+    #[clap(long, conflicts_with_all = ["CommonKafkaOpts", "KafkaTopics"], requires = "offline_output")]
+    input_file: Option<PathBuf>,
 
-    /// Topic to publish digitiser event messages to
-    #[clap(long)]
-    event_topic: String,
+    /// File to write digitiser event messages to when running offline.
+    #[clap(long, requires = "input_file")]
+    output_file: Option<PathBuf>,
+
+    /// If set when running offline, a JSON summary of the events found in each channel is written to this file.
+    #[clap(long, requires = "input_file")]
+    summary_file: Option<PathBuf>,
+
+    /// If set when running offline, the events found are benchmarked against the true events in this file,
+    /// rather than written to the output file, see README.md.
+    #[clap(long, requires_all = ["input_file", "benchmark_file"], conflicts_with = "summary_file")]
+    truth_file: Option<PathBuf>,
+
+    /// File to write the table of benchmark results to.
+    #[clap(long, requires = "truth_file")]
+    benchmark_file: Option<PathBuf>,
+
+    /// If set when benchmarking, a JSON file of values of detector settings, every combination of which is benchmarked, see README.md.
+    #[clap(long, requires = "truth_file")]
+    sweep: Option<PathBuf>,
+
+    /// The maximum difference, in ns, between the times of a detected event and a true event for them to be matched when benchmarking.
+    #[clap(long, default_value = "4")]
+    match_tolerance: Real,
+
+    /// If set, new detector settings are consumed from this topic, see README.md.
+    #[clap(
+        long,
+        requires = "control_acknowledgement_topic",
+        conflicts_with = "input_file"
+    )]
+    control_topic: Option<String>,
+
+    /// Topic to publish acknowledgements of messages on the control topic to.
+    #[clap(long, requires = "control_topic")]
+    control_acknowledgement_topic: Option<String>,
 
     /// Determines whether events should register as positive or negative intensity
     #[clap(long)]
@@ -78,11 +147,97 @@ struct Cli {
     #[clap(long, default_value = "0")]
     baseline: Intensity,
 
+    /// If set, the baseline of every channel is estimated from the start of each trace using this method, instead of using the configured baseline value.
+    #[clap(long)]
+    baseline_estimate: Option<BaselineEstimate>,
+
+    /// Number of samples at the start of each trace from which the baseline is estimated. This portion of the trace should be event free.
+    #[clap(long, default_value = "100")]
+    baseline_estimate_length: usize,
+
+    /// Fraction of the lowest, and of the highest, samples which are discarded when estimating the baseline with a trimmed mean.
+    #[clap(long, default_value = "0.1")]
+    baseline_estimate_trim: Real,
+
+    /// Path to a JSON file of per-channel detector settings, see README.md.
+    /// Channels which are not listed in the file use the polarity, baseline and mode given on the command line.
+    #[clap(long)]
+    detector_settings: Option<PathBuf>,
+
+    /// Path to a JSON file of per-channel time and intensity calibrations, see README.md.
+    /// Channels which are not listed in the file are not calibrated.
+    #[clap(long)]
+    calibration: Option<PathBuf>,
+
+    /// If set, a channel is masked once this many events per microsecond (MHz) are found in each of `auto_mask_traces` consecutive traces.
+    #[clap(long)]
+    auto_mask_max_event_rate: Option<Real>,
+
+    /// If set, a channel is masked once more than this fraction of the samples are clipped in each of `auto_mask_traces` consecutive traces.
+    #[clap(long)]
+    auto_mask_max_clipping_fraction: Option<Real>,
+
+    /// The number of consecutive traces which must exceed an auto-mask limit before the channel is masked.
+    #[clap(long, default_value = "100")]
+    auto_mask_traces: usize,
+
+    /// If set, the dead time, in ns, after each event, from which the live fraction and true event rate of each channel are estimated, see README.md.
+    /// Otherwise the cool-off of each channel's detector is used.
+    #[clap(long)]
+    dead_time: Option<Real>,
+
+    /// Frames with any of these veto flags set are dropped. Given in decimal, or in hexadecimal or binary with a `0x` or `0b` prefix.
+    #[clap(long, default_value = "0", value_parser = veto::parse_veto_mask)]
+    drop_veto_mask: u16,
+
+    /// Frames with any of these veto flags set, but none of `drop_veto_mask`, are passed on with their event lists tagged as vetoed.
+    #[clap(long, default_value = "0", value_parser = veto::parse_veto_mask)]
+    tag_veto_mask: u16,
+
+    /// What is done with frames whose running flag is not set.
+    #[clap(long, default_value = "pass")]
+    not_running: VetoAction,
+
+    /// If set, every stage of the processing of the selected channel traces is written to this directory, see README.md.
+    #[clap(long)]
+    capture_dir: Option<PathBuf>,
+
+    /// Only one in this many frames, by frame number, is captured.
+    #[clap(long, default_value = "1", requires = "capture_dir")]
+    capture_every: FrameNumber,
+
+    /// If given, only these channels are captured.
+    /// Can be passed as `--capture-channel 4:0 --capture-channel 4:1 ...` or `--capture-channel=4:0,4:1,...`
+    #[clap(long, value_delimiter = ',', value_parser = capture::parse_capture_channel, requires = "capture_dir")]
+    capture_channel: Vec<(DigitizerId, Channel)>,
+
+    /// If set, the trace messages of a sample of frames are republished to this topic, see README.md.
+    #[clap(long, conflicts_with = "input_file")]
+    trace_sample_topic: Option<String>,
+
+    /// Only the trace messages of one in this many frames, by frame number, are republished.
+    #[clap(long, default_value = "100", requires = "trace_sample_topic")]
+    trace_sample_every: FrameNumber,
+
+    /// Each republished trace is downsampled by averaging each run of this many samples.
+    #[clap(long, default_value = "1", value_parser = clap::value_parser!(u64).range(1..), requires = "trace_sample_topic")]
+    trace_sample_downsampling: u64,
+
+    /// This many of the least significant bits of each republished sample are zeroed, so that the trace compresses better.
+    #[clap(long, default_value = "0", value_parser = clap::value_parser!(u8).range(..16), requires = "trace_sample_topic")]
+    trace_sample_dropped_bits: u8,
+
     /// Size of the send eventlist buffer.
-    /// If this limit is exceeded, the component will exit.
+    /// The consumer is paused whilst the buffer is filled beyond its high-water mark.
     #[clap(long, default_value = "1024")]
     send_eventlist_buffer_size: usize,
 
+    #[clap(flatten)]
+    backpressure_options: BackpressureOpts,
+
+    #[clap(flatten)]
+    transaction_options: TransactionOpts,
+
     /// Endpoint on which OpenMetrics flavour metrics are available
     #[clap(long, env, default_value = "127.0.0.1:9090")]
     observability_address: SocketAddr,
@@ -99,6 +254,72 @@ struct Cli {
     pub(crate) mode: Mode,
 }
 
+/// The Kafka consumer group and topics, which are required unless running offline.
+#[derive(Debug, Args)]
+struct KafkaTopics {
+    /// Kafka consumer group
+    #[clap(long)]
+    consumer_group: String,
+
+    /// The Kafka topic that trace messages are consumed from
+    #[clap(long)]
+    trace_topic: String,
+
+    /// Topic to publish digitiser event messages to
+    #[clap(long)]
+    event_topic: String,
+}
+
+impl Cli {
+    fn baseline_estimation(&self) -> Option<BaselineEstimation> {
+        self.baseline_estimate
+            .map(|baseline_estimate| BaselineEstimation {
+                estimator: match baseline_estimate {
+                    BaselineEstimate::Median => BaselineEstimator::Median,
+                    BaselineEstimate::TrimmedMean => {
+                        BaselineEstimator::TrimmedMean(self.baseline_estimate_trim)
+                    }
+                },
+                length: self.baseline_estimate_length,
+            })
+    }
+
+    fn auto_mask(&self) -> Option<AutoMask> {
+        (self.auto_mask_max_event_rate.is_some() || self.auto_mask_max_clipping_fraction.is_some())
+            .then(|| {
+                AutoMask::new(AutoMaskLimits {
+                    max_event_rate: self.auto_mask_max_event_rate,
+                    max_clipping_fraction: self.auto_mask_max_clipping_fraction,
+                    traces: self.auto_mask_traces,
+                })
+            })
+    }
+
+    fn debug_capture(&self) -> Option<DebugCapture> {
+        self.capture_dir.clone().map(|directory| DebugCapture {
+            directory,
+            every: self.capture_every,
+            channels: self.capture_channel.clone(),
+        })
+    }
+
+    fn trace_sampling(&self) -> Option<TraceSampling> {
+        self.trace_sample_topic.is_some().then(|| TraceSampling {
+            every: self.trace_sample_every,
+            downsampling: self.trace_sample_downsampling,
+            dropped_bits: self.trace_sample_dropped_bits,
+        })
+    }
+
+    fn veto_policy(&self) -> VetoPolicy {
+        VetoPolicy {
+            drop_mask: self.drop_veto_mask,
+            tag_mask: self.tag_veto_mask,
+            not_running: self.not_running,
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> miette::Result<()> {
     let args = Cli::parse();
@@ -108,25 +329,125 @@ async fn main() -> miette::Result<()> {
         args.otel_namespace.clone()
     ));
 
-    let kafka_opts = &args.common_kafka_options;
+    let default_detector_settings = ChannelDetectorSettings {
+        polarity: args.polarity,
+        baseline: args.baseline,
+        mode: args.mode.clone(),
+        filters: Vec::new(),
+    };
+    let detector_settings = match &args.detector_settings {
+        Some(path) => DetectorSettings::from_file(path, default_detector_settings.clone())
+            .into_diagnostic()?,
+        None => DetectorSettings::new(default_detector_settings.clone()),
+    };
+    detector_settings.validate().into_diagnostic()?;
+    debug!("Detector settings: {detector_settings:?}");
+    let calibration = args
+        .calibration
+        .as_deref()
+        .map(Calibration::from_file)
+        .transpose()
+        .into_diagnostic()?;
+    debug!("Calibration: {calibration:?}");
+    let new_trace_processor = |detector_settings| TraceProcessor {
+        baseline_estimation: args.baseline_estimation(),
+        auto_mask: args.auto_mask(),
+        calibration: calibration.clone(),
+        veto_policy: args.veto_policy(),
+        capture: args.debug_capture(),
+        dead_time: args.dead_time,
+        ..TraceProcessor::new(detector_settings)
+    };
+
+    if let Some(input_file) = &args.input_file {
+        if let Some(truth_file) = &args.truth_file {
+            let sweep = args
+                .sweep
+                .as_deref()
+                .map(Sweep::from_file)
+                .transpose()
+                .into_diagnostic()?
+                .unwrap_or_default();
+            return benchmark::run(
+                input_file,
+                truth_file,
+                args.benchmark_file
+                    .as_deref()
+                    .expect("benchmark file should be given with the truth file"),
+                &sweep,
+                args.match_tolerance,
+                &detector_settings,
+                args.baseline_estimation().as_ref(),
+            )
+            .into_diagnostic();
+        }
+        return offline::run(
+            input_file,
+            args.output_file
+                .as_deref()
+                .expect("output file should be given when running offline"),
+            args.summary_file.as_deref(),
+            &mut new_trace_processor(detector_settings),
+        )
+        .into_diagnostic();
+    }
+    let mut trace_processor = new_trace_processor(detector_settings);
+
+    // As the input file is not given, the `source` group requires the broker, and with it the topics.
+    let (Some(kafka_opts), Some(kafka_topics)) = (&args.common_kafka_options, &args.kafka_topics)
+    else {
+        unreachable!("the broker and topics are required unless running offline");
+    };
 
-    let client_config = digital_muon_common::generate_kafka_client_config(
+    let mut client_config = digital_muon_common::generate_kafka_client_config(
         &kafka_opts.broker,
         &kafka_opts.username,
         &kafka_opts.password,
     );
 
-    let producer: FutureProducer = client_config.create().into_diagnostic()?;
+    // Samples are produced outside any transaction, so that a sample which fails to be published cannot fail one.
+    let sample_producer: Option<FutureProducer> = args
+        .trace_sample_topic
+        .is_some()
+        .then(|| client_config.create())
+        .transpose()
+        .into_diagnostic()?;
 
-    let consumer = digital_muon_common::create_default_consumer(
+    let producer = args
+        .transaction_options
+        .create_producer(&mut client_config)
+        .into_diagnostic()?;
+
+    // In transactional mode, the offsets of consumed messages are committed with the event lists produced from them.
+    let transaction = args.transaction_options.transaction();
+
+    let trace_topic = kafka_topics.trace_topic.as_str();
+    let consumer = digital_muon_common::create_consumer_with_context(
         &kafka_opts.broker,
         &kafka_opts.username,
         &kafka_opts.password,
-        &args.consumer_group,
-        Some(&[args.trace_topic.as_str()]),
+        &kafka_topics.consumer_group,
+        Some(&[trace_topic]),
+        TransactionalConsumerContext::new(&producer, transaction.as_ref()),
     )
     .into_diagnostic()?;
 
+    let control = args
+        .control_topic
+        .as_deref()
+        .zip(args.control_acknowledgement_topic.as_deref())
+        .map(|(control_topic, acknowledgement_topic)| {
+            digital_muon_common::create_latest_message_consumer(
+                &kafka_opts.broker,
+                &kafka_opts.username,
+                &kafka_opts.password,
+                control_topic,
+            )
+            .map(|(control_consumer, replayed)| (control_consumer, replayed, acknowledgement_topic))
+        })
+        .transpose()
+        .into_diagnostic()?;
+
     // Install exporter and register metrics
     let builder = PrometheusBuilder::new();
     builder
@@ -162,37 +483,169 @@ async fn main() -> miette::Result<()> {
         metrics::Unit::Count,
         "Number of events found per channel"
     );
+    describe_counter!(
+        PILE_UPS_RESOLVED_METRIC,
+        metrics::Unit::Count,
+        "Number of candidate pulses resolved into more than one event per channel"
+    );
+    describe_counter!(
+        SATURATED_EVENTS_METRIC,
+        metrics::Unit::Count,
+        "Number of events per channel whose pulse reached the limit of the ADC"
+    );
+    describe_gauge!(
+        ESTIMATED_BASELINE_METRIC,
+        "Baseline estimated from the last trace of each channel"
+    );
+    describe_gauge!(
+        LIVE_FRACTION_METRIC,
+        "Fraction of the last trace of each channel during which the detector could register an event"
+    );
+    describe_gauge!(
+        MASKED_CHANNELS_METRIC,
+        "Set to 1 for each masked channel, labelled with the reason it is masked"
+    );
+    describe_counter!(
+        VETOED_FRAMES_METRIC,
+        metrics::Unit::Count,
+        "Number of frames dropped or tagged by the veto policy, per veto reason"
+    );
+    describe_counter!(
+        SAMPLED_TRACES_METRIC,
+        metrics::Unit::Count,
+        "Number of trace messages republished to the trace sample topic, per digitiser"
+    );
+    describe_gauge!(
+        SEND_QUEUE_DEPTH,
+        "Number of event lists waiting to be produced"
+    );
+    describe_gauge!(
+        CONSUMER_PAUSED,
+        "Set to 1 whilst the consumer is paused as the send buffer is full"
+    );
+    describe_counter!(
+        CONSUMER_PAUSED_TIME,
+        metrics::Unit::Milliseconds,
+        "Total time for which the consumer has been paused"
+    );
+    describe_gauge!(
+        ASSIGNED_PARTITIONS,
+        "Set to 1 for each partition assigned to this instance by the consumer group"
+    );
+
+    let mut partition_assignment = PartitionAssignment::default();
+    let mut partition_assignment_interval =
+        tokio::time::interval(PARTITION_ASSIGNMENT_POLL_INTERVAL);
+
+    let mut backpressure =
+        Backpressure::new(&args.backpressure_options, args.send_eventlist_buffer_size)
+            .into_diagnostic()?;
+    let mut backpressure_interval = tokio::time::interval(BACKPRESSURE_POLL_INTERVAL);
 
     let (sender, producer_task_handle) =
         create_producer_task(args.send_eventlist_buffer_size).into_diagnostic()?;
 
+    let transactions = KafkaTransactions::new(&producer, &consumer);
+
     // Is used to await any sigint signals
     let mut sigint = signal(SignalKind::interrupt()).into_diagnostic()?;
 
     component_info_metric("trace-to-events");
+    control::detector_settings_info_metric(&trace_processor.detector_settings, true);
+    control::masked_channels_metric(&trace_processor.detector_settings, true);
+
+    // The latest settings on the control topic are applied before any trace message is processed.
+    if let Some((control_consumer, replayed, acknowledgement_topic)) = &control {
+        replay_control_messages(control_consumer, *replayed, |m| {
+            if let Some(transaction) = &transaction {
+                transaction
+                    .lock()
+                    .unwrap()
+                    .begin(&transactions)
+                    .into_diagnostic()?;
+            }
+            control::process_control_message(
+                &producer,
+                acknowledgement_topic,
+                &default_detector_settings,
+                &mut trace_processor.detector_settings,
+                m,
+            );
+            Ok::<_, miette::Report>(())
+        })
+        .await?;
+    }
 
     loop {
         tokio::select! {
+            msg = recv_control(control.as_ref().map(|(control_consumer, _, _)| control_consumer)) => match msg {
+                Ok(m) => {
+                    if let Some(transaction) = &transaction {
+                        transaction.lock().unwrap().begin(&transactions).into_diagnostic()?;
+                    }
+                    if let Some((_, _, acknowledgement_topic)) = &control {
+                        control::process_control_message(
+                            &producer,
+                            acknowledgement_topic,
+                            &default_detector_settings,
+                            &mut trace_processor.detector_settings,
+                            &m,
+                        );
+                    }
+                }
+                Err(e) => warn!("Kafka error: {}", e)
+            },
             msg = consumer.recv() => match msg {
                 Ok(m) => {
-                    process_kafka_message(
+                    if let Some(transaction) = &transaction {
+                        transaction.lock().unwrap().begin(&transactions).into_diagnostic()?;
+                    }
+                    match process_kafka_message(
                         &tracer,
                         &args,
+                        &kafka_topics.event_topic,
+                        &mut trace_processor,
                         &sender,
                         &producer,
+                        sample_producer.as_ref(),
                         &m,
-                    ).into_diagnostic()?;
-                    consumer.commit_message(&m, CommitMode::Async).unwrap();
+                    ) {
+                        Err(TrySendError::Full(future)) if !args.backpressure_options.exit_on_full_send_buffer => {
+                            // Waits for space in the send buffer.
+                            sender.send(future).await.into_diagnostic()?;
+                        }
+                        result => result.into_diagnostic()?,
+                    }
+                    backpressure.update(&consumer, trace_topic, queue_depth(&sender)).into_diagnostic()?;
+                    match &transaction {
+                        Some(transaction) => transaction.lock().unwrap().set_offset(m.topic(), m.partition(), m.offset() + 1),
+                        None => consumer.commit_message(&m, CommitMode::Async).unwrap(),
+                    }
                 }
                 Err(e) => warn!("Kafka error: {}", e)
             },
+            _ = backpressure_interval.tick() => {
+                backpressure.update(&consumer, trace_topic, queue_depth(&sender)).into_diagnostic()?;
+            }
+            _ = partition_assignment_interval.tick() => {
+                partition_assignment.update(&consumer).into_diagnostic()?;
+            }
             _ = sigint.recv() => {
+                if let Some(transaction) = &transaction {
+                    transaction.lock().unwrap().commit(&transactions).into_diagnostic()?;
+                }
                 //  Wait for the channel to close and
                 //  all pending production tasks to finish
                 producer_task_handle.await.into_diagnostic()?;
                 return Ok(());
             }
         }
+        if let Some(transaction) = &transaction {
+            let mut transaction = transaction.lock().unwrap();
+            if transaction.is_due() {
+                transaction.commit(&transactions).into_diagnostic()?;
+            }
+        }
     }
 }
 
@@ -207,8 +660,11 @@ fn spanned_root_as_digitizer_analog_trace_message(
 fn process_kafka_message(
     tracer: &TracerEngine,
     args: &Cli,
+    event_topic: &str,
+    trace_processor: &mut TraceProcessor,
     sender: &DigitiserEventListToBufferSender,
     producer: &FutureProducer,
+    sample_producer: Option<&FutureProducer>,
     m: &BorrowedMessage,
 ) -> Result<(), TrySendDigitiserEventListError> {
     debug!(
@@ -223,18 +679,17 @@ fn process_kafka_message(
     if let Some(payload) = m.payload() {
         if digitizer_analog_trace_message_buffer_has_identifier(payload) {
             match spanned_root_as_digitizer_analog_trace_message(payload) {
-                Ok(data) => {
-                    let kafka_timestamp_ms = m.timestamp().to_millis().unwrap_or(-1);
-                    process_digitiser_trace_message(
-                        tracer,
-                        m.headers(),
-                        args,
-                        sender,
-                        producer,
-                        kafka_timestamp_ms,
-                        data,
-                    )?
-                }
+                Ok(data) => process_digitiser_trace_message(
+                    tracer,
+                    m,
+                    args,
+                    event_topic,
+                    trace_processor,
+                    sender,
+                    producer,
+                    sample_producer,
+                    data,
+                )?,
                 Err(e) => {
                     warn!("Failed to parse message: {}", e);
                     counter!(
@@ -260,7 +715,7 @@ fn process_kafka_message(
     skip_all,
     fields(
         digitiser_id = message.digitizer_id(),
-        kafka_message_timestamp_ms = kafka_timestamp_ms,
+        kafka_message_timestamp_ms = m.timestamp().to_millis().unwrap_or(-1),
         metadata_timestamp,
         metadata_frame_number,
         metadata_period_number,
@@ -271,11 +726,13 @@ fn process_kafka_message(
 )]
 fn process_digitiser_trace_message(
     tracer: &TracerEngine,
-    headers: Option<&BorrowedHeaders>,
+    m: &BorrowedMessage,
     args: &Cli,
+    event_topic: &str,
+    trace_processor: &mut TraceProcessor,
     sender: &DigitiserEventListToBufferSender,
     producer: &FutureProducer,
-    kafka_timestamp_ms: i64,
+    sample_producer: Option<&FutureProducer>,
     message: DigitizerAnalogTraceMessage,
 ) -> Result<(), TrySendDigitiserEventListError> {
     let did = format!("{}", message.digitizer_id());
@@ -315,7 +772,7 @@ fn process_digitiser_trace_message(
         LAST_MESSAGE_FRAME_NUMBER,
         &[
             messages_received::get_label(MessageKind::Trace),
-            ("digitizer_id", did)
+            ("digitizer_id", did.clone())
         ]
     )
     .set(message.metadata().frame_number() as f64);
@@ -328,22 +785,28 @@ fn process_digitiser_trace_message(
         })
         .ok();
 
-    headers.conditional_extract_to_current_span(tracer.use_otel());
+    m.headers()
+        .conditional_extract_to_current_span(tracer.use_otel());
+
+    let sampling = args
+        .trace_sampling()
+        .filter(|sampling| sampling.selects(message.metadata().frame_number()));
+    if let (Some(topic), Some(sample_producer), Some(sampling)) =
+        (&args.trace_sample_topic, sample_producer, sampling)
+    {
+        republish_trace_message(topic, &sampling, sample_producer, m, &message, &did);
+    }
+
     let mut fbb = FlatBufferBuilder::new();
-    processing::process(
-        &mut fbb,
-        &message,
-        &DetectorSettings {
-            polarity: &args.polarity,
-            baseline: args.baseline,
-            mode: &args.mode,
-        },
-    );
+    if !processing::process(&mut fbb, &message, trace_processor) {
+        return Ok(());
+    }
 
-    let future_record = FutureRecord::to(&args.event_topic)
+    let future_record = FutureRecord::to(event_topic)
         .payload(fbb.finished_data())
         .conditional_inject_current_span_into_headers(tracer.use_otel())
-        .key("Digitiser Events List");
+        // Keyed by digitiser, so that all of a digitiser's event lists are produced to the same partition.
+        .key(did.as_str());
 
     let future = producer.send_result(future_record).expect("Producer sends");
 
@@ -353,7 +816,11 @@ fn process_digitiser_trace_message(
                 error!("Send-Frame Channel Closed");
             }
             TrySendError::Full(_) => {
-                error!("Send-Frame Buffer Full");
+                if args.backpressure_options.exit_on_full_send_buffer {
+                    error!("Send-Frame Buffer Full");
+                } else {
+                    warn!("Send-Frame Buffer Full, waiting for space");
+                }
             }
         }
         Err(e)
@@ -362,6 +829,63 @@ fn process_digitiser_trace_message(
     }
 }
 
+/// Republishes a trace message to the trace sample topic, reducing it first if required.
+///
+/// Samples are for monitoring only, so a failure to publish one is recorded, but otherwise ignored.
+/// # Parameters
+/// - topic: the trace sample topic.
+/// - sampling: how the trace message is reduced.
+/// - producer: a non-transactional producer, so that samples are never part of the open transaction.
+/// - m: the Kafka message containing the trace message.
+/// - message: the trace message.
+/// - did: the digitiser id, by which the sample is keyed.
+fn republish_trace_message(
+    topic: &str,
+    sampling: &TraceSampling,
+    producer: &FutureProducer,
+    m: &BorrowedMessage,
+    message: &DigitizerAnalogTraceMessage,
+    did: &str,
+) {
+    let mut fbb = FlatBufferBuilder::new();
+    let payload = if sampling.is_lossless() {
+        m.payload().unwrap_or_default()
+    } else {
+        sampling.reduce_message(&mut fbb, message);
+        fbb.finished_data()
+    };
+    let future_record = FutureRecord::to(topic).payload(payload).key(did);
+
+    match producer.send_result(future_record) {
+        Ok(future) => {
+            counter!(SAMPLED_TRACES_METRIC, &[("digitizer_id", did.to_owned())]).increment(1);
+            tokio::spawn(async move {
+                if let Ok(Err((e, _))) = future.await {
+                    warn!("Failed to publish trace sample: {e}");
+                    counter!(
+                        FAILURES,
+                        &[failures::get_label(FailureKind::KafkaPublishFailed)]
+                    )
+                    .increment(1);
+                }
+            });
+        }
+        Err((e, _)) => {
+            warn!("Failed to publish trace sample: {e}");
+            counter!(
+                FAILURES,
+                &[failures::get_label(FailureKind::KafkaPublishFailed)]
+            )
+            .increment(1);
+        }
+    }
+}
+
+/// Returns the number of event lists in the send buffer.
+fn queue_depth(sender: &DigitiserEventListToBufferSender) -> usize {
+    sender.max_capacity() - sender.capacity()
+}
+
 // The following functions control the kafka producer thread
 fn create_producer_task(
     send_digitiser_eventlist_buffer_size: usize,
@@ -430,3 +954,65 @@ async fn flush_eventlist(future: DeliveryFuture) -> Option<()> {
     produce_eventlist_to_kafka(future).await;
     Some(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        let mode = ["fixed-threshold-discriminator", "--threshold", "5"];
+        Cli::try_parse_from(
+            ["trace-to-events", "--polarity", "positive"]
+                .iter()
+                .chain(args)
+                .chain(mode.iter()),
+        )
+    }
+
+    #[test]
+    fn kafka_options_required_unless_offline() {
+        let args = parse(&[
+            "--broker",
+            "localhost:9092",
+            "--consumer-group",
+            "group",
+            "--trace-topic",
+            "traces",
+            "--event-topic",
+            "events",
+        ])
+        .unwrap();
+        assert!(args.common_kafka_options.is_some());
+        assert!(args.kafka_topics.is_some());
+
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--broker", "localhost:9092", "--trace-topic", "traces"]).is_err());
+        assert!(
+            parse(&[
+                "--consumer-group",
+                "group",
+                "--trace-topic",
+                "traces",
+                "--event-topic",
+                "events"
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn kafka_options_rejected_offline() {
+        let offline = ["--input-file", "traces.bin", "--output-file", "events.bin"];
+        let args = parse(&offline).unwrap();
+        assert!(args.common_kafka_options.is_none());
+        assert!(args.kafka_topics.is_none());
+
+        for kafka_option in [
+            ["--broker", "localhost:9092"],
+            ["--trace-topic", "traces"],
+            ["--username", "user"],
+        ] {
+            assert!(parse(&[offline.as_slice(), kafka_option.as_slice()].concat()).is_err());
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Automatically masks channels whose traces are consistently noisy or clipped.
+use crate::{channels::ChannelEvents, parameters::MaskReason};
+use digital_muon_common::{Channel, DigitizerId};
+use digital_muon_pulse_detection::Real;
+use std::collections::HashMap;
+
+/// The limits beyond which a channel is automatically masked.
+#[derive(Debug, Clone)]
+pub(crate) struct AutoMaskLimits {
+    /// The maximum event rate of a trace, in events per microsecond (MHz).
+    pub(crate) max_event_rate: Option<Real>,
+    /// The maximum fraction of the samples of a trace which may be at the limit of the ADC towards which pulses extend.
+    pub(crate) max_clipping_fraction: Option<Real>,
+    /// The number of consecutive traces which must exceed a limit before the channel is masked.
+    pub(crate) traces: usize,
+}
+
+impl AutoMaskLimits {
+    /// Returns the limit the trace exceeds, if any.
+    fn exceeded(&self, events: &ChannelEvents, trace_duration: Real) -> Option<MaskReason> {
+        let event_rate = events.time.len() as Real / trace_duration;
+        if self.max_event_rate.is_some_and(|max| event_rate > max) {
+            Some(MaskReason::EventRate)
+        } else if self
+            .max_clipping_fraction
+            .is_some_and(|max| events.clipping_fraction > max)
+        {
+            Some(MaskReason::Clipping)
+        } else {
+            None
+        }
+    }
+}
+
+/// Tracks, for each channel, how many consecutive traces have exceeded the limits.
+#[derive(Debug)]
+pub(crate) struct AutoMask {
+    limits: AutoMaskLimits,
+    exceeded: HashMap<(DigitizerId, Channel), usize>,
+}
+
+impl AutoMask {
+    pub(crate) fn new(limits: AutoMaskLimits) -> Self {
+        Self {
+            limits,
+            exceeded: Default::default(),
+        }
+    }
+
+    /// Records the events found in a channel's trace, returning why the channel should be masked, if it should.
+    /// # Parameters
+    /// - digitizer_id, channel: identifies the channel.
+    /// - events: the events found in the trace.
+    /// - trace_duration: the duration of the trace, in microseconds.
+    pub(crate) fn update(
+        &mut self,
+        digitizer_id: DigitizerId,
+        channel: Channel,
+        events: &ChannelEvents,
+        trace_duration: Real,
+    ) -> Option<MaskReason> {
+        let reason = self.limits.exceeded(events, trace_duration);
+        if reason.is_none() {
+            self.exceeded.remove(&(digitizer_id, channel));
+            return None;
+        }
+
+        let exceeded = self.exceeded.entry((digitizer_id, channel)).or_default();
+        *exceeded += 1;
+        if *exceeded >= self.limits.traces {
+            self.exceeded.remove(&(digitizer_id, channel));
+            reason
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(num_events: usize, clipping_fraction: Real) -> ChannelEvents {
+        ChannelEvents {
+            time: vec![0; num_events],
+            voltage: vec![0; num_events],
+            clipping_fraction,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn masked_after_consecutive_traces() {
+        let mut auto_mask = AutoMask::new(AutoMaskLimits {
+            max_event_rate: Some(2.0),
+            max_clipping_fraction: None,
+            traces: 3,
+        });
+
+        // 5 events in 2us is 2.5 MHz.
+        assert_eq!(auto_mask.update(0, 1, &events(5, 0.0), 2.0), None);
+        assert_eq!(auto_mask.update(0, 1, &events(5, 0.0), 2.0), None);
+        // Other channels are tracked separately.
+        assert_eq!(auto_mask.update(0, 2, &events(5, 0.0), 2.0), None);
+        assert_eq!(
+            auto_mask.update(0, 1, &events(5, 0.0), 2.0),
+            Some(MaskReason::EventRate)
+        );
+    }
+
+    #[test]
+    fn count_reset_by_good_trace() {
+        let mut auto_mask = AutoMask::new(AutoMaskLimits {
+            max_event_rate: None,
+            max_clipping_fraction: Some(0.1),
+            traces: 2,
+        });
+
+        assert_eq!(auto_mask.update(0, 1, &events(0, 0.5), 1.0), None);
+        assert_eq!(auto_mask.update(0, 1, &events(0, 0.05), 1.0), None);
+        assert_eq!(auto_mask.update(0, 1, &events(0, 0.5), 1.0), None);
+        assert_eq!(
+            auto_mask.update(0, 1, &events(0, 0.5), 1.0),
+            Some(MaskReason::Clipping)
+        );
+    }
+
+    #[test]
+    fn no_limits() {
+        let mut auto_mask = AutoMask::new(AutoMaskLimits {
+            max_event_rate: None,
+            max_clipping_fraction: None,
+            traces: 1,
+        });
+        assert_eq!(auto_mask.update(0, 1, &events(1000, 1.0), 1.0), None);
+    }
+}
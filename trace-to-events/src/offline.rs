@@ -0,0 +1,299 @@
+//! Processes trace messages read from a file, rather than consumed from Kafka.
+//!
+//! The input file is a sequence of `dat2` messages, each preceded by its length in bytes
+//! as a little-endian `u32`. The `dev2` messages are written to the output file in the same format.
+use crate::processing::{self, TraceProcessor};
+use digital_muon_common::{Channel, DigitizerId};
+use digital_muon_streaming_types::{
+    dat2_digitizer_analog_trace_v2_generated::{
+        digitizer_analog_trace_message_buffer_has_identifier,
+        root_as_digitizer_analog_trace_message,
+    },
+    dev2_digitizer_event_v2_generated::root_as_digitizer_event_list_message,
+    flatbuffers::FlatBufferBuilder,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+};
+use thiserror::Error;
+use tracing::{info, warn};
+
+#[derive(Debug, Error)]
+pub(crate) enum OfflineError {
+    #[error("IO Error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("Json Error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Message {0} is truncated")]
+    Truncated(usize),
+}
+
+/// The number of events found in a single channel.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ChannelSummary {
+    digitizer_id: DigitizerId,
+    channel: Channel,
+    events: usize,
+}
+
+/// Summarises the events found in a file of trace messages.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct OfflineSummary {
+    /// The number of trace messages processed.
+    messages: usize,
+    /// The number of messages which were not valid trace messages, and were skipped.
+    invalid_messages: usize,
+    /// The number of trace messages whose frames were dropped by the veto policy.
+    dropped_messages: usize,
+    /// The total number of events found.
+    events: usize,
+    /// The events found in each channel, ordered by digitiser id and channel.
+    channels: Vec<ChannelSummary>,
+}
+
+/// Reads the next length-prefixed message, returning `None` at the end of the file.
+/// # Parameters
+/// - reader: the source of the messages.
+/// - number: the position of the message in the file, starting from one, used in errors.
+pub(crate) fn read_message(
+    reader: &mut impl Read,
+    number: usize,
+) -> Result<Option<Vec<u8>>, OfflineError> {
+    let mut length = [0u8; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut message = vec![0u8; u32::from_le_bytes(length) as usize];
+    reader.read_exact(&mut message).map_err(|e| {
+        if e.kind() == ErrorKind::UnexpectedEof {
+            OfflineError::Truncated(number)
+        } else {
+            e.into()
+        }
+    })?;
+    Ok(Some(message))
+}
+
+/// Writes a length-prefixed message.
+pub(crate) fn write_message(writer: &mut impl Write, message: &[u8]) -> std::io::Result<()> {
+    let length = u32::try_from(message.len())
+        .map_err(|_| std::io::Error::new(ErrorKind::InvalidInput, "Message is too long"))?;
+    writer.write_all(&length.to_le_bytes())?;
+    writer.write_all(message)
+}
+
+/// Processes every trace message from `input`, writing the event list messages to `output`.
+/// # Parameters
+/// - input: the length-prefixed trace messages.
+/// - output: where the length-prefixed event list messages are written.
+/// - processor: the settings used to process each channel.
+pub(crate) fn process_messages(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    processor: &mut TraceProcessor,
+) -> Result<OfflineSummary, OfflineError> {
+    let mut summary = OfflineSummary::default();
+    let mut channel_events = BTreeMap::<(DigitizerId, Channel), usize>::new();
+
+    for number in 1.. {
+        let Some(payload) = read_message(input, number)? else {
+            break;
+        };
+
+        let trace = if digitizer_analog_trace_message_buffer_has_identifier(&payload) {
+            root_as_digitizer_analog_trace_message(&payload)
+                .inspect_err(|e| warn!("Failed to parse message {number}: {e}"))
+                .ok()
+        } else {
+            warn!("Message {number} is not a trace message");
+            None
+        };
+        let Some(trace) = trace else {
+            summary.invalid_messages += 1;
+            continue;
+        };
+
+        // Every channel of the trace is included in the summary, even if it has no events.
+        for channel_trace in trace.channels().into_iter().flatten() {
+            channel_events
+                .entry((trace.digitizer_id(), channel_trace.channel()))
+                .or_default();
+        }
+
+        let mut fbb = FlatBufferBuilder::new();
+        if !processing::process(&mut fbb, &trace, processor) {
+            summary.dropped_messages += 1;
+            continue;
+        }
+
+        if let Ok(events) = root_as_digitizer_event_list_message(fbb.finished_data()) {
+            for channel in events.channel().into_iter().flatten() {
+                *channel_events
+                    .entry((events.digitizer_id(), channel))
+                    .or_default() += 1;
+                summary.events += 1;
+            }
+        }
+        write_message(output, fbb.finished_data())?;
+        summary.messages += 1;
+    }
+
+    summary.channels = channel_events
+        .into_iter()
+        .map(|((digitizer_id, channel), events)| ChannelSummary {
+            digitizer_id,
+            channel,
+            events,
+        })
+        .collect();
+    Ok(summary)
+}
+
+/// Processes a file of trace messages, see [process_messages].
+/// # Parameters
+/// - input_path: the file of trace messages.
+/// - output_path: the file the event list messages are written to.
+/// - summary_path: if set, a JSON summary of the events found is written to this file.
+/// - processor: the settings used to process each channel.
+pub(crate) fn run(
+    input_path: &Path,
+    output_path: &Path,
+    summary_path: Option<&Path>,
+    processor: &mut TraceProcessor,
+) -> Result<(), OfflineError> {
+    let mut input = BufReader::new(File::open(input_path)?);
+    let mut output = BufWriter::new(File::create(output_path)?);
+
+    let summary = process_messages(&mut input, &mut output, processor)?;
+    output.flush()?;
+    info!(
+        "Processed {} trace messages ({} invalid, {} dropped), finding {} events",
+        summary.messages, summary.invalid_messages, summary.dropped_messages, summary.events
+    );
+
+    if let Some(summary_path) = summary_path {
+        let mut summary_file = BufWriter::new(File::create(summary_path)?);
+        serde_json::to_writer_pretty(&mut summary_file, &summary)?;
+        summary_file.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{detector_settings, trace_message};
+
+    #[test]
+    fn read_written_messages() {
+        let mut file = Vec::new();
+        write_message(&mut file, &[1, 2, 3]).unwrap();
+        write_message(&mut file, &[]).unwrap();
+        assert_eq!(file[..4], 3u32.to_le_bytes());
+
+        let mut reader = file.as_slice();
+        assert_eq!(read_message(&mut reader, 1).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(read_message(&mut reader, 2).unwrap(), Some(vec![]));
+        assert_eq!(read_message(&mut reader, 3).unwrap(), None);
+    }
+
+    #[test]
+    fn truncated_message() {
+        let mut file = Vec::new();
+        write_message(&mut file, &[1, 2, 3]).unwrap();
+        file.pop();
+
+        assert!(matches!(
+            read_message(&mut file.as_slice(), 1),
+            Err(OfflineError::Truncated(1))
+        ));
+    }
+
+    #[test]
+    fn process_file_of_messages() {
+        let mut input = Vec::new();
+        write_message(
+            &mut input,
+            &trace_message(2, 0, &[&[0, 8, 0, 8, 0], &[0, 0, 0, 0, 0]]),
+        )
+        .unwrap();
+        write_message(&mut input, b"not a trace message").unwrap();
+        write_message(&mut input, &trace_message(1, 0, &[&[0, 0, 9, 0, 0]])).unwrap();
+
+        let mut output = Vec::new();
+        let summary = process_messages(
+            &mut input.as_slice(),
+            &mut output,
+            &mut TraceProcessor::new(detector_settings()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            summary,
+            OfflineSummary {
+                messages: 2,
+                invalid_messages: 1,
+                dropped_messages: 0,
+                events: 3,
+                channels: vec![
+                    ChannelSummary {
+                        digitizer_id: 1,
+                        channel: 0,
+                        events: 1
+                    },
+                    ChannelSummary {
+                        digitizer_id: 2,
+                        channel: 0,
+                        events: 2
+                    },
+                    ChannelSummary {
+                        digitizer_id: 2,
+                        channel: 1,
+                        events: 0
+                    },
+                ],
+            }
+        );
+
+        let mut reader = output.as_slice();
+        let message = read_message(&mut reader, 1).unwrap().unwrap();
+        let message = root_as_digitizer_event_list_message(&message).unwrap();
+        assert_eq!(message.digitizer_id(), 2);
+        assert_eq!(message.time().unwrap().iter().collect::<Vec<_>>(), [1, 3]);
+
+        let message = read_message(&mut reader, 2).unwrap().unwrap();
+        let message = root_as_digitizer_event_list_message(&message).unwrap();
+        assert_eq!(message.digitizer_id(), 1);
+        assert_eq!(message.time().unwrap().iter().collect::<Vec<_>>(), [2]);
+
+        assert_eq!(read_message(&mut reader, 3).unwrap(), None);
+    }
+
+    #[test]
+    fn summary_json() {
+        let summary = OfflineSummary {
+            messages: 1,
+            invalid_messages: 0,
+            dropped_messages: 0,
+            events: 2,
+            channels: vec![ChannelSummary {
+                digitizer_id: 4,
+                channel: 3,
+                events: 2,
+            }],
+        };
+        assert_eq!(
+            serde_json::to_string(&summary).unwrap(),
+            r#"{"messages":1,"invalid-messages":0,"dropped-messages":0,"events":2,"channels":[{"digitizer-id":4,"channel":3,"events":2}]}"#
+        );
+    }
+}
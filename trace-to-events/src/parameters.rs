@@ -1,90 +1,430 @@
-use crate::pulse_detection::Real;
-use clap::{Parser, Subcommand, ValueEnum};
-use digital_muon_common::Intensity;
-
-#[derive(Debug)]
-pub(crate) struct DetectorSettings<'a> {
-    pub(crate) mode: &'a Mode,
-    pub(crate) polarity: &'a Polarity,
+use clap::{Subcommand, ValueEnum};
+use digital_muon_common::{Channel, DigitizerId, Intensity};
+pub(crate) use digital_muon_pulse_detection::parameters::{
+    AdvancedMuonDetectorParameters, ConstantFractionDiscriminatorParameters,
+    DifferentialThresholdDiscriminatorParameters, FixedThresholdDiscriminatorParameters,
+    TemplateFitDetectorParameters,
+};
+use digital_muon_pulse_detection::{
+    Real, baseline_estimator::BaselineEstimator, template_fit::BiexpTemplate,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs::File, path::Path};
+use thiserror::Error;
+
+/// The settings used to detect events on a single channel.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ChannelDetectorSettings {
+    pub(crate) polarity: Polarity,
+    #[serde(default)]
     pub(crate) baseline: Intensity,
+    #[serde(flatten)]
+    pub(crate) mode: Mode,
+    /// Filters applied in turn to the baselined trace, before events are detected.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) filters: Vec<FilterSettings>,
 }
 
-#[derive(Clone, Copy, Debug, ValueEnum)]
-pub(crate) enum Polarity {
-    Positive,
-    Negative,
+fn default_filter_order() -> usize {
+    2
 }
 
-#[derive(Default, Debug, Clone, Parser)]
-pub(crate) struct FixedThresholdDiscriminatorParameters {
-    /// If the detector is armed, an event is registered when the trace passes this value for the given duration.
-    #[clap(long)]
-    pub(crate) threshold: Real,
+/// A digital filter which can be applied to a trace ahead of the detector. See README.md.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(
+    tag = "filter",
+    rename_all = "kebab-case",
+    rename_all_fields = "kebab-case"
+)]
+pub(crate) enum FilterSettings {
+    /// Finite impulse response filter, the first coefficient of which applies to the most recent sample.
+    Fir { coefficients: Vec<Real> },
+    /// Butterworth low-pass filter, with the cutoff frequency in MHz.
+    ButterworthLowPass {
+        cutoff: Real,
+        #[serde(default = "default_filter_order")]
+        order: usize,
+    },
+    /// Butterworth high-pass filter, with the cutoff frequency in MHz.
+    ButterworthHighPass {
+        cutoff: Real,
+        #[serde(default = "default_filter_order")]
+        order: usize,
+    },
+    /// Moving median of the given number of samples, which rejects narrow spikes.
+    Median { size: usize },
+    /// Matched filter for a biexponential pulse, with time constants in ns, sampled over the given number of samples.
+    Matched {
+        template_rise: Real,
+        template_decay: Real,
+        length: usize,
+    },
+}
 
-    /// The duration, in samples, that the trace must exceed the threshold for.
-    #[clap(long, default_value = "1")]
-    pub(crate) duration: i32,
+/// Why the settings of a channel are invalid.
+#[derive(Debug, Error, PartialEq)]
+pub(crate) enum InvalidSettings {
+    #[error("FIR filter has no coefficients")]
+    NoFirCoefficients,
+    #[error("FIR filter coefficients must be finite")]
+    NonFiniteFirCoefficient,
+    #[error("Butterworth filter cutoff {0} MHz must be positive")]
+    ButterworthCutoff(Real),
+    #[error("Butterworth filter order must be positive")]
+    ButterworthOrder,
+    #[error("Median filter size must be positive")]
+    MedianSize,
+    #[error("Matched filter time constants must be positive")]
+    MatchedTemplate,
+    #[error("Matched filter length must be at least 2 samples")]
+    MatchedLength,
+    #[error("Pulse template time constants must be positive")]
+    PulseTemplate,
+}
 
-    /// After an event is registered, the detector disarms for this many samples.
-    #[clap(long, default_value = "0")]
-    pub(crate) cool_off: i32,
+impl FilterSettings {
+    /// Checks the parameters of the filter, other than those which depend on the sample rate of the trace.
+    pub(crate) fn validate(&self) -> Result<(), InvalidSettings> {
+        match self {
+            FilterSettings::Fir { coefficients } => {
+                if coefficients.is_empty() {
+                    return Err(InvalidSettings::NoFirCoefficients);
+                }
+                if !coefficients.iter().all(|c| c.is_finite()) {
+                    return Err(InvalidSettings::NonFiniteFirCoefficient);
+                }
+            }
+            FilterSettings::ButterworthLowPass { cutoff, order }
+            | FilterSettings::ButterworthHighPass { cutoff, order } => {
+                if !(cutoff.is_finite() && *cutoff > 0.0) {
+                    return Err(InvalidSettings::ButterworthCutoff(*cutoff));
+                }
+                if *order == 0 {
+                    return Err(InvalidSettings::ButterworthOrder);
+                }
+            }
+            FilterSettings::Median { size } => {
+                if *size == 0 {
+                    return Err(InvalidSettings::MedianSize);
+                }
+            }
+            FilterSettings::Matched {
+                template_rise,
+                template_decay,
+                length,
+            } => {
+                if BiexpTemplate::new(*template_rise, *template_decay).is_none() {
+                    return Err(InvalidSettings::MatchedTemplate);
+                }
+                // The template is zero at its onset, so a single sample has no norm.
+                if *length < 2 {
+                    return Err(InvalidSettings::MatchedLength);
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
-#[derive(Default, Debug, Clone, Parser)]
-pub(crate) struct DifferentialThresholdDiscriminatorParameters {
-    /// If the detector is armed, an event is registered when the trace passes this value for the given duration.
-    #[clap(long)]
-    pub(crate) threshold: Real,
+impl ChannelDetectorSettings {
+    /// Checks the settings, so that traces can be processed without further validation.
+    pub(crate) fn validate(&self) -> Result<(), InvalidSettings> {
+        if matches!(&self.mode, Mode::TemplateFitDetector(parameters)
+            if BiexpTemplate::new(parameters.template_rise, parameters.template_decay).is_none())
+        {
+            return Err(InvalidSettings::PulseTemplate);
+        }
+        self.filters.iter().try_for_each(FilterSettings::validate)
+    }
+}
 
-    /// The duration, in samples, that the trace must exceed the threshold for.
-    #[clap(long, default_value = "1")]
-    pub(crate) duration: i32,
+/// An entry of the detector settings file, which applies to a single channel of a single digitiser.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ChannelDetectorSettingsEntry {
+    digitizer_id: DigitizerId,
+    channel: Channel,
+    #[serde(flatten)]
+    settings: ChannelDetectorSettings,
+}
 
-    /// After an event is registered, the detector disarms for this many samples.
-    #[clap(long, default_value = "0")]
-    pub(crate) cool_off: i32,
+/// Why a channel is masked.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum MaskReason {
+    /// The channel was masked by the detector settings.
+    #[default]
+    Configured,
+    /// The channel was masked automatically, as its event rate exceeded the limit.
+    EventRate,
+    /// The channel was masked automatically, as its clipping fraction exceeded the limit.
+    Clipping,
+}
 
-    /// If set, the pulse height is the value of the rising edge, scaled by this factor,
-    /// otherwise the maximum trace value is used for the pulse height.
-    pub(crate) constant_multiple: Option<Real>,
+impl MaskReason {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            MaskReason::Configured => "configured",
+            MaskReason::EventRate => "event-rate",
+            MaskReason::Clipping => "clipping",
+        }
+    }
 }
 
-#[derive(Default, Debug, Clone, Parser)]
-pub(crate) struct AdvancedMuonDetectorParameters {
-    /// Differential threshold for detecting muon onset. See README.md.
-    #[clap(long)]
-    pub(crate) muon_onset: Real,
+/// An entry of the masked channels in the detector settings file.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct MaskedChannelEntry {
+    digitizer_id: DigitizerId,
+    channel: Channel,
+    #[serde(default)]
+    reason: MaskReason,
+}
+
+impl From<((DigitizerId, Channel), MaskReason)> for MaskedChannelEntry {
+    fn from(((digitizer_id, channel), reason): ((DigitizerId, Channel), MaskReason)) -> Self {
+        Self {
+            digitizer_id,
+            channel,
+            reason,
+        }
+    }
+}
+
+///
+/// This struct is created from the detector settings JSON file.
+///
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct DetectorSettingsFile {
+    /// If present, replaces the settings given on the command line for all channels not listed in `channels`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default: Option<ChannelDetectorSettings>,
+    #[serde(default)]
+    channels: Vec<ChannelDetectorSettingsEntry>,
+    /// Channels for which no events are found.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    masked: Vec<MaskedChannelEntry>,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum DetectorSettingsError {
+    #[error("Duplicate settings for digitiser {0}, channel {1}")]
+    DuplicateChannel(DigitizerId, Channel),
+    #[error("Invalid default settings: {0}")]
+    InvalidDefault(InvalidSettings),
+    #[error("Invalid settings for digitiser {0}, channel {1}: {2}")]
+    InvalidChannel(DigitizerId, Channel, InvalidSettings),
+    #[error("Json Error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("File Error: {0}")]
+    IO(#[from] std::io::Error),
+}
+
+/// The detector settings of every channel, keyed by digitiser id and channel.
+/// Channels which are not explicitly configured use the default settings.
+/// Masked channels are skipped entirely.
+#[derive(Debug, Clone)]
+pub(crate) struct DetectorSettings {
+    default: ChannelDetectorSettings,
+    channels: HashMap<(DigitizerId, Channel), ChannelDetectorSettings>,
+    masked: HashMap<(DigitizerId, Channel), MaskReason>,
+}
+
+impl DetectorSettings {
+    /// Creates settings which apply `default` to every channel.
+    pub(crate) fn new(default: ChannelDetectorSettings) -> Self {
+        Self {
+            default,
+            channels: Default::default(),
+            masked: Default::default(),
+        }
+    }
+
+    /// Creates settings from a parsed settings file.
+    /// # Parameters
+    /// - settings_file: the parsed file.
+    /// - default: settings to use for unlisted channels, if the file does not specify its own default.
+    pub(crate) fn from_settings_file(
+        settings_file: DetectorSettingsFile,
+        default: ChannelDetectorSettings,
+    ) -> Result<Self, DetectorSettingsError> {
+        let mut channels = HashMap::new();
+        for entry in settings_file.channels {
+            if channels
+                .insert((entry.digitizer_id, entry.channel), entry.settings)
+                .is_some()
+            {
+                return Err(DetectorSettingsError::DuplicateChannel(
+                    entry.digitizer_id,
+                    entry.channel,
+                ));
+            }
+        }
+        let masked = settings_file
+            .masked
+            .into_iter()
+            .map(|entry| ((entry.digitizer_id, entry.channel), entry.reason))
+            .collect();
+        let settings = Self {
+            default: settings_file.default.unwrap_or(default),
+            channels,
+            masked,
+        };
+        settings.validate()?;
+        Ok(settings)
+    }
 
-    /// Differential threshold for detecting muon peak. See README.md.
-    #[clap(long)]
-    pub(crate) muon_fall: Real,
+    /// Checks the default settings, and those of each configured channel.
+    pub(crate) fn validate(&self) -> Result<(), DetectorSettingsError> {
+        for (key, settings) in self.iter() {
+            settings.validate().map_err(|e| match key {
+                Some((digitizer_id, channel)) => {
+                    DetectorSettingsError::InvalidChannel(digitizer_id, channel, e)
+                }
+                None => DetectorSettingsError::InvalidDefault(e),
+            })?;
+        }
+        Ok(())
+    }
 
-    /// Differential threshold for detecting muon termination. See README.md.
-    #[clap(long)]
-    pub(crate) muon_termination: Real,
+    /// Loads settings from a JSON file.
+    /// # Parameters
+    /// - path: location of the settings file.
+    /// - default: settings to use for unlisted channels, if the file does not specify its own default.
+    pub(crate) fn from_file(
+        path: &Path,
+        default: ChannelDetectorSettings,
+    ) -> Result<Self, DetectorSettingsError> {
+        let settings_file: DetectorSettingsFile = serde_json::from_reader(File::open(path)?)?;
+        Self::from_settings_file(settings_file, default)
+    }
 
-    /// Length of time a threshold must be passed to register. See README.md.
-    #[clap(long)]
-    pub(crate) duration: Real,
+    /// Converts the settings back into the form of a settings file, with the channels in order.
+    pub(crate) fn to_settings_file(&self) -> DetectorSettingsFile {
+        let mut channels = self
+            .channels
+            .iter()
+            .map(
+                |(&(digitizer_id, channel), settings)| ChannelDetectorSettingsEntry {
+                    digitizer_id,
+                    channel,
+                    settings: settings.clone(),
+                },
+            )
+            .collect::<Vec<_>>();
+        channels.sort_by_key(|entry| (entry.digitizer_id, entry.channel));
+        let mut masked = self
+            .masked()
+            .map(MaskedChannelEntry::from)
+            .collect::<Vec<_>>();
+        masked.sort_by_key(|entry| (entry.digitizer_id, entry.channel));
+        DetectorSettingsFile {
+            default: Some(self.default.clone()),
+            channels,
+            masked,
+        }
+    }
 
-    /// Size of initial portion of the trace to use for determining the baseline. Initial portion should be event free.
-    #[clap(long)]
-    pub(crate) baseline_length: Option<usize>,
+    /// Iterates over the default settings, followed by the settings of each configured channel.
+    /// The default settings have no digitiser id or channel.
+    pub(crate) fn iter(
+        &self,
+    ) -> impl Iterator<Item = (Option<(DigitizerId, Channel)>, &ChannelDetectorSettings)> {
+        std::iter::once((None, &self.default)).chain(
+            self.channels
+                .iter()
+                .map(|(&key, settings)| (Some(key), settings)),
+        )
+    }
 
-    /// Size of the moving average window to use for the lopass filter.
-    #[clap(long)]
-    pub(crate) smoothing_window_size: Option<usize>,
+    /// Returns the settings to use for the given channel of the given digitiser.
+    pub(crate) fn get(
+        &self,
+        digitizer_id: DigitizerId,
+        channel: Channel,
+    ) -> &ChannelDetectorSettings {
+        self.channels
+            .get(&(digitizer_id, channel))
+            .unwrap_or(&self.default)
+    }
 
-    /// Optional parameter which (if set) filters out events whose peak is greater than the given value.
-    #[clap(long)]
-    pub(crate) max_amplitude: Option<Real>,
+    /// Returns why the given channel of the given digitiser is masked, or `None` if it is not.
+    pub(crate) fn mask_reason(
+        &self,
+        digitizer_id: DigitizerId,
+        channel: Channel,
+    ) -> Option<MaskReason> {
+        self.masked.get(&(digitizer_id, channel)).copied()
+    }
 
-    /// Optional parameter which (if set) filters out events whose peak is less than the given value.
-    #[clap(long)]
-    pub(crate) min_amplitude: Option<Real>,
+    /// Masks the given channel of the given digitiser, if it is not already masked.
+    pub(crate) fn mask(&mut self, digitizer_id: DigitizerId, channel: Channel, reason: MaskReason) {
+        self.masked.entry((digitizer_id, channel)).or_insert(reason);
+    }
+
+    /// Iterates over the masked channels, and why each is masked.
+    pub(crate) fn masked(&self) -> impl Iterator<Item = ((DigitizerId, Channel), MaskReason)> {
+        self.masked.iter().map(|(&key, &reason)| (key, reason))
+    }
+
+    /// Returns the channels which are masked automatically in these settings, but not in `replacement`,
+    /// so are unmasked when these settings are replaced, in order.
+    pub(crate) fn auto_masks_cleared_by(
+        &self,
+        replacement: &Self,
+    ) -> Vec<((DigitizerId, Channel), MaskReason)> {
+        let mut cleared = self
+            .masked()
+            .filter(|(key, reason)| {
+                *reason != MaskReason::Configured && !replacement.masked.contains_key(key)
+            })
+            .collect::<Vec<_>>();
+        cleared.sort_by_key(|&(key, _)| key);
+        cleared
+    }
+
+    /// Returns new settings, in which `f` is applied to the default settings and the settings of each configured channel.
+    pub(crate) fn try_map<E>(
+        &self,
+        mut f: impl FnMut(&ChannelDetectorSettings) -> Result<ChannelDetectorSettings, E>,
+    ) -> Result<Self, E> {
+        Ok(Self {
+            default: f(&self.default)?,
+            channels: self
+                .channels
+                .iter()
+                .map(|(&key, settings)| Ok((key, f(settings)?)))
+                .collect::<Result<_, E>>()?,
+            masked: self.masked.clone(),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Polarity {
+    Positive,
+    Negative,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub(crate) enum BaselineEstimate {
+    Median,
+    TrimmedMean,
+}
+
+/// Settings for estimating the baseline of every channel from the start of each trace.
+#[derive(Debug, Clone)]
+pub(crate) struct BaselineEstimation {
+    pub(crate) estimator: BaselineEstimator,
+    /// The number of samples, at the start of each trace, from which the baseline is estimated.
+    pub(crate) length: usize,
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "mode")]
 pub(crate) enum Mode {
     /// Detects events using a fixed threshold discriminator. Event lists consist of time and voltage values.
     FixedThresholdDiscriminator(FixedThresholdDiscriminatorParameters),
@@ -92,4 +432,120 @@ pub(crate) enum Mode {
     DifferentialThresholdDiscriminator(DifferentialThresholdDiscriminatorParameters),
     /// Detects events using differential discriminators. Event lists consist of time and voltage values.
     AdvancedMuonDetector(AdvancedMuonDetectorParameters),
+    /// Detects events using a constant fraction discriminator, which gives sub-sample timing independent of pulse height. Event lists consist of time and voltage values.
+    ConstantFractionDiscriminator(ConstantFractionDiscriminatorParameters),
+    /// Detects candidate pulses as the advanced muon detector does, then fits a pulse template to each to resolve overlapping pulses. Event lists consist of time and voltage values.
+    TemplateFitDetector(TemplateFitDetectorParameters),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_channel_settings_rejected() {
+        let settings_file: DetectorSettingsFile = serde_json::from_str(
+            r#"
+            {
+                "channels": [
+                    { "digitizer-id": 2, "channel": 3, "polarity": "negative", "mode": "fixed-threshold-discriminator", "threshold": 1 },
+                    { "digitizer-id": 2, "channel": 3, "polarity": "negative", "mode": "fixed-threshold-discriminator", "threshold": 2 }
+                ]
+            }
+            "#,
+        )
+        .unwrap();
+        let default = ChannelDetectorSettings {
+            mode: Mode::FixedThresholdDiscriminator(Default::default()),
+            polarity: Polarity::Positive,
+            baseline: Intensity::default(),
+            filters: Vec::new(),
+        };
+        assert!(matches!(
+            DetectorSettings::from_settings_file(settings_file, default),
+            Err(DetectorSettingsError::DuplicateChannel(2, 3))
+        ));
+    }
+
+    #[test]
+    fn invalid_filter_settings_rejected() {
+        let settings_file: DetectorSettingsFile = serde_json::from_str(
+            r#"
+            {
+                "channels": [
+                    {
+                        "digitizer-id": 2, "channel": 3, "polarity": "negative", "mode": "fixed-threshold-discriminator", "threshold": 1,
+                        "filters": [{ "filter": "median", "size": 3 }, { "filter": "butterworth-low-pass", "cutoff": 0 }]
+                    }
+                ]
+            }
+            "#,
+        )
+        .unwrap();
+        let default = ChannelDetectorSettings {
+            mode: Mode::FixedThresholdDiscriminator(Default::default()),
+            polarity: Polarity::Positive,
+            baseline: Intensity::default(),
+            filters: vec![FilterSettings::Median { size: 0 }],
+        };
+        assert!(matches!(
+            DetectorSettings::new(default.clone()).validate(),
+            Err(DetectorSettingsError::InvalidDefault(
+                InvalidSettings::MedianSize
+            ))
+        ));
+        assert!(matches!(
+            DetectorSettings::from_settings_file(
+                settings_file,
+                ChannelDetectorSettings {
+                    filters: Vec::new(),
+                    ..default
+                }
+            ),
+            Err(DetectorSettingsError::InvalidChannel(
+                2,
+                3,
+                InvalidSettings::ButterworthCutoff(0.0)
+            ))
+        ));
+    }
+
+    #[test]
+    fn auto_masks_cleared_by_new_settings() {
+        let default = ChannelDetectorSettings {
+            mode: Mode::FixedThresholdDiscriminator(Default::default()),
+            polarity: Polarity::Positive,
+            baseline: Intensity::default(),
+            filters: Vec::new(),
+        };
+        let mut settings = DetectorSettings::new(default.clone());
+        settings.mask(0, 1, MaskReason::EventRate);
+        settings.mask(0, 2, MaskReason::Clipping);
+        settings.mask(0, 3, MaskReason::Configured);
+        let mut new_settings = DetectorSettings::new(default);
+        new_settings.mask(0, 2, MaskReason::Configured);
+
+        // Configured masks, and channels masked by the new settings, are not reported.
+        assert_eq!(
+            settings.auto_masks_cleared_by(&new_settings),
+            vec![((0, 1), MaskReason::EventRate)]
+        );
+    }
+
+    #[test]
+    fn invalid_pulse_template_rejected() {
+        // The default time constants are zero.
+        let default = ChannelDetectorSettings {
+            mode: Mode::TemplateFitDetector(Default::default()),
+            polarity: Polarity::Positive,
+            baseline: Intensity::default(),
+            filters: Vec::new(),
+        };
+        assert!(matches!(
+            DetectorSettings::new(default).validate(),
+            Err(DetectorSettingsError::InvalidDefault(
+                InvalidSettings::PulseTemplate
+            ))
+        ));
+    }
 }
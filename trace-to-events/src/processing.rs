@@ -1,8 +1,18 @@
-use crate::{channels::find_channel_events, parameters::DetectorSettings, pulse_detection::Real};
+use crate::{
+    calibration::Calibration,
+    capture::DebugCapture,
+    channels::{PulseShapes, find_channel_events},
+    masking::AutoMask,
+    parameters::{BaselineEstimation, DetectorSettings},
+    statistics::{ChannelStatistics, Statistics, detector_dead_time},
+    veto::{VetoAction, VetoPolicy},
+};
+use chrono::{DateTime, Utc};
 use digital_muon_common::{
     Channel, EventData,
     spanned::{SpanWrapper, Spanned},
 };
+use digital_muon_pulse_detection::Real;
 use digital_muon_streaming_types::{
     dat2_digitizer_analog_trace_v2_generated::DigitizerAnalogTraceMessage,
     dev2_digitizer_event_v2_generated::{
@@ -12,25 +22,102 @@ use digital_muon_streaming_types::{
     flatbuffers::FlatBufferBuilder,
     frame_metadata_v2_generated::{FrameMetadataV2, FrameMetadataV2Args},
 };
-use metrics::counter;
+use metrics::{counter, gauge};
 use rayon::prelude::*;
-use tracing::debug;
+use tracing::{debug, warn};
+
+/// The settings, and state, with which every trace message is processed.
+pub(crate) struct TraceProcessor {
+    pub(crate) detector_settings: DetectorSettings,
+    /// If set, the baseline of each channel is estimated from its trace.
+    pub(crate) baseline_estimation: Option<BaselineEstimation>,
+    /// If set, channels which exceed its limits are masked.
+    pub(crate) auto_mask: Option<AutoMask>,
+    /// If set, the times and intensities of the events of each listed channel are calibrated.
+    pub(crate) calibration: Option<Calibration>,
+    /// Determines which frames are dropped or tagged according to their veto flags and running flag.
+    pub(crate) veto_policy: VetoPolicy,
+    /// If set, every stage of the processing of the selected channel traces is captured.
+    pub(crate) capture: Option<DebugCapture>,
+    /// If set, the dead time, in ns, after each event, otherwise the dead time of each channel's detector is used.
+    pub(crate) dead_time: Option<Real>,
+}
+
+impl TraceProcessor {
+    /// Creates a processor which uses the given settings, and no other options.
+    pub(crate) fn new(detector_settings: DetectorSettings) -> Self {
+        Self {
+            detector_settings,
+            baseline_estimation: None,
+            auto_mask: None,
+            calibration: None,
+            veto_policy: Default::default(),
+            capture: None,
+            dead_time: None,
+        }
+    }
+}
+
+/// Identifies the frame of a trace message by its frame number and, if it is valid, its timestamp.
+fn frame_name(trace: &DigitizerAnalogTraceMessage) -> String {
+    let timestamp: Option<DateTime<Utc>> = trace
+        .metadata()
+        .timestamp()
+        .copied()
+        .and_then(|timestamp| timestamp.try_into().ok());
+    match timestamp {
+        Some(timestamp) => format!(
+            "frame-{}_{}",
+            trace.metadata().frame_number(),
+            timestamp.format("%Y%m%dT%H%M%S%.9fZ")
+        ),
+        None => format!("frame-{}", trace.metadata().frame_number()),
+    }
+}
 
+/// Finds the events of a trace message, and builds the event list message in `fbb`.
+///
+/// Returns `false` if the frame is dropped by the veto policy, in which case nothing is built.
 #[tracing::instrument(skip_all, fields(num_total_pulses = tracing::field::Empty))]
 pub(crate) fn process<'a>(
     fbb: &mut FlatBufferBuilder<'a>,
     trace: &'a DigitizerAnalogTraceMessage,
-    detector_settings: &DetectorSettings,
-) {
+    processor: &mut TraceProcessor,
+) -> bool {
     debug!(
         "Dig ID: {}, Metadata: {:?}",
         trace.digitizer_id(),
         trace.metadata()
     );
 
-    let sample_time_in_ns: Real = 1_000_000_000.0 / trace.sample_rate() as Real;
+    let (veto_action, veto_reasons) = processor
+        .veto_policy
+        .decide(trace.metadata().veto_flags(), trace.metadata().running());
+    for reason in &veto_reasons {
+        counter!(
+            crate::VETOED_FRAMES_METRIC,
+            &[
+                ("digitizer_id", format!("{}", trace.digitizer_id())),
+                ("reason", reason.label()),
+                ("action", veto_action.as_str().to_owned()),
+            ]
+        )
+        .increment(1);
+    }
+    if veto_action == VetoAction::Drop {
+        debug!("Frame dropped by veto policy: {veto_reasons:?}");
+        return false;
+    }
 
-    let vec: Vec<(Channel, _)> = trace
+    let sample_time_in_ns: Real = 1_000_000_000.0 / trace.sample_rate() as Real;
+    let detector_settings = &processor.detector_settings;
+    let baseline_estimation = processor.baseline_estimation.as_ref();
+    let calibration = processor.calibration.as_ref();
+    let debug_capture = processor.capture.as_ref();
+    let frame_number = trace.metadata().frame_number();
+
+    // Masked channels are skipped, so have no events.
+    let vec: Vec<(Channel, usize, Option<_>)> = trace
         .channels()
         .unwrap()
         .iter()
@@ -45,31 +132,129 @@ pub(crate) fn process<'a>(
 
             channel_span.in_scope(|| {
                 let channel = spanned_channel_trace.channel();
-                let events = find_channel_events(
-                    spanned_channel_trace,
-                    sample_time_in_ns,
-                    detector_settings,
-                );
-                (channel, events)
+                let num_samples = spanned_channel_trace
+                    .voltage()
+                    .map(|voltage| voltage.len())
+                    .unwrap_or_default();
+                let events = detector_settings
+                    .mask_reason(trace.digitizer_id(), channel)
+                    .is_none()
+                    .then(|| {
+                        let mut events = find_channel_events(
+                            spanned_channel_trace,
+                            sample_time_in_ns,
+                            detector_settings.get(trace.digitizer_id(), channel),
+                            baseline_estimation,
+                            debug_capture.is_some_and(|debug_capture| {
+                                debug_capture.selects(frame_number, trace.digitizer_id(), channel)
+                            }),
+                        );
+                        if let Some(channel_calibration) = calibration
+                            .and_then(|calibration| calibration.get(trace.digitizer_id(), channel))
+                        {
+                            channel_calibration.apply(&mut events);
+                        }
+                        events
+                    });
+                (channel, num_samples, events)
             })
         })
         .collect();
 
+    // Pulse shapes are only included if some channel's mode assembles pulses.
+    let mut pulse_shapes = vec
+        .iter()
+        .any(|(_, _, channel_events)| {
+            channel_events
+                .as_ref()
+                .is_some_and(|channel_events| channel_events.pulse_shapes.is_some())
+        })
+        .then(PulseShapes::default);
+
     let mut events = EventData::default();
-    for (channel, (time, voltage)) in vec {
-        let num_events = voltage.len();
-        counter!(
-            crate::EVENTS_FOUND_METRIC,
-            &[
-                ("digitizer_id", format!("{}", trace.digitizer_id())),
-                ("channel", format!("{channel}"))
-            ]
-        )
-        .increment(num_events as u64);
+    let mut masked_channels = Vec::<Channel>::new();
+    let mut saturated = Vec::<bool>::new();
+    let mut statistics = Statistics::default();
+    for (channel, num_samples, channel_events) in vec {
+        let Some(mut channel_events) = channel_events else {
+            masked_channels.push(channel);
+            continue;
+        };
+
+        if let Some((debug_capture, capture)) = debug_capture.zip(channel_events.capture.take()) {
+            let path = debug_capture.path(&frame_name(trace), trace.digitizer_id(), channel);
+            if let Err(e) = debug_capture.write(&path, &capture) {
+                warn!("Failed to write capture to {}: {e}", path.display());
+            }
+        }
 
-        events.channel.extend_from_slice(&vec![channel; time.len()]);
-        events.time.extend_from_slice(&time);
-        events.voltage.extend_from_slice(&voltage);
+        let labels = [
+            ("digitizer_id", format!("{}", trace.digitizer_id())),
+            ("channel", format!("{channel}")),
+        ];
+        let num_events = channel_events.voltage.len();
+        counter!(crate::EVENTS_FOUND_METRIC, &labels).increment(num_events as u64);
+        counter!(crate::PILE_UPS_RESOLVED_METRIC, &labels)
+            .increment(channel_events.pile_ups as u64);
+        let num_saturated = channel_events.saturated.iter().filter(|&&s| s).count();
+        counter!(crate::SATURATED_EVENTS_METRIC, &labels).increment(num_saturated as u64);
+        if let Some(estimated_baseline) = channel_events.estimated_baseline {
+            gauge!(crate::ESTIMATED_BASELINE_METRIC, &labels).set(estimated_baseline);
+        }
+
+        events
+            .channel
+            .extend_from_slice(&vec![channel; channel_events.time.len()]);
+        events.time.extend_from_slice(&channel_events.time);
+        events.voltage.extend_from_slice(&channel_events.voltage);
+        saturated.extend_from_slice(&channel_events.saturated);
+        if let Some(pulse_shapes) = &mut pulse_shapes {
+            pulse_shapes.extend(channel_events.pulse_shapes.as_ref(), num_events);
+        }
+
+        let trace_duration_in_ns = num_samples as Real * sample_time_in_ns;
+        let dead_time = processor.dead_time.unwrap_or_else(|| {
+            detector_dead_time(
+                &processor
+                    .detector_settings
+                    .get(trace.digitizer_id(), channel)
+                    .mode,
+                sample_time_in_ns,
+            )
+        });
+        let channel_statistics =
+            ChannelStatistics::new(&channel_events.time, trace_duration_in_ns, dead_time);
+        gauge!(crate::LIVE_FRACTION_METRIC, &labels).set(channel_statistics.live_fraction);
+        statistics.push(channel, &channel_statistics);
+
+        // The channel is masked from the next trace onwards.
+        let trace_duration_in_us = trace_duration_in_ns / 1_000.0;
+        if let Some(reason) = processor.auto_mask.as_mut().and_then(|auto_mask| {
+            auto_mask.update(
+                trace.digitizer_id(),
+                channel,
+                &channel_events,
+                trace_duration_in_us,
+            )
+        }) {
+            warn!(
+                "Masking digitiser {}, channel {channel}, due to its {}",
+                trace.digitizer_id(),
+                reason.as_str()
+            );
+            processor
+                .detector_settings
+                .mask(trace.digitizer_id(), channel, reason);
+            gauge!(
+                crate::MASKED_CHANNELS_METRIC,
+                &[
+                    ("digitizer_id", format!("{}", trace.digitizer_id())),
+                    ("channel", format!("{channel}")),
+                    ("reason", reason.as_str().to_owned()),
+                ]
+            )
+            .set(1);
+        }
     }
 
     let metadata = FrameMetadataV2Args {
@@ -85,6 +270,30 @@ pub(crate) fn process<'a>(
     let time = Some(fbb.create_vector(&events.time));
     let voltage = Some(fbb.create_vector(&events.voltage));
     let channel = Some(fbb.create_vector(&events.channel));
+    let width = pulse_shapes
+        .as_ref()
+        .map(|pulse_shapes| fbb.create_vector(&pulse_shapes.width));
+    let area = pulse_shapes
+        .as_ref()
+        .map(|pulse_shapes| fbb.create_vector(&pulse_shapes.area));
+    let rise_time = pulse_shapes
+        .as_ref()
+        .map(|pulse_shapes| fbb.create_vector(&pulse_shapes.rise_time));
+    let truncated = pulse_shapes
+        .as_ref()
+        .map(|pulse_shapes| fbb.create_vector(&pulse_shapes.truncated));
+    let masked_channels =
+        (!masked_channels.is_empty()).then(|| fbb.create_vector(&masked_channels));
+    // Saturation flags are only included if some event is saturated.
+    let saturated = saturated
+        .contains(&true)
+        .then(|| fbb.create_vector(&saturated));
+    let calibration_version =
+        calibration.map(|calibration| fbb.create_string(calibration.version()));
+    let statistics_channel = Some(fbb.create_vector(&statistics.channel));
+    let live_fraction = Some(fbb.create_vector(&statistics.live_fraction));
+    let observed_rate = Some(fbb.create_vector(&statistics.observed_rate));
+    let estimated_rate = Some(fbb.create_vector(&statistics.estimated_rate));
 
     let message = DigitizerEventListMessageArgs {
         digitizer_id: trace.digitizer_id(),
@@ -92,79 +301,52 @@ pub(crate) fn process<'a>(
         time,
         voltage,
         channel,
+        width,
+        area,
+        rise_time,
+        truncated,
+        masked_channels,
+        calibration_version,
+        saturated,
+        vetoed: veto_action == VetoAction::Tag,
+        statistics_channel,
+        live_fraction,
+        observed_rate,
+        estimated_rate,
     };
     let message = DigitizerEventListMessage::create(fbb, &message);
     finish_digitizer_event_list_message_buffer(fbb, message);
 
     tracing::Span::current().record("num_total_pulses", events.channel.len());
+    true
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
         Mode, Polarity,
-        parameters::{AdvancedMuonDetectorParameters, FixedThresholdDiscriminatorParameters},
+        parameters::{
+            AdvancedMuonDetectorParameters, ChannelDetectorSettings,
+            ConstantFractionDiscriminatorParameters, DetectorSettingsFile, FilterSettings,
+            FixedThresholdDiscriminatorParameters, MaskReason,
+        },
     };
 
     use super::*;
+    use crate::masking::AutoMaskLimits;
+    use crate::test_fixtures::create_trace_message;
     use chrono::Utc;
     use digital_muon_common::Intensity;
+    use digital_muon_pulse_detection::baseline_estimator::BaselineEstimator;
     use digital_muon_streaming_types::{
-        dat2_digitizer_analog_trace_v2_generated::{
-            ChannelTrace, ChannelTraceArgs, DigitizerAnalogTraceMessage,
-            DigitizerAnalogTraceMessageArgs, finish_digitizer_analog_trace_message_buffer,
-            root_as_digitizer_analog_trace_message,
-        },
+        dat2_digitizer_analog_trace_v2_generated::root_as_digitizer_analog_trace_message,
         dev2_digitizer_event_v2_generated::{
             digitizer_event_list_message_buffer_has_identifier,
             root_as_digitizer_event_list_message,
         },
-        frame_metadata_v2_generated::{FrameMetadataV2, FrameMetadataV2Args, GpsTime},
+        frame_metadata_v2_generated::GpsTime,
     };
 
-    fn create_message(
-        fbb: &mut FlatBufferBuilder<'_>,
-        channel_intensities: &[&[Intensity]],
-        time: &GpsTime,
-    ) {
-        let metadata = FrameMetadataV2Args {
-            frame_number: 0,
-            period_number: 0,
-            protons_per_pulse: 0,
-            running: true,
-            timestamp: Some(time),
-            veto_flags: 0,
-        };
-        let metadata = FrameMetadataV2::create(fbb, &metadata);
-
-        let channel_vectors: Vec<_> = channel_intensities
-            .iter()
-            .map(|intensities| Some(fbb.create_vector::<u16>(intensities)))
-            .collect();
-        let channel_traces: Vec<_> = channel_vectors
-            .iter()
-            .enumerate()
-            .map(|(i, intensities)| {
-                ChannelTrace::create(
-                    fbb,
-                    &ChannelTraceArgs {
-                        channel: i as Channel,
-                        voltage: *intensities,
-                    },
-                )
-            })
-            .collect();
-
-        let message = DigitizerAnalogTraceMessageArgs {
-            digitizer_id: 0,
-            metadata: Some(metadata),
-            sample_rate: 1_000_000_000,
-            channels: Some(fbb.create_vector(&channel_traces)),
-        };
-        let message = DigitizerAnalogTraceMessage::create(fbb, &message);
-        finish_digitizer_analog_trace_message_buffer(fbb, message);
-    }
-
     #[test]
     fn fixed_threshold_discriminator_positive_zero_baseline() {
         let mut fbb = FlatBufferBuilder::new();
@@ -172,7 +354,7 @@ mod tests {
         let time: GpsTime = Utc::now().into();
         let channels: Vec<&[Intensity]> =
             vec![[0, 1, 2, 1, 0, 1, 2, 1, 8, 0, 2, 8, 3, 1, 2].as_slice()];
-        create_message(&mut fbb, &channels, &time);
+        create_trace_message(&mut fbb, 0, 0, &time, &channels);
         let message = fbb.finished_data().to_vec();
         let message = root_as_digitizer_analog_trace_message(&message).unwrap();
 
@@ -185,11 +367,12 @@ mod tests {
         process(
             &mut fbb,
             &message,
-            &DetectorSettings {
-                mode: &Mode::FixedThresholdDiscriminator(test_parameters),
-                polarity: &Polarity::Positive,
+            &mut TraceProcessor::new(DetectorSettings::new(ChannelDetectorSettings {
+                mode: Mode::FixedThresholdDiscriminator(test_parameters),
+                polarity: Polarity::Positive,
                 baseline: Intensity::default(),
-            },
+                filters: Vec::new(),
+            })),
         );
 
         assert!(digitizer_event_list_message_buffer_has_identifier(
@@ -211,6 +394,12 @@ mod tests {
             vec![8, 8],
             event_message.voltage().unwrap().iter().collect::<Vec<_>>()
         );
+
+        assert!(event_message.width().is_none());
+        assert!(event_message.area().is_none());
+        assert!(event_message.rise_time().is_none());
+        assert!(event_message.truncated().is_none());
+        assert!(event_message.calibration_version().is_none());
     }
 
     #[test]
@@ -222,7 +411,7 @@ mod tests {
             [0, 1, 2, 1, 0, 1, 2, 1, 9, 0, 2, 8, 3, 1, 2].as_slice(),
             [0, 1, 2, 1, 0, 1, 2, 1, 8, 0, 2, 9, 3, 1, 2].as_slice(),
         ];
-        create_message(&mut fbb, &channels, &time);
+        create_trace_message(&mut fbb, 0, 0, &time, &channels);
         let message = fbb.finished_data().to_vec();
         let message = root_as_digitizer_analog_trace_message(&message).unwrap();
 
@@ -235,11 +424,12 @@ mod tests {
         process(
             &mut fbb,
             &message,
-            &DetectorSettings {
-                mode: &Mode::FixedThresholdDiscriminator(test_parameters),
-                polarity: &Polarity::Positive,
+            &mut TraceProcessor::new(DetectorSettings::new(ChannelDetectorSettings {
+                mode: Mode::FixedThresholdDiscriminator(test_parameters),
+                polarity: Polarity::Positive,
                 baseline: Intensity::default(),
-            },
+                filters: Vec::new(),
+            })),
         );
 
         assert!(digitizer_event_list_message_buffer_has_identifier(
@@ -263,13 +453,693 @@ mod tests {
         );
     }
 
+    #[test]
+    fn constant_fraction_discriminator_positive_zero_baseline() {
+        let mut fbb = FlatBufferBuilder::new();
+
+        let time: GpsTime = Utc::now().into();
+        let channel0: Vec<u16> = vec![0, 0, 0, 0, 2, 4, 6, 8, 10, 8, 6, 4, 2, 0, 0, 0];
+        create_trace_message(&mut fbb, 0, 0, &time, &[channel0.as_slice()]);
+        let message = fbb.finished_data().to_vec();
+        let message = root_as_digitizer_analog_trace_message(&message).unwrap();
+
+        let test_parameters = ConstantFractionDiscriminatorParameters {
+            threshold: 3.0,
+            fraction: 0.5,
+            delay: 2,
+        };
+        let mut fbb = FlatBufferBuilder::new();
+        process(
+            &mut fbb,
+            &message,
+            &mut TraceProcessor::new(DetectorSettings::new(ChannelDetectorSettings {
+                mode: Mode::ConstantFractionDiscriminator(test_parameters),
+                polarity: Polarity::Positive,
+                baseline: Intensity::default(),
+                filters: Vec::new(),
+            })),
+        );
+
+        assert!(digitizer_event_list_message_buffer_has_identifier(
+            fbb.finished_data()
+        ));
+        let event_message = root_as_digitizer_event_list_message(fbb.finished_data()).unwrap();
+
+        assert_eq!(
+            vec![0],
+            event_message.channel().unwrap().iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec![7],
+            event_message.time().unwrap().iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec![10],
+            event_message.voltage().unwrap().iter().collect::<Vec<_>>()
+        );
+    }
+
+    const DETECTOR_SETTINGS_JSON: &str = r#"
+    {
+        "channels": [
+            {
+                "digitizer-id": 0,
+                "channel": 1,
+                "polarity": "positive",
+                "mode": "fixed-threshold-discriminator",
+                "threshold": 8.5
+            }
+        ]
+    }
+    "#;
+
+    #[test]
+    fn fixed_threshold_discriminator_per_channel_settings() {
+        let mut fbb = FlatBufferBuilder::new();
+
+        let time: GpsTime = Utc::now().into();
+        let channels: Vec<&[Intensity]> = vec![
+            [0, 1, 2, 1, 0, 1, 2, 1, 9, 0, 2, 8, 3, 1, 2].as_slice(),
+            [0, 1, 2, 1, 0, 1, 2, 1, 8, 0, 2, 9, 3, 1, 2].as_slice(),
+        ];
+        create_trace_message(&mut fbb, 0, 0, &time, &channels);
+        let message = fbb.finished_data().to_vec();
+        let message = root_as_digitizer_analog_trace_message(&message).unwrap();
+
+        let default = ChannelDetectorSettings {
+            mode: Mode::FixedThresholdDiscriminator(FixedThresholdDiscriminatorParameters {
+                threshold: 5.0,
+                duration: 1,
+                cool_off: 0,
+            }),
+            polarity: Polarity::Positive,
+            baseline: Intensity::default(),
+            filters: Vec::new(),
+        };
+        let settings_file: DetectorSettingsFile =
+            serde_json::from_str(DETECTOR_SETTINGS_JSON).unwrap();
+        let detector_settings =
+            DetectorSettings::from_settings_file(settings_file, default).unwrap();
+
+        let mut fbb = FlatBufferBuilder::new();
+        process(
+            &mut fbb,
+            &message,
+            &mut TraceProcessor::new(detector_settings),
+        );
+
+        assert!(digitizer_event_list_message_buffer_has_identifier(
+            fbb.finished_data()
+        ));
+        let event_message = root_as_digitizer_event_list_message(fbb.finished_data()).unwrap();
+
+        assert_eq!(
+            vec![0, 0, 1],
+            event_message.channel().unwrap().iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec![8, 11, 11],
+            event_message.time().unwrap().iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec![9, 8, 9],
+            event_message.voltage().unwrap().iter().collect::<Vec<_>>()
+        );
+    }
+
+    const FILTERED_SETTINGS_JSON: &str = r#"
+    {
+        "channels": [
+            {
+                "digitizer-id": 0,
+                "channel": 1,
+                "polarity": "positive",
+                "mode": "fixed-threshold-discriminator",
+                "threshold": 5,
+                "filters": [
+                    { "filter": "median", "size": 3 },
+                    { "filter": "fir", "coefficients": [0.5, 0.5] }
+                ]
+            }
+        ]
+    }
+    "#;
+
+    #[test]
+    fn fixed_threshold_discriminator_filtered_channel() {
+        let mut fbb = FlatBufferBuilder::new();
+
+        let time: GpsTime = Utc::now().into();
+        // Channel 1 has a single sample spike, followed by a wider pulse.
+        let channels: Vec<&[Intensity]> = vec![
+            [0, 1, 9, 1, 0, 1, 2, 1, 8, 8, 8, 8, 3, 1, 2].as_slice(),
+            [0, 1, 9, 1, 0, 1, 2, 1, 8, 8, 8, 8, 3, 1, 2].as_slice(),
+        ];
+        create_trace_message(&mut fbb, 0, 0, &time, &channels);
+        let message = fbb.finished_data().to_vec();
+        let message = root_as_digitizer_analog_trace_message(&message).unwrap();
+
+        let default = ChannelDetectorSettings {
+            mode: Mode::FixedThresholdDiscriminator(FixedThresholdDiscriminatorParameters {
+                threshold: 5.0,
+                duration: 1,
+                cool_off: 0,
+            }),
+            polarity: Polarity::Positive,
+            baseline: Intensity::default(),
+            filters: Vec::new(),
+        };
+        let settings_file: DetectorSettingsFile =
+            serde_json::from_str(FILTERED_SETTINGS_JSON).unwrap();
+        let detector_settings =
+            DetectorSettings::from_settings_file(settings_file, default).unwrap();
+        assert_eq!(
+            detector_settings.get(0, 1).filters,
+            [
+                FilterSettings::Median { size: 3 },
+                FilterSettings::Fir {
+                    coefficients: vec![0.5, 0.5]
+                }
+            ]
+        );
+
+        let mut fbb = FlatBufferBuilder::new();
+        process(
+            &mut fbb,
+            &message,
+            &mut TraceProcessor::new(detector_settings),
+        );
+
+        let event_message = root_as_digitizer_event_list_message(fbb.finished_data()).unwrap();
+
+        // The spike is removed by the median filter, and the delays of the filters are corrected for.
+        assert_eq!(
+            vec![0, 0, 1],
+            event_message.channel().unwrap().iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec![2, 8, 8],
+            event_message.time().unwrap().iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec![9, 8, 8],
+            event_message.voltage().unwrap().iter().collect::<Vec<_>>()
+        );
+    }
+
+    const MASKED_SETTINGS_JSON: &str = r#"
+    {
+        "masked": [
+            { "digitizer-id": 0, "channel": 0 },
+            { "digitizer-id": 0, "channel": 2, "reason": "event-rate" }
+        ]
+    }
+    "#;
+
+    #[test]
+    fn masked_channels_have_no_events() {
+        let mut fbb = FlatBufferBuilder::new();
+
+        let time: GpsTime = Utc::now().into();
+        let channels: Vec<&[Intensity]> = vec![
+            [0, 1, 2, 1, 0, 1, 2, 1, 9, 0, 2, 8, 3, 1, 2].as_slice(),
+            [0, 1, 2, 1, 0, 1, 2, 1, 8, 0, 2, 9, 3, 1, 2].as_slice(),
+            [0, 1, 2, 1, 0, 1, 2, 1, 8, 0, 2, 9, 3, 1, 2].as_slice(),
+        ];
+        create_trace_message(&mut fbb, 0, 0, &time, &channels);
+        let message = fbb.finished_data().to_vec();
+        let message = root_as_digitizer_analog_trace_message(&message).unwrap();
+
+        let default = ChannelDetectorSettings {
+            mode: Mode::FixedThresholdDiscriminator(FixedThresholdDiscriminatorParameters {
+                threshold: 5.0,
+                duration: 1,
+                cool_off: 0,
+            }),
+            polarity: Polarity::Positive,
+            baseline: Intensity::default(),
+            filters: Vec::new(),
+        };
+        let settings_file: DetectorSettingsFile =
+            serde_json::from_str(MASKED_SETTINGS_JSON).unwrap();
+        let detector_settings =
+            DetectorSettings::from_settings_file(settings_file, default).unwrap();
+        assert_eq!(
+            detector_settings.mask_reason(0, 0),
+            Some(MaskReason::Configured)
+        );
+        assert_eq!(
+            detector_settings.mask_reason(0, 2),
+            Some(MaskReason::EventRate)
+        );
+        assert_eq!(detector_settings.mask_reason(0, 1), None);
+
+        // The masks are included when the settings are written back out.
+        let settings_json = serde_json::to_value(detector_settings.to_settings_file()).unwrap();
+        assert_eq!(
+            settings_json["masked"],
+            serde_json::json!([
+                { "digitizer-id": 0, "channel": 0, "reason": "configured" },
+                { "digitizer-id": 0, "channel": 2, "reason": "event-rate" }
+            ])
+        );
+
+        let mut fbb = FlatBufferBuilder::new();
+        process(
+            &mut fbb,
+            &message,
+            &mut TraceProcessor::new(detector_settings),
+        );
+
+        let event_message = root_as_digitizer_event_list_message(fbb.finished_data()).unwrap();
+
+        assert_eq!(
+            vec![1, 1],
+            event_message.channel().unwrap().iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec![8, 11],
+            event_message.time().unwrap().iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec![0, 2],
+            event_message
+                .masked_channels()
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn noisy_channel_auto_masked() {
+        let time: GpsTime = Utc::now().into();
+        let channels: Vec<&[Intensity]> = vec![
+            [0, 9, 0, 9, 0, 9, 0, 9, 0, 9].as_slice(),
+            [0, 0, 0, 0, 9, 0, 0, 0, 0, 0].as_slice(),
+        ];
+        let mut fbb = FlatBufferBuilder::new();
+        create_trace_message(&mut fbb, 0, 0, &time, &channels);
+        let message = fbb.finished_data().to_vec();
+        let message = root_as_digitizer_analog_trace_message(&message).unwrap();
+
+        let mut processor = TraceProcessor {
+            // Channel 0 has 5 events in 10ns, which is 500 MHz.
+            auto_mask: Some(AutoMask::new(AutoMaskLimits {
+                max_event_rate: Some(200.0),
+                max_clipping_fraction: None,
+                traces: 2,
+            })),
+            ..TraceProcessor::new(DetectorSettings::new(ChannelDetectorSettings {
+                mode: Mode::FixedThresholdDiscriminator(FixedThresholdDiscriminatorParameters {
+                    threshold: 5.0,
+                    duration: 1,
+                    cool_off: 0,
+                }),
+                polarity: Polarity::Positive,
+                baseline: Intensity::default(),
+                filters: Vec::new(),
+            }))
+        };
+
+        let mut channels_with_events = Vec::new();
+        for _ in 0..3 {
+            let mut fbb = FlatBufferBuilder::new();
+            process(&mut fbb, &message, &mut processor);
+            let event_message = root_as_digitizer_event_list_message(fbb.finished_data()).unwrap();
+            let mut channels: Vec<_> = event_message.channel().unwrap().iter().collect();
+            channels.dedup();
+            channels_with_events.push(channels);
+        }
+
+        assert_eq!(channels_with_events, [vec![0, 1], vec![0, 1], vec![1]]);
+        assert_eq!(
+            processor.detector_settings.mask_reason(0, 0),
+            Some(MaskReason::EventRate)
+        );
+    }
+
+    #[test]
+    fn clipped_channel_auto_masked() {
+        let time: GpsTime = Utc::now().into();
+        let channels: Vec<&[Intensity]> = vec![
+            // The baseline of a positive channel at the bottom of the range is not clipped.
+            [0; 10].as_slice(),
+            [
+                0,
+                0,
+                0,
+                0,
+                Intensity::MAX,
+                Intensity::MAX,
+                Intensity::MAX,
+                0,
+                0,
+                0,
+            ]
+            .as_slice(),
+        ];
+        let mut fbb = FlatBufferBuilder::new();
+        create_trace_message(&mut fbb, 0, 0, &time, &channels);
+        let message = fbb.finished_data().to_vec();
+        let message = root_as_digitizer_analog_trace_message(&message).unwrap();
+
+        let mut processor = TraceProcessor {
+            auto_mask: Some(AutoMask::new(AutoMaskLimits {
+                max_event_rate: None,
+                max_clipping_fraction: Some(0.2),
+                traces: 1,
+            })),
+            ..TraceProcessor::new(DetectorSettings::new(ChannelDetectorSettings {
+                mode: Mode::FixedThresholdDiscriminator(FixedThresholdDiscriminatorParameters {
+                    threshold: 5.0,
+                    duration: 1,
+                    cool_off: 0,
+                }),
+                polarity: Polarity::Positive,
+                baseline: Intensity::default(),
+                filters: Vec::new(),
+            }))
+        };
+
+        let mut fbb = FlatBufferBuilder::new();
+        process(&mut fbb, &message, &mut processor);
+        assert_eq!(processor.detector_settings.mask_reason(0, 0), None);
+        assert_eq!(
+            processor.detector_settings.mask_reason(0, 1),
+            Some(MaskReason::Clipping)
+        );
+    }
+
+    #[test]
+    fn fixed_threshold_discriminator_saturated() {
+        let mut fbb = FlatBufferBuilder::new();
+
+        let time: GpsTime = Utc::now().into();
+        let channels: Vec<&[Intensity]> = vec![
+            [
+                0,
+                1,
+                2,
+                Intensity::MAX,
+                Intensity::MAX,
+                1,
+                2,
+                1,
+                8,
+                0,
+                2,
+                8,
+                3,
+                1,
+                2,
+            ]
+            .as_slice(),
+            [0, 1, 2, 1, 0, 1, 2, 1, 8, 0, 2, 9, 3, 1, 2].as_slice(),
+        ];
+        create_trace_message(&mut fbb, 0, 0, &time, &channels);
+        let message = fbb.finished_data().to_vec();
+        let message = root_as_digitizer_analog_trace_message(&message).unwrap();
+
+        let mut fbb = FlatBufferBuilder::new();
+        process(
+            &mut fbb,
+            &message,
+            &mut TraceProcessor::new(DetectorSettings::new(ChannelDetectorSettings {
+                mode: Mode::FixedThresholdDiscriminator(FixedThresholdDiscriminatorParameters {
+                    threshold: 5.0,
+                    duration: 1,
+                    cool_off: 0,
+                }),
+                polarity: Polarity::Positive,
+                baseline: Intensity::default(),
+                filters: Vec::new(),
+            })),
+        );
+
+        let event_message = root_as_digitizer_event_list_message(fbb.finished_data()).unwrap();
+
+        assert_eq!(
+            vec![0, 0, 0, 1, 1],
+            event_message.channel().unwrap().iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec![3, 8, 11, 8, 11],
+            event_message.time().unwrap().iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec![true, false, false, false, false],
+            event_message
+                .saturated()
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn saturated_run_outside_pulses_not_flagged() {
+        let mut fbb = FlatBufferBuilder::new();
+
+        let time: GpsTime = Utc::now().into();
+        // The clipped spike is too short to register an event, and the preceding pulse has ended before it.
+        let channels: Vec<&[Intensity]> = vec![[0, 8, 8, 0, 0, Intensity::MAX, 0, 0].as_slice()];
+        create_trace_message(&mut fbb, 0, 0, &time, &channels);
+        let message = fbb.finished_data().to_vec();
+        let message = root_as_digitizer_analog_trace_message(&message).unwrap();
+
+        let mut fbb = FlatBufferBuilder::new();
+        process(
+            &mut fbb,
+            &message,
+            &mut TraceProcessor::new(DetectorSettings::new(ChannelDetectorSettings {
+                mode: Mode::FixedThresholdDiscriminator(FixedThresholdDiscriminatorParameters {
+                    threshold: 5.0,
+                    duration: 2,
+                    cool_off: 0,
+                }),
+                polarity: Polarity::Positive,
+                baseline: Intensity::default(),
+                filters: Vec::new(),
+            })),
+        );
+
+        let event_message = root_as_digitizer_event_list_message(fbb.finished_data()).unwrap();
+
+        assert_eq!(
+            vec![1],
+            event_message.time().unwrap().iter().collect::<Vec<_>>()
+        );
+        assert!(event_message.saturated().is_none());
+    }
+
+    #[test]
+    fn fixed_threshold_discriminator_channel_statistics() {
+        let mut fbb = FlatBufferBuilder::new();
+
+        let time: GpsTime = Utc::now().into();
+        let channels: Vec<&[Intensity]> = vec![
+            [0, 1, 2, 1, 0, 1, 2, 1, 8, 0, 2, 8, 3, 1, 2].as_slice(),
+            [0, 1, 2, 1, 0, 1, 2, 1, 0, 0, 2, 0, 3, 1, 2].as_slice(),
+        ];
+        create_trace_message(&mut fbb, 0, 0, &time, &channels);
+        let message = fbb.finished_data().to_vec();
+        let message = root_as_digitizer_analog_trace_message(&message).unwrap();
+
+        let mut processor = TraceProcessor::new(DetectorSettings::new(ChannelDetectorSettings {
+            mode: Mode::FixedThresholdDiscriminator(FixedThresholdDiscriminatorParameters {
+                threshold: 5.0,
+                duration: 1,
+                cool_off: 2,
+            }),
+            polarity: Polarity::Positive,
+            baseline: Intensity::default(),
+            filters: Vec::new(),
+        }));
+
+        // Each of the events, at 8 ns and 11 ns, is followed by 2 ns of cool-off.
+        let mut fbb = FlatBufferBuilder::new();
+        process(&mut fbb, &message, &mut processor);
+        let event_message = root_as_digitizer_event_list_message(fbb.finished_data()).unwrap();
+
+        assert_eq!(
+            vec![8, 11],
+            event_message.time().unwrap().iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![0, 1],
+            event_message
+                .statistics_channel()
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![(11.0 / 15.0) as f32, 1.0],
+            event_message
+                .live_fraction()
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![(2_000.0 / 15.0) as f32, 0.0],
+            event_message
+                .observed_rate()
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![(2_000.0 / 11.0) as f32, 0.0],
+            event_message
+                .estimated_rate()
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        );
+
+        // A configured dead time overrides the cool-off, and is cut short by the next event.
+        processor.dead_time = Some(5.0);
+        let mut fbb = FlatBufferBuilder::new();
+        process(&mut fbb, &message, &mut processor);
+        let event_message = root_as_digitizer_event_list_message(fbb.finished_data()).unwrap();
+
+        assert_eq!(
+            vec![(8.0 / 15.0) as f32, 1.0],
+            event_message
+                .live_fraction()
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn advanced_negative_saturated_amplitude_estimated() {
+        let time: GpsTime = Utc::now().into();
+        let channel0: Vec<u16> = vec![20, 20, 20, 14, 6, 0, 0, 0, 8, 14, 18, 20, 19, 20, 20];
+        let mut fbb = FlatBufferBuilder::new();
+        create_trace_message(&mut fbb, 0, 0, &time, &[channel0.as_slice()]);
+        let message = fbb.finished_data().to_vec();
+        let message = root_as_digitizer_analog_trace_message(&message).unwrap();
+
+        let mut voltages = Vec::new();
+        for estimate_saturated_amplitude in [false, true] {
+            let test_parameters = AdvancedMuonDetectorParameters {
+                muon_onset: 0.5,
+                muon_fall: -0.01,
+                muon_termination: 0.001,
+                duration: 0.0,
+                estimate_saturated_amplitude,
+                ..Default::default()
+            };
+            let mut fbb = FlatBufferBuilder::new();
+            process(
+                &mut fbb,
+                &message,
+                &mut TraceProcessor::new(DetectorSettings::new(ChannelDetectorSettings {
+                    mode: Mode::AdvancedMuonDetector(test_parameters),
+                    polarity: Polarity::Negative,
+                    baseline: 20,
+                    filters: Vec::new(),
+                })),
+            );
+
+            let event_message = root_as_digitizer_event_list_message(fbb.finished_data()).unwrap();
+            assert_eq!(
+                vec![true],
+                event_message
+                    .saturated()
+                    .unwrap()
+                    .iter()
+                    .collect::<Vec<_>>()
+            );
+            voltages.extend(event_message.voltage().unwrap().iter());
+        }
+
+        // The tangents at the steepest rise and sharpest fall meet well above the clipped peak.
+        assert_eq!(voltages, [13, 25]);
+    }
+
+    #[test]
+    fn fixed_threshold_discriminator_calibrated() {
+        let mut fbb = FlatBufferBuilder::new();
+
+        let time: GpsTime = Utc::now().into();
+        let channels: Vec<&[Intensity]> = vec![
+            [0, 1, 2, 1, 0, 1, 2, 1, 9, 0, 2, 8, 3, 1, 2].as_slice(),
+            [0, 1, 2, 1, 0, 1, 2, 1, 8, 0, 2, 9, 3, 1, 2].as_slice(),
+        ];
+        create_trace_message(&mut fbb, 0, 0, &time, &channels);
+        let message = fbb.finished_data().to_vec();
+        let message = root_as_digitizer_analog_trace_message(&message).unwrap();
+
+        let calibration_file = serde_json::from_str(
+            r#"
+            {
+                "version": "v3",
+                "channels": [
+                    { "digitizer-id": 0, "channel": 1, "time-offset": 4, "gain": 1.5, "offset": 2 }
+                ]
+            }
+            "#,
+        )
+        .unwrap();
+        let mut processor = TraceProcessor {
+            calibration: Some(Calibration::from_calibration_file(calibration_file).unwrap()),
+            ..TraceProcessor::new(DetectorSettings::new(ChannelDetectorSettings {
+                mode: Mode::FixedThresholdDiscriminator(FixedThresholdDiscriminatorParameters {
+                    threshold: 5.0,
+                    duration: 1,
+                    cool_off: 0,
+                }),
+                polarity: Polarity::Positive,
+                baseline: Intensity::default(),
+                filters: Vec::new(),
+            }))
+        };
+
+        let mut fbb = FlatBufferBuilder::new();
+        process(&mut fbb, &message, &mut processor);
+
+        let event_message = root_as_digitizer_event_list_message(fbb.finished_data()).unwrap();
+
+        assert_eq!(
+            vec![0, 0, 1, 1],
+            event_message.channel().unwrap().iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec![8, 11, 12, 15],
+            event_message.time().unwrap().iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec![9, 8, 14, 16],
+            event_message.voltage().unwrap().iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(event_message.calibration_version(), Some("v3"));
+    }
+
     #[test]
     fn advanced_positive_zero_baseline() {
         let mut fbb = FlatBufferBuilder::new();
 
         let time: GpsTime = Utc::now().into();
         let channel0: Vec<u16> = vec![0, 1, 2, 1, 0, 1, 2, 1, 8, 0, 2, 8, 3, 1, 2];
-        create_message(&mut fbb, &[channel0.as_slice()], &time);
+        create_trace_message(&mut fbb, 0, 0, &time, &[channel0.as_slice()]);
         let message = fbb.finished_data().to_vec();
         let message = root_as_digitizer_analog_trace_message(&message).unwrap();
 
@@ -286,11 +1156,12 @@ mod tests {
         process(
             &mut fbb,
             &message,
-            &DetectorSettings {
-                mode: &Mode::AdvancedMuonDetector(test_parameters),
-                polarity: &Polarity::Positive,
+            &mut TraceProcessor::new(DetectorSettings::new(ChannelDetectorSettings {
+                mode: Mode::AdvancedMuonDetector(test_parameters),
+                polarity: Polarity::Positive,
                 baseline: Intensity::default(),
-            },
+                filters: Vec::new(),
+            })),
         );
 
         assert!(digitizer_event_list_message_buffer_has_identifier(
@@ -314,13 +1185,102 @@ mod tests {
         );
     }
 
+    #[test]
+    fn advanced_pulse_shapes_with_fixed_threshold_channel() {
+        let mut fbb = FlatBufferBuilder::new();
+
+        let time: GpsTime = Utc::now().into();
+        let channel0: Vec<u16> = vec![0, 1, 2, 1, 0, 1, 2, 1, 8, 0, 2, 8, 3, 1, 2];
+        let channel1: Vec<u16> = vec![0, 0, 0, 0, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0];
+        create_trace_message(
+            &mut fbb,
+            0,
+            0,
+            &time,
+            &[channel0.as_slice(), channel1.as_slice()],
+        );
+        let message = fbb.finished_data().to_vec();
+        let message = root_as_digitizer_analog_trace_message(&message).unwrap();
+
+        let settings_file: DetectorSettingsFile =
+            serde_json::from_str(PULSE_SHAPES_SETTINGS_JSON).unwrap();
+        let detector_settings = DetectorSettings::from_settings_file(
+            settings_file,
+            ChannelDetectorSettings {
+                mode: Mode::AdvancedMuonDetector(AdvancedMuonDetectorParameters {
+                    muon_onset: 0.5,
+                    muon_fall: -0.01,
+                    muon_termination: 0.001,
+                    duration: 0.0,
+                    smoothing_window_size: Some(2),
+                    ..Default::default()
+                }),
+                polarity: Polarity::Positive,
+                baseline: Intensity::default(),
+                filters: Vec::new(),
+            },
+        )
+        .unwrap();
+
+        let mut fbb = FlatBufferBuilder::new();
+        process(
+            &mut fbb,
+            &message,
+            &mut TraceProcessor::new(detector_settings),
+        );
+        let event_message = root_as_digitizer_event_list_message(fbb.finished_data()).unwrap();
+
+        assert_eq!(
+            vec![0, 0, 1],
+            event_message.channel().unwrap().iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![3, 2, 0],
+            event_message.width().unwrap().iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![4.0, 8.0, 0.0],
+            event_message.area().unwrap().iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![1, 0, 0],
+            event_message
+                .rise_time()
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![false, false, false],
+            event_message
+                .truncated()
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    const PULSE_SHAPES_SETTINGS_JSON: &str = r#"
+    {
+        "channels": [
+            {
+                "digitizer-id": 0,
+                "channel": 1,
+                "polarity": "positive",
+                "mode": "fixed-threshold-discriminator",
+                "threshold": 5.0
+            }
+        ]
+    }
+    "#;
+
     #[test]
     fn fixed_threshold_discriminator_positive_nonzero_baseline() {
         let mut fbb = FlatBufferBuilder::new();
 
         let time: GpsTime = Utc::now().into();
         let channel0: Vec<u16> = vec![3, 4, 5, 4, 3, 4, 5, 4, 11, 3, 5, 11, 6, 4, 5];
-        create_message(&mut fbb, &[channel0.as_slice()], &time);
+        create_trace_message(&mut fbb, 0, 0, &time, &[channel0.as_slice()]);
         let message = fbb.finished_data().to_vec();
         let message = root_as_digitizer_analog_trace_message(&message).unwrap();
 
@@ -333,11 +1293,12 @@ mod tests {
         process(
             &mut fbb,
             &message,
-            &DetectorSettings {
-                mode: &Mode::FixedThresholdDiscriminator(test_parameters),
-                polarity: &Polarity::Positive,
+            &mut TraceProcessor::new(DetectorSettings::new(ChannelDetectorSettings {
+                mode: Mode::FixedThresholdDiscriminator(test_parameters),
+                polarity: Polarity::Positive,
                 baseline: 3,
-            },
+                filters: Vec::new(),
+            })),
         );
 
         assert!(digitizer_event_list_message_buffer_has_identifier(
@@ -361,13 +1322,67 @@ mod tests {
         );
     }
 
+    #[test]
+    fn fixed_threshold_discriminator_positive_estimated_baseline() {
+        let mut fbb = FlatBufferBuilder::new();
+
+        let time: GpsTime = Utc::now().into();
+        let channel0: Vec<u16> = vec![3, 4, 5, 4, 3, 4, 5, 4, 11, 3, 5, 11, 6, 4, 5];
+        create_trace_message(&mut fbb, 0, 0, &time, &[channel0.as_slice()]);
+        let message = fbb.finished_data().to_vec();
+        let message = root_as_digitizer_analog_trace_message(&message).unwrap();
+
+        let test_parameters = FixedThresholdDiscriminatorParameters {
+            threshold: 5.0,
+            duration: 1,
+            cool_off: 0,
+        };
+        let mut fbb = FlatBufferBuilder::new();
+        process(
+            &mut fbb,
+            &message,
+            &mut TraceProcessor {
+                baseline_estimation: Some(BaselineEstimation {
+                    estimator: BaselineEstimator::Median,
+                    length: 4,
+                }),
+                ..TraceProcessor::new(DetectorSettings::new(ChannelDetectorSettings {
+                    mode: Mode::FixedThresholdDiscriminator(test_parameters),
+                    polarity: Polarity::Positive,
+                    baseline: Intensity::default(),
+                    filters: Vec::new(),
+                }))
+            },
+        );
+
+        assert!(digitizer_event_list_message_buffer_has_identifier(
+            fbb.finished_data()
+        ));
+        let event_message = root_as_digitizer_event_list_message(fbb.finished_data()).unwrap();
+
+        assert_eq!(
+            vec![0, 0],
+            event_message.channel().unwrap().iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec![8, 11],
+            event_message.time().unwrap().iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec![7, 7],
+            event_message.voltage().unwrap().iter().collect::<Vec<_>>()
+        );
+    }
+
     #[test]
     fn advanced_positive_nonzero_baseline() {
         let mut fbb = FlatBufferBuilder::new();
 
         let time: GpsTime = Utc::now().into();
         let channel0: Vec<u16> = vec![3, 4, 5, 4, 3, 4, 5, 4, 11, 3, 5, 11, 6, 4, 5];
-        create_message(&mut fbb, &[channel0.as_slice()], &time);
+        create_trace_message(&mut fbb, 0, 0, &time, &[channel0.as_slice()]);
         let message = fbb.finished_data().to_vec();
         let message = root_as_digitizer_analog_trace_message(&message).unwrap();
 
@@ -384,11 +1399,12 @@ mod tests {
         process(
             &mut fbb,
             &message,
-            &DetectorSettings {
-                mode: &Mode::AdvancedMuonDetector(test_parameters),
-                polarity: &Polarity::Positive,
+            &mut TraceProcessor::new(DetectorSettings::new(ChannelDetectorSettings {
+                mode: Mode::AdvancedMuonDetector(test_parameters),
+                polarity: Polarity::Positive,
                 baseline: 3,
-            },
+                filters: Vec::new(),
+            })),
         );
 
         assert!(digitizer_event_list_message_buffer_has_identifier(
@@ -418,7 +1434,7 @@ mod tests {
 
         let time: GpsTime = Utc::now().into();
         let channel0: Vec<u16> = vec![10, 9, 8, 9, 10, 9, 8, 9, 2, 10, 8, 2, 7, 9, 8];
-        create_message(&mut fbb, &[channel0.as_slice()], &time);
+        create_trace_message(&mut fbb, 0, 0, &time, &[channel0.as_slice()]);
         let message = fbb.finished_data().to_vec();
         let message = root_as_digitizer_analog_trace_message(&message).unwrap();
 
@@ -431,11 +1447,12 @@ mod tests {
         process(
             &mut fbb,
             &message,
-            &DetectorSettings {
-                mode: &Mode::FixedThresholdDiscriminator(test_parameters),
-                polarity: &Polarity::Negative,
+            &mut TraceProcessor::new(DetectorSettings::new(ChannelDetectorSettings {
+                mode: Mode::FixedThresholdDiscriminator(test_parameters),
+                polarity: Polarity::Negative,
                 baseline: 10,
-            },
+                filters: Vec::new(),
+            })),
         );
 
         assert!(digitizer_event_list_message_buffer_has_identifier(
@@ -465,7 +1482,7 @@ mod tests {
 
         let time: GpsTime = Utc::now().into();
         let channel0: Vec<u16> = vec![10, 9, 8, 9, 10, 9, 8, 9, 2, 10, 8, 2, 7, 9, 8];
-        create_message(&mut fbb, &[channel0.as_slice()], &time);
+        create_trace_message(&mut fbb, 0, 0, &time, &[channel0.as_slice()]);
         let message = fbb.finished_data().to_vec();
         let message = root_as_digitizer_analog_trace_message(&message).unwrap();
 
@@ -482,11 +1499,12 @@ mod tests {
         process(
             &mut fbb,
             &message,
-            &DetectorSettings {
-                mode: &Mode::AdvancedMuonDetector(test_parameters),
-                polarity: &Polarity::Negative,
+            &mut TraceProcessor::new(DetectorSettings::new(ChannelDetectorSettings {
+                mode: Mode::AdvancedMuonDetector(test_parameters),
+                polarity: Polarity::Negative,
                 baseline: 10,
-            },
+                filters: Vec::new(),
+            })),
         );
 
         assert!(digitizer_event_list_message_buffer_has_identifier(
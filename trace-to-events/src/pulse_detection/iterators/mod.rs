@@ -1,4 +0,0 @@
-pub(crate) mod event;
-
-use super::{Assembler, Detector, Pulse, TracePoint};
-pub(crate) use event::{AssembleFilter, EventFilter};
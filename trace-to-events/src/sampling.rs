@@ -0,0 +1,187 @@
+//! Selects a sample of trace messages to be republished to a monitoring topic, so that a record of the waveforms is kept after they are processed.
+//!
+//! Sampled traces can be reduced in size by downsampling them, and by zeroing the least significant bits of each sample.
+use digital_muon_common::{FrameNumber, Intensity};
+use digital_muon_streaming_types::{
+    dat2_digitizer_analog_trace_v2_generated::{
+        ChannelTrace, ChannelTraceArgs, DigitizerAnalogTraceMessage,
+        DigitizerAnalogTraceMessageArgs, finish_digitizer_analog_trace_message_buffer,
+    },
+    flatbuffers::FlatBufferBuilder,
+    frame_metadata_v2_generated::{FrameMetadataV2, FrameMetadataV2Args},
+};
+
+/// Determines which trace messages are sampled, and how they are reduced.
+#[derive(Clone, Debug)]
+pub(crate) struct TraceSampling {
+    /// Only the trace messages of one in this many frames, by frame number, are sampled.
+    pub(crate) every: FrameNumber,
+    /// Each sampled trace is downsampled by averaging each run of this many samples.
+    pub(crate) downsampling: u64,
+    /// This many of the least significant bits of each sample are zeroed.
+    pub(crate) dropped_bits: u8,
+}
+
+impl TraceSampling {
+    /// Returns `true` if the trace messages of the given frame are sampled.
+    pub(crate) fn selects(&self, frame_number: FrameNumber) -> bool {
+        frame_number % self.every.max(1) == 0
+    }
+
+    /// Returns `true` if sampled traces are republished unchanged.
+    pub(crate) fn is_lossless(&self) -> bool {
+        self.downsampling <= 1 && self.dropped_bits == 0
+    }
+
+    /// Downsamples, and zeroes the dropped bits of, the samples of a trace.
+    ///
+    /// Each run of `downsampling` samples is replaced by its mean, rounded to the nearest integer,
+    /// where the final run may be shorter than the others.
+    fn reduce(&self, voltage: &[Intensity]) -> Vec<Intensity> {
+        let mask = Intensity::MAX
+            .checked_shl(self.dropped_bits.into())
+            .unwrap_or_default();
+        voltage
+            .chunks(self.downsampling.max(1) as usize)
+            .map(|run| {
+                let sum: u64 = run.iter().copied().map(u64::from).sum();
+                let len = run.len() as u64;
+                let mean = (sum + len / 2) / len;
+                // The mean of a run of intensities is itself a valid intensity.
+                Intensity::try_from(mean).unwrap_or(Intensity::MAX) & mask
+            })
+            .collect()
+    }
+
+    /// Builds a reduced copy of a trace message, in which each trace is reduced, and the sample rate adjusted accordingly.
+    /// # Parameters
+    /// - fbb: the builder to which the reduced message is written.
+    /// - trace: the trace message to reduce.
+    pub(crate) fn reduce_message(
+        &self,
+        fbb: &mut FlatBufferBuilder,
+        trace: &DigitizerAnalogTraceMessage,
+    ) {
+        let metadata = FrameMetadataV2Args {
+            frame_number: trace.metadata().frame_number(),
+            period_number: trace.metadata().period_number(),
+            running: trace.metadata().running(),
+            protons_per_pulse: trace.metadata().protons_per_pulse(),
+            timestamp: trace.metadata().timestamp(),
+            veto_flags: trace.metadata().veto_flags(),
+        };
+        let metadata = FrameMetadataV2::create(fbb, &metadata);
+
+        let channels = trace
+            .channels()
+            .into_iter()
+            .flatten()
+            .map(|channel_trace| {
+                let voltage = channel_trace
+                    .voltage()
+                    .map(|voltage| self.reduce(&voltage.iter().collect::<Vec<_>>()))
+                    .unwrap_or_default();
+                let voltage = Some(fbb.create_vector(&voltage));
+                ChannelTrace::create(
+                    fbb,
+                    &ChannelTraceArgs {
+                        channel: channel_trace.channel(),
+                        voltage,
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let message = DigitizerAnalogTraceMessageArgs {
+            digitizer_id: trace.digitizer_id(),
+            metadata: Some(metadata),
+            sample_rate: trace.sample_rate() / self.downsampling.max(1),
+            channels: Some(fbb.create_vector(&channels)),
+        };
+        let message = DigitizerAnalogTraceMessage::create(fbb, &message);
+        finish_digitizer_analog_trace_message_buffer(fbb, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::trace_message;
+    use digital_muon_streaming_types::dat2_digitizer_analog_trace_v2_generated::root_as_digitizer_analog_trace_message;
+
+    fn sampling(downsampling: u64, dropped_bits: u8) -> TraceSampling {
+        TraceSampling {
+            every: 10,
+            downsampling,
+            dropped_bits,
+        }
+    }
+
+    #[test]
+    fn frames_selected() {
+        assert!(sampling(1, 0).selects(0));
+        assert!(sampling(1, 0).selects(30));
+        assert!(!sampling(1, 0).selects(31));
+        assert!(sampling(1, 0).is_lossless());
+        assert!(!sampling(2, 0).is_lossless());
+        assert!(!sampling(1, 4).is_lossless());
+    }
+
+    #[test]
+    fn traces_downsampled() {
+        assert_eq!(sampling(1, 0).reduce(&[3, 8, 5]), vec![3, 8, 5]);
+        assert_eq!(sampling(2, 0).reduce(&[3, 8, 5, 5, 7]), vec![6, 5, 7]);
+        assert_eq!(sampling(4, 0).reduce(&[]), vec![]);
+    }
+
+    #[test]
+    fn dropped_bits_zeroed() {
+        assert_eq!(
+            sampling(1, 4).reduce(&[15, 16, 0x1234]),
+            vec![0, 16, 0x1230]
+        );
+        assert_eq!(
+            sampling(2, 2).reduce(&[Intensity::MAX, Intensity::MAX]),
+            vec![Intensity::MAX - 3]
+        );
+    }
+
+    #[test]
+    fn message_reduced() {
+        let message = trace_message(4, 30, &[&[1, 2, 3, 4, 5], &[8, 8]]);
+        let message = root_as_digitizer_analog_trace_message(&message).unwrap();
+
+        let mut fbb = FlatBufferBuilder::new();
+        sampling(2, 0).reduce_message(&mut fbb, &message);
+        let reduced = root_as_digitizer_analog_trace_message(fbb.finished_data()).unwrap();
+
+        assert_eq!(reduced.digitizer_id(), 4);
+        assert_eq!(reduced.sample_rate(), 500_000_000);
+        assert_eq!(reduced.metadata().frame_number(), 30);
+        assert_eq!(
+            reduced.metadata().timestamp(),
+            message.metadata().timestamp()
+        );
+        let channels = reduced.channels().unwrap();
+        assert_eq!(channels.len(), 2);
+        assert_eq!(channels.get(1).channel(), 1);
+        assert_eq!(
+            channels
+                .get(0)
+                .voltage()
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>(),
+            vec![2, 4, 5]
+        );
+        assert_eq!(
+            channels
+                .get(1)
+                .voltage()
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>(),
+            vec![8]
+        );
+    }
+}
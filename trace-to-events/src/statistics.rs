@@ -0,0 +1,161 @@
+//! Estimates the live fraction, and the observed and true event rates, of each channel trace.
+//!
+//! After each event the detector is dead for a fixed time, during which no further event can be registered.
+//! Under this non-paralysable dead-time model, the true event rate is the number of events found divided by the live time of the trace.
+use crate::parameters::Mode;
+use digital_muon_common::{Channel, Time};
+use digital_muon_pulse_detection::Real;
+
+/// Returns the dead time, in ns, after each event found by the given mode.
+///
+/// This is the cool-off of the threshold discriminators, and zero for modes which have none.
+pub(crate) fn detector_dead_time(mode: &Mode, sample_time: Real) -> Real {
+    let cool_off = match mode {
+        Mode::FixedThresholdDiscriminator(parameters) => parameters.cool_off,
+        Mode::DifferentialThresholdDiscriminator(parameters) => parameters.cool_off,
+        Mode::AdvancedMuonDetector(_)
+        | Mode::ConstantFractionDiscriminator(_)
+        | Mode::TemplateFitDetector(_) => 0,
+    };
+    cool_off.max(0) as Real * sample_time
+}
+
+/// The live fraction and event rates of a single channel trace.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ChannelStatistics {
+    /// Fraction of the trace during which the detector could register an event.
+    pub(crate) live_fraction: Real,
+    /// Events found per microsecond of trace (MHz).
+    pub(crate) observed_rate: Real,
+    /// True events per microsecond (MHz), estimated under a non-paralysable dead-time model.
+    /// This is infinite if events were found but the detector was never live.
+    pub(crate) estimated_rate: Real,
+}
+
+impl ChannelStatistics {
+    /// Estimates the statistics of a channel trace from the times of its events.
+    /// # Parameters
+    /// - times: the times, in ns, of the events found in the trace.
+    /// - trace_duration: the duration of the trace, in ns.
+    /// - dead_time: the time, in ns, after each event for which the detector is dead.
+    pub(crate) fn new(times: &[Time], trace_duration: Real, dead_time: Real) -> Self {
+        if trace_duration <= 0.0 {
+            return Self {
+                live_fraction: 1.0,
+                observed_rate: 0.0,
+                estimated_rate: 0.0,
+            };
+        }
+
+        let mut times = times.iter().map(|&time| time as Real).collect::<Vec<_>>();
+        times.sort_by(Real::total_cmp);
+        // Each event's dead time is cut short by the next event, or the end of the trace.
+        let ends = times
+            .iter()
+            .skip(1)
+            .copied()
+            .chain(std::iter::once(trace_duration));
+        let dead = times
+            .iter()
+            .zip(ends)
+            .map(|(&start, end)| (end.min(trace_duration) - start).clamp(0.0, dead_time.max(0.0)))
+            .sum::<Real>();
+        let live_time = trace_duration - dead;
+
+        let num_events = times.len() as Real;
+        Self {
+            live_fraction: live_time / trace_duration,
+            observed_rate: 1_000.0 * num_events / trace_duration,
+            estimated_rate: if times.is_empty() {
+                0.0
+            } else {
+                1_000.0 * num_events / live_time
+            },
+        }
+    }
+}
+
+/// The statistics of every processed channel of a trace message, one entry per channel.
+#[derive(Debug, Default)]
+pub(crate) struct Statistics {
+    pub(crate) channel: Vec<Channel>,
+    pub(crate) live_fraction: Vec<f32>,
+    pub(crate) observed_rate: Vec<f32>,
+    pub(crate) estimated_rate: Vec<f32>,
+}
+
+impl Statistics {
+    pub(crate) fn push(&mut self, channel: Channel, statistics: &ChannelStatistics) {
+        self.channel.push(channel);
+        self.live_fraction.push(statistics.live_fraction as f32);
+        self.observed_rate.push(statistics.observed_rate as f32);
+        self.estimated_rate.push(statistics.estimated_rate as f32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parameters::{
+        ConstantFractionDiscriminatorParameters, FixedThresholdDiscriminatorParameters,
+    };
+
+    #[test]
+    fn dead_time_of_modes() {
+        let mode = Mode::FixedThresholdDiscriminator(FixedThresholdDiscriminatorParameters {
+            threshold: 5.0,
+            duration: 1,
+            cool_off: 10,
+        });
+        assert_eq!(detector_dead_time(&mode, 2.0), 20.0);
+
+        let mode = Mode::ConstantFractionDiscriminator(ConstantFractionDiscriminatorParameters {
+            threshold: 5.0,
+            fraction: 0.5,
+            delay: 3,
+        });
+        assert_eq!(detector_dead_time(&mode, 2.0), 0.0);
+    }
+
+    #[test]
+    fn quiet_channel() {
+        assert_eq!(
+            ChannelStatistics::new(&[], 1_000.0, 10.0),
+            ChannelStatistics {
+                live_fraction: 1.0,
+                observed_rate: 0.0,
+                estimated_rate: 0.0,
+            }
+        );
+    }
+
+    #[test]
+    fn dead_time_corrected() {
+        // Four events in 1 us, each followed by 100 ns of dead time.
+        assert_eq!(
+            ChannelStatistics::new(&[100, 300, 500, 700], 1_000.0, 100.0),
+            ChannelStatistics {
+                live_fraction: 0.6,
+                observed_rate: 4.0,
+                estimated_rate: 4_000.0 / 600.0,
+            }
+        );
+    }
+
+    #[test]
+    fn dead_time_truncated() {
+        // The dead time of the last event is cut short by the end of the trace,
+        // and the dead time of the first by the second event, which is out of order.
+        let statistics = ChannelStatistics::new(&[950, 0, 50], 1_000.0, 100.0);
+        assert_eq!(statistics.live_fraction, 0.8);
+        assert_eq!(statistics.observed_rate, 3.0);
+    }
+
+    #[test]
+    fn saturated_channel() {
+        let statistics = ChannelStatistics::new(&[0, 100], 200.0, 100.0);
+        assert_eq!(statistics.live_fraction, 0.0);
+        assert_eq!(statistics.observed_rate, 10.0);
+        assert!(statistics.estimated_rate.is_infinite());
+    }
+}
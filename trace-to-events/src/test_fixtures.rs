@@ -0,0 +1,100 @@
+//! Builds the trace messages and detector settings shared by the tests of several modules.
+use crate::parameters::{
+    ChannelDetectorSettings, DetectorSettings, FixedThresholdDiscriminatorParameters, Mode,
+    Polarity,
+};
+use digital_muon_common::{Channel, DigitizerId, FrameNumber, Intensity};
+use digital_muon_streaming_types::{
+    dat2_digitizer_analog_trace_v2_generated::{
+        ChannelTrace, ChannelTraceArgs, DigitizerAnalogTraceMessage,
+        DigitizerAnalogTraceMessageArgs, finish_digitizer_analog_trace_message_buffer,
+    },
+    flatbuffers::{FlatBufferBuilder, WIPOffset},
+    frame_metadata_v2_generated::{FrameMetadataV2, FrameMetadataV2Args, GpsTime},
+};
+
+/// The timestamp of the messages built by [trace_message].
+pub(crate) fn timestamp() -> GpsTime {
+    GpsTime::new(24, 1, 0, 0, 0, 0, 0, 0)
+}
+
+/// Writes the metadata of a running frame to the builder.
+pub(crate) fn create_metadata<'a>(
+    fbb: &mut FlatBufferBuilder<'a>,
+    frame_number: FrameNumber,
+    time: &GpsTime,
+) -> WIPOffset<FrameMetadataV2<'a>> {
+    FrameMetadataV2::create(
+        fbb,
+        &FrameMetadataV2Args {
+            frame_number,
+            timestamp: Some(time),
+            running: true,
+            ..Default::default()
+        },
+    )
+}
+
+/// Writes a trace message, sampled at 1 GHz, to the builder.
+/// # Parameters
+/// - fbb: the builder to which the message is written.
+/// - digitizer_id: the digitiser which sent the message.
+/// - frame_number: the frame number of the message.
+/// - time: the timestamp of the message.
+/// - channels: the trace of each channel, where channel `i` has the `i`th trace.
+pub(crate) fn create_trace_message(
+    fbb: &mut FlatBufferBuilder<'_>,
+    digitizer_id: DigitizerId,
+    frame_number: FrameNumber,
+    time: &GpsTime,
+    channels: &[&[Intensity]],
+) {
+    let metadata = create_metadata(fbb, frame_number, time);
+    let channel_traces: Vec<_> = channels
+        .iter()
+        .enumerate()
+        .map(|(i, intensities)| {
+            let voltage = Some(fbb.create_vector(intensities));
+            ChannelTrace::create(
+                fbb,
+                &ChannelTraceArgs {
+                    channel: i as Channel,
+                    voltage,
+                },
+            )
+        })
+        .collect();
+    let message = DigitizerAnalogTraceMessageArgs {
+        digitizer_id,
+        metadata: Some(metadata),
+        sample_rate: 1_000_000_000,
+        channels: Some(fbb.create_vector(&channel_traces)),
+    };
+    let message = DigitizerAnalogTraceMessage::create(fbb, &message);
+    finish_digitizer_analog_trace_message_buffer(fbb, message);
+}
+
+/// Returns a trace message, as written by [create_trace_message], with the fixed [timestamp].
+pub(crate) fn trace_message(
+    digitizer_id: DigitizerId,
+    frame_number: FrameNumber,
+    channels: &[&[Intensity]],
+) -> Vec<u8> {
+    let mut fbb = FlatBufferBuilder::new();
+    create_trace_message(&mut fbb, digitizer_id, frame_number, &timestamp(), channels);
+    fbb.finished_data().to_vec()
+}
+
+/// Returns settings which find events of positive pulses above a fixed threshold of 5 on every channel.
+pub(crate) fn detector_settings() -> DetectorSettings {
+    DetectorSettings::new(ChannelDetectorSettings {
+        polarity: Polarity::Positive,
+        baseline: 0,
+        mode: Mode::FixedThresholdDiscriminator(FixedThresholdDiscriminatorParameters {
+            threshold: 5.0,
+            duration: 1,
+            cool_off: 0,
+        }),
+        filters: Vec::new(),
+    })
+}
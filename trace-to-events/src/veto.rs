@@ -0,0 +1,164 @@
+//! Decides how each frame is handled according to its veto flags and running flag.
+use clap::ValueEnum;
+
+/// What is done with the traces of a frame which is vetoed for some reason.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub(crate) enum VetoAction {
+    /// The frame is processed as normal.
+    #[default]
+    Pass,
+    /// The frame is processed, and its event list is flagged as vetoed.
+    Tag,
+    /// The frame is not processed, and no event list is produced.
+    Drop,
+}
+
+impl VetoAction {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            VetoAction::Pass => "pass",
+            VetoAction::Tag => "tag",
+            VetoAction::Drop => "drop",
+        }
+    }
+}
+
+/// A reason for which a frame is vetoed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum VetoReason {
+    /// The given bit of the frame's veto flags is set.
+    VetoFlag(u32),
+    /// The frame's running flag is not set.
+    NotRunning,
+}
+
+impl VetoReason {
+    pub(crate) fn label(&self) -> String {
+        match self {
+            VetoReason::VetoFlag(bit) => format!("veto-flag-{bit}"),
+            VetoReason::NotRunning => "not-running".to_owned(),
+        }
+    }
+}
+
+/// Parses a veto flag mask, given in decimal, or in hexadecimal or binary with a `0x` or `0b` prefix.
+pub(crate) fn parse_veto_mask(mask: &str) -> Result<u16, std::num::ParseIntError> {
+    if let Some(hex) = mask.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16)
+    } else if let Some(bin) = mask.strip_prefix("0b") {
+        u16::from_str_radix(bin, 2)
+    } else {
+        mask.parse()
+    }
+}
+
+/// The action to take for frames with each veto flag set, and for frames which are not running.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct VetoPolicy {
+    /// Frames with any of these veto flags set are dropped.
+    pub(crate) drop_mask: u16,
+    /// Frames with any of these veto flags set, but none of `drop_mask`, are tagged.
+    pub(crate) tag_mask: u16,
+    /// The action to take for frames whose running flag is not set.
+    pub(crate) not_running: VetoAction,
+}
+
+impl VetoPolicy {
+    /// Returns the action to take for a frame, together with the reasons for which it is taken.
+    ///
+    /// If more than one reason applies, the most severe action is taken, and only the reasons which call for it are returned.
+    /// # Parameters
+    /// - veto_flags: the veto flags of the frame's metadata.
+    /// - running: the running flag of the frame's metadata.
+    pub(crate) fn decide(&self, veto_flags: u16, running: bool) -> (VetoAction, Vec<VetoReason>) {
+        let flag_actions = (0..u16::BITS)
+            .filter(|bit| veto_flags & (1 << bit) != 0)
+            .map(|bit| {
+                let action = if self.drop_mask & (1 << bit) != 0 {
+                    VetoAction::Drop
+                } else if self.tag_mask & (1 << bit) != 0 {
+                    VetoAction::Tag
+                } else {
+                    VetoAction::Pass
+                };
+                (action, VetoReason::VetoFlag(bit))
+            });
+        let running_action = (!running).then_some((self.not_running, VetoReason::NotRunning));
+        let actions = flag_actions.chain(running_action).collect::<Vec<_>>();
+
+        let action = actions
+            .iter()
+            .map(|(action, _)| *action)
+            .max()
+            .unwrap_or_default();
+        let reasons = actions
+            .into_iter()
+            .filter(|(a, _)| *a == action && action != VetoAction::Pass)
+            .map(|(_, reason)| reason)
+            .collect();
+        (action, reasons)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> VetoPolicy {
+        VetoPolicy {
+            drop_mask: 0b0001,
+            tag_mask: 0b0110,
+            not_running: VetoAction::Tag,
+        }
+    }
+
+    #[test]
+    fn unvetoed_frame_passes() {
+        assert_eq!(policy().decide(0, true), (VetoAction::Pass, vec![]));
+        // Flags outside both masks are ignored.
+        assert_eq!(policy().decide(0b1000, true), (VetoAction::Pass, vec![]));
+    }
+
+    #[test]
+    fn most_severe_action_taken() {
+        assert_eq!(
+            policy().decide(0b0110, true),
+            (
+                VetoAction::Tag,
+                vec![VetoReason::VetoFlag(1), VetoReason::VetoFlag(2)]
+            )
+        );
+        assert_eq!(
+            policy().decide(0b0010, false),
+            (
+                VetoAction::Tag,
+                vec![VetoReason::VetoFlag(1), VetoReason::NotRunning]
+            )
+        );
+        assert_eq!(
+            policy().decide(0b0011, false),
+            (VetoAction::Drop, vec![VetoReason::VetoFlag(0)])
+        );
+    }
+
+    #[test]
+    fn not_running_dropped() {
+        let policy = VetoPolicy {
+            not_running: VetoAction::Drop,
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.decide(0b0100, false),
+            (VetoAction::Drop, vec![VetoReason::NotRunning])
+        );
+        assert_eq!(policy.decide(0b0100, true), (VetoAction::Pass, vec![]));
+    }
+
+    #[test]
+    fn veto_masks_parsed() {
+        assert_eq!(parse_veto_mask("5"), Ok(5));
+        assert_eq!(parse_veto_mask("0x1f"), Ok(0x1f));
+        assert_eq!(parse_veto_mask("0b101"), Ok(5));
+        assert!(parse_veto_mask("0x10000").is_err());
+    }
+}